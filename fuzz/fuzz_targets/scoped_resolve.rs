@@ -0,0 +1,78 @@
+// Copyright (c) 2022 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Fuzz `scoped_resolve()` and `safe_join()` against a symlink maze built from the input.
+//!
+//! The input is split by NUL bytes: the first byte of the first chunk selects the root among
+//! the directories of the maze, the last chunk is the path to resolve, and the chunks between
+//! them are the targets of symlinks placed in the maze. A directory next to the root holds the
+//! symlinks of the maze which point outside, and must never be reached.
+
+#![no_main]
+
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::symlink;
+use std::path::{Component, Path};
+
+use libfuzzer_sys::fuzz_target;
+use safe_path::{safe_join, scoped_resolve};
+
+// The directories of the maze, relative to the top of the maze.
+const DIRS: &[&str] = &["root", "root/a", "root/a/b", "root/c"];
+// At most so many symlinks are placed in the maze.
+const MAX_LINKS: usize = 16;
+
+fuzz_target!(|data: &[u8]| {
+    let mut chunks: Vec<&[u8]> = data.split(|&b| b == 0).collect();
+    let unsafe_path = Path::new(OsStr::from_bytes(chunks.pop().unwrap_or_default()));
+    let selector = chunks.first().and_then(|c| c.first()).copied().unwrap_or(0);
+
+    let maze = tempfile::tempdir().unwrap();
+    let top = maze.path().canonicalize().unwrap();
+    for dir in DIRS.iter().chain(["outside"].iter()) {
+        fs::create_dir(top.join(dir)).unwrap();
+    }
+    // Targets escaping the root the usual ways.
+    symlink("../../outside", top.join("root/a/up")).unwrap();
+    symlink(top.join("outside"), top.join("root/c/abs")).unwrap();
+    for (i, target) in chunks.iter().skip(1).take(MAX_LINKS).enumerate() {
+        let link = top.join(DIRS[i % DIRS.len()]).join(format!("l{}", i));
+        // Invalid targets, such as empty ones, are simply not placed.
+        let _ = symlink(OsStr::from_bytes(target), link);
+    }
+
+    let root = top.join(DIRS[selector as usize % DIRS.len()]);
+    if let Ok(path) = scoped_resolve(&root, unsafe_path) {
+        assert!(
+            path.components().all(|c| matches!(c, Component::Normal(_))),
+            "{:?} resolved to {:?} which is not lexically under the root",
+            unsafe_path,
+            path
+        );
+    }
+    if let Ok(path) = safe_join(&root, unsafe_path) {
+        let suffix = path.strip_prefix(&root).unwrap_or_else(|_| {
+            panic!("{:?} joined to {:?} outside of {:?}", unsafe_path, path, root)
+        });
+        assert!(
+            suffix.components().all(|c| matches!(c, Component::Normal(_))),
+            "{:?} joined to {:?} which is not lexically under the root",
+            unsafe_path,
+            path
+        );
+        // Every symlink on the way has been resolved, so the existing target is where it says.
+        if let Ok(real) = path.canonicalize() {
+            assert!(
+                real.starts_with(&root),
+                "{:?} joined to {:?} which opens {:?} outside of the root",
+                unsafe_path,
+                path,
+                real
+            );
+        }
+    }
+});
@@ -0,0 +1,155 @@
+// Copyright (c) 2022 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+/// The xattr storing the access ACL of a file.
+pub(crate) const ACL_ACCESS_XATTR: &str = "system.posix_acl_access";
+/// The xattr storing the default ACL of a directory, inherited by new files in it.
+pub(crate) const ACL_DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+const ACL_XATTR_VERSION: u32 = 2;
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+
+/// The qualifier of an [AclEntry], which decides who the entry applies to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AclTag {
+    /// The owner of the file, `ACL_USER_OBJ`.
+    UserObj,
+    /// The user with the uid, `ACL_USER`.
+    User(u32),
+    /// The owning group of the file, `ACL_GROUP_OBJ`.
+    GroupObj,
+    /// The group with the gid, `ACL_GROUP`.
+    Group(u32),
+    /// The upper bound of the permissions granted to the named users and all groups,
+    /// `ACL_MASK`.
+    Mask,
+    /// Everyone else, `ACL_OTHER`.
+    Other,
+}
+
+impl AclTag {
+    /// Get the tag and the id encoded in the xattr.
+    fn to_raw(self) -> (u16, u32) {
+        match self {
+            AclTag::UserObj => (0x01, ACL_UNDEFINED_ID),
+            AclTag::User(uid) => (0x02, uid),
+            AclTag::GroupObj => (0x04, ACL_UNDEFINED_ID),
+            AclTag::Group(gid) => (0x08, gid),
+            AclTag::Mask => (0x10, ACL_UNDEFINED_ID),
+            AclTag::Other => (0x20, ACL_UNDEFINED_ID),
+        }
+    }
+}
+
+/// An entry of a POSIX ACL, as set by `setfacl(1)`, such as `g:1000:rwx` for
+/// `AclEntry::new(AclTag::Group(1000), 0o7)`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AclEntry {
+    /// Who the entry applies to.
+    pub tag: AclTag,
+    /// The permissions granted, a combination of read `0o4`, write `0o2` and execute `0o1`.
+    pub perm: u16,
+}
+
+impl AclEntry {
+    /// Create an entry granting `perm` to `tag`.
+    pub fn new(tag: AclTag, perm: u16) -> Self {
+        AclEntry { tag, perm }
+    }
+}
+
+/// Encode `entries` in the format of the `system.posix_acl_*` xattrs.
+///
+/// The kernel requires the entries ordered by tag and id, so they are sorted first. Whether the
+/// ACL is complete, such as having a mask with named entries, is left to the kernel to check.
+pub(crate) fn encode(entries: &[AclEntry]) -> Vec<u8> {
+    let mut raw: Vec<_> = entries
+        .iter()
+        .map(|e| (e.tag.to_raw(), e.perm & 0o7))
+        .collect();
+    raw.sort_unstable();
+
+    let mut buf = Vec::with_capacity(4 + raw.len() * 8);
+    buf.extend_from_slice(&ACL_XATTR_VERSION.to_le_bytes());
+    for ((tag, id), perm) in raw {
+        buf.extend_from_slice(&tag.to_le_bytes());
+        buf.extend_from_slice(&perm.to_le_bytes());
+        buf.extend_from_slice(&id.to_le_bytes());
+    }
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decode the `system.posix_acl_*` xattr format, the reverse of [encode()].
+    fn decode(buf: &[u8]) -> Vec<AclEntry> {
+        assert_eq!(buf[..4], ACL_XATTR_VERSION.to_le_bytes());
+        buf[4..]
+            .chunks(8)
+            .map(|c| {
+                let tag = u16::from_le_bytes([c[0], c[1]]);
+                let perm = u16::from_le_bytes([c[2], c[3]]);
+                let id = u32::from_le_bytes([c[4], c[5], c[6], c[7]]);
+                let tag = match tag {
+                    0x01 => AclTag::UserObj,
+                    0x02 => AclTag::User(id),
+                    0x04 => AclTag::GroupObj,
+                    0x08 => AclTag::Group(id),
+                    0x10 => AclTag::Mask,
+                    0x20 => AclTag::Other,
+                    _ => panic!("unknown tag {:#x}", tag),
+                };
+                AclEntry::new(tag, perm)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_acl_encode() {
+        assert_eq!(encode(&[]), b"\x02\x00\x00\x00");
+
+        // getfattr -e hex -n system.posix_acl_access after `setfacl -m u::rwx,g::r-x,o::---`.
+        let entries = [
+            AclEntry::new(AclTag::Other, 0),
+            AclEntry::new(AclTag::UserObj, 0o7),
+            AclEntry::new(AclTag::GroupObj, 0o5),
+        ];
+        let expected = b"\x02\x00\x00\x00\
+            \x01\x00\x07\x00\xff\xff\xff\xff\
+            \x04\x00\x05\x00\xff\xff\xff\xff\
+            \x20\x00\x00\x00\xff\xff\xff\xff";
+        assert_eq!(encode(&entries), &expected[..]);
+
+        // `setfacl -m u::rwx,u:1000:rw-,g::r-x,g:100:r--,m::rwx,o::r--`, named entries are
+        // sorted by id.
+        let entries = [
+            AclEntry::new(AclTag::UserObj, 0o7),
+            AclEntry::new(AclTag::User(1000), 0o6),
+            AclEntry::new(AclTag::User(2), 0o4),
+            AclEntry::new(AclTag::GroupObj, 0o5),
+            AclEntry::new(AclTag::Group(100), 0o4),
+            AclEntry::new(AclTag::Mask, 0o7),
+            AclEntry::new(AclTag::Other, 0o4),
+        ];
+        let expected = b"\x02\x00\x00\x00\
+            \x01\x00\x07\x00\xff\xff\xff\xff\
+            \x02\x00\x04\x00\x02\x00\x00\x00\
+            \x02\x00\x06\x00\xe8\x03\x00\x00\
+            \x04\x00\x05\x00\xff\xff\xff\xff\
+            \x08\x00\x04\x00\x64\x00\x00\x00\
+            \x10\x00\x07\x00\xff\xff\xff\xff\
+            \x20\x00\x04\x00\xff\xff\xff\xff";
+        let encoded = encode(&entries);
+        assert_eq!(encoded, &expected[..]);
+
+        let mut sorted = entries.to_vec();
+        sorted.sort_by_key(|e| e.tag.to_raw());
+        assert_eq!(decode(&encoded), sorted);
+    }
+}
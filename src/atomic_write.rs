@@ -0,0 +1,196 @@
+// Copyright (c) 2022 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::fs::File;
+use std::io::{Error, Result, Write};
+use std::os::unix::io::{AsRawFd, OwnedFd};
+use std::path::Path;
+
+use crate::safe_copy::create_temp;
+use crate::safe_link::walk_parent;
+use crate::sys;
+
+/// Safely replace the content of the file `unsafe_path` scoped under `root` with `contents`,
+/// atomically even across a crash.
+///
+/// The parent directory of `unsafe_path`, which must exist, is resolved with the same rules as
+/// [crate::safe_open_handle()]. A uniquely named temporary file is created next to the
+/// destination by `openat(O_CREAT | O_EXCL | O_NOFOLLOW)` relative to the pinned fd of the
+/// parent, set to exactly `mode` regardless of the umask, filled with `contents` and flushed by
+/// `fsync()`. It's then moved over the destination by `renameat()` on the pinned fd of the
+/// parent, which is finally flushed too. So readers and a crash at any point see either the
+/// whole old content or the whole new one, never a partial file. The temporary file is removed
+/// if any step fails, but it may be left behind by a crash, named after the destination with a
+/// `.` prefix and a random suffix.
+///
+/// If the destination is a symlink, the symlink itself is replaced by the new file, and its
+/// target is never written, so a symlink squatting at the destination can't redirect the write.
+///
+/// # Errors
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | `root` or the parent directory of `unsafe_path` doesn't exist | `NotFound` |
+/// | `root` or a path component is not a directory | `NotADirectory` |
+/// | the destination is a directory | `IsADirectory` |
+/// | too many levels of symlinks | `FilesystemLoop` |
+/// | the final component is missing, `.` or `..` | `InvalidFilename` |
+/// | the path contains invalid component | `InvalidFilename` |
+pub fn atomic_write<R: AsRef<Path>, U: AsRef<Path>>(
+    root: R,
+    unsafe_path: U,
+    contents: &[u8],
+    mode: u32,
+) -> Result<()> {
+    let (walk, name) = walk_parent(root.as_ref(), unsafe_path.as_ref())?;
+    let parent = walk.fd();
+    let (tmp_name, fd) = create_temp(parent, name, mode)?;
+
+    let result =
+        write_temp(fd, contents, mode).and_then(|_| sys::renameat(parent, &tmp_name, parent, name));
+    if let Err(e) = result {
+        let _ = sys::unlinkat(parent, &tmp_name, 0);
+        return Err(e);
+    }
+    sys::fsync_dir(parent)
+}
+
+/// Set the mode of the temporary file `fd`, fill it with `contents` and flush it.
+fn write_temp(fd: OwnedFd, contents: &[u8], mode: u32) -> Result<()> {
+    // The process umask has been applied by openat(), so set the exact mode. The fd is opened
+    // for writing, so fchmod() takes it as is.
+    // Safe because fchmod() doesn't touch any memory.
+    if unsafe { libc::fchmod(fd.as_raw_fd(), mode as libc::mode_t) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    let mut file = File::from(fd);
+    file.write_all(contents)?;
+    sys::fsync(&file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{Maze, HOST_FILE};
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_atomic_write() {
+        let maze = Maze::new();
+        maze.dir("a").symlink("link_dir", "/a");
+        let rootfs_path = maze.root();
+        let entries = || fs::read_dir(rootfs_path.join("a")).unwrap().count();
+
+        atomic_write(rootfs_path, "../link_dir/state", b"old", 0o600).unwrap();
+        assert_eq!(fs::read(rootfs_path.join("a/state")).unwrap(), b"old");
+        let meta = fs::metadata(rootfs_path.join("a/state")).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o600);
+
+        atomic_write(rootfs_path, "a/state", b"new content", 0o644).unwrap();
+        assert_eq!(
+            fs::read(rootfs_path.join("a/state")).unwrap(),
+            b"new content"
+        );
+        let meta = fs::metadata(rootfs_path.join("a/state")).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o644);
+        assert_eq!(entries(), 1);
+
+        let long = "n".repeat(255);
+        atomic_write(rootfs_path, format!("a/{}", long), b"long", 0o644).unwrap();
+        assert_eq!(
+            fs::read(rootfs_path.join("a").join(&long)).unwrap(),
+            b"long"
+        );
+
+        let err = atomic_write(rootfs_path, "link_dir/..//a", b"x", 0o644).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::IsADirectory);
+        let err = atomic_write(rootfs_path, "b/state", b"x", 0o644).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let err = atomic_write(rootfs_path, "a/..", b"x", 0o644).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidFilename);
+        assert_eq!(entries(), 2);
+    }
+
+    #[test]
+    fn test_atomic_write_symlink() {
+        let maze = Maze::new();
+        maze.symlink("hostname", maze.host().join(HOST_FILE));
+        let rootfs_path = maze.root();
+
+        // The symlink is replaced, its target is never written.
+        atomic_write(rootfs_path, "hostname", b"container", 0o644).unwrap();
+        let meta = fs::symlink_metadata(rootfs_path.join("hostname")).unwrap();
+        assert!(meta.file_type().is_file());
+        assert_eq!(
+            fs::read(rootfs_path.join("hostname")).unwrap(),
+            b"container"
+        );
+        maze.assert_escapes_rejected(|escape| {
+            atomic_write(rootfs_path, escape.join(HOST_FILE), b"x", 0o644)
+        });
+    }
+
+    #[test]
+    fn test_atomic_write_failed() {
+        let maze = Maze::new();
+        let rootfs_path = maze.root();
+        let old = vec![b'o'; 1 << 20];
+        let new = vec![b'n'; 4 << 20];
+        atomic_write(rootfs_path, "state", &old, 0o644).unwrap();
+
+        // Failing after the temporary file is written, either to flush or to move it, leaves the
+        // old content and no temporary file.
+        for syscall in ["fsync", "renameat"].iter() {
+            sys::inject_error(syscall, libc::EIO);
+            let err = atomic_write(rootfs_path, "state", &new, 0o644).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EIO));
+            assert_eq!(fs::read(rootfs_path.join("state")).unwrap(), old);
+            assert_eq!(fs::read_dir(rootfs_path).unwrap().count(), 1);
+        }
+    }
+
+    #[test]
+    fn test_atomic_write_crashed() {
+        // Crash in a child process running only this test.
+        const CHILD_ENV: &str = "SAFE_PATH_TEST_ATOMIC_WRITE_CRASHED_CHILD";
+        if let Some(root) = std::env::var_os(CHILD_ENV) {
+            sys::inject_crash("renameat");
+            let _ = atomic_write(root, "state", b"new", 0o644);
+            unreachable!();
+        }
+
+        let maze = Maze::new();
+        let rootfs_path = maze.root();
+        let old = vec![b'o'; 1 << 20];
+        atomic_write(rootfs_path, "state", &old, 0o644).unwrap();
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "atomic_write::tests::test_atomic_write_crashed",
+                "--test-threads=1",
+            ])
+            .env(CHILD_ENV, rootfs_path)
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+
+        // Killed between writing the temporary file and renaming it, the whole old content is
+        // still there, and the complete temporary file is left behind.
+        assert_eq!(fs::read(rootfs_path.join("state")).unwrap(), old);
+        let names: Vec<_> = fs::read_dir(rootfs_path)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name != "state")
+            .collect();
+        assert_eq!(names.len(), 1);
+        assert!(names[0].starts_with(".state."), "{}", names[0]);
+        assert_eq!(fs::read(rootfs_path.join(&names[0])).unwrap(), b"new");
+
+        // A later write still succeeds.
+        atomic_write(rootfs_path, "state", b"newer", 0o644).unwrap();
+        assert_eq!(fs::read(rootfs_path.join("state")).unwrap(), b"newer");
+    }
+}
@@ -0,0 +1,123 @@
+// Copyright (c) 2022 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Audit events of path resolutions through the `tracing` crate.
+
+use std::borrow::Cow;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+
+use crate::{CreatedDir, SafePathBuf, SafePathError};
+
+/// Result of a path resolution which may be reported.
+pub(crate) trait Resolved {
+    fn resolved(&self) -> &Path;
+}
+
+impl Resolved for PathBuf {
+    fn resolved(&self) -> &Path {
+        self
+    }
+}
+
+impl Resolved for Cow<'_, Path> {
+    fn resolved(&self) -> &Path {
+        self
+    }
+}
+
+impl Resolved for SafePathBuf {
+    fn resolved(&self) -> &Path {
+        self.target()
+    }
+}
+
+impl Resolved for CreatedDir {
+    fn resolved(&self) -> &Path {
+        self.path.target()
+    }
+}
+
+/// Report the result of a path resolution as an event of the current span.
+pub(crate) fn report<T: Resolved>(result: &Result<T>) {
+    match result {
+        Ok(v) => tracing::debug!(resolved = %v.resolved().display(), "path resolved"),
+        Err(e) => match SafePathError::from_io_error(e) {
+            Some(SafePathError::OutsideRoot { .. })
+            | Some(SafePathError::TargetChanged { .. })
+            | Some(SafePathError::OwnerMismatch { .. })
+            | Some(SafePathError::ForbiddenFilesystem { .. })
+            | Some(SafePathError::ForbiddenComponent { .. })
+            | Some(SafePathError::HardlinkedFile { .. })
+            | Some(SafePathError::SymlinkEncountered { .. }) => {
+                tracing::warn!(error = %e, "path rejected")
+            }
+            _ => tracing::debug!(error = %e, "path resolution failed"),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{safe_join, SafeDirBuilder, SafePathBuf};
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_tracing_events() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        std::fs::create_dir(rootfs_path.join("a")).unwrap();
+        std::os::unix::fs::symlink("/a", rootfs_path.join("s")).unwrap();
+
+        let capture = Capture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            safe_join(rootfs_path, "s/b").unwrap();
+            SafePathBuf::new(rootfs_path, "s").unwrap();
+            SafeDirBuilder::new(rootfs_path)
+                .unwrap()
+                .create("/outside")
+                .unwrap_err();
+        });
+
+        let output = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        let root = rootfs_path.display();
+        assert!(lines.iter().any(|l| l.contains("DEBUG")
+            && l.contains(&format!(
+                "safe_join{{root={} input=s/b flags=follow}}",
+                root
+            ))
+            && l.contains(&format!("resolved={}/a/b", root))));
+        assert!(lines.iter().any(|l| l.contains("DEBUG")
+            && l.contains("SafePathBuf::new")
+            && l.contains(&format!("resolved={}/a", root))));
+        assert!(lines.iter().any(|l| l.contains("WARN")
+            && l.contains("SafeDirBuilder::create")
+            && l.contains("input=/outside")
+            && l.contains("flags=recursive=false")
+            && l.contains("error=")));
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright (c) 2022 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Command line interface to safely handle paths scoped under a root directory, for shell
+//! scripts.
+//!
+//! ```text
+//! safe-path join [--no-follow] [--strict] <root> <path>
+//! safe-path resolve [--no-follow] [--strict] <root> <path>
+//! safe-path mkdir [--mode MODE] <root> <path>
+//! ```
+//!
+//! `--no-follow` doesn't follow a symlink at the final component, which must exist, see
+//! `SafePathBuf::new_nofollow()`. `--strict` fails on a non-directory followed by another
+//! component, see `ResolveOptions::stop_at_non_directory()`.
+//!
+//! The result is printed on stdout, byte for byte. On failure, the name of the typed error, or
+//! the error kind if there's none, and the message are printed on stderr and the exit code is 1.
+//! Invalid usage exits with code 2.
+
+use std::ffi::{OsStr, OsString};
+use std::io::{Error, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use safe_path::{
+    safe_join_or_create, scoped_resolve_with, ResolveOptions, SafePathBuf, SafePathError,
+};
+
+const USAGE: &str = "Usage:
+    safe-path join [--no-follow] [--strict] <root> <path>
+    safe-path resolve [--no-follow] [--strict] <root> <path>
+    safe-path mkdir [--mode MODE] <root> <path>";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    exit(2);
+}
+
+#[derive(Default)]
+struct Options {
+    no_follow: bool,
+    strict: bool,
+    mode: Option<u32>,
+}
+
+/// Split the leading options from the positional arguments.
+fn parse_options(mut args: &[OsString]) -> (Options, &[OsString]) {
+    let mut opts = Options::default();
+    while let Some((arg, rest)) = args.split_first() {
+        match arg.to_str() {
+            Some("--no-follow") => opts.no_follow = true,
+            Some("--strict") => opts.strict = true,
+            Some("--mode") => {
+                let (mode, rest) = rest.split_first().unwrap_or_else(|| usage());
+                let mode = mode.to_str().and_then(|m| u32::from_str_radix(m, 8).ok());
+                opts.mode = Some(mode.unwrap_or_else(|| usage()));
+                args = rest;
+                continue;
+            }
+            Some(opt) if opt.starts_with("--") => usage(),
+            _ => break,
+        }
+        args = rest;
+    }
+    (opts, args)
+}
+
+fn resolve(root: &OsStr, path: &OsStr, opts: &Options, join: bool) -> Result<PathBuf, Error> {
+    if opts.no_follow {
+        // Opening every component already fails on a non-directory followed by another one.
+        let path = SafePathBuf::new_nofollow(root, path)?;
+        return match join {
+            true => Ok(path.target().to_path_buf()),
+            false => Ok(path.relative_to_root(root).unwrap_or_default()),
+        };
+    }
+    let mut resolve_opts = ResolveOptions::new();
+    resolve_opts.stop_at_non_directory(opts.strict);
+    let path = scoped_resolve_with(root, path, &resolve_opts)?;
+    // The same as safe_join() without options.
+    match join {
+        true => Ok(Path::new(root).canonicalize()?.join(path)),
+        false => Ok(path),
+    }
+}
+
+fn run(args: &[OsString]) -> Result<PathBuf, Error> {
+    let (cmd, args) = args.split_first().unwrap_or_else(|| usage());
+    let (opts, args) = parse_options(args);
+    let (root, path) = match args {
+        [root, path] => (root.as_os_str(), path.as_os_str()),
+        _ => usage(),
+    };
+    match cmd.to_str() {
+        Some("join") if opts.mode.is_none() => resolve(root, path, &opts, true),
+        Some("resolve") if opts.mode.is_none() => resolve(root, path, &opts, false),
+        Some("mkdir") if !opts.no_follow && !opts.strict => {
+            safe_join_or_create(root, path, opts.mode.unwrap_or(0o755), false)
+                .map(|p| p.target().to_path_buf())
+        }
+        _ => usage(),
+    }
+}
+
+/// Get the name of the typed error carried by `err`, or of its kind.
+fn error_name(err: &Error) -> String {
+    match SafePathError::from_io_error(err) {
+        Some(e) => {
+            let debug = format!("{:?}", e);
+            let end = debug
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(debug.len());
+            debug[..end].to_string()
+        }
+        None => format!("{:?}", err.kind()),
+    }
+}
+
+fn main() {
+    let args: Vec<OsString> = std::env::args_os().skip(1).collect();
+    match run(&args) {
+        Ok(path) => {
+            let mut stdout = std::io::stdout().lock();
+            let _ = stdout
+                .write_all(path.as_os_str().as_bytes())
+                .and_then(|_| stdout.write_all(b"\n"));
+        }
+        Err(e) => {
+            eprintln!("{}: {}", error_name(&e), e);
+            exit(1);
+        }
+    }
+}
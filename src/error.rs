@@ -0,0 +1,590 @@
+// Copyright (c) 2022 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::FileType;
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+
+/// Failures detected by this crate when handling paths.
+///
+/// All functions of this crate return [std::io::Error] to match the `std::fs` APIs. When a
+/// failure is detected by the crate itself instead of by a syscall, the returned `io::Error` has
+/// an [ErrorKind] describing the failure class and carries a `SafePathError` as its inner error,
+/// which may be retrieved by [SafePathError::from_io_error()].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SafePathError {
+    /// The root path is invalid, such as not being an absolute path.
+    InvalidRoot {
+        /// The invalid root path.
+        root: PathBuf,
+    },
+    /// The path contains a component which is invalid on Linux, such as a Windows prefix or
+    /// a name with embedded NUL byte.
+    InvalidComponent {
+        /// The path containing the invalid component.
+        path: PathBuf,
+    },
+    /// Too many levels of symlinks were expanded when resolving the path.
+    TooManySymlinks {
+        /// The path being resolved.
+        path: PathBuf,
+    },
+    /// A path component which should be a directory is not a directory.
+    NotADirectory {
+        /// The path of the offending component.
+        path: PathBuf,
+    },
+    /// The path is outside of the root directory.
+    OutsideRoot {
+        /// The offending path.
+        path: PathBuf,
+        /// The root directory.
+        root: PathBuf,
+    },
+    /// A path component resides on a filesystem type which is forbidden by the caller.
+    ForbiddenFilesystem {
+        /// The path of the offending component.
+        path: PathBuf,
+        /// The filesystem type, as reported by `statfs(2)`.
+        fs_type: i64,
+    },
+    /// A path component has a name forbidden by the caller, such as starting with a forbidden
+    /// prefix.
+    ForbiddenComponent {
+        /// The name of the offending component.
+        name: OsString,
+    },
+    /// A regular file has more than one hard link, so it may alias an inode outside of the root.
+    HardlinkedFile {
+        /// The path of the file.
+        path: PathBuf,
+        /// The number of hard links.
+        nlink: u64,
+    },
+    /// The target of the path changed underneath, possibly under attacking.
+    TargetChanged {
+        /// The expected target path.
+        expected: PathBuf,
+        /// The actual target path.
+        actual: PathBuf,
+    },
+    /// A symlink is met where symlinks are not allowed.
+    SymlinkEncountered {
+        /// The path of the symlink.
+        path: PathBuf,
+    },
+    /// A configured limit, such as the depth of directories to create, is exceeded.
+    LimitExceeded {
+        /// The configured limit.
+        limit: usize,
+        /// The requested amount.
+        requested: usize,
+    },
+    /// The pinned target is not the expected inode.
+    IdentityMismatch {
+        /// The target path.
+        path: PathBuf,
+        /// The expected `(dev, ino)` pair.
+        expected: (u64, u64),
+        /// The actual `(dev, ino)` pair.
+        actual: (u64, u64),
+    },
+    /// The owner of a created directory is not the configured owner, possibly changed by a
+    /// racing chown.
+    OwnerMismatch {
+        /// The directory path.
+        path: PathBuf,
+        /// The expected `(uid, gid)` pair.
+        expected: (u32, u32),
+        /// The actual `(uid, gid)` pair.
+        actual: (u32, u32),
+    },
+    /// An operation, such as creating a hardlink, can't cross filesystems.
+    CrossDevice {
+        /// The source path.
+        source: PathBuf,
+        /// The destination path on another filesystem.
+        path: PathBuf,
+    },
+    /// The path resolves to another filesystem or mount than the root.
+    CrossesMount {
+        /// The resolved target path.
+        path: PathBuf,
+        /// The `st_dev` of the root.
+        root_dev: u64,
+        /// The `st_dev` of the target.
+        dev: u64,
+    },
+    /// An id in the container is outside of all the configured id mappings.
+    UnmappedId {
+        /// The id in the container.
+        id: u32,
+        /// Whether the id is a group id instead of a user id.
+        gid: bool,
+    },
+    /// The resolution processes more components than allowed, counting those introduced by
+    /// expanding symlinks.
+    TooManyComponents {
+        /// The path being resolved.
+        path: PathBuf,
+        /// The configured limit.
+        limit: usize,
+    },
+    /// The process runs out of file descriptors when opening a component of a deep tree.
+    TooManyOpenFiles {
+        /// The depth below the root of the component failed to open.
+        depth: usize,
+        /// The underlying `EMFILE` or `ENFILE` error.
+        error: Error,
+    },
+    /// The path to remove resolves to the root directory itself.
+    RootRemoval {
+        /// The root directory.
+        root: PathBuf,
+    },
+    /// The path to create already exists.
+    AlreadyExists {
+        /// The existing path.
+        path: PathBuf,
+        /// The existing path, relative to the root.
+        relative: PathBuf,
+    },
+    /// The parent directory of the path to create doesn't exist, and it's not allowed to create
+    /// the parents.
+    ParentNotFound {
+        /// The path of the first missing parent directory.
+        path: PathBuf,
+        /// The path of the first missing parent directory, relative to the root.
+        relative: PathBuf,
+    },
+    /// The filesystem doesn't support POSIX ACLs, or they are disabled by the mount options.
+    AclUnsupported {
+        /// The directory on which the ACL was applied.
+        path: PathBuf,
+    },
+    /// The filesystem or the kernel doesn't support exchanging two paths atomically.
+    ExchangeUnsupported {
+        /// The first path to exchange.
+        a: PathBuf,
+        /// The second path to exchange.
+        b: PathBuf,
+    },
+    /// The content of a file read as text is not valid UTF-8.
+    InvalidUtf8 {
+        /// The file read.
+        path: PathBuf,
+        /// The length of the valid UTF-8 prefix of the content.
+        valid_up_to: usize,
+    },
+    /// The path to read as a symlink is not a symlink.
+    NotASymlink {
+        /// The offending path.
+        path: PathBuf,
+    },
+}
+
+impl SafePathError {
+    /// Get the [ErrorKind] of the failure.
+    ///
+    /// | Error | ErrorKind |
+    /// |-------|-----------|
+    /// | `InvalidRoot` | `InvalidInput` |
+    /// | `InvalidComponent` | `InvalidFilename` |
+    /// | `TooManySymlinks` | `FilesystemLoop`, the same kind as `ELOOP` |
+    /// | `NotADirectory` | `NotADirectory` |
+    /// | `OutsideRoot` | `InvalidInput` |
+    /// | `ForbiddenFilesystem` | `PermissionDenied` |
+    /// | `ForbiddenComponent` | `PermissionDenied` |
+    /// | `HardlinkedFile` | `PermissionDenied` |
+    /// | `TargetChanged` | `Other` |
+    /// | `SymlinkEncountered` | `FilesystemLoop`, the same kind as `ELOOP` |
+    /// | `LimitExceeded` | `InvalidInput` |
+    /// | `IdentityMismatch` | `Other` |
+    /// | `OwnerMismatch` | `Other` |
+    /// | `CrossDevice` | `CrossesDevices`, the same kind as `EXDEV` |
+    /// | `CrossesMount` | `CrossesDevices`, the same kind as `EXDEV` |
+    /// | `UnmappedId` | `InvalidInput` |
+    /// | `TooManyComponents` | `InvalidInput` |
+    /// | `TooManyOpenFiles` | the kind of the underlying error |
+    /// | `RootRemoval` | `PermissionDenied` |
+    /// | `AlreadyExists` | `AlreadyExists` |
+    /// | `ParentNotFound` | `NotFound` |
+    /// | `AclUnsupported` | `Unsupported` |
+    /// | `ExchangeUnsupported` | `Unsupported` |
+    /// | `InvalidUtf8` | `InvalidData` |
+    /// | `NotASymlink` | `InvalidInput`, the same kind as `EINVAL` of `readlink(2)` |
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            SafePathError::InvalidRoot { .. } => ErrorKind::InvalidInput,
+            SafePathError::InvalidComponent { .. } => ErrorKind::InvalidFilename,
+            // `ErrorKind::FilesystemLoop` is not stable yet, so get it from the errno.
+            SafePathError::TooManySymlinks { .. } => Error::from_raw_os_error(libc::ELOOP).kind(),
+            SafePathError::NotADirectory { .. } => ErrorKind::NotADirectory,
+            SafePathError::OutsideRoot { .. } => ErrorKind::InvalidInput,
+            SafePathError::ForbiddenFilesystem { .. } => ErrorKind::PermissionDenied,
+            SafePathError::ForbiddenComponent { .. } => ErrorKind::PermissionDenied,
+            SafePathError::HardlinkedFile { .. } => ErrorKind::PermissionDenied,
+            SafePathError::TargetChanged { .. } => ErrorKind::Other,
+            SafePathError::SymlinkEncountered { .. } => {
+                Error::from_raw_os_error(libc::ELOOP).kind()
+            }
+            SafePathError::LimitExceeded { .. } => ErrorKind::InvalidInput,
+            SafePathError::IdentityMismatch { .. } => ErrorKind::Other,
+            SafePathError::OwnerMismatch { .. } => ErrorKind::Other,
+            SafePathError::CrossDevice { .. } | SafePathError::CrossesMount { .. } => {
+                Error::from_raw_os_error(libc::EXDEV).kind()
+            }
+            SafePathError::UnmappedId { .. } => ErrorKind::InvalidInput,
+            SafePathError::TooManyComponents { .. } => ErrorKind::InvalidInput,
+            SafePathError::TooManyOpenFiles { error, .. } => error.kind(),
+            SafePathError::RootRemoval { .. } => ErrorKind::PermissionDenied,
+            SafePathError::AlreadyExists { .. } => ErrorKind::AlreadyExists,
+            SafePathError::ParentNotFound { .. } => ErrorKind::NotFound,
+            SafePathError::AclUnsupported { .. } => ErrorKind::Unsupported,
+            SafePathError::ExchangeUnsupported { .. } => ErrorKind::Unsupported,
+            SafePathError::InvalidUtf8 { .. } => ErrorKind::InvalidData,
+            SafePathError::NotASymlink { .. } => ErrorKind::InvalidInput,
+        }
+    }
+
+    /// Get the `SafePathError` carried by an `io::Error` returned by this crate.
+    ///
+    /// The context attached to the error, such as [CreateBlocked], is looked through.
+    pub fn from_io_error(err: &Error) -> Option<&SafePathError> {
+        let inner = err.get_ref()?;
+        if let Some(annotated) = inner.downcast_ref::<Annotated>() {
+            return Self::from_io_error(&annotated.error);
+        }
+        if let Some(blocked) = inner.downcast_ref::<CreateBlocked>() {
+            return Self::from_io_error(&blocked.error);
+        }
+        inner.downcast_ref::<SafePathError>()
+    }
+
+    pub(crate) fn invalid_name<N: Into<OsString>>(name: N) -> Self {
+        SafePathError::InvalidComponent {
+            path: PathBuf::from(name.into()),
+        }
+    }
+}
+
+impl fmt::Display for SafePathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SafePathError::InvalidRoot { root } => {
+                write!(f, "Invalid root path: {}", root.display())
+            }
+            SafePathError::InvalidComponent { path } => {
+                write!(f, "Invalid path component in: {}", path.display())
+            }
+            SafePathError::TooManySymlinks { path } => {
+                write!(f, "Too many levels of symlinks: {}", path.display())
+            }
+            SafePathError::NotADirectory { path } => match path.file_name() {
+                Some(name) => write!(
+                    f,
+                    "component '{}' at {} is not a directory",
+                    name.to_string_lossy(),
+                    path.display()
+                ),
+                None => write!(f, "Not a directory: {}", path.display()),
+            },
+            SafePathError::OutsideRoot { path, root } => write!(
+                f,
+                "Invalid path: {} is not under root {}",
+                path.display(),
+                root.display()
+            ),
+            SafePathError::ForbiddenFilesystem { path, fs_type } => write!(
+                f,
+                "Forbidden filesystem type {:#x}: {}",
+                fs_type,
+                path.display()
+            ),
+            SafePathError::ForbiddenComponent { name } => {
+                write!(f, "Forbidden path component: {:?}", name)
+            }
+            SafePathError::HardlinkedFile { path, nlink } => write!(
+                f,
+                "Regular file with {} hard links may alias another file: {}",
+                nlink,
+                path.display()
+            ),
+            SafePathError::TargetChanged { expected, actual } => write!(
+                f,
+                "The target path changes from {} to {} underneath, possible under attacking!!!",
+                expected.display(),
+                actual.display()
+            ),
+            SafePathError::SymlinkEncountered { path } => {
+                write!(f, "Symlink is not allowed: {}", path.display())
+            }
+            SafePathError::LimitExceeded { limit, requested } => {
+                write!(
+                    f,
+                    "Limit exceeded: {} requested, {} allowed",
+                    requested, limit
+                )
+            }
+            SafePathError::IdentityMismatch {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "The identity of {} changes from (dev {:#x}, ino {}) to (dev {:#x}, ino {})",
+                path.display(),
+                expected.0,
+                expected.1,
+                actual.0,
+                actual.1
+            ),
+            SafePathError::OwnerMismatch {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "The owner of {} changes from (uid {}, gid {}) to (uid {}, gid {})",
+                path.display(),
+                expected.0,
+                expected.1,
+                actual.0,
+                actual.1
+            ),
+            SafePathError::CrossDevice { source, path } => write!(
+                f,
+                "Cross-device operation from {} to {}",
+                source.display(),
+                path.display()
+            ),
+            SafePathError::CrossesMount {
+                path,
+                root_dev,
+                dev,
+            } if root_dev == dev => write!(
+                f,
+                "{} is on another mount of device {:#x} than the root",
+                path.display(),
+                dev
+            ),
+            SafePathError::CrossesMount {
+                path,
+                root_dev,
+                dev,
+            } => write!(
+                f,
+                "{} is on device {:#x} instead of device {:#x} of the root",
+                path.display(),
+                dev,
+                root_dev
+            ),
+            SafePathError::UnmappedId { id, gid } => write!(
+                f,
+                "Container {} {} is not mapped to the host",
+                if *gid { "gid" } else { "uid" },
+                id
+            ),
+            SafePathError::TooManyComponents { path, limit } => write!(
+                f,
+                "Too many components, more than {}, to resolve: {}",
+                limit,
+                path.display()
+            ),
+            SafePathError::TooManyOpenFiles { depth, error } => {
+                write!(f, "Out of file descriptors at depth {}: {}", depth, error)
+            }
+            SafePathError::RootRemoval { root } => {
+                write!(
+                    f,
+                    "Refusing to remove the root directory {}",
+                    root.display()
+                )
+            }
+            SafePathError::AlreadyExists { path, .. } => {
+                write!(f, "Path already exists: {}", path.display())
+            }
+            SafePathError::ParentNotFound { path, .. } => {
+                write!(f, "Parent directory doesn't exist: {}", path.display())
+            }
+            SafePathError::AclUnsupported { path } => {
+                write!(f, "POSIX ACLs are not supported on {}", path.display())
+            }
+            SafePathError::ExchangeUnsupported { a, b } => write!(
+                f,
+                "Atomic exchange of {} and {} is not supported",
+                a.display(),
+                b.display()
+            ),
+            SafePathError::InvalidUtf8 { path, valid_up_to } => write!(
+                f,
+                "Invalid UTF-8 after byte {} of {}",
+                valid_up_to,
+                path.display()
+            ),
+            SafePathError::NotASymlink { path } => {
+                write!(f, "Not a symlink: {}", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for SafePathError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SafePathError::TooManyOpenFiles { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<SafePathError> for Error {
+    fn from(err: SafePathError) -> Self {
+        Error::new(err.kind(), err)
+    }
+}
+
+/// The position where creating a path is blocked at a component which can't be opened or
+/// created, attached to the underlying error as context.
+///
+/// The `io::Error` carrying it keeps the [ErrorKind] of the underlying error, and
+/// [SafePathError::from_io_error()] still gets the [SafePathError] of the underlying error, if
+/// any.
+#[derive(Debug)]
+pub struct CreateBlocked {
+    /// The deepest existing directory, relative to the root.
+    pub existing: PathBuf,
+    /// The name of the blocking component under `existing`.
+    pub blocker: OsString,
+    /// The file type of the blocking component, if it exists.
+    pub file_type: Option<FileType>,
+    /// The components after the blocking one, which were never attempted.
+    pub not_attempted: Vec<OsString>,
+    error: Error,
+}
+
+impl CreateBlocked {
+    /// Attach the position where creating a path is blocked to `error`.
+    pub(crate) fn wrap(
+        existing: PathBuf,
+        blocker: OsString,
+        file_type: Option<FileType>,
+        not_attempted: Vec<OsString>,
+        error: Error,
+    ) -> Error {
+        let kind = error.kind();
+        let blocked = CreateBlocked {
+            existing,
+            blocker,
+            file_type,
+            not_attempted,
+            error,
+        };
+        Error::new(kind, blocked)
+    }
+
+    /// Get the `CreateBlocked` attached to an `io::Error` returned by this crate.
+    pub fn from_io_error(err: &Error) -> Option<&CreateBlocked> {
+        let inner = err.get_ref()?;
+        match inner.downcast_ref::<Annotated>() {
+            Some(annotated) => Self::from_io_error(&annotated.error),
+            None => inner.downcast_ref::<CreateBlocked>(),
+        }
+    }
+}
+
+impl fmt::Display for CreateBlocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let desc = match self.file_type {
+            None => "nonexistent",
+            Some(t) if t.is_dir() => "a directory",
+            Some(t) if t.is_file() => "a regular file",
+            Some(t) if t.is_symlink() => "a symlink",
+            Some(t) if t.is_fifo() => "a fifo",
+            Some(t) if t.is_socket() => "a socket",
+            Some(t) if t.is_block_device() => "a block device",
+            Some(_) => "a character device",
+        };
+        let blocked = Path::new("/").join(&self.existing).join(&self.blocker);
+        write!(f, "Blocked at {} ({})", blocked.display(), desc)?;
+        if !self.not_attempted.is_empty() {
+            let rest: PathBuf = self.not_attempted.iter().collect();
+            write!(f, " with {} not attempted", rest.display())?;
+        }
+        write!(f, ": {}", self.error)
+    }
+}
+
+impl std::error::Error for CreateBlocked {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// An error annotated with a note about what was done after the failure, such as a cleanup.
+///
+/// The annotated `io::Error` keeps the [ErrorKind] of the original error, which is available as
+/// its source.
+#[derive(Debug)]
+pub(crate) struct Annotated {
+    error: Error,
+    note: String,
+}
+
+impl Annotated {
+    /// Annotate `error` with `note`.
+    pub(crate) fn wrap(error: Error, note: String) -> Error {
+        Error::new(error.kind(), Annotated { error, note })
+    }
+}
+
+impl fmt::Display for Annotated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.error, self.note)
+    }
+}
+
+impl std::error::Error for Annotated {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_safe_path_error() {
+        let err: Error = SafePathError::TooManySymlinks {
+            path: PathBuf::from("/a"),
+        }
+        .into();
+        assert_eq!(err.kind(), Error::from_raw_os_error(libc::ELOOP).kind());
+        assert_eq!(err.to_string(), "Too many levels of symlinks: /a");
+        assert!(matches!(
+            SafePathError::from_io_error(&err),
+            Some(SafePathError::TooManySymlinks { .. })
+        ));
+
+        let err = Annotated::wrap(err, "rolled back".to_string());
+        assert_eq!(err.kind(), Error::from_raw_os_error(libc::ELOOP).kind());
+        assert_eq!(
+            err.to_string(),
+            "Too many levels of symlinks: /a (rolled back)"
+        );
+        assert!(matches!(
+            SafePathError::from_io_error(&err),
+            Some(SafePathError::TooManySymlinks { .. })
+        ));
+
+        let err = Error::from_raw_os_error(libc::ENOENT);
+        assert!(SafePathError::from_io_error(&err).is_none());
+    }
+}
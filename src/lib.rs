@@ -34,24 +34,201 @@
 //!   is scoped under `root`.
 //! - [scoped_resolve](crate::scoped_resolve()): resolve `unsafe_path` to a relative path, rooted
 //!   at and constrained by `root`.
+//! - [scoped_resolve_with](crate::scoped_resolve_with()): resolve `unsafe_path` like
+//!   `scoped_resolve()`, with additional policies configured by [ResolveOptions](crate::ResolveOptions).
+//! - [scoped_resolve_chain](crate::scoped_resolve_chain()): resolve `unsafe_path` scoped under
+//!   `root`, and return a pinned [SafePathBuf] of each component of the resolved path.
+//! - [scoped_resolve_rel](crate::scoped_resolve_rel()): resolve `unsafe_path` to a relative
+//!   path, rooted at `root_rel` which is itself resolved scoped under a pinned base directory fd.
+//! - [safe_open_handle](crate::safe_open_handle()): resolve `unsafe_path` scoped under `root`
+//!   straight into an `O_PATH` file descriptor, without an intermediate path string.
+//! - [safe_open](crate::safe_open()): resolve `unsafe_path` scoped under `root` into a pinned
+//!   `SafePathBuf` in one step, instead of `safe_join()` followed by `SafePathBuf::from_path()`.
+//! - [safe_open_file](crate::safe_open_file()): safely open or create `unsafe_path` scoped under
+//!   `root` with [OpenFlags](crate::OpenFlags), and return a `File` ready for IO.
+//! - [resolve_existing_prefix](crate::resolve_existing_prefix()): resolve the existing prefix of
+//!   `unsafe_path` scoped under `root` into a pinned directory, and return the trailing components
+//!   which don't exist yet.
 //! - [SafePathBuf](crate::SafePathBuf): safe version of `PathBuf` to protect from TOCTOU style
 //!   of attacks.
 //! - [SafeDirBuilder](crate::SafeDirBuilder): safe version of `DirBuilder` to protect from TOCTOU
 //!   style of attacks.
+//! - [safe_join_or_create](crate::safe_join_or_create()): safely join `unsafe_path` to `root`
+//!   and create the missing trailing directories in one step.
+//! - [is_mount_point](crate::is_mount_point()): check whether `unsafe_path` scoped under `root`
+//!   is a mount point, by the pinned file descriptors of the target and its parent.
+//! - [assert_same_fs](crate::assert_same_fs()): check that `unsafe_path` scoped under `root`
+//!   resolves to the same filesystem as `root`.
+//! - [safe_hardlink](crate::safe_hardlink()): safely create a hardlink at `link` scoped under
+//!   `root` to the pinned `existing` scoped under `root`.
+//! - [safe_symlink](crate::safe_symlink()): safely create a symlink at `link` scoped under
+//!   `root` by `symlinkat()` relative to the pinned fd of its parent.
+//! - [safe_read_link](crate::safe_read_link()): safely read the target of the symlink at
+//!   `unsafe_path` scoped under `root`, without following it.
+//! - [safe_read_link_resolved](crate::safe_read_link_resolved()): safely read the target of the
+//!   symlink at `unsafe_path` scoped under `root`, and resolve where it lands under `root`.
+//! - [safe_metadata](crate::safe_metadata()): safely query the metadata of `unsafe_path` scoped
+//!   under `root` on the pinned fd of the target, following a final symlink.
+//! - [safe_symlink_metadata](crate::safe_symlink_metadata()): safely query the metadata of
+//!   `unsafe_path` scoped under `root`, without following a final symlink.
+//! - [safe_mknod](crate::safe_mknod()): safely create a device node or fifo at `unsafe_path`
+//!   scoped under `root`, without following a symlink at the final component.
+//! - [safe_remove_file](crate::safe_remove_file()): safely remove the file at `unsafe_path`
+//!   scoped under `root`, by `unlinkat()` relative to the pinned fd of its parent.
+//! - [safe_remove_dir_all](crate::safe_remove_dir_all()): safely remove the tree at `unsafe_path`
+//!   scoped under `root` by directory fds only, without crossing into other filesystems.
+//! - [safe_rename](crate::safe_rename()): safely rename `from` to `to` scoped under `root` by
+//!   `renameat()` relative to the pinned fds of their parents, optionally without replacing `to`.
+//! - [safe_exchange](crate::safe_exchange()): safely exchange `a` and `b` scoped under `root`
+//!   atomically by `renameat2(RENAME_EXCHANGE)` relative to the pinned fds of their parents.
+//! - [safe_set_times](crate::safe_set_times()): safely set the access and modification times of
+//!   `unsafe_path` scoped under `root` by `utimensat()` relative to the pinned fd of its parent.
+//! - [safe_copy_file](crate::safe_copy_file()): safely copy a host file to `dst` scoped under
+//!   `root`, creating it by `openat(O_NOFOLLOW)` relative to the pinned fd of its parent.
+//! - [safe_copy_dir_all](crate::safe_copy_dir_all()): safely copy a host directory tree to `dst`
+//!   scoped under `root`, creating each entry relative to the pinned fd of its parent.
+//! - [atomic_write](crate::atomic_write()): safely replace the content of the file at
+//!   `unsafe_path` scoped under `root` by a temporary file renamed over it.
+//! - [safe_access](crate::safe_access()): check the accessibility of `unsafe_path` scoped under
+//!   `root` by the pinned fd of its parent.
+//!
+//! # Features
+//! - `log`: emit `trace!` messages through the [log](https://docs.rs/log) crate for each step of
+//!   path resolution, which helps to diagnose why a path resolved the way it did, and the
+//!   warnings of [SafePathBuf::with_drop_check()].
+//! - `tracing`: instrument `safe_join()`, `scoped_resolve()`, `safe_open()`,
+//!   `SafePathBuf::new()`, `SafePathBuf::from_path()`, `SafeDirBuilder::create()`,
+//!   `SafeDirBuilder::create_file()` and `safe_mknod()` with [tracing](https://docs.rs/tracing)
+//!   spans carrying the `root`, `input` and `flags` fields. A `debug` event with the `resolved`
+//!   field is emitted on success, and an event with the `error` field on failure, at `warn`
+//!   level for attacks such as escaping the root or TOCTOU.
+//! - `serde`: implement `serde::Serialize` and `serde::Deserialize` for [SafeDirBuilder],
+//!   covering its settings but not the pinned root directory or the
+//!   [SafeDirBuilder::after_create()] hook.
+//! - `cap-std`: add `SafePathBuf::into_cap_std_dir()` to convert a validated directory into a
+//!   [cap_std](https://docs.rs/cap-std) `Dir`.
+//! - `tokio`: add `SafeDirBuilder::create_async()` to create directories on the blocking thread
+//!   pool of the [tokio](https://docs.rs/tokio) runtime, instead of blocking the async workers.
 
 #![deny(missing_docs)]
 use std::fs::{File, OpenOptions};
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::Path;
 
+// Emit a trace message through the `log` crate if the `log` feature is enabled, and through the
+// `tracing` crate if the `tracing` feature is enabled, otherwise the arguments are not even
+// evaluated.
+macro_rules! trace {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "log")]
+        log::trace!($($arg)+);
+        #[cfg(feature = "tracing")]
+        tracing::trace!($($arg)+);
+    };
+}
+
+// Emit a warning through the `log` crate if the `log` feature is enabled, and through the
+// `tracing` crate if the `tracing` feature is enabled, otherwise the arguments are not even
+// evaluated.
+macro_rules! warning {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "log")]
+        log::warn!($($arg)+);
+        #[cfg(feature = "tracing")]
+        tracing::warn!($($arg)+);
+    };
+}
+
+// Run `$body` in a tracing span named `$name` with the `root`, `input` and `flags` fields, and
+// report its result as an event, if the `tracing` feature is enabled. The body is evaluated in
+// a closure, so `?` returns from the body instead of the enclosing function.
+macro_rules! instrument {
+    ($name:literal, $root:expr, $input:expr, $flags:expr, $body:expr) => {{
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!(
+            $name,
+            root = %$root.display(),
+            input = %$input.display(),
+            flags = %$flags
+        )
+        .entered();
+        #[allow(clippy::redundant_closure_call)]
+        let result = (|| $body)();
+        #[cfg(feature = "tracing")]
+        crate::audit::report(&result);
+        result
+    }};
+}
+
+mod acl;
+pub use acl::{AclEntry, AclTag};
+
+mod atomic_write;
+pub use atomic_write::atomic_write;
+
+#[cfg(feature = "tracing")]
+mod audit;
+
+mod error;
+pub use error::{CreateBlocked, SafePathError};
+
+mod mount;
+pub use mount::{assert_same_fs, is_mount_point};
+
+mod resolver;
+pub use resolver::{force_backend, resolver_info, Backend, ResolverInfo, BACKEND_ENV};
+
+mod safe_access;
+pub use safe_access::{safe_access, AccessMode};
+
+mod safe_copy;
+pub use safe_copy::{safe_copy_dir_all, safe_copy_file, CopyOptions, CopyStats, CopyTreeOptions};
+
 mod safe_dir_builder;
-pub use safe_dir_builder::SafeDirBuilder;
+pub use safe_dir_builder::{
+    safe_join_or_create, CreatePlan, CreatedDir, DeviceKind, IdMap, SafeDirBuilder, SafeTempDir,
+    StagedDir,
+};
 
 mod safe_join;
-pub use safe_join::{safe_join, scoped_resolve};
+pub use safe_join::{
+    resolve_existing_prefix, safe_join, safe_open, safe_open_file, safe_open_handle,
+    scoped_resolve, scoped_resolve_chain, scoped_resolve_cow, scoped_resolve_iter,
+    scoped_resolve_rel, scoped_resolve_shared, scoped_resolve_with, ResolveOptions,
+};
+
+mod safe_link;
+pub use safe_link::{safe_hardlink, safe_read_link, safe_read_link_resolved, safe_symlink};
+
+mod safe_metadata;
+pub use safe_metadata::{safe_metadata, safe_symlink_metadata};
+
+mod safe_mknod;
+pub use safe_mknod::safe_mknod;
+
+mod safe_remove;
+pub use safe_remove::{
+    safe_remove_dir_all, safe_remove_dir_all_with, safe_remove_file, RemoveOptions,
+};
+
+mod safe_rename;
+pub use safe_rename::{safe_exchange, safe_rename, safe_rename_noreplace};
+
+mod safe_set_times;
+pub use safe_set_times::safe_set_times;
 
 mod safe_path_buf;
-pub use safe_path_buf::SafePathBuf;
+pub use safe_path_buf::{DirHandle, OpenFlags, SafePathBuf};
+
+mod statx;
+pub use statx::{Statx, StatxMask};
+
+mod remove;
+pub use remove::RemovedStats;
+mod sys;
+#[cfg(test)]
+mod test_util;
+mod walk;
 
 /// Open a direcoty/path by path.
 fn open_by_path<P: AsRef<Path>>(path: P) -> std::io::Result<File> {
@@ -0,0 +1,139 @@
+// Copyright (c) 2022 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::ffi::OsStr;
+use std::io::Result;
+use std::path::Path;
+
+use crate::walk::ScopedWalk;
+use crate::{sys, SafePathError};
+
+/// Check whether `unsafe_path` scoped under `root` is a mount point.
+///
+/// The path is resolved with the same rules as [crate::safe_open_handle()], then the `st_dev` of
+/// the pinned target is compared with the `st_dev` of its pinned parent directory, so there's no
+/// window to swap the path between resolving and checking, and `/proc/self/mountinfo` is not
+/// parsed. If `unsafe_path` resolves to `root` itself, it's compared with the real parent of
+/// `root`.
+///
+/// Like the classic `mountpoint(1)` check, a bind mount from the same filesystem is not detected
+/// because it has the same `st_dev` as its parent.
+///
+/// # Errors
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | `root` or the target doesn't exist | `NotFound` |
+/// | `root` or a path component is not a directory | `NotADirectory` |
+/// | too many levels of symlinks | `FilesystemLoop` |
+/// | `unsafe_path` contains invalid component | `InvalidFilename` |
+pub fn is_mount_point<R: AsRef<Path>, U: AsRef<Path>>(root: R, unsafe_path: U) -> Result<bool> {
+    let mut walk = ScopedWalk::new(root)?;
+    walk.walk(unsafe_path.as_ref(), true, false)?;
+
+    let st = sys::fstat(walk.fd())?;
+    let parent_st = match walk.parent_fd() {
+        Some(parent) => sys::fstat(&parent?)?,
+        None => {
+            let parent = sys::openat(walk.fd(), OsStr::new(".."), libc::O_PATH, 0)?;
+            sys::fstat(&parent)?
+        }
+    };
+
+    // The parent of "/" is itself, which is always a mount point.
+    Ok(st.st_dev != parent_st.st_dev || st.st_ino == parent_st.st_ino)
+}
+
+/// Check that `unsafe_path` scoped under `root` resolves to the same filesystem as `root`.
+///
+/// The path is resolved with the same rules as [crate::safe_open_handle()], then the `st_dev` of
+/// the pinned target is compared with the `st_dev` of the pinned `root`. This rejects targets on
+/// filesystems mounted under `root`, or reached through a mount on the way, such as a volume
+/// which is expected to stay on the backing store of `root`. Like [is_mount_point()], a bind
+/// mount from the same filesystem is not detected.
+///
+/// # Errors
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | the target is on another filesystem | `CrossesDevices`, with [SafePathError::CrossesMount] |
+/// | `root` or the target doesn't exist | `NotFound` |
+/// | `root` or a path component is not a directory | `NotADirectory` |
+/// | too many levels of symlinks | `FilesystemLoop` |
+/// | `unsafe_path` contains invalid component | `InvalidFilename` |
+pub fn assert_same_fs<R: AsRef<Path>, U: AsRef<Path>>(root: R, unsafe_path: U) -> Result<()> {
+    let mut walk = ScopedWalk::new(root.as_ref())?;
+    walk.walk(unsafe_path.as_ref(), true, false)?;
+
+    let st = sys::fstat(walk.fd())?;
+    let root_st = sys::fstat(walk.root_fd())?;
+    if st.st_dev != root_st.st_dev {
+        #[allow(clippy::unnecessary_cast)]
+        return Err(SafePathError::CrossesMount {
+            path: root.as_ref().join(walk.path()),
+            root_dev: root_st.st_dev as u64,
+            dev: st.st_dev as u64,
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn test_is_mount_point() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir(rootfs_path.join("a")).unwrap();
+        symlink("/a", rootfs_path.join("s")).unwrap();
+
+        assert!(!is_mount_point(rootfs_path, "a").unwrap());
+        assert!(!is_mount_point(rootfs_path, "s").unwrap());
+        let err = is_mount_point(rootfs_path, "b").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        // The root itself is compared with its real parent.
+        assert!(is_mount_point("/", "").unwrap());
+        assert!(is_mount_point("/", "../..").unwrap());
+        if Path::new("/proc/self").exists() {
+            assert!(is_mount_point("/", "proc").unwrap());
+            assert!(is_mount_point("/proc", "").unwrap());
+            assert!(!is_mount_point("/", "proc/self").unwrap());
+        }
+    }
+
+    #[test]
+    fn test_assert_same_fs() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir(rootfs_path.join("a")).unwrap();
+        symlink("/a", rootfs_path.join("s")).unwrap();
+
+        assert_same_fs(rootfs_path, "").unwrap();
+        assert_same_fs(rootfs_path, "s/../a").unwrap();
+        let err = assert_same_fs(rootfs_path, "b").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        if Path::new("/proc/self").exists() {
+            let err = assert_same_fs("/", "proc/version").unwrap_err();
+            assert_eq!(
+                err.kind(),
+                std::io::Error::from_raw_os_error(libc::EXDEV).kind()
+            );
+            match SafePathError::from_io_error(&err) {
+                Some(SafePathError::CrossesMount { path, .. }) => {
+                    assert_eq!(path, Path::new("/proc/version"))
+                }
+                _ => panic!("unexpected error {}", err),
+            }
+            assert_same_fs("/proc", "version").unwrap();
+        }
+    }
+}
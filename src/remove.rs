@@ -0,0 +1,256 @@
+// Copyright (c) 2022 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Removal of directory trees anchored at directory file descriptors.
+
+use std::ffi::{OsStr, OsString};
+use std::io::Result;
+use std::os::unix::io::OwnedFd;
+use std::path::PathBuf;
+
+use crate::statx::{Statx, StatxMask};
+use crate::walk::{file_id, too_many_open_files, PARENT_DIR};
+use crate::{sys, SafePathError};
+
+/// The mount which the entries of a removal must stay on.
+struct Mount {
+    dev: u64,
+    // `None` if mount ids are not reported by `statx(2)`.
+    id: Option<u64>,
+}
+
+/// A directory being emptied by [remove_all_at()].
+struct Level {
+    name: OsString,
+    // Closed for the outermost directories when too many fds are open.
+    fd: Option<OwnedFd>,
+    id: (u64, u64),
+    // The entries not removed yet.
+    entries: Vec<OsString>,
+}
+
+/// The number of entries removed by [crate::safe_remove_dir_all()].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RemovedStats {
+    /// The number of non-directories removed, including symlinks.
+    pub files: u64,
+    /// The number of directories removed, including the top one.
+    pub dirs: u64,
+}
+
+/// Remove the entry `name` under the directory `parent`, recursively if it's a directory,
+/// keeping at most `max_fds` fds open besides `parent`, and return the number of entries removed.
+///
+/// Each directory is opened by `openat(O_NOFOLLOW | O_DIRECTORY)` relative to its parent and its
+/// entries are removed relative to it, so a symlink is removed itself instead of being followed,
+/// and a directory moved elsewhere during the removal never redirects it outside of the tree.
+/// The fds of the outermost directories are closed when the tree is deeper than `max_fds`, and
+/// reopened by ".." relative to the child when ascending, verifying the identity of each.
+///
+/// Unless `xdev` is set, an entry on another mount than `parent`, such as a mount point in the
+/// tree, fails the removal with [SafePathError::CrossesMount] carrying its path relative to
+/// `parent`, before anything in it is removed. The tree is not scanned beforehand, so the
+/// entries visited before it are already removed by then. Mounts are told apart by
+/// `STATX_MNT_ID`, and only by `st_dev` without it, before Linux 5.8, which misses a bind mount
+/// of the same filesystem.
+pub(crate) fn remove_all_at(
+    parent: &OwnedFd,
+    name: &OsStr,
+    max_fds: usize,
+    xdev: bool,
+) -> Result<RemovedStats> {
+    let mut stats = RemovedStats::default();
+    let mount = if xdev {
+        None
+    } else {
+        Some(Mount {
+            dev: file_id(&sys::fstat(parent)?).0,
+            id: Statx::of(parent, StatxMask::MNT_ID)?.mount_id(),
+        })
+    };
+
+    let st = sys::fstatat_nofollow(parent, name)?;
+    if let Some(e) = crossed_mount(mount.as_ref(), parent, name, &st)? {
+        return Err(e(PathBuf::from(name)).into());
+    }
+    if !sys::is_dir(&st) {
+        sys::unlinkat(parent, name, 0)?;
+        stats.files += 1;
+        return Ok(stats);
+    }
+
+    let max_fds = max_fds.max(1);
+    let mut stack = vec![open_level(parent, name.to_os_string(), 1)?];
+    let mut open = 1;
+    // The index in `stack` of the outermost directory which may have its fd open.
+    let mut first_open = 0;
+    loop {
+        let depth = stack.len();
+        // Safe to unwrap() because the stack is never empty in the loop.
+        let level = stack.last_mut().unwrap();
+        // The fd of the innermost directory is never closed.
+        let dir = level.fd.as_ref().unwrap();
+        if let Some(child) = level.entries.pop() {
+            let st = sys::fstatat_nofollow(dir, &child)?;
+            if let Some(e) = crossed_mount(mount.as_ref(), dir, &child, &st)? {
+                let names = stack.iter().map(|l| l.name.as_os_str());
+                return Err(e(names.chain(Some(child.as_os_str())).collect()).into());
+            }
+            if !sys::is_dir(&st) {
+                sys::unlinkat(dir, &child, 0)?;
+                stats.files += 1;
+                continue;
+            }
+            let level = open_level(dir, child, depth + 1)?;
+            stack.push(level);
+            open += 1;
+            while open > max_fds && first_open < stack.len() - 1 {
+                let level = &mut stack[first_open];
+                if let Some(fd) = level.fd.take() {
+                    level.id = file_id(&sys::fstat(&fd)?);
+                    open -= 1;
+                }
+                first_open += 1;
+            }
+            continue;
+        }
+
+        // The directory is empty now, so remove it from its parent.
+        // Safe to unwrap() because the stack is not empty.
+        let level = stack.pop().unwrap();
+        open -= 1;
+        let fd = level.fd.unwrap();
+        let up_index = stack.len().saturating_sub(1);
+        let up = match stack.last_mut() {
+            Some(up) => up,
+            None => {
+                sys::unlinkat(parent, &level.name, libc::AT_REMOVEDIR)?;
+                stats.dirs += 1;
+                return Ok(stats);
+            }
+        };
+        if up.fd.is_none() {
+            let flags = libc::O_PATH | libc::O_NOFOLLOW | libc::O_DIRECTORY;
+            let reopened = sys::openat(&fd, OsStr::new(PARENT_DIR), flags, 0)
+                .map_err(|e| too_many_open_files(e, depth - 1))?;
+            let actual = file_id(&sys::fstat(&reopened)?);
+            if actual != up.id {
+                return Err(SafePathError::IdentityMismatch {
+                    path: PathBuf::from(&up.name),
+                    expected: up.id,
+                    actual,
+                }
+                .into());
+            }
+            up.fd = Some(reopened);
+            open += 1;
+            first_open = first_open.min(up_index);
+        }
+        drop(fd);
+        // Safe to unwrap() because it's open or just reopened.
+        sys::unlinkat(up.fd.as_ref().unwrap(), &level.name, libc::AT_REMOVEDIR)?;
+        stats.dirs += 1;
+    }
+}
+
+/// Check whether the entry `name` under `dir` with the status `st` is on another mount than
+/// `mount`, and return a constructor of the error for the path of the entry if so.
+fn crossed_mount(
+    mount: Option<&Mount>,
+    dir: &OwnedFd,
+    name: &OsStr,
+    st: &libc::stat,
+) -> Result<Option<impl FnOnce(PathBuf) -> SafePathError>> {
+    let mount = match mount {
+        Some(mount) => mount,
+        None => return Ok(None),
+    };
+    let dev = file_id(st).0;
+    // Only a directory may hold entries of another mount of the same device, a bind mounted
+    // file can't be unlinked anyway.
+    let crossed = dev != mount.dev
+        || match mount.id {
+            Some(id) if sys::is_dir(st) => {
+                matches!(sys::mount_id_at(dir, name)?, Some(child) if child != id)
+            }
+            _ => false,
+        };
+    if !crossed {
+        return Ok(None);
+    }
+    let root_dev = mount.dev;
+    Ok(Some(move |path| SafePathError::CrossesMount {
+        path,
+        root_dev,
+        dev,
+    }))
+}
+
+/// Open the directory `name` under `parent` at `depth` and read its entries.
+fn open_level(parent: &OwnedFd, name: OsString, depth: usize) -> Result<Level> {
+    let flags = libc::O_PATH | libc::O_NOFOLLOW | libc::O_DIRECTORY;
+    let fd = sys::openat(parent, &name, flags, 0).map_err(|e| too_many_open_files(e, depth))?;
+    let entries = sys::read_dir(&fd).map_err(|e| too_many_open_files(e, depth))?;
+    Ok(Level {
+        name,
+        fd: Some(fd),
+        id: (0, 0),
+        entries,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::walk::MAX_OPEN_FDS_DEFAULT;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn test_remove_all_at() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir_all(rootfs_path.join("a/b/c")).unwrap();
+        fs::create_dir(rootfs_path.join("outside")).unwrap();
+        fs::write(rootfs_path.join("outside/f"), "f").unwrap();
+        fs::write(rootfs_path.join("a/b/f"), "f").unwrap();
+        symlink("../../outside", rootfs_path.join("a/b/s")).unwrap();
+
+        let root = sys::openat(
+            &sys::CurrentDir,
+            rootfs_path.as_os_str(),
+            libc::O_PATH | libc::O_DIRECTORY,
+            0,
+        )
+        .unwrap();
+        remove_all_at(&root, OsStr::new("a"), MAX_OPEN_FDS_DEFAULT, true).unwrap();
+        assert!(!rootfs_path.join("a").exists());
+        assert!(rootfs_path.join("outside/f").exists());
+
+        remove_all_at(&root, OsStr::new("outside"), 1, true).unwrap();
+        assert!(!rootfs_path.join("outside").exists());
+    }
+
+    #[test]
+    fn test_remove_all_at_max_fds() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        let deep = rootfs_path.join(vec!["d"; 32].join("/"));
+        fs::create_dir_all(&deep).unwrap();
+        fs::write(deep.join("f"), "f").unwrap();
+        fs::create_dir_all(rootfs_path.join("d/d/e/f")).unwrap();
+
+        let root = sys::openat(
+            &sys::CurrentDir,
+            rootfs_path.as_os_str(),
+            libc::O_PATH | libc::O_DIRECTORY,
+            0,
+        )
+        .unwrap();
+        let stats = remove_all_at(&root, OsStr::new("d"), 2, false).unwrap();
+        assert!(!rootfs_path.join("d").exists());
+        assert_eq!(stats, RemovedStats { files: 1, dirs: 34 });
+    }
+}
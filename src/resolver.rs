@@ -0,0 +1,252 @@
+// Copyright (c) 2022 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+#[cfg(test)]
+use std::cell::Cell;
+use std::ffi::CStr;
+use std::mem::MaybeUninit;
+use std::os::unix::io::{AsRawFd, OwnedFd};
+use std::path::Path;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::OnceLock;
+
+use crate::sys;
+
+/// Environment variable to override the resolution backend, with the same values as
+/// [Backend::name()].
+pub const BACKEND_ENV: &str = "SAFE_PATH_BACKEND";
+
+/// Mechanism used by [crate::safe_open_handle()] to resolve a path scoped under a root.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// The `openat2(2)` syscall with `RESOLVE_IN_ROOT`, so the kernel enforces the scoping.
+    Openat2,
+    /// A userspace walk opening each component by `openat(2)` relative to its parent.
+    OpenatWalk,
+    /// Resolve by [crate::safe_join()], open the path and verify it by reading the
+    /// `/proc/self/fd/` symlink.
+    ProcReadlink,
+}
+
+impl Backend {
+    /// Get the name of the backend, as accepted by the [BACKEND_ENV] environment variable.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Openat2 => "openat2",
+            Backend::OpenatWalk => "openat-walk",
+            Backend::ProcReadlink => "proc-readlink",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [Backend::Openat2, Backend::OpenatWalk, Backend::ProcReadlink]
+            .iter()
+            .copied()
+            .find(|b| b.name() == name)
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Backend::Openat2 => 1,
+            Backend::OpenatWalk => 2,
+            Backend::ProcReadlink => 3,
+        }
+    }
+
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(Backend::Openat2),
+            2 => Some(Backend::OpenatWalk),
+            3 => Some(Backend::ProcReadlink),
+            _ => None,
+        }
+    }
+}
+
+/// Information about the path resolution backend of the running system.
+#[derive(Clone, Debug)]
+pub struct ResolverInfo {
+    /// The active backend.
+    pub backend: Backend,
+    /// Whether the `openat2(2)` syscall is available.
+    pub openat2_available: bool,
+    /// Whether `/proc/self/fd` is available.
+    pub proc_available: bool,
+    /// The kernel release, as reported by `uname(2)`.
+    pub kernel_release: String,
+}
+
+#[derive(Debug)]
+struct Detected {
+    openat2_available: bool,
+    proc_available: bool,
+    kernel_release: String,
+    env_backend: Option<Backend>,
+}
+
+static DETECTED: OnceLock<Detected> = OnceLock::new();
+static FORCED_BACKEND: AtomicU8 = AtomicU8::new(0);
+
+#[cfg(test)]
+thread_local! {
+    // The backend overridden for the current thread by `override_backend()`.
+    static THREAD_BACKEND: Cell<u8> = const { Cell::new(0) };
+}
+
+fn detected() -> &'static Detected {
+    DETECTED.get_or_init(|| {
+        let openat2_available = sys::openat2(
+            &sys::CurrentDir,
+            Path::new("/"),
+            libc::O_PATH,
+            libc::RESOLVE_IN_ROOT,
+        )
+        .is_ok();
+
+        Detected {
+            openat2_available,
+            proc_available: Path::new("/proc/self/fd").is_dir(),
+            kernel_release: kernel_release(),
+            env_backend: std::env::var(BACKEND_ENV)
+                .ok()
+                .and_then(|v| Backend::from_name(&v)),
+        }
+    })
+}
+
+fn kernel_release() -> String {
+    let mut uts = MaybeUninit::<libc::utsname>::uninit();
+    // Safe because the kernel fully initializes `uts` on success.
+    if unsafe { libc::uname(uts.as_mut_ptr()) } < 0 {
+        return String::new();
+    }
+    let uts = unsafe { uts.assume_init() };
+    // Safe because `release` is NUL terminated by the kernel.
+    unsafe { CStr::from_ptr(uts.release.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Get the active resolution backend.
+pub(crate) fn backend() -> Backend {
+    #[cfg(test)]
+    if let Some(backend) = Backend::from_u8(THREAD_BACKEND.with(|b| b.get())) {
+        return backend;
+    }
+    if let Some(backend) = Backend::from_u8(FORCED_BACKEND.load(Ordering::Relaxed)) {
+        return backend;
+    }
+    let detected = detected();
+    if let Some(backend) = detected.env_backend {
+        backend
+    } else if detected.openat2_available {
+        Backend::Openat2
+    } else {
+        Backend::OpenatWalk
+    }
+}
+
+/// Report which path resolution backend is active and what the running system supports.
+///
+/// The system capabilities are probed on the first call and cached afterwards. The active backend
+/// is selected in this order:
+/// - the backend forced by [force_backend()].
+/// - the backend named by the [BACKEND_ENV] environment variable.
+/// - [Backend::Openat2] if the `openat2(2)` syscall is available.
+/// - [Backend::OpenatWalk] otherwise.
+pub fn resolver_info() -> ResolverInfo {
+    let detected = detected();
+    ResolverInfo {
+        backend: backend(),
+        openat2_available: detected.openat2_available,
+        proc_available: detected.proc_available,
+        kernel_release: detected.kernel_release.clone(),
+    }
+}
+
+/// Force the path resolution backend used by this process, or restore the default selection
+/// with `None`.
+///
+/// This is mainly for testing. Forcing a backend unsupported by the running system causes path
+/// resolution to fail.
+pub fn force_backend(backend: Option<Backend>) {
+    FORCED_BACKEND.store(backend.map(|b| b.to_u8()).unwrap_or(0), Ordering::Relaxed);
+}
+
+/// Override the backend for the current thread until the returned guard is dropped.
+///
+/// Unlike [force_backend()], it doesn't affect the tests running in parallel in other threads.
+#[cfg(test)]
+pub(crate) fn override_backend(backend: Backend) -> BackendGuard {
+    BackendGuard(THREAD_BACKEND.with(|b| b.replace(backend.to_u8())))
+}
+
+/// Restore the backend of the current thread overridden by [override_backend()] when dropped.
+#[cfg(test)]
+pub(crate) struct BackendGuard(u8);
+
+#[cfg(test)]
+impl Drop for BackendGuard {
+    fn drop(&mut self) {
+        THREAD_BACKEND.with(|b| b.set(self.0));
+    }
+}
+
+/// Open `unsafe_path` scoped under the directory `root_fd` by `openat2(2)`, with `O_PATH` and
+/// the extra `flags` such as `O_NOFOLLOW`.
+pub(crate) fn openat2_in_root<F: AsRawFd>(
+    root_fd: &F,
+    unsafe_path: &Path,
+    flags: libc::c_int,
+) -> std::io::Result<OwnedFd> {
+    let path = if unsafe_path.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        unsafe_path
+    };
+    sys::openat2(
+        root_fd,
+        path,
+        libc::O_PATH | flags,
+        libc::RESOLVE_IN_ROOT | libc::RESOLVE_NO_MAGICLINKS,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolver_info() {
+        let info = resolver_info();
+        assert!(!info.kernel_release.is_empty());
+        if std::env::var(BACKEND_ENV).is_err() {
+            if info.openat2_available {
+                assert_eq!(info.backend, Backend::Openat2);
+            } else {
+                assert_eq!(info.backend, Backend::OpenatWalk);
+            }
+        }
+
+        for backend in [Backend::Openat2, Backend::OpenatWalk, Backend::ProcReadlink] {
+            assert_eq!(Backend::from_name(backend.name()), Some(backend));
+            assert_eq!(Backend::from_u8(backend.to_u8()), Some(backend));
+        }
+        let default = info.backend;
+        {
+            let _guard = override_backend(Backend::ProcReadlink);
+            assert_eq!(resolver_info().backend, Backend::ProcReadlink);
+            let _guard = override_backend(Backend::OpenatWalk);
+            assert_eq!(resolver_info().backend, Backend::OpenatWalk);
+        }
+        assert_eq!(resolver_info().backend, default);
+        // Other threads are not affected.
+        let _guard = override_backend(Backend::ProcReadlink);
+        let backend = std::thread::spawn(|| resolver_info().backend)
+            .join()
+            .unwrap();
+        assert_eq!(backend, default);
+    }
+}
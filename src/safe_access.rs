@@ -0,0 +1,118 @@
+// Copyright (c) 2022 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::ffi::OsStr;
+use std::io::Result;
+use std::ops::BitOr;
+use std::path::Path;
+
+use crate::sys;
+use crate::walk::ScopedWalk;
+
+/// The accessibility to check by [safe_access()], which may be combined by `|`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccessMode(libc::c_int);
+
+impl AccessMode {
+    /// Check whether the target exists, as `F_OK`.
+    pub const EXISTS: AccessMode = AccessMode(libc::F_OK);
+    /// Check whether the target is readable, as `R_OK`.
+    pub const READ: AccessMode = AccessMode(libc::R_OK);
+    /// Check whether the target is writable, as `W_OK`.
+    pub const WRITE: AccessMode = AccessMode(libc::W_OK);
+    /// Check whether the target is executable or searchable, as `X_OK`.
+    pub const EXECUTE: AccessMode = AccessMode(libc::X_OK);
+}
+
+impl BitOr for AccessMode {
+    type Output = AccessMode;
+
+    fn bitor(self, rhs: AccessMode) -> AccessMode {
+        AccessMode(self.0 | rhs.0)
+    }
+}
+
+/// Safely check whether the target of `unsafe_path` scoped under `root` is accessible by the
+/// effective user and group of the process with `mode`.
+///
+/// The path is resolved with the same rules as [crate::safe_open_handle()], then the target is
+/// checked by `faccessat(2)` with `AT_EACCESS` relative to the pinned fd of its parent, so the
+/// check can't be redirected to another file by changing the path. `Ok(false)` is returned if
+/// the target doesn't exist or isn't accessible with `mode`.
+///
+/// # Security
+/// The result is only a probe: the permissions or the file itself may change right after the
+/// check, so it must not be used as a security decision for a later operation, which should
+/// handle its own permission errors. A later operation relative to the same pinned parent at
+/// least acts on the same directory entry.
+///
+/// # Errors
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | `root` doesn't exist | `NotFound` |
+/// | `root` or a path component is not a directory | `NotADirectory` |
+/// | too many levels of symlinks | `FilesystemLoop` |
+/// | the path contains invalid component | `InvalidFilename` |
+pub fn safe_access<R: AsRef<Path>, U: AsRef<Path>>(
+    root: R,
+    unsafe_path: U,
+    mode: AccessMode,
+) -> Result<bool> {
+    let mut walk = ScopedWalk::new(root)?;
+    walk.walk(unsafe_path.as_ref(), true, true)?;
+    if !walk.take_missing().is_empty() {
+        return Ok(false);
+    }
+
+    // The target is resolved, check it by its name in the parent, or the root by itself.
+    let result = match walk.names().last().cloned() {
+        Some(name) => {
+            // Safe to unwrap() because the walk is not at the root.
+            let parent = walk.parent_fd().unwrap()?;
+            let flags = libc::AT_EACCESS | libc::AT_SYMLINK_NOFOLLOW;
+            sys::faccessat(&parent, &name, mode.0, flags)
+        }
+        None => sys::faccessat(walk.fd(), OsStr::new("."), mode.0, libc::AT_EACCESS),
+    };
+    match result {
+        Ok(()) => Ok(true),
+        Err(e) => match e.raw_os_error() {
+            Some(libc::EACCES) | Some(libc::EPERM) | Some(libc::EROFS) | Some(libc::ETXTBSY) => {
+                Ok(false)
+            }
+            _ => Err(e),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    #[test]
+    fn test_safe_access() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir(rootfs_path.join("a")).unwrap();
+        fs::write(rootfs_path.join("a/f"), "f").unwrap();
+        fs::set_permissions(rootfs_path.join("a/f"), fs::Permissions::from_mode(0o600)).unwrap();
+        symlink("/a/f", rootfs_path.join("s")).unwrap();
+
+        assert!(safe_access(rootfs_path, "s", AccessMode::EXISTS).unwrap());
+        assert!(safe_access(rootfs_path, "../a/f", AccessMode::READ | AccessMode::WRITE).unwrap());
+        assert!(safe_access(rootfs_path, "/", AccessMode::EXECUTE).unwrap());
+        assert!(safe_access(rootfs_path, "a", AccessMode::EXECUTE).unwrap());
+        assert!(!safe_access(rootfs_path, "missing", AccessMode::EXISTS).unwrap());
+        assert!(!safe_access(rootfs_path, "a/missing/f", AccessMode::EXISTS).unwrap());
+        // Root bypasses the permission bits except for execute.
+        assert!(!safe_access(rootfs_path, "s", AccessMode::EXECUTE).unwrap());
+
+        let err = safe_access(rootfs_path, "a/f/x", AccessMode::EXISTS).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+    }
+}
@@ -0,0 +1,705 @@
+// Copyright (c) 2022 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Error, Result};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::io::OwnedFd;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::safe_rename::final_name;
+use crate::walk::{file_id, ScopedWalk};
+use crate::{sys, SafePathError};
+
+// The maximum number of bytes to copy by a single `copy_file_range()`.
+const COPY_CHUNK: usize = 1 << 30;
+
+/// Options to control how [safe_copy_file()] creates the destination.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CopyOptions {
+    /// Copy the permission bits of the source, instead of creating the destination with mode
+    /// `0o666` masked by the umask.
+    pub preserve_mode: bool,
+    /// Copy the owner and group of the source, which usually requires `CAP_CHOWN`.
+    pub preserve_owner: bool,
+    /// Replace an existing regular file at the destination, instead of failing with
+    /// `AlreadyExists`.
+    pub overwrite: bool,
+}
+
+/// Options to control how [safe_copy_dir_all()] copies a tree.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CopyTreeOptions {
+    /// Follow symlinks in the source tree and copy their targets, instead of recreating the
+    /// symlinks verbatim. A symlink looping to an ancestor directory fails the copy.
+    pub follow_source_symlinks: bool,
+    /// Copy the owner and group of each entry, which usually requires `CAP_CHOWN`.
+    pub preserve_owner: bool,
+    /// Recreate regular files linked several times in the source tree as hard links of the
+    /// same copy, instead of copying each of them.
+    pub preserve_hardlinks: bool,
+    /// Recreate block and character devices in the destination, instead of skipping them. A
+    /// device node in the root gives access to the device to whoever can open it there.
+    pub copy_devices: bool,
+}
+
+/// The number of entries created by [safe_copy_dir_all()].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CopyStats {
+    /// The number of files copied, including fifos, sockets and device nodes.
+    pub files: u64,
+    /// The number of directories created, including the top one.
+    pub dirs: u64,
+    /// The number of symlinks recreated.
+    pub symlinks: u64,
+    /// The number of hard links recreated.
+    pub hardlinks: u64,
+    /// The number of bytes of file data copied.
+    pub bytes: u64,
+    /// The number of device nodes skipped without [CopyTreeOptions::copy_devices].
+    pub skipped: u64,
+}
+
+/// Safely copy the host directory `src` recursively to `dst` scoped under `root`, and return the
+/// number of entries created.
+///
+/// The source is a trusted or semi-trusted host tree and walked by path, while `dst` usually
+/// comes from untrusted config: the parent directory of `dst` is resolved with the same rules as
+/// [crate::safe_open_handle()], so it never escapes `root`, then every destination entry is
+/// created relative to the pinned fd of its parent, directories by `mkdirat(2)` and files by
+/// `openat(O_CREAT | O_EXCL | O_NOFOLLOW)`. So nothing is ever created through a symlink, and
+/// `dst` must not exist yet.
+///
+/// Symlinks in the source are recreated verbatim unless [CopyTreeOptions::follow_source_symlinks]
+/// is set. The permission bits of each entry are preserved, and the owners are preserved with
+/// [CopyTreeOptions::preserve_owner]. Device nodes are skipped unless
+/// [CopyTreeOptions::copy_devices] is set. The mode of a directory is applied after its entries are
+/// copied, so read-only directories are copied too. The entries created before a failure are
+/// left in place.
+///
+/// # Errors
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | `src`, `root` or the parent directory of `dst` doesn't exist | `NotFound` |
+/// | `src`, `root` or a path component is not a directory | `NotADirectory` |
+/// | `dst` exists | `AlreadyExists` |
+/// | a followed source symlink loops to an ancestor | `FilesystemLoop` |
+/// | too many levels of symlinks | `FilesystemLoop` |
+/// | the final component of `dst` is missing, `.` or `..` | `InvalidFilename` |
+/// | the path contains invalid component | `InvalidFilename` |
+pub fn safe_copy_dir_all(
+    src: &Path,
+    root: &Path,
+    dst: &Path,
+    opts: CopyTreeOptions,
+) -> Result<CopyStats> {
+    let meta = fs::metadata(src)?;
+    if !meta.is_dir() {
+        return Err(SafePathError::NotADirectory {
+            path: src.to_path_buf(),
+        }
+        .into());
+    }
+
+    let name = final_name(dst)?;
+    let mut walk = ScopedWalk::new(root)?;
+    walk.walk(dst.parent().unwrap(), true, false)?;
+
+    let mut copier = TreeCopier {
+        opts,
+        stats: CopyStats::default(),
+        links: HashMap::new(),
+        ancestors: Vec::new(),
+    };
+    copier.copy_dir(src, &meta, walk.fd(), name)?;
+    Ok(copier.stats)
+}
+
+/// The state of [safe_copy_dir_all()].
+struct TreeCopier {
+    opts: CopyTreeOptions,
+    stats: CopyStats,
+    // The first copy of each hardlinked source file by its `(dev, ino)`, with its parent.
+    links: HashMap<(u64, u64), (Rc<OwnedFd>, OsString)>,
+    // The `(dev, ino)` of the source directories being copied.
+    ancestors: Vec<(u64, u64)>,
+}
+
+impl TreeCopier {
+    /// Copy the source directory `src` with `meta` to a new directory `name` under `parent`.
+    fn copy_dir(
+        &mut self,
+        src: &Path,
+        meta: &Metadata,
+        parent: &OwnedFd,
+        name: &OsStr,
+    ) -> Result<()> {
+        let id = (meta.dev(), meta.ino());
+        if self.ancestors.contains(&id) {
+            return Err(Error::from_raw_os_error(libc::ELOOP));
+        }
+
+        sys::mkdirat(parent, name, 0o700)?;
+        self.stats.dirs += 1;
+        let flags = libc::O_PATH | libc::O_NOFOLLOW | libc::O_DIRECTORY;
+        let dir = Rc::new(sys::openat(parent, name, flags, 0)?);
+
+        self.ancestors.push(id);
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            let path = entry.path();
+            let meta = match self.opts.follow_source_symlinks {
+                true => fs::metadata(&path)?,
+                false => fs::symlink_metadata(&path)?,
+            };
+            self.copy_entry(&path, &meta, &dir, &entry.file_name())?;
+        }
+        self.ancestors.pop();
+
+        self.copy_attrs(&*dir, meta)
+    }
+
+    /// Copy the source entry `src` with `meta` to `name` under `parent`.
+    fn copy_entry(
+        &mut self,
+        src: &Path,
+        meta: &Metadata,
+        parent: &Rc<OwnedFd>,
+        name: &OsStr,
+    ) -> Result<()> {
+        let file_type = meta.file_type();
+        if file_type.is_dir() {
+            return self.copy_dir(src, meta, parent, name);
+        }
+
+        if file_type.is_file() && self.opts.preserve_hardlinks && meta.nlink() > 1 {
+            let id = (meta.dev(), meta.ino());
+            if let Some((first_parent, first_name)) = self.links.get(&id) {
+                sys::linkat(&**first_parent, first_name, &**parent, name, 0)?;
+                self.stats.hardlinks += 1;
+                return Ok(());
+            }
+            self.links.insert(id, (parent.clone(), name.to_os_string()));
+        }
+
+        let nofollow = match self.opts.follow_source_symlinks {
+            true => 0,
+            false => libc::O_NOFOLLOW,
+        };
+        if file_type.is_symlink() {
+            sys::symlinkat(&fs::read_link(src)?, &**parent, name)?;
+            self.stats.symlinks += 1;
+            if self.opts.preserve_owner {
+                let fd = sys::openat(&**parent, name, libc::O_PATH | libc::O_NOFOLLOW, 0)?;
+                sys::fchown(&fd, meta.uid(), meta.gid())?;
+            }
+            return Ok(());
+        } else if file_type.is_file() {
+            let mut src_file = OpenOptions::new()
+                .read(true)
+                .custom_flags(nofollow)
+                .open(src)?;
+            let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW;
+            let mut dst_file = File::from(sys::openat(&**parent, name, flags, 0o600)?);
+            self.stats.bytes += copy_data(&mut src_file, &mut dst_file)?;
+            self.stats.files += 1;
+            return self.copy_attrs(&dst_file, meta);
+        }
+
+        // Fifos, sockets and device nodes are recreated empty.
+        let kind = match file_type {
+            t if t.is_fifo() => libc::S_IFIFO,
+            t if t.is_socket() => libc::S_IFSOCK,
+            t if t.is_block_device() => libc::S_IFBLK,
+            t if t.is_char_device() => libc::S_IFCHR,
+            _ => {
+                return Err(Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} has an unknown file type", src.display()),
+                ))
+            }
+        };
+        if (kind == libc::S_IFBLK || kind == libc::S_IFCHR) && !self.opts.copy_devices {
+            self.stats.skipped += 1;
+            return Ok(());
+        }
+        sys::mknodat(&**parent, name, kind | 0o600, meta.rdev())?;
+        self.stats.files += 1;
+        let fd = sys::openat(&**parent, name, libc::O_PATH | libc::O_NOFOLLOW, 0)?;
+        self.copy_attrs(&fd, meta)
+    }
+
+    /// Apply the owner and the mode of `meta` to the created entry `fd`.
+    fn copy_attrs<F: std::os::unix::io::AsRawFd>(&self, fd: &F, meta: &Metadata) -> Result<()> {
+        // Change the owner first, which clears the setuid and setgid bits.
+        if self.opts.preserve_owner {
+            sys::fchown(fd, meta.uid(), meta.gid())?;
+        }
+        sys::fchmod(fd, meta.mode() & 0o7777)
+    }
+}
+
+/// Safely copy the host file `src` to `dst` scoped under `root`, and return the number of bytes
+/// copied.
+///
+/// `src` is a trusted host path and opened as is, while `dst` usually comes from untrusted
+/// config: the parent directory of `dst` is resolved with the same rules as
+/// [crate::safe_open_handle()], so it never escapes `root`, then the destination is created by
+/// `openat(O_CREAT | O_EXCL | O_NOFOLLOW)` relative to the pinned fd of the parent. So a symlink
+/// placed at `dst` is rejected instead of followed.
+///
+/// With [CopyOptions::overwrite], an existing destination is pinned by `openat(O_PATH |
+/// O_NOFOLLOW)` and checked to be a regular file, but it's never opened for writing: the data is
+/// copied to a temporary file created next to it, which is then moved over it by `renameat()`.
+/// So a device or a fifo at `dst` is never opened, and the existing content is kept intact if
+/// the copy fails. The new file has the permission bits of the replaced one unless
+/// [CopyOptions::preserve_mode] is set, but it's owned by the caller unless
+/// [CopyOptions::preserve_owner] is set, and other hard links to the replaced file keep the old
+/// content.
+///
+/// The data is copied by `copy_file_range(2)` in the kernel, falling back to `read(2)` and
+/// `write(2)` where it's not supported, such as across filesystems on older kernels. The file
+/// created by the call is removed again if the copy fails.
+///
+/// # Errors
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | `src`, `root` or the parent directory of `dst` doesn't exist | `NotFound` |
+/// | `src` is a directory | `IsADirectory` |
+/// | `root` or a path component is not a directory | `NotADirectory` |
+/// | `dst` exists without [CopyOptions::overwrite] | `AlreadyExists` |
+/// | `dst` is a symlink | `FilesystemLoop`, with [SafePathError::SymlinkEncountered] |
+/// | `dst` is a directory | `IsADirectory` |
+/// | `dst` exists but is another non-regular file | `InvalidInput` |
+/// | too many levels of symlinks | `FilesystemLoop` |
+/// | the final component of `dst` is missing, `.` or `..` | `InvalidFilename` |
+/// | the path contains invalid component | `InvalidFilename` |
+pub fn safe_copy_file(src: &Path, root: &Path, dst: &Path, opts: CopyOptions) -> Result<u64> {
+    let mut src_file = File::open(src)?;
+    let src_meta = src_file.metadata()?;
+    if src_meta.is_dir() {
+        return Err(Error::from_raw_os_error(libc::EISDIR));
+    }
+
+    let name = final_name(dst)?;
+    let mut walk = ScopedWalk::new(root)?;
+    walk.walk(dst.parent().unwrap(), true, false)?;
+    let dst_path = || walk.root().join(walk.path()).join(name);
+
+    // An existing destination is replaced by a temporary file renamed over it, so it's kept
+    // intact if the copy fails, and is never opened itself.
+    let (tmp_name, existing_mode, fd) = if opts.overwrite {
+        let existing_mode = match sys::openat(walk.fd(), name, libc::O_PATH | libc::O_NOFOLLOW, 0) {
+            Ok(fd) => {
+                let st = sys::fstat(&fd)?;
+                if sys::is_symlink(&st) {
+                    return Err(SafePathError::SymlinkEncountered { path: dst_path() }.into());
+                } else if sys::is_dir(&st) {
+                    return Err(Error::from_raw_os_error(libc::EISDIR));
+                } else if st.st_mode & libc::S_IFMT != libc::S_IFREG {
+                    return Err(Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{} is not a regular file", dst_path().display()),
+                    ));
+                }
+                Some(st.st_mode & 0o7777)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let (tmp_name, fd) = create_temp(walk.fd(), name, 0o666)?;
+        (Some(tmp_name), existing_mode, fd)
+    } else {
+        let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW;
+        let fd =
+            sys::openat(walk.fd(), name, flags, 0o666).map_err(|e| match e.raw_os_error() {
+                Some(libc::ELOOP) => SafePathError::SymlinkEncountered { path: dst_path() }.into(),
+                _ => e,
+            })?;
+        (None, None, fd)
+    };
+    let mut dst_file = File::from(fd);
+
+    let result = (|| {
+        // Change the owner first, which clears the setuid and setgid bits.
+        if opts.preserve_owner {
+            sys::fchown(&dst_file, src_meta.uid(), src_meta.gid())?;
+        }
+        match (opts.preserve_mode, existing_mode) {
+            (true, _) => sys::fchmod(&dst_file, src_meta.mode() & 0o7777)?,
+            (false, Some(mode)) => sys::fchmod(&dst_file, mode)?,
+            (false, None) => {}
+        }
+        let copied = copy_data(&mut src_file, &mut dst_file)?;
+        if let Some(tmp_name) = &tmp_name {
+            sys::renameat(walk.fd(), tmp_name, walk.fd(), name)?;
+        }
+        Ok(copied)
+    })();
+    if result.is_err() {
+        match &tmp_name {
+            Some(tmp_name) => {
+                let _ = sys::unlinkat(walk.fd(), tmp_name, 0);
+            }
+            // Only if the name still refers to the created file.
+            None => {
+                let created = sys::fstat(&dst_file);
+                let current = sys::fstatat_nofollow(walk.fd(), name);
+                if let (Ok(created), Ok(current)) = (created, current) {
+                    if file_id(&created) == file_id(&current) {
+                        let _ = sys::unlinkat(walk.fd(), name, 0);
+                    }
+                }
+            }
+        }
+    }
+
+    result
+}
+
+/// Create a uniquely named temporary file for `name` under `parent`, retrying with another name
+/// if it already exists.
+///
+/// The file is named after `name` with a `.` prefix and a random suffix, so it's hidden next to
+/// it and may be renamed over it.
+pub(crate) fn create_temp(
+    parent: &OwnedFd,
+    name: &OsStr,
+    mode: u32,
+) -> Result<(OsString, OwnedFd)> {
+    let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW;
+    loop {
+        let mut random = [0u8; 8];
+        sys::getrandom(&mut random)?;
+        let mut tmp_name = OsString::from(".");
+        // Keep the name within NAME_MAX however long the destination name is.
+        let len = name.len().min(255 - 18);
+        tmp_name.push(OsStr::from_bytes(&name.as_bytes()[..len]));
+        tmp_name.push(format!(".{:016x}", u64::from_ne_bytes(random)));
+        match sys::openat(parent, &tmp_name, flags, mode) {
+            Ok(fd) => return Ok((tmp_name, fd)),
+            Err(e) if e.raw_os_error() == Some(libc::EEXIST) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Copy the rest of `src` to `dst` by `copy_file_range(2)`, or by `read(2)` and `write(2)` if
+/// it's not supported between them.
+fn copy_data(src: &mut File, dst: &mut File) -> Result<u64> {
+    let mut copied = 0u64;
+    loop {
+        match sys::copy_file_range(src, dst, COPY_CHUNK) {
+            Ok(0) => return Ok(copied),
+            Ok(n) => copied += n as u64,
+            // Only fall back before copying anything, the offsets are consistent then.
+            Err(e)
+                if copied == 0
+                    && matches!(
+                        e.raw_os_error(),
+                        Some(libc::EXDEV)
+                            | Some(libc::ENOSYS)
+                            | Some(libc::EOPNOTSUPP)
+                            | Some(libc::EINVAL)
+                            | Some(libc::EPERM)
+                    ) =>
+            {
+                return io::copy(src, dst);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{Maze, TestMount, HOST_DATA, HOST_FILE};
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_safe_copy_file() {
+        let maze = Maze::new();
+        maze.dir("etc").symlink("s", "/etc");
+        let rootfs_path = maze.root();
+        let src = maze.host().join(HOST_FILE);
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let n = safe_copy_file(
+            &src,
+            rootfs_path,
+            Path::new("../s/hosts"),
+            Default::default(),
+        )
+        .unwrap();
+        assert_eq!(n, HOST_DATA.len() as u64);
+        let dst = rootfs_path.join("etc/hosts");
+        assert_eq!(fs::read_to_string(&dst).unwrap(), HOST_DATA);
+        assert_ne!(dst.metadata().unwrap().mode() & 0o777, 0o600);
+
+        // Exclusive by default.
+        let err = safe_copy_file(
+            &src,
+            rootfs_path,
+            Path::new("etc/hosts"),
+            Default::default(),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+
+        fs::write(&dst, "a much longer content").unwrap();
+        let opts = CopyOptions {
+            preserve_mode: true,
+            preserve_owner: true,
+            overwrite: true,
+        };
+        safe_copy_file(&src, rootfs_path, Path::new("etc/hosts"), opts).unwrap();
+        assert_eq!(fs::read_to_string(&dst).unwrap(), HOST_DATA);
+        assert_eq!(dst.metadata().unwrap().mode() & 0o7777, 0o600);
+        // The replacing file keeps the permission bits of the replaced one.
+        fs::set_permissions(&dst, fs::Permissions::from_mode(0o640)).unwrap();
+        let keep_mode = CopyOptions {
+            overwrite: true,
+            ..Default::default()
+        };
+        safe_copy_file(&src, rootfs_path, Path::new("etc/hosts"), keep_mode).unwrap();
+        assert_eq!(dst.metadata().unwrap().mode() & 0o7777, 0o640);
+
+        let err = safe_copy_file(&src, rootfs_path, Path::new("etc"), opts).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::IsADirectory);
+        if crate::safe_mknod(
+            rootfs_path,
+            "null",
+            libc::S_IFCHR | 0o666,
+            libc::makedev(1, 3),
+        )
+        .is_ok()
+        {
+            let err = safe_copy_file(&src, rootfs_path, Path::new("null"), opts).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+        // A fifo without a reader is rejected before it's opened, rather than failing with ENXIO.
+        crate::safe_mknod(rootfs_path, "fifo", libc::S_IFIFO | 0o666, 0).unwrap();
+        let err = safe_copy_file(&src, rootfs_path, Path::new("fifo"), opts).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let err = safe_copy_file(maze.host(), rootfs_path, Path::new("x"), opts).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::IsADirectory);
+        let err = safe_copy_file(&src, rootfs_path, Path::new("a/x"), opts).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let err = safe_copy_file(&src, rootfs_path, Path::new("etc/.."), opts).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidFilename);
+    }
+
+    #[test]
+    fn test_safe_copy_file_symlink() {
+        let maze = Maze::new();
+        let victim = maze.host().join("victim");
+        fs::write(&victim, "victim").unwrap();
+        maze.symlink("etc/resolv.conf", &victim);
+        let rootfs_path = maze.root();
+        let src = maze.host().join(HOST_FILE);
+
+        for overwrite in [false, true].iter() {
+            let opts = CopyOptions {
+                overwrite: *overwrite,
+                ..Default::default()
+            };
+            let err =
+                safe_copy_file(&src, rootfs_path, Path::new("etc/resolv.conf"), opts).unwrap_err();
+            if *overwrite {
+                assert!(matches!(
+                    SafePathError::from_io_error(&err),
+                    Some(SafePathError::SymlinkEncountered { .. })
+                ));
+            } else {
+                assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+            }
+        }
+        // The parent is resolved under the root.
+        let opts = CopyOptions {
+            overwrite: true,
+            ..Default::default()
+        };
+        maze.assert_escapes_rejected(|escape| {
+            safe_copy_file(&src, rootfs_path, &escape.join("victim"), opts)
+        });
+        assert_eq!(fs::read_to_string(&victim).unwrap(), "victim");
+        assert!(fs::symlink_metadata(rootfs_path.join("etc/resolv.conf"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+    }
+
+    #[test]
+    fn test_safe_copy_dir_all() {
+        let maze = Maze::new();
+        maze.dir("volumes");
+        let rootfs_path = maze.root();
+        let template = tempfile::tempdir().expect("failed to create tmpdir");
+        let src = template.path();
+        fs::create_dir_all(src.join("sub/deep")).unwrap();
+        fs::write(src.join("a"), "a").unwrap();
+        fs::write(src.join("sub/deep/b"), "bb").unwrap();
+        fs::hard_link(src.join("a"), src.join("sub/a_link")).unwrap();
+        std::os::unix::fs::symlink("../a", src.join("sub/s")).unwrap();
+        fs::set_permissions(src.join("a"), fs::Permissions::from_mode(0o640)).unwrap();
+        fs::set_permissions(src.join("sub/deep"), fs::Permissions::from_mode(0o555)).unwrap();
+
+        let opts = CopyTreeOptions {
+            preserve_hardlinks: true,
+            ..Default::default()
+        };
+        let stats = safe_copy_dir_all(src, rootfs_path, Path::new("volumes/v"), opts).unwrap();
+        assert_eq!(
+            stats,
+            CopyStats {
+                files: 2,
+                dirs: 3,
+                symlinks: 1,
+                hardlinks: 1,
+                bytes: 3,
+                skipped: 0,
+            }
+        );
+        let dst = rootfs_path.join("volumes/v");
+        assert_eq!(fs::read_to_string(dst.join("sub/deep/b")).unwrap(), "bb");
+        assert_eq!(fs::read_link(dst.join("sub/s")).unwrap(), Path::new("../a"));
+        let meta = dst.join("a").metadata().unwrap();
+        assert_eq!(meta.mode() & 0o777, 0o640);
+        assert_eq!(meta.nlink(), 2);
+        assert_eq!(meta.ino(), dst.join("sub/a_link").metadata().unwrap().ino());
+        let meta = dst.join("sub/deep").metadata().unwrap();
+        assert_eq!(meta.mode() & 0o777, 0o555);
+
+        // Following symlinks and without hardlinks, everything is a plain copy.
+        let opts = CopyTreeOptions {
+            follow_source_symlinks: true,
+            ..Default::default()
+        };
+        let stats = safe_copy_dir_all(src, rootfs_path, Path::new("w"), opts).unwrap();
+        assert_eq!((stats.files, stats.symlinks, stats.hardlinks), (4, 0, 0));
+        let meta = fs::symlink_metadata(rootfs_path.join("w/sub/s")).unwrap();
+        assert!(meta.is_file());
+        assert_ne!(
+            rootfs_path.join("w/a").metadata().unwrap().ino(),
+            rootfs_path.join("w/sub/a_link").metadata().unwrap().ino()
+        );
+
+        let err = safe_copy_dir_all(src, rootfs_path, Path::new("w"), opts).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        let err = safe_copy_dir_all(&src.join("a"), rootfs_path, Path::new("x"), opts).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+
+        // A source loop is detected when following symlinks.
+        std::os::unix::fs::symlink("..", src.join("sub/up")).unwrap();
+        let err = safe_copy_dir_all(src, rootfs_path, Path::new("y"), opts).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ELOOP));
+        // Let the temporary directories be removed without privileges.
+        for dir in [src, &dst, &rootfs_path.join("w"), &rootfs_path.join("y")].iter() {
+            let _ = fs::set_permissions(dir.join("sub/deep"), fs::Permissions::from_mode(0o755));
+        }
+    }
+
+    #[test]
+    fn test_safe_copy_dir_all_devices() {
+        let maze = Maze::new();
+        let rootfs_path = maze.root();
+        let template = tempfile::tempdir().expect("failed to create tmpdir");
+        let src = template.path();
+        crate::safe_mknod(src, "fifo", libc::S_IFIFO | 0o600, 0).unwrap();
+        if crate::safe_mknod(src, "null", libc::S_IFCHR | 0o600, libc::makedev(1, 3)).is_err() {
+            return;
+        }
+
+        let opts = CopyTreeOptions::default();
+        let stats = safe_copy_dir_all(src, rootfs_path, Path::new("a"), opts).unwrap();
+        assert_eq!((stats.files, stats.skipped), (1, 1));
+        assert!(fs::symlink_metadata(rootfs_path.join("a/fifo"))
+            .unwrap()
+            .file_type()
+            .is_fifo());
+        assert!(!rootfs_path.join("a/null").exists());
+
+        let opts = CopyTreeOptions {
+            copy_devices: true,
+            ..Default::default()
+        };
+        let stats = safe_copy_dir_all(src, rootfs_path, Path::new("b"), opts).unwrap();
+        assert_eq!((stats.files, stats.skipped), (2, 0));
+        let meta = fs::symlink_metadata(rootfs_path.join("b/null")).unwrap();
+        assert!(meta.file_type().is_char_device());
+        assert_eq!(meta.rdev(), libc::makedev(1, 3));
+    }
+
+    #[test]
+    fn test_safe_copy_dir_all_symlinked_dst() {
+        let maze = Maze::new();
+        maze.symlink("v", maze.host());
+        let rootfs_path = maze.root();
+        let template = tempfile::tempdir().expect("failed to create tmpdir");
+        fs::write(template.path().join(HOST_FILE), "evil").unwrap();
+
+        let opts = CopyTreeOptions::default();
+        let err =
+            safe_copy_dir_all(template.path(), rootfs_path, Path::new("v"), opts).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        maze.assert_escapes_rejected(|escape| {
+            safe_copy_dir_all(template.path(), rootfs_path, &escape.join("x"), opts)
+        });
+    }
+
+    #[test]
+    fn test_safe_copy_file_fallback() {
+        let maze = Maze::new();
+        maze.dir("m");
+        let rootfs_path = maze.root();
+        let src = maze.host().join("big");
+        let data: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+        fs::write(&src, &data).unwrap();
+
+        sys::inject_error("copy_file_range", libc::EXDEV);
+        let n = safe_copy_file(&src, rootfs_path, Path::new("f"), Default::default()).unwrap();
+        assert_eq!(n, data.len() as u64);
+        assert_eq!(fs::read(rootfs_path.join("f")).unwrap(), data);
+
+        // Across a real filesystem boundary, where copy_file_range() may or may not work.
+        if let Some(_tmpfs) = TestMount::tmpfs(&rootfs_path.join("m")) {
+            let n =
+                safe_copy_file(&src, rootfs_path, Path::new("m/f"), Default::default()).unwrap();
+            assert_eq!(n, data.len() as u64);
+            assert_eq!(fs::read(rootfs_path.join("m/f")).unwrap(), data);
+        }
+
+        // A new destination is removed if the copy fails.
+        sys::inject_error("copy_file_range", libc::EIO);
+        let err =
+            safe_copy_file(&src, rootfs_path, Path::new("g"), Default::default()).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        assert!(!rootfs_path.join("g").exists());
+
+        // Also with overwrite, but an existing destination is kept.
+        let opts = CopyOptions {
+            overwrite: true,
+            ..Default::default()
+        };
+        sys::inject_error("copy_file_range", libc::EIO);
+        let err = safe_copy_file(&src, rootfs_path, Path::new("g"), opts).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        assert!(!rootfs_path.join("g").exists());
+        sys::inject_error("copy_file_range", libc::EIO);
+        fs::write(rootfs_path.join("f"), "old").unwrap();
+        let entries = fs::read_dir(rootfs_path).unwrap().count();
+        let err = safe_copy_file(&src, rootfs_path, Path::new("f"), opts).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        assert_eq!(fs::read_to_string(rootfs_path.join("f")).unwrap(), "old");
+        assert_eq!(fs::read_dir(rootfs_path).unwrap().count(), entries);
+    }
+}
@@ -3,159 +3,3669 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use std::fs::DirBuilder;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs;
+#[cfg(feature = "tokio")]
+use std::future::Future;
 use std::io::{Error, ErrorKind, Result};
-use std::os::unix::fs::DirBuilderExt;
-use std::path::{Path, PathBuf};
+use std::os::unix::io::{AsRawFd, OwnedFd};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::{safe_join, SafePathBuf};
+use crate::acl::{self, AclEntry, ACL_ACCESS_XATTR, ACL_DEFAULT_XATTR};
+use crate::error::{Annotated, CreateBlocked};
+use crate::walk::{too_many_open_files, ScopedWalk, MAX_OPEN_FDS_DEFAULT};
+use crate::{remove, safe_join, sys, SafePathBuf, SafePathError};
 
 const DIRECTORY_MODE_DEFAULT: u32 = 0o700;
-const DIRECTORY_MODE_MASK: u32 = 0o777;
+const DIRECTORY_MODE_MASK: u32 = 0o7777;
+const MAX_DEPTH_DEFAULT: usize = 128;
+const MAX_NEW_DIRS_DEFAULT: usize = 128;
+const FILE_MODE_DEFAULT: u32 = 0o600;
+const FILE_MODE_MASK: u32 = 0o777;
+const STAGING_PREFIX: &str = ".staging";
 
-/// Safe version of `DirBuilder` to protect from TOCTOU style of attacks.
+static STAGING_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Result of [SafeDirBuilder::create_reporting()], telling what the call actually created.
+#[derive(Debug)]
+pub struct CreatedDir {
+    /// The pinned handle of the directory.
+    pub path: SafePathBuf,
+    /// Whether the directory itself was created by this call, instead of already existing.
+    pub created: bool,
+    /// The directories created by this call, from the outermost to the innermost.
+    ///
+    /// Directories concurrently created by others are not included.
+    pub created_components: Vec<PathBuf>,
+}
+
+/// Result of [SafeDirBuilder::check()], telling what [SafeDirBuilder::create()] would do.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CreatePlan {
+    /// The resolved path of the deepest existing directory.
+    pub existing: PathBuf,
+    /// The directories which would be created, from the outermost to the innermost.
+    pub to_create: Vec<PathBuf>,
+    /// The mode which would be applied to the final directory.
+    pub mode: u32,
+    /// The mode which would be applied to the intermediate directories.
+    pub parents_mode: u32,
+    /// The owner which would be applied to the new directories, if any.
+    pub owner: Option<(u32, u32)>,
+}
+
+/// A range of ids mapped from a user namespace to the host, as a line of `/proc/PID/uid_map`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IdMap {
+    /// The first id of the range in the container.
+    pub container_id: u32,
+    /// The first id of the range on the host.
+    pub host_id: u32,
+    /// The number of ids in the range.
+    pub size: u32,
+}
+
+impl IdMap {
+    /// Map the container `id` to the host by the first of `maps` containing it.
+    fn map(maps: &[IdMap], id: u32) -> Option<u32> {
+        maps.iter()
+            .find_map(|m| {
+                let offset = id.checked_sub(m.container_id)?;
+                if offset < m.size {
+                    m.host_id.checked_add(offset)
+                } else {
+                    None
+                }
+            })
+            // (uid_t)-1 means leaving the id unchanged to chown().
+            .filter(|&id| id != u32::MAX)
+    }
+}
+
+/// Type of the device node created by [SafeDirBuilder::create_device()].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceKind {
+    /// A character device, `S_IFCHR`.
+    Char,
+    /// A block device, `S_IFBLK`.
+    Block,
+}
+
+impl DeviceKind {
+    fn file_type(self) -> u32 {
+        match self {
+            DeviceKind::Char => libc::S_IFCHR,
+            DeviceKind::Block => libc::S_IFBLK,
+        }
+    }
+}
+
+/// A directory created off to the side by [SafeDirBuilder::create_staged()], to be populated
+/// and then atomically published at its final path.
+///
+/// If the `StagedDir` is dropped without being published, the staging directory is left behind.
+#[derive(Debug)]
+pub struct StagedDir {
+    dir: SafePathBuf,
+    parent: OwnedFd,
+    name: OsString,
+    final_name: OsString,
+    sync: bool,
+}
+
+impl StagedDir {
+    /// Get the pinned handle of the staging directory, to populate it.
+    pub fn path(&self) -> &SafePathBuf {
+        &self.dir
+    }
+
+    /// Atomically move the staging directory to its final path, and return the pinned handle of
+    /// the published directory.
+    ///
+    /// The staging directory is moved by `renameat()` relative to the pinned fd of the parent
+    /// directory, replacing an existing empty directory at the final path. A symlink at the final
+    /// path is never followed.
+    ///
+    /// # Errors
+    /// | Condition | ErrorKind |
+    /// |-----------|-----------|
+    /// | the final path is a non-empty directory | `DirectoryNotEmpty` |
+    /// | the final path is not a directory | `NotADirectory` |
+    pub fn publish(self) -> Result<SafePathBuf> {
+        sys::renameat(&self.parent, &self.name, &self.parent, &self.final_name)?;
+        if self.sync {
+            sys::fsync_dir(&self.parent)?;
+        }
+        // Read the target again, as the pinned directory has been moved.
+        SafePathBuf::from_file(self.dir.into_file())
+    }
+}
+
+/// A temporary directory created by [SafeDirBuilder::create_temp()], which is removed with all
+/// its contents when dropped.
 #[derive(Debug)]
+pub struct SafeTempDir {
+    // Always `Some` until kept.
+    dir: Option<SafePathBuf>,
+    parent: OwnedFd,
+    name: OsString,
+    // The device and inode numbers of the created directory.
+    ident: (libc::dev_t, libc::ino_t),
+    max_open_fds: usize,
+}
+
+impl SafeTempDir {
+    /// Get the pinned handle of the temporary directory.
+    pub fn path(&self) -> &SafePathBuf {
+        // Safe to unwrap() because `dir` is only taken by `keep()`, which consumes `self`.
+        self.dir.as_ref().unwrap()
+    }
+
+    /// Keep the temporary directory instead of removing it on drop, and return its pinned
+    /// handle.
+    pub fn keep(mut self) -> SafePathBuf {
+        // Safe to unwrap() because `dir` is only taken here.
+        self.dir.take().unwrap()
+    }
+}
+
+impl Drop for SafeTempDir {
+    fn drop(&mut self) {
+        if self.dir.is_none() {
+            return;
+        }
+        // Best effort, and only if `name` in the pinned parent is still the created directory.
+        match sys::fstatat_nofollow(&self.parent, &self.name) {
+            Ok(st) if (st.st_dev, st.st_ino) == self.ident => {
+                let _ = remove::remove_all_at(&self.parent, &self.name, self.max_open_fds, true);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The signature of the hook invoked with each directory created by a [SafeDirBuilder].
+type HookFn = dyn Fn(&SafePathBuf) -> Result<()> + Send + Sync;
+
+/// A hook invoked with each directory created by a [SafeDirBuilder].
+#[derive(Clone)]
+struct Hook(Arc<HookFn>);
+
+impl fmt::Debug for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Hook")
+    }
+}
+
+/// Safe version of `DirBuilder` to protect from TOCTOU style of attacks.
+///
+/// A configured builder may be cloned to fork its settings, the clones share the pinned root
+/// directory, if any. With the `serde` feature, the settings may be serialized to log what a
+/// builder will do, and deserialized into a builder with the same settings. A deserialized
+/// builder is never pinned, and its root is validated as [SafeDirBuilder::new()].
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SafeDirBuilder {
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "deserialize_root"))]
     root: PathBuf,
+    // The pinned root directory, if created by `from_safe_path()`.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    root_fd: Option<Arc<OwnedFd>>,
     mode: u32,
+    parents_mode: Option<u32>,
     recursive: bool,
+    file_mode: u32,
+    exists_ok: bool,
+    owner: Option<(u32, u32)>,
+    // The owner in the container, mapped to the host by `uid_map` and `gid_map`.
+    container_owner: Option<(u32, u32)>,
+    uid_map: Vec<IdMap>,
+    gid_map: Vec<IdMap>,
+    chown_existing: bool,
+    verify_owner: bool,
+    sync: bool,
+    rollback: bool,
+    no_follow: bool,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    after_create: Option<Hook>,
+    max_depth: usize,
+    max_new_dirs: usize,
+    max_open_fds: usize,
+    umask: u32,
+    atime: Option<SystemTime>,
+    mtime: Option<SystemTime>,
+    acl_access: Vec<AclEntry>,
+    acl_default: Vec<AclEntry>,
+}
+
+/// Deserialize the root directory of a [SafeDirBuilder], validated as [SafeDirBuilder::new()].
+#[cfg(feature = "serde")]
+fn deserialize_root<'de, D>(deserializer: D) -> std::result::Result<PathBuf, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let root: PathBuf = serde::Deserialize::deserialize(deserializer)?;
+    SafeDirBuilder::new(root)
+        .map(|builder| builder.root)
+        .map_err(serde::de::Error::custom)
 }
 
 impl SafeDirBuilder {
     /// Creates a new set of options with default mode/security settings for all platforms and
     /// also non-recursive.
+    ///
+    /// # Errors
+    /// | Condition | ErrorKind |
+    /// |-----------|-----------|
+    /// | `root` doesn't exist | `NotFound` |
+    /// | `root` is not a directory | `NotADirectory` |
     pub fn new<P: AsRef<Path>>(root: P) -> Result<Self> {
         let root = root.as_ref().canonicalize()?;
         if !root.is_dir() {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("Invalid path: {}", root.display()),
-            ));
+            return Err(SafePathError::NotADirectory { path: root }.into());
+        }
+
+        Ok(Self::with_root(root, None))
+    }
+
+    /// Creates a new set of options like [SafeDirBuilder::new()], with the root directory
+    /// pinned by `root`.
+    ///
+    /// All directories are created relative to the pinned root directory instead of its path, so
+    /// the builder keeps working on the same directory even if it's renamed or mounted over
+    /// afterwards. In this mode, the paths passed to [SafeDirBuilder::create()] and friends are
+    /// relative to the root, and an absolute path is also scoped under the root.
+    ///
+    /// # Errors
+    /// | Condition | ErrorKind |
+    /// |-----------|-----------|
+    /// | `root` is not a directory | `NotADirectory` |
+    pub fn from_safe_path(root: SafePathBuf) -> Result<Self> {
+        let path = root.target().to_path_buf();
+        let fd: OwnedFd = root.into_file().into();
+        if !sys::is_dir(&sys::fstat(&fd)?) {
+            return Err(SafePathError::NotADirectory { path }.into());
         }
 
-        Ok(SafeDirBuilder {
+        Ok(Self::with_root(path, Some(Arc::new(fd))))
+    }
+
+    /// Creates a new set of options with the default settings, the same as
+    /// [SafeDirBuilder::new()].
+    ///
+    /// # Errors
+    /// The same as [SafeDirBuilder::new()].
+    pub fn with_defaults<P: AsRef<Path>>(root: P) -> Result<Self> {
+        Self::new(root)
+    }
+
+    /// Creates a new set of options with the default settings for the root `root`, which may be
+    /// pinned by `root_fd`.
+    fn with_root(root: PathBuf, root_fd: Option<Arc<OwnedFd>>) -> Self {
+        SafeDirBuilder {
             root,
+            root_fd,
             mode: DIRECTORY_MODE_DEFAULT,
+            parents_mode: None,
             recursive: false,
-        })
+            file_mode: FILE_MODE_DEFAULT,
+            exists_ok: false,
+            owner: None,
+            container_owner: None,
+            uid_map: Vec::new(),
+            gid_map: Vec::new(),
+            chown_existing: false,
+            verify_owner: false,
+            sync: false,
+            rollback: false,
+            no_follow: false,
+            after_create: None,
+            max_depth: MAX_DEPTH_DEFAULT,
+            max_new_dirs: MAX_NEW_DIRS_DEFAULT,
+            max_open_fds: MAX_OPEN_FDS_DEFAULT,
+            umask: 0,
+            atime: None,
+            mtime: None,
+            acl_access: Vec::new(),
+            acl_default: Vec::new(),
+        }
     }
 
-    /// Indicates that directories should be created recursively, creating all parent directories.
+    /// Indicates whether directories should be created recursively, creating all parent
+    /// directories. This option defaults to false.
     ///
     /// Parents that do not exist are created with the same security and permissions settings.
-    pub fn recursive(&mut self) -> &mut Self {
-        self.recursive = true;
+    pub fn recursive(&mut self, recursive: bool) -> &mut Self {
+        self.recursive = recursive;
         self
     }
 
-    /// Sets the mode to create new directories with. This option defaults to 0o755.
-    pub fn mode(&mut self, mode: u32) -> &mut Self {
+    /// Gets whether directories are created recursively.
+    pub fn is_recursive(&self) -> bool {
+        self.recursive
+    }
+
+    /// Gets the mode to create new directories with.
+    pub fn current_mode(&self) -> u32 {
+        self.mode
+    }
+
+    /// Gets the root directory, under which all directories are created.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Restores all the options to their defaults, keeping the root directory.
+    pub fn reset(&mut self) -> &mut Self {
+        *self = Self::with_root(self.root.clone(), self.root_fd.take());
+        self
+    }
+
+    /// Sets the mode to create new directories with, replacing the default of 0o700.
+    ///
+    /// The mode lands on disk exactly as requested regardless of the process umask: each new
+    /// directory is explicitly set to `mode` by its pinned fd right after being created, which
+    /// also keeps the setuid, setgid and sticky bits that `mkdir(2)` may drop. For example,
+    /// 0o2775 creates group-inheriting directories for shared volumes. A umask may be applied
+    /// explicitly by [SafeDirBuilder::umask()].
+    pub fn default_mode(&mut self, mode: u32) -> &mut Self {
         self.mode = mode & DIRECTORY_MODE_MASK;
         self
     }
 
+    /// Sets the mode to create new directories with, the same as
+    /// [SafeDirBuilder::default_mode()], to match `std::fs::DirBuilder`.
+    pub fn mode(&mut self, mode: u32) -> &mut Self {
+        self.default_mode(mode)
+    }
+
+    /// Sets the mode to create the intermediate parent directories with in recursive mode, while
+    /// the mode set by [SafeDirBuilder::default_mode()] applies only to the final directory.
+    ///
+    /// For example, parents 0o755 and the final directory 0o700 for a private directory inside
+    /// a shared tree. The mode is applied regardless of the process umask, the same as
+    /// [SafeDirBuilder::default_mode()]. If unset, the parents are created with the same mode as
+    /// the final directory. The parent directories of [SafeDirBuilder::create_file()] and
+    /// [SafeDirBuilder::create_staged()] are all intermediate ones.
+    pub fn parents_mode(&mut self, mode: u32) -> &mut Self {
+        self.parents_mode = Some(mode & DIRECTORY_MODE_MASK);
+        self
+    }
+
+    /// Sets the mode to create new files with by [SafeDirBuilder::create_file()]. This option
+    /// defaults to 0o600.
+    ///
+    /// Unlike `mkdir(2)` with a later `fchmod()`, `openat(O_CREAT)` alone is subject to the
+    /// process umask, so each new file is also explicitly set to `mode` by its fd right after
+    /// being created, and the mode lands on disk exactly as requested, the same as directories.
+    pub fn file_mode(&mut self, mode: u32) -> &mut Self {
+        self.file_mode = mode & FILE_MODE_MASK;
+        self
+    }
+
+    /// Sets a umask to apply to the modes of new directories and files, instead of the process
+    /// umask. This option defaults to 0, so the modes land on disk exactly as requested.
+    ///
+    /// The modes configured by [SafeDirBuilder::default_mode()],
+    /// [SafeDirBuilder::parents_mode()] and [SafeDirBuilder::file_mode()] are masked by `mask`
+    /// for both creation paths, the same way whatever the process umask is. The process umask is
+    /// never changed, which would race with other threads.
+    pub fn umask(&mut self, mask: u32) -> &mut Self {
+        self.umask = mask & DIRECTORY_MODE_MASK;
+        self
+    }
+
+    /// Indicates whether an existing target is accepted instead of failing with `AlreadyExists`.
+    ///
+    /// [SafeDirBuilder::create()] accepts an existing directory in non-recursive mode, including
+    /// one concurrently created by others between resolving and creating it, which is verified to
+    /// be a real directory instead of a symlink or file. The handle of the existing directory is
+    /// returned, and [SafeDirBuilder::create_reporting()] reports it as not created. In both
+    /// recursive and non-recursive mode, a symlink at the final component is never followed when
+    /// this is set, and fails with `FilesystemLoop` even if it points to a directory.
+    /// [SafeDirBuilder::create_file()] accepts an existing regular file.
+    pub fn exists_ok(&mut self, exists_ok: bool) -> &mut Self {
+        self.exists_ok = exists_ok;
+        self
+    }
+
+    /// Sets the owner of new directories.
+    ///
+    /// Each directory created by this builder is chowned by its pinned fd right after being
+    /// created, so it never becomes visible with a different owner at a redirected location.
+    /// Pre-existing directories are left alone unless [SafeDirBuilder::chown_existing()] is set.
+    pub fn owner(&mut self, uid: u32, gid: u32) -> &mut Self {
+        self.owner = Some((uid, gid));
+        self.container_owner = None;
+        self
+    }
+
+    /// Sets the id mappings of the user namespace of a container, used to map the owner set by
+    /// [SafeDirBuilder::owner_in_container()] to the host.
+    ///
+    /// Each id is mapped by the first range containing it, like the `uid_map` and `gid_map` files
+    /// of `/proc/PID`.
+    pub fn id_mappings(&mut self, uid_map: &[IdMap], gid_map: &[IdMap]) -> &mut Self {
+        self.uid_map = uid_map.to_vec();
+        self.gid_map = gid_map.to_vec();
+        self
+    }
+
+    /// Sets the owner of new directories as ids in the container, which are mapped to the host
+    /// ids by [SafeDirBuilder::id_mappings()] and then applied like [SafeDirBuilder::owner()].
+    ///
+    /// The mapping is checked before creating anything, and the creation fails with
+    /// [SafePathError::UnmappedId] if an id is outside of all the ranges.
+    pub fn owner_in_container(&mut self, uid: u32, gid: u32) -> &mut Self {
+        self.container_owner = Some((uid, gid));
+        self.owner = None;
+        self
+    }
+
+    /// Indicates whether the configured [SafeDirBuilder::owner()] is also applied to the
+    /// requested directory when it already exists, and to directories concurrently created by
+    /// others in recursive mode.
+    pub fn chown_existing(&mut self, chown_existing: bool) -> &mut Self {
+        self.chown_existing = chown_existing;
+        self
+    }
+
+    /// Indicates whether the owner of each directory chowned by the builder is verified before
+    /// returning.
+    ///
+    /// When enabled with an owner configured by [SafeDirBuilder::owner()] or
+    /// [SafeDirBuilder::owner_in_container()], the directories chowned by the call are checked
+    /// by `fstat()` on their pinned fds after all of them are created, and the call fails with
+    /// [SafePathError::OwnerMismatch] if any of them has been chowned to someone else in the
+    /// meantime, after rolling back the created directories if
+    /// [SafeDirBuilder::rollback_on_failure()] is enabled.
+    pub fn verify_owner(&mut self, enabled: bool) -> &mut Self {
+        self.verify_owner = enabled;
+        self
+    }
+
+    /// Sets the access and modification times of new directories, `None` leaves the time set by
+    /// the kernel on creation.
+    ///
+    /// The times are applied through the pinned fd of each directory created by the call, from
+    /// the innermost to the outermost after all creations, because creating a child changes the
+    /// modification time of its parent. Existing directories are never touched.
+    pub fn times(&mut self, atime: Option<SystemTime>, mtime: Option<SystemTime>) -> &mut Self {
+        self.atime = atime;
+        self.mtime = mtime;
+        self
+    }
+
+    /// Sets the POSIX ACLs of new directories, an empty slice leaves the ACL unset.
+    ///
+    /// The `access` ACL controls access to the directory itself, and the `default` ACL is
+    /// inherited by files and directories created in it later, as set by `setfacl -d`. The ACLs
+    /// are written as the `system.posix_acl_access` and `system.posix_acl_default` xattrs
+    /// through the pinned fd of each new directory, after its mode and owner are applied, so
+    /// the group class bits of the mode follow the mask of the access ACL. Existing directories
+    /// are never touched.
+    ///
+    /// An ACL must contain the [crate::AclTag::UserObj], [crate::AclTag::GroupObj] and
+    /// [crate::AclTag::Other] entries, and a [crate::AclTag::Mask] if it contains named
+    /// entries, or the creation fails with `InvalidInput`.
+    ///
+    /// If the filesystem doesn't support ACLs, the creation fails with
+    /// [SafePathError::AclUnsupported].
+    pub fn acl(&mut self, access: &[AclEntry], default: &[AclEntry]) -> &mut Self {
+        self.acl_access = access.to_vec();
+        self.acl_default = default.to_vec();
+        self
+    }
+
+    /// Indicates whether new directories and files are flushed to the storage before returning.
+    ///
+    /// When enabled, each newly created directory and its parent directory are synced by
+    /// `fsync()`, from the innermost to the outermost, so the creation survives a crash once
+    /// the call returns. Filesystems which don't support syncing directories are tolerated.
+    pub fn sync(&mut self, enabled: bool) -> &mut Self {
+        self.sync = enabled;
+        self
+    }
+
+    /// Indicates whether symlinks are rejected anywhere in the paths to create.
+    ///
+    /// Symlinks are always resolved under the root, but one created by an untrusted process
+    /// inside the root may still redirect `create("a/b/c")` to another place inside the root.
+    /// When enabled, every existing component met must be a real directory, checked by
+    /// `openat(O_NOFOLLOW)` relative to its parent, and a symlink anywhere in the path fails the
+    /// call with [SafePathError::SymlinkEncountered].
+    pub fn no_follow(&mut self, enabled: bool) -> &mut Self {
+        self.no_follow = enabled;
+        self
+    }
+
+    /// Sets the maximum depth of directories below the root to create, replacing the default of
+    /// 128.
+    ///
+    /// A request for a deeper directory fails with [SafePathError::LimitExceeded] before any
+    /// directory is created, which prevents a malicious spec from exhausting inodes and time by
+    /// a very long directory chain.
+    pub fn max_depth(&mut self, n: usize) -> &mut Self {
+        self.max_depth = n;
+        self
+    }
+
+    /// Sets the maximum number of directories to create by a single call, replacing the default
+    /// of 128.
+    ///
+    /// A request to create more directories fails with [SafePathError::LimitExceeded] before any
+    /// directory is created. The count is also checked while creating, and the call is aborted,
+    /// with rollback if [SafeDirBuilder::rollback_on_failure()] is enabled, if it's exceeded.
+    pub fn max_new_dirs(&mut self, n: usize) -> &mut Self {
+        self.max_new_dirs = n;
+        self
+    }
+
+    /// Sets the maximum number of fds held open at once when walking, creating or removing a
+    /// deep directory tree, replacing the default of 64.
+    ///
+    /// The fds of the outermost directories are closed beyond the limit, and reopened relative
+    /// to a pinned neighbour when needed, verifying their identity, so a deep tree or a low
+    /// `RLIMIT_NOFILE` doesn't exhaust the fd table halfway. Running out of fds anyway fails
+    /// with [SafePathError::TooManyOpenFiles]. At least 2 fds are kept open.
+    pub fn max_open_fds(&mut self, n: usize) -> &mut Self {
+        self.max_open_fds = n;
+        self
+    }
+
+    /// Sets a hook to be invoked with a pinned handle of each directory created by this builder,
+    /// such as to set an SELinux context, an xattr or an ACL on it.
+    ///
+    /// The hook is invoked right after the directory is created, chmodded and chowned, before
+    /// the next component is processed, so labeling it by the handle never races with changes
+    /// to the path. Pre-existing directories are not passed to the hook. An error returned by the
+    /// hook fails the call, after rolling back the created directories if
+    /// [SafeDirBuilder::rollback_on_failure()] is enabled.
+    pub fn after_create<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: Fn(&SafePathBuf) -> Result<()> + Send + Sync + 'static,
+    {
+        self.after_create = Some(Hook(Arc::new(hook)));
+        self
+    }
+
+    /// Indicates whether the directories created by a failed call are removed before returning
+    /// the error.
+    ///
+    /// When enabled, exactly the directories created by the failed call are removed by
+    /// `unlinkat(AT_REMOVEDIR)` relative to the pinned fds of their parents, from the innermost
+    /// to the outermost, leaving pre-existing directories alone. A directory which is no longer
+    /// empty, because someone else has populated it in the meantime, is kept. The returned error
+    /// keeps its [ErrorKind] and notes the outcome of the rollback in its message.
+    pub fn rollback_on_failure(&mut self, enabled: bool) -> &mut Self {
+        self.rollback = enabled;
+        self
+    }
+
     /// Creates the specified directory with the options configured in this builder.
     ///
-    /// The `path` must be a subdirectory of `SafePathBuf::root()`, otherwise error will be returned.
-    /// It is considered an error if the directory already exists unless recursive mode is enabled.
+    /// A relative `path` is relative to the root, and ".." or symlinks never go beyond the root,
+    /// like [crate::scoped_resolve()]. The current working directory of the process is never
+    /// consulted, so `create("a/b")` always creates `a/b` under the root, wherever the process
+    /// is. An absolute `path` must be a subdirectory of the root,
+    /// otherwise error will be returned. If the builder is created by
+    /// [SafeDirBuilder::from_safe_path()], an absolute `path` is also relative to the pinned root.
+    /// It is considered an error if the directory already exists unless recursive mode or
+    /// [SafeDirBuilder::exists_ok()] is enabled.
+    ///
+    /// Directories concurrently created by others are accepted wherever an existing one is, each
+    /// re-verified by `openat(O_DIRECTORY | O_NOFOLLOW)` to be a real directory.
+    ///
+    /// # Errors
+    /// | Condition | ErrorKind |
+    /// |-----------|-----------|
+    /// | `path` is not under the root | `InvalidInput` |
+    /// | a path component or the final path is not a directory | `NotADirectory` |
+    /// | the directory already exists in non-recursive mode without `exists_ok` | `AlreadyExists`, with [SafePathError::AlreadyExists] |
+    /// | the parent directory doesn't exist in non-recursive mode | `NotFound`, with [SafePathError::ParentNotFound] |
+    /// | too many levels of symlinks | `FilesystemLoop` |
+    /// | a symlink is met with `no_follow` set | `FilesystemLoop` |
+    /// | the final component is a symlink and `exists_ok` is set | `FilesystemLoop` |
+    /// | `max_depth` or `max_new_dirs` is exceeded | `InvalidInput` |
+    /// | the path contains invalid component | `InvalidFilename` |
+    ///
+    /// Errors from the underlying syscalls are returned as is. The `io::Error` carries a
+    /// [SafePathError] for failures detected by the builder itself. When a component can't be
+    /// opened or created, [CreateBlocked] is attached to the failure, telling the deepest existing
+    /// directory, the blocking component and the components never attempted.
     pub fn create<P: AsRef<Path>>(&self, path: P) -> Result<SafePathBuf> {
-        let mut root = self.root.clone();
-        let path = safe_join("/", path)?;
-        let mut suffix = path.strip_prefix(&root).map_err(|_| {
-            Error::new(
-                ErrorKind::Other,
-                format!("Invalid path: {}", path.display()),
-            )
-        })?;
-        if suffix.file_name().is_none() {
-            return SafePathBuf::from_path(root);
-        }
-        if !self.recursive {
-            if let Some(parent) = path.parent() {
-                root = root.join(parent);
+        instrument!(
+            "SafeDirBuilder::create",
+            self.root,
+            path.as_ref(),
+            format_args!("recursive={} mode={:#o}", self.recursive, self.mode),
+            {
+                let suffix = self.scoped_suffix(path.as_ref())?;
+                self.do_create(&suffix, false).map(|c| c.path)
             }
-            // Safe to unwrap() because we have verified `suffix` is not empty.
-            suffix = Path::new(suffix.file_name().unwrap());
-        }
+        )
+    }
 
-        for comp in suffix {
-            let file = SafePathBuf::from_path(&root)?;
-            if !file.target().is_dir() {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    format!("Invalid path: {}", root.display()),
-                ));
+    /// Creates the specified directory like [SafeDirBuilder::create()], and reports which
+    /// directories were actually created by this call.
+    ///
+    /// This is useful to only fix up the ownership of directories created by ourselves, or to
+    /// account for idempotent creations. In recursive mode, an existing directory is not an
+    /// error and is reported with `created` being false.
+    ///
+    /// # Errors
+    /// The same as [SafeDirBuilder::create()].
+    pub fn create_reporting<P: AsRef<Path>>(&self, path: P) -> Result<CreatedDir> {
+        instrument!(
+            "SafeDirBuilder::create_reporting",
+            self.root,
+            path.as_ref(),
+            format_args!("recursive={} mode={:#o}", self.recursive, self.mode),
+            {
+                let suffix = self.scoped_suffix(path.as_ref())?;
+                self.do_create(&suffix, false)
             }
-            root = root.join(comp);
-            DirBuilder::new()
-                .mode(self.mode)
-                .recursive(true)
-                .create(&root)?;
-        }
+        )
+    }
 
-        let result = SafePathBuf::from_path(&root)?;
-        if !result.target().is_dir() {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("Invalid path: {}", root.display()),
-            ));
+    /// Creates the specified directory like [SafeDirBuilder::create()], and tells whether the
+    /// directory itself was created by this call, instead of already existing.
+    ///
+    /// It's a shorthand of [SafeDirBuilder::create_reporting()] for idempotent provisioning,
+    /// which only cares about the final directory. An existing directory is detected by
+    /// `mkdirat()` failing with `EEXIST`, so a directory concurrently created by another process
+    /// is reported as existing.
+    ///
+    /// # Errors
+    /// The same as [SafeDirBuilder::create()].
+    pub fn create_checked<P: AsRef<Path>>(&self, path: P) -> Result<(SafePathBuf, bool)> {
+        self.create_reporting(path).map(|c| (c.path, c.created))
+    }
+
+    /// Creates the specified directory like [SafeDirBuilder::create_reporting()], on the
+    /// blocking thread pool of the tokio runtime.
+    ///
+    /// Creating a directory chain on a slow filesystem, such as NFS, may block for a long time,
+    /// so the walk is moved off the async worker threads. The settings of the builder are cloned
+    /// into the blocking task, so `self` stays usable, and may be reconfigured, while the
+    /// creation is in progress. The returned [CreatedDir] reports the directories created by the
+    /// call, and on failure the created directories are rolled back if
+    /// [SafeDirBuilder::rollback_on_failure()] is enabled.
+    ///
+    /// Dropping the future doesn't abort the creation, which runs to completion on the blocking
+    /// pool, then its pinned fds are closed along with the unused result. Only available with
+    /// the `tokio` feature, and must be polled within a tokio runtime.
+    ///
+    /// # Errors
+    /// The same as [SafeDirBuilder::create()], and `Interrupted` if the runtime shuts down
+    /// before the creation is started.
+    #[cfg(feature = "tokio")]
+    pub fn create_async<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> impl Future<Output = Result<CreatedDir>> + Send + 'static {
+        let builder = self.clone();
+        let path = path.as_ref().to_path_buf();
+        async move {
+            match tokio::task::spawn_blocking(move || builder.create_reporting(path)).await {
+                Ok(result) => result,
+                Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+                Err(e) => Err(Error::new(ErrorKind::Interrupted, e)),
+            }
         }
+    }
+
+    /// Creates each of the specified directories like [SafeDirBuilder::create()], and returns
+    /// the per-item results in the same order.
+    ///
+    /// The items are processed in order with the same semantics as individual `create()` calls,
+    /// but the walk of the previous item is kept and reused for the next one, ascending to
+    /// their common parent, such as "a" for "a/b" then "a/d", so the shared parent directories
+    /// are resolved only once when related items are adjacent. Only that one walk is kept, so
+    /// the fds held by the call are bounded by [SafeDirBuilder::max_open_fds()], besides the
+    /// one pinned by each returned [SafePathBuf]. A failing item doesn't stop the others.
+    pub fn create_all<I, P>(&self, paths: I) -> Vec<Result<SafePathBuf>>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let mut cached: Option<(PathBuf, ScopedWalk)> = None;
+        paths
+            .into_iter()
+            .map(|path| {
+                let suffix = self.scoped_suffix(path.as_ref())?;
+                let (mut walk, remain) = match cached.take() {
+                    Some((prefix, mut walk)) => match shared_depth(&walk, &prefix, &suffix) {
+                        Some(depth) => {
+                            walk.truncate(depth)?;
+                            (walk, suffix.components().skip(depth).collect())
+                        }
+                        // A symlink was expanded, so only a later item under the whole
+                        // previous one can reuse its walk.
+                        None if suffix.starts_with(&prefix) => {
+                            let remain = suffix.strip_prefix(&prefix).unwrap().to_path_buf();
+                            (walk, remain)
+                        }
+                        None => (self.start_walk()?, suffix.clone()),
+                    },
+                    None => (self.start_walk()?, suffix.clone()),
+                };
 
-        Ok(result)
+                self.create_in(&mut walk, &remain, false)?;
+                let path = SafePathBuf::from_file(walk.fd().try_clone()?.into())?;
+                cached = Some((suffix, walk));
+                Ok(path)
+            })
+            .collect()
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs;
-    use std::os::unix::fs::MetadataExt;
+    /// Creates the specified regular file, and the missing parent directories with the options
+    /// configured in this builder.
+    ///
+    /// The `path` must be under the root, as for [SafeDirBuilder::create()]. The parent directory
+    /// must exist unless recursive mode is enabled. The file is created with the mode configured
+    /// by [SafeDirBuilder::file_mode()] by `openat()` with `O_CREAT | O_EXCL | O_NOFOLLOW`
+    /// relative to the pinned fd of its parent, so a symlink at the final component is never
+    /// followed. It is considered an error if the file already exists unless
+    /// [SafeDirBuilder::exists_ok()] is set.
+    ///
+    /// # Errors
+    /// | Condition | ErrorKind |
+    /// |-----------|-----------|
+    /// | `path` is not under the root | `InvalidInput` |
+    /// | `path` has no file name | `InvalidFilename` |
+    /// | a parent component is not a directory | `NotADirectory` |
+    /// | the parent directory doesn't exist in non-recursive mode | `NotFound` |
+    /// | the file already exists and `exists_ok` is not set | `AlreadyExists` |
+    /// | the final component is a symlink and `exists_ok` is set | `FilesystemLoop` |
+    /// | the final component is a directory and `exists_ok` is set | `IsADirectory` |
+    /// | too many levels of symlinks | `FilesystemLoop` |
+    ///
+    /// Errors from the underlying syscalls are returned as is.
+    pub fn create_file<P: AsRef<Path>>(&self, path: P) -> Result<SafePathBuf> {
+        instrument!(
+            "SafeDirBuilder::create_file",
+            self.root,
+            path.as_ref(),
+            format_args!(
+                "recursive={} mode={:#o} file_mode={:#o} exists_ok={}",
+                self.recursive, self.mode, self.file_mode, self.exists_ok
+            ),
+            {
+                let (walk, name, created) = self.create_parent(path.as_ref())?;
 
-    #[test]
-    fn test_safe_dir_builder() {
-        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
-        let rootfs_path = rootfs_dir.path();
+                let flags = libc::O_RDONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW;
+                let fd = self
+                    .open_file(&walk, name, flags)
+                    .and_then(|fd| self.apply_times(&walk, &created).map(|_| fd))
+                    .map_err(|e| self.rollback(&walk, &created, e))?;
+
+                SafePathBuf::from_file(fd.into())
+            }
+        )
+    }
 
-        SafeDirBuilder::new(rootfs_path.join("__does_not_exist__")).unwrap_err();
+    /// Creates a fifo with `mode` at `path`, and the missing parent directories with the options
+    /// configured in this builder.
+    ///
+    /// The parent directory is resolved, and created in recursive mode, like
+    /// [SafeDirBuilder::create_file()], then the fifo is created by `mknodat()` relative to the
+    /// pinned fd of the parent. The final component is never followed, so an existing file or
+    /// symlink at `path` fails the creation. The exact `mode`, masked by
+    /// [SafeDirBuilder::umask()], is applied regardless of the process umask.
+    ///
+    /// # Errors
+    /// | Condition | ErrorKind |
+    /// |-----------|-----------|
+    /// | `path` is not under the root | `InvalidInput` |
+    /// | `path` has no file name | `InvalidFilename` |
+    /// | a parent component is not a directory | `NotADirectory` |
+    /// | the parent directory doesn't exist in non-recursive mode | `NotFound` |
+    /// | the final component already exists, including a symlink | `AlreadyExists` |
+    /// | too many levels of symlinks | `FilesystemLoop` |
+    pub fn create_fifo<P: AsRef<Path>>(&self, path: P, mode: u32) -> Result<SafePathBuf> {
+        self.create_node(path.as_ref(), libc::S_IFIFO, mode, 0)
+    }
 
-        fs::write(rootfs_path.join("txt"), "test").unwrap();
-        SafeDirBuilder::new(rootfs_path.join("txt")).unwrap_err();
+    /// Creates a device node of `kind` with `mode` and device number `dev` at `path`, and the
+    /// missing parent directories with the options configured in this builder.
+    ///
+    /// This is the same as [SafeDirBuilder::create_fifo()] except for the type of the node.
+    /// Creating device nodes requires `CAP_MKNOD`.
+    ///
+    /// # Errors
+    /// | Condition | ErrorKind |
+    /// |-----------|-----------|
+    /// | the caller has no `CAP_MKNOD` | `PermissionDenied` |
+    ///
+    /// Other errors are the same as [SafeDirBuilder::create_fifo()].
+    pub fn create_device<P: AsRef<Path>>(
+        &self,
+        path: P,
+        mode: u32,
+        dev: u64,
+        kind: DeviceKind,
+    ) -> Result<SafePathBuf> {
+        self.create_node(path.as_ref(), kind.file_type(), mode, dev)
+            .map_err(|e| match e.raw_os_error() {
+                Some(libc::EPERM) => Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!(
+                        "Creating device node requires CAP_MKNOD: {}",
+                        self.root.join(path.as_ref()).display()
+                    ),
+                ),
+                _ => e,
+            })
+    }
 
-        let mut builder = SafeDirBuilder::new(&rootfs_path).unwrap();
-        builder.create("/txt/a").unwrap_err();
+    /// Creates a symlink at `link_path` pointing to `target`, and the missing parent directories
+    /// with the options configured in this builder.
+    ///
+    /// The parent directory of `link_path` is resolved, and created in recursive mode, like
+    /// [SafeDirBuilder::create_file()], then the symlink is created by `symlinkat()` relative to
+    /// the pinned fd of the parent. The `target` is stored verbatim, it may be absolute or
+    /// relative and needn't exist. Note that an absolute `target` is interpreted against the
+    /// root of the filesystem by anyone following the symlink outside of this crate.
+    ///
+    /// # Errors
+    /// | Condition | ErrorKind |
+    /// |-----------|-----------|
+    /// | `link_path` is not under the root | `InvalidInput` |
+    /// | `link_path` has no file name | `InvalidFilename` |
+    /// | a parent component is not a directory | `NotADirectory` |
+    /// | the parent directory doesn't exist in non-recursive mode | `NotFound` |
+    /// | the final component already exists, including a symlink | `AlreadyExists`, with [SafePathError::AlreadyExists] |
+    /// | too many levels of symlinks | `FilesystemLoop` |
+    pub fn create_symlink<P: AsRef<Path>>(&self, link_path: P, target: &Path) -> Result<()> {
+        let (walk, name, created) = self.create_parent(link_path.as_ref())?;
 
-        let path = builder.create(rootfs_path.join(".")).unwrap();
-        assert_eq!(path.target(), rootfs_path);
-        builder.create(rootfs_path.join("a/b")).unwrap_err();
-        builder.create(rootfs_path.join("a/b/c")).unwrap_err();
-        builder.create(rootfs_path.join("txt")).unwrap_err();
+        sys::symlinkat(target, walk.fd(), name)
+            .map_err(|e| walk.exists_error(e, name))
+            .and_then(|_| self.apply_times(&walk, &created))
+            .and_then(|_| match self.sync {
+                true => sys::fsync_dir(walk.fd()),
+                false => Ok(()),
+            })
+            .map_err(|e| self.rollback(&walk, &created, e))
+    }
 
-        let path = builder.create(rootfs_path.join("a")).unwrap();
-        assert_eq!(path.target(), rootfs_path.join("a"));
-        assert!(rootfs_path.join("a").is_dir());
+    /// Creates a hardlink at `link_path` to the pinned `existing` file, and the missing parent
+    /// directories with the options configured in this builder.
+    ///
+    /// The parent directory of `link_path` is resolved, and created in recursive mode, like
+    /// [SafeDirBuilder::create_file()], then the hardlink is created by `linkat()` from the
+    /// pinned fd of `existing` into the pinned fd of the parent, so the link always refers to
+    /// the validated inode, as [crate::safe_hardlink()] does.
+    ///
+    /// # Errors
+    /// | Condition | ErrorKind |
+    /// |-----------|-----------|
+    /// | `link_path` is not under the root | `InvalidInput` |
+    /// | `link_path` has no file name | `InvalidFilename` |
+    /// | a parent component is not a directory | `NotADirectory` |
+    /// | the parent directory doesn't exist in non-recursive mode | `NotFound` |
+    /// | the final component already exists, including a symlink | `AlreadyExists`, with [SafePathError::AlreadyExists] |
+    /// | `existing` is on another filesystem | `CrossesDevices`, with [SafePathError::CrossDevice] |
+    /// | `existing` is a directory | `PermissionDenied` |
+    pub fn create_hardlink<P: AsRef<Path>>(
+        &self,
+        link_path: P,
+        existing: &SafePathBuf,
+    ) -> Result<SafePathBuf> {
+        instrument!(
+            "SafeDirBuilder::create_hardlink",
+            self.root,
+            link_path.as_ref(),
+            format_args!("existing={}", existing.target().display()),
+            {
+                let (walk, name, created) = self.create_parent(link_path.as_ref())?;
 
-        builder.recursive();
-        builder.mode(0o740);
-        let path = builder.create(rootfs_path.join("a/b/c/d")).unwrap();
-        assert_eq!(path.target(), rootfs_path.join("a/b/c/d"));
-        assert!(rootfs_path.join("a/b/c/d").is_dir());
-        assert_eq!(
-            rootfs_path.join("a").metadata().unwrap().mode() & 0o777,
-            DIRECTORY_MODE_DEFAULT,
-        );
-        assert_eq!(
-            rootfs_path.join("a/b").metadata().unwrap().mode() & 0o777,
-            0o740
-        );
-        assert_eq!(
-            rootfs_path.join("a/b/c").metadata().unwrap().mode() & 0o777,
-            0o740
-        );
-        assert_eq!(
-            rootfs_path.join("a/b/c/d").metadata().unwrap().mode() & 0o777,
-            0o740
-        );
+                let fd = walk
+                    .link_at(existing.as_file(), name, || existing.target().to_path_buf())
+                    .and_then(|_| self.apply_times(&walk, &created))
+                    .and_then(|_| match self.sync {
+                        true => sys::fsync_dir(walk.fd()),
+                        false => Ok(()),
+                    })
+                    .and_then(|_| sys::openat(walk.fd(), name, libc::O_PATH | libc::O_NOFOLLOW, 0))
+                    .map_err(|e| self.rollback(&walk, &created, e))?;
+
+                SafePathBuf::from_file(fd.into())
+            }
+        )
+    }
+
+    fn create_node(&self, path: &Path, file_type: u32, mode: u32, dev: u64) -> Result<SafePathBuf> {
+        instrument!(
+            "SafeDirBuilder::create_node",
+            self.root,
+            path,
+            format_args!("type={:#o} mode={:#o} dev={:#x}", file_type, mode, dev),
+            {
+                let (walk, name, created) = self.create_parent(path)?;
+
+                let mode = mode & FILE_MODE_MASK & !self.umask;
+                sys::mknodat(walk.fd(), name, file_type | mode, dev)
+                    .map_err(|e| self.rollback(&walk, &created, e))?;
+                let fd = sys::openat(walk.fd(), name, libc::O_PATH | libc::O_NOFOLLOW, 0)
+                    .and_then(|fd| {
+                        // The process umask has been applied by mknodat(), so set the exact mode.
+                        sys::fchmod(&fd, mode)?;
+                        self.apply_times(&walk, &created)?;
+                        if self.sync {
+                            sys::fsync_dir(walk.fd())?;
+                        }
+                        Ok(fd)
+                    })
+                    .map_err(|e| {
+                        let _ = sys::unlinkat(walk.fd(), name, 0);
+                        self.rollback(&walk, &created, e)
+                    })?;
+
+                SafePathBuf::from_file(fd.into())
+            }
+        )
+    }
+
+    /// Creates an empty staging directory to be atomically published at `final_path` later.
+    ///
+    /// This allows to build a fully-populated directory tree off to the side, then publish it by
+    /// [StagedDir::publish()], so a half-built directory is never exposed at `final_path`. The
+    /// staging directory is created with a name prefixed by `.staging` next to `final_path`, so
+    /// the move is atomic on the same filesystem. The parent directory of `final_path` is
+    /// resolved, and created in recursive mode, like [SafeDirBuilder::create_file()].
+    ///
+    /// # Errors
+    /// | Condition | ErrorKind |
+    /// |-----------|-----------|
+    /// | `final_path` is not under the root | `InvalidInput` |
+    /// | `final_path` has no file name | `InvalidFilename` |
+    /// | a parent component is not a directory | `NotADirectory` |
+    /// | the parent directory doesn't exist in non-recursive mode | `NotFound` |
+    pub fn create_staged<P: AsRef<Path>>(&self, final_path: P) -> Result<StagedDir> {
+        let (walk, final_name, created) = self.create_parent(final_path.as_ref())?;
+        let final_name = final_name.to_os_string();
+
+        let next_name = || {
+            Ok(OsString::from(format!(
+                "{}.{}.{}",
+                STAGING_PREFIX,
+                std::process::id(),
+                STAGING_COUNTER.fetch_add(1, Ordering::Relaxed)
+            )))
+        };
+        let (name, fd) = self
+            .make_unique_dir(walk.fd(), next_name)
+            .and_then(|dir| self.apply_times(&walk, &created).map(|_| dir))
+            .map_err(|e| self.rollback(&walk, &created, e))?;
+
+        Ok(StagedDir {
+            dir: SafePathBuf::from_file(fd.into())?,
+            parent: walk.into_fd(),
+            name,
+            final_name,
+            sync: self.sync,
+        })
+    }
 
-        builder.create(rootfs_path.join("txt/e/f")).unwrap_err();
+    /// Reports the directories which [SafeDirBuilder::create()] would create for `path`, without
+    /// touching the filesystem.
+    ///
+    /// The same scoping and existence checks as [SafeDirBuilder::create()] are performed, so the
+    /// same errors are returned for escapes, non-directory components, or exceeded limits, but
+    /// no directory is created. The directories are returned from the outermost to the
+    /// innermost, and an empty list means the directory already exists. The result may be stale
+    /// if the filesystem is changed concurrently.
+    ///
+    /// # Errors
+    /// The same as [SafeDirBuilder::create()].
+    pub fn dry_run<P: AsRef<Path>>(&self, path: P) -> Result<Vec<PathBuf>> {
+        self.check(path).map(|plan| plan.to_create)
+    }
+
+    /// Checks whether [SafeDirBuilder::create()] would succeed for `path`, and reports what it
+    /// would do, without any writes to the filesystem.
+    ///
+    /// The resolution is shared with [SafeDirBuilder::create()], so the same typed errors are
+    /// returned for escapes, a file in the way, non-directory components or exceeded limits.
+    /// The returned [CreatePlan] has the resolved existing prefix, the directories to create,
+    /// and the modes and owner to apply to them. The plan may be stale if the filesystem is
+    /// changed concurrently.
+    ///
+    /// # Errors
+    /// The same as [SafeDirBuilder::create()].
+    pub fn check<P: AsRef<Path>>(&self, path: P) -> Result<CreatePlan> {
+        let suffix = self.scoped_suffix(path.as_ref())?;
+        let mut walk = self.start_walk()?;
+        let missing = self.plan_in(&mut walk, &suffix, false)?;
+
+        let existing = self.root.join(walk.path());
+        let mut path = existing.clone();
+        let to_create = missing
+            .iter()
+            .map(|name| {
+                path.push(name);
+                path.clone()
+            })
+            .collect();
+        Ok(CreatePlan {
+            existing,
+            to_create,
+            mode: self.mode & !self.umask,
+            parents_mode: self.parents_mode.unwrap_or(self.mode) & !self.umask,
+            owner: self.host_owner()?,
+        })
+    }
+
+    /// Creates a randomly named temporary directory under `parent`, which is removed with all its
+    /// contents when the returned [SafeTempDir] is dropped.
+    ///
+    /// This is `tempfile::tempdir_in()` with the guarantees of this crate: `parent` is resolved
+    /// under the root, and created in recursive mode, like the parent directory of
+    /// [SafeDirBuilder::create_file()]. The temporary directory is named `prefix` followed by
+    /// random characters, and created by `mkdirat()` relative to the pinned fd of `parent`,
+    /// retrying with another name if it already exists. The removal on drop is best effort,
+    /// relative to the pinned fd of `parent`, and skipped if the name no longer refers to the
+    /// created directory. Use [SafeTempDir::keep()] to keep it instead.
+    ///
+    /// # Errors
+    /// | Condition | ErrorKind |
+    /// |-----------|-----------|
+    /// | `parent` is not under the root | `InvalidInput` |
+    /// | a component of `parent` is not a directory | `NotADirectory` |
+    /// | `parent` doesn't exist in non-recursive mode | `NotFound` |
+    /// | `prefix` contains "/" or NUL | `InvalidFilename` |
+    pub fn create_temp<P: AsRef<Path>>(&self, parent: P, prefix: &str) -> Result<SafeTempDir> {
+        if prefix.contains('/') || prefix.contains('\0') {
+            return Err(SafePathError::invalid_name(prefix).into());
+        }
+        let (walk, created) = self.walk_parent(parent.as_ref())?;
+
+        let next_name = || {
+            let mut random = [0u8; 8];
+            sys::getrandom(&mut random)?;
+            Ok(OsString::from(format!(
+                "{}{:016x}",
+                prefix,
+                u64::from_ne_bytes(random)
+            )))
+        };
+        let (name, fd) = self
+            .make_unique_dir(walk.fd(), next_name)
+            .and_then(|dir| self.apply_times(&walk, &created).map(|_| dir))
+            .map_err(|e| self.rollback(&walk, &created, e))?;
+
+        let st = sys::fstat(&fd)?;
+        Ok(SafeTempDir {
+            dir: Some(SafePathBuf::from_file(fd.into())?),
+            parent: walk.into_fd(),
+            name,
+            ident: (st.st_dev, st.st_ino),
+            max_open_fds: self.max_open_fds,
+        })
+    }
+
+    /// Removes the empty directory at `path` under the root.
+    ///
+    /// `path` is resolved like [SafeDirBuilder::create()] without creating anything, except that
+    /// a symlink at the final component is not followed. The directory is removed by
+    /// `unlinkat(AT_REMOVEDIR)` relative to the pinned fd of its parent, so it's never
+    /// re-resolved from the root, and the root itself is never removed.
+    ///
+    /// # Errors
+    /// | Condition | ErrorKind |
+    /// |-----------|-----------|
+    /// | `path` is not under the root | `InvalidInput` |
+    /// | `path` resolves to the root | `PermissionDenied`, with [SafePathError::RootRemoval] |
+    /// | `path` doesn't exist | `NotFound` |
+    /// | `path` is not a directory, including a symlink | `NotADirectory` |
+    /// | the directory is not empty | `DirectoryNotEmpty` |
+    pub fn remove_dir<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let (walk, name) = self.walk_removal(path.as_ref())?;
+        // Safe to unwrap() because the walk is never at the root.
+        let parent = walk.parent_fd().unwrap()?;
+
+        sys::unlinkat(&parent, &name, libc::AT_REMOVEDIR)?;
+        if self.sync {
+            sys::fsync_dir(&parent)?;
+        }
+        Ok(())
+    }
+
+    /// Removes `path` under the root, recursively if it's a directory.
+    ///
+    /// `path` is resolved like [SafeDirBuilder::remove_dir()]. Each directory of the tree is
+    /// opened by `openat(O_NOFOLLOW)` relative to its parent and its entries are removed by
+    /// `unlinkat()` relative to it, so symlinks in the tree, including one at `path`, are
+    /// removed themselves instead of being followed.
+    ///
+    /// # Errors
+    /// | Condition | ErrorKind |
+    /// |-----------|-----------|
+    /// | `path` is not under the root | `InvalidInput` |
+    /// | `path` resolves to the root | `PermissionDenied`, with [SafePathError::RootRemoval] |
+    /// | `path` doesn't exist | `NotFound` |
+    /// | out of fds, see [SafeDirBuilder::max_open_fds()] | [SafePathError::TooManyOpenFiles] |
+    pub fn remove_dir_all<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let (walk, name) = self.walk_removal(path.as_ref())?;
+        // Safe to unwrap() because the walk is never at the root.
+        let parent = walk.parent_fd().unwrap()?;
+
+        remove::remove_all_at(&parent, &name, self.max_open_fds, true)?;
+        if self.sync {
+            sys::fsync_dir(&parent)?;
+        }
+        Ok(())
+    }
+
+    /// Walk to `path` without following a symlink at the final component, and return the walk
+    /// with the name of the final component, which is never the root.
+    fn walk_removal(&self, path: &Path) -> Result<(ScopedWalk, OsString)> {
+        let suffix = self.scoped_suffix(path)?;
+
+        let mut walk = self.open_walk()?;
+        walk.walk(&suffix, false, false)?;
+        if walk.is_root() {
+            return Err(SafePathError::RootRemoval {
+                root: self.root.clone(),
+            }
+            .into());
+        }
+        let name = walk.names()[walk.names().len() - 1].clone();
+        Ok((walk, name))
+    }
+
+    /// Create a directory under `parent` with the first name returned by `next_name` which
+    /// doesn't exist yet.
+    fn make_unique_dir<F>(&self, parent: &OwnedFd, next_name: F) -> Result<(OsString, OwnedFd)>
+    where
+        F: Fn() -> Result<OsString>,
+    {
+        let name = loop {
+            let name = next_name()?;
+            match sys::mkdirat(parent, &name, self.mode & !self.umask) {
+                Ok(()) => break name,
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        let fd = sys::openat(
+            parent,
+            &name,
+            libc::O_PATH | libc::O_NOFOLLOW | libc::O_DIRECTORY,
+            0,
+        )
+        .and_then(|fd| {
+            sys::fchmod(&fd, self.mode & !self.umask)?;
+            self.chown(&fd)?;
+            self.run_hook(&fd)?;
+            Ok(fd)
+        });
+        match fd {
+            Ok(fd) => Ok((name, fd)),
+            Err(e) => {
+                // The staging directory is never left behind, it's not visible to the caller.
+                let _ = sys::unlinkat(parent, &name, libc::AT_REMOVEDIR);
+                Err(e)
+            }
+        }
+    }
+
+    /// Split `path` into the parent directory and the final component, then walk to the parent
+    /// directory, creating the missing ones in recursive mode.
+    ///
+    /// The final component is never resolved, otherwise a symlink would be followed.
+    ///
+    /// The depths of the directories created are returned too, as by
+    /// [SafeDirBuilder::create_missing()].
+    fn create_parent<'a>(&self, path: &'a Path) -> Result<(ScopedWalk, &'a OsStr, Vec<usize>)> {
+        let (parent, name) = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => return Err(SafePathError::invalid_name(path).into()),
+        };
+        let (walk, created) = self.walk_parent(parent)?;
+
+        Ok((walk, name, created))
+    }
+
+    /// Walk to the parent directory `parent`, creating the missing ones in recursive mode, and
+    /// return the depths of the directories created, as [SafeDirBuilder::create_missing()].
+    fn walk_parent(&self, parent: &Path) -> Result<(ScopedWalk, Vec<usize>)> {
+        let suffix = self.scoped_suffix(parent)?;
+
+        let mut walk = self.start_walk()?;
+        if let Err(e) = walk.walk(&suffix, true, true) {
+            return Err(self.walk_failed(&mut walk, e));
+        }
+        let missing = walk.take_missing();
+        if !self.recursive && !missing.is_empty() {
+            let relative = walk.path().join(&missing[0]);
+            return Err(SafePathError::ParentNotFound {
+                path: self.root.join(&relative),
+                relative,
+            }
+            .into());
+        }
+        self.check_limits(&walk, missing.len())?;
+        let created = self.create_missing(&mut walk, missing, false)?;
+        if !walk.is_dir() {
+            let err = SafePathError::NotADirectory {
+                path: self.root.join(walk.path()),
+            };
+            return Err(self.blocked_at_last(&walk, Vec::new(), err.into()));
+        }
+
+        Ok((walk, created))
+    }
+
+    /// Create the regular file `name` under the parent directory pinned by `walk` with `flags`,
+    /// accepting an existing one if configured.
+    fn open_file(&self, walk: &ScopedWalk, name: &OsStr, flags: libc::c_int) -> Result<OwnedFd> {
+        let mode = self.file_mode & !self.umask;
+        let fd = match sys::openat(walk.fd(), name, flags, mode) {
+            Ok(fd) => {
+                // The process umask has been applied by openat(), so set the exact mode.
+                sys::fchmod(&fd, mode)?;
+                if self.sync {
+                    sys::fsync(&fd)?;
+                    sys::fsync_dir(walk.fd())?;
+                }
+                fd
+            }
+            Err(e) if self.exists_ok && e.kind() == ErrorKind::AlreadyExists => {
+                let fd = sys::openat(walk.fd(), name, libc::O_PATH | libc::O_NOFOLLOW, 0)?;
+                let st = sys::fstat(&fd)?;
+                if sys::is_symlink(&st) {
+                    return Err(Error::from_raw_os_error(libc::ELOOP));
+                } else if sys::is_dir(&st) {
+                    return Err(Error::from_raw_os_error(libc::EISDIR));
+                } else if st.st_mode & libc::S_IFMT != libc::S_IFREG {
+                    return Err(e);
+                }
+                fd
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(fd)
+    }
+
+    /// Resolve the absolute `path` and get its suffix relative to the root.
+    ///
+    /// If `path` is relative or the root is pinned, `path` is already relative to the root and
+    /// is resolved by the walk.
+    fn scoped_suffix(&self, path: &Path) -> Result<PathBuf> {
+        if self.root_fd.is_some() || path.is_relative() {
+            return Ok(path.to_path_buf());
+        }
+        let path = safe_join("/", path)?;
+        match path.strip_prefix(&self.root) {
+            Ok(suffix) => Ok(suffix.to_path_buf()),
+            Err(_) => Err(SafePathError::OutsideRoot {
+                path,
+                root: self.root.clone(),
+            }
+            .into()),
+        }
+    }
+
+    /// Start a walk at the root, pinned or not, to create directories.
+    fn start_walk(&self) -> Result<ScopedWalk> {
+        // Fail before creating anything if the owner can't be mapped.
+        self.host_owner()?;
+        self.open_walk()
+    }
+
+    /// Start a walk at the root, pinned or not.
+    fn open_walk(&self) -> Result<ScopedWalk> {
+        let mut walk = match &self.root_fd {
+            Some(fd) => ScopedWalk::from_fd(self.root.clone(), fd.try_clone()?),
+            None => ScopedWalk::new(&self.root)?,
+        };
+        walk.set_no_follow(self.no_follow);
+        walk.set_max_fds(self.max_open_fds);
+        walk.set_directories(true);
+        Ok(walk)
+    }
+
+    /// Walk `unsafe_path` under the root and create the missing trailing directories, each one
+    /// by `mkdirat()` relative to the pinned fd of its parent.
+    fn do_create(&self, unsafe_path: &Path, file_ok: bool) -> Result<CreatedDir> {
+        let mut walk = self.start_walk()?;
+        let created_components = self.create_in(&mut walk, unsafe_path, file_ok)?;
+
+        let target = self.root.join(walk.path());
+        Ok(CreatedDir {
+            path: SafePathBuf::from_file(walk.into_fd().into())?,
+            created: created_components.last() == Some(&target),
+            created_components,
+        })
+    }
+
+    /// Walk `unsafe_path` from the current position of `walk`, create the missing trailing
+    /// directories, and return the paths of the directories actually created.
+    ///
+    /// On success, the walk is left at the target.
+    fn create_in(
+        &self,
+        walk: &mut ScopedWalk,
+        unsafe_path: &Path,
+        file_ok: bool,
+    ) -> Result<Vec<PathBuf>> {
+        let missing = self.plan_in(walk, unsafe_path, file_ok)?;
+        let existing = missing.is_empty() && !walk.is_root();
+
+        let created = self.create_missing(walk, missing, true)?;
+
+        if existing && self.chown_existing {
+            self.chown(walk.fd())?;
+            self.check_owner(walk, walk.names().len())?;
+        }
+
+        Ok(created
+            .iter()
+            .map(|&depth| {
+                self.root
+                    .join(walk.names()[..depth].iter().collect::<PathBuf>())
+            })
+            .collect())
+    }
+
+    /// Walk `unsafe_path` from the current position of `walk`, check whether the target may be
+    /// created with the options, and return the missing trailing components to create.
+    ///
+    /// Nothing is written, so [SafeDirBuilder::create()] and [SafeDirBuilder::dry_run()] share
+    /// the same checks. On success, the walk is left at the deepest existing component.
+    fn plan_in(
+        &self,
+        walk: &mut ScopedWalk,
+        unsafe_path: &Path,
+        file_ok: bool,
+    ) -> Result<Vec<OsString>> {
+        // An existing final component is only accepted as a real directory with `exists_ok`.
+        if let Err(e) = walk.walk(unsafe_path, !self.exists_ok, true) {
+            return Err(self.walk_failed(walk, e));
+        }
+
+        let missing = walk.take_missing();
+        if missing.is_empty() {
+            if !self.recursive && !self.exists_ok && !walk.is_root() {
+                return Err(SafePathError::AlreadyExists {
+                    path: self.root.join(walk.path()),
+                    relative: walk.path(),
+                }
+                .into());
+            }
+            // A directory is opened with O_DIRECTORY by the walk, so only other file types are
+            // checked further.
+            if !walk.is_dir() {
+                if sys::is_symlink(&sys::fstat(walk.fd())?) {
+                    return Err(SafePathError::SymlinkEncountered {
+                        path: self.root.join(walk.path()),
+                    }
+                    .into());
+                }
+                if !file_ok {
+                    let err = SafePathError::NotADirectory {
+                        path: self.root.join(walk.path()),
+                    };
+                    return Err(self.blocked_at_last(walk, Vec::new(), err.into()));
+                }
+            }
+        } else if !self.recursive && missing.len() > 1 {
+            let relative = walk.path().join(&missing[0]);
+            return Err(SafePathError::ParentNotFound {
+                path: self.root.join(&relative),
+                relative,
+            }
+            .into());
+        }
+        self.check_limits(walk, missing.len())?;
+
+        Ok(missing)
+    }
+
+    /// Create the `missing` directories one by one by `mkdirat()` relative to the pinned fd of
+    /// its parent, descending the walk into each of them, and return the depths of the
+    /// directories actually created, that is their indices in `walk.fds()`.
+    ///
+    /// The last of `missing` is created with the mode of the final directory if `leaf` is true,
+    /// and all the others with the mode of parent directories. On failure, the directories
+    /// created are rolled back if configured.
+    fn create_missing(
+        &self,
+        walk: &mut ScopedWalk,
+        missing: Vec<OsString>,
+        leaf: bool,
+    ) -> Result<Vec<usize>> {
+        let depth = walk.names().len() + 1;
+        let count = missing.len();
+        let mut created = Vec::new();
+        // The depths of the directories chowned by the call, to verify their owner.
+        let mut chowned = Vec::new();
+        for (i, name) in missing.iter().enumerate() {
+            let mode = match self.parents_mode {
+                Some(mode) if !leaf || i + 1 < count => mode,
+                _ => self.mode,
+            } & !self.umask;
+            let is_new = match sys::mkdirat(walk.fd(), name, mode) {
+                Ok(()) => true,
+                // Someone else may have created it concurrently, the O_DIRECTORY | O_NOFOLLOW
+                // below ensures it's a real directory.
+                Err(e)
+                    if (self.recursive || self.exists_ok)
+                        && e.kind() == ErrorKind::AlreadyExists =>
+                {
+                    false
+                }
+                Err(e) => {
+                    let e = self.blocked(
+                        walk.fd(),
+                        walk.path(),
+                        name.clone(),
+                        missing[i + 1..].to_vec(),
+                        e,
+                    );
+                    return Err(self.rollback(walk, &created, e));
+                }
+            };
+            let fd = match sys::openat(
+                walk.fd(),
+                name,
+                libc::O_PATH | libc::O_NOFOLLOW | libc::O_DIRECTORY,
+                0,
+            ) {
+                Ok(fd) => fd,
+                Err(e) => {
+                    let e = too_many_open_files(e, walk.names().len() + 1);
+                    if is_new && self.rollback {
+                        // Not pinned yet, so try removing it by name before the earlier ones.
+                        let _ = sys::unlinkat(walk.fd(), name, libc::AT_REMOVEDIR);
+                    }
+                    return Err(self.rollback(walk, &created, e));
+                }
+            };
+            walk.push(name.clone(), fd);
+            if is_new {
+                created.push(walk.names().len());
+            }
+            let applied = if is_new {
+                sys::fchmod(walk.fd(), mode)
+            } else {
+                Ok(())
+            }
+            .and_then(|_| {
+                if is_new || self.chown_existing {
+                    chowned.push(walk.names().len());
+                    self.chown(walk.fd())
+                } else {
+                    Ok(())
+                }
+            })
+            .and_then(|_| {
+                if is_new {
+                    self.apply_acl(walk.fd(), || self.root.join(walk.path()))
+                } else {
+                    Ok(())
+                }
+            })
+            .and_then(|_| {
+                if is_new {
+                    self.run_hook(walk.fd())
+                } else {
+                    Ok(())
+                }
+            });
+            if let Err(e) = applied {
+                return Err(self.rollback(walk, &created, e));
+            }
+        }
+
+        for &depth in chowned.iter() {
+            if let Err(e) = self.check_owner(walk, depth) {
+                return Err(self.rollback(walk, &created, e));
+            }
+        }
+        // Otherwise the times are applied after creating the leaf under the new directories.
+        if leaf {
+            if let Err(e) = self.apply_times(walk, &created) {
+                return Err(self.rollback(walk, &created, e));
+            }
+        }
+        if self.sync && !created.is_empty() {
+            for depth in (depth - 1..=walk.names().len()).rev() {
+                if let Err(e) = walk.fd_at(depth).and_then(|fd| sys::fsync_dir(&fd)) {
+                    return Err(self.rollback(walk, &created, e));
+                }
+            }
+        }
+
+        Ok(created)
+    }
+
+    /// Apply the configured times, if any, to the directories at the depths `created` in `walk`,
+    /// from the innermost to the outermost.
+    fn apply_times(&self, walk: &ScopedWalk, created: &[usize]) -> Result<()> {
+        if self.atime.is_none() && self.mtime.is_none() {
+            return Ok(());
+        }
+        let times = [to_timespec(self.atime), to_timespec(self.mtime)];
+        for &depth in created.iter().rev() {
+            sys::futimens(&walk.fd_at(depth)?, &times)?;
+        }
+
+        Ok(())
+    }
+
+    /// Check the limits of creating `count` directories below the current position of `walk`.
+    fn check_limits(&self, walk: &ScopedWalk, count: usize) -> Result<()> {
+        self.check_limit(self.max_depth, walk.names().len() + count)?;
+        self.check_limit(self.max_new_dirs, count)
+    }
+
+    /// Check `requested` against `limit`.
+    fn check_limit(&self, limit: usize, requested: usize) -> Result<()> {
+        if requested > limit {
+            return Err(SafePathError::LimitExceeded { limit, requested }.into());
+        }
+        Ok(())
+    }
+
+    /// Remove the directories created at `created` depths of `walk` if rollback is configured,
+    /// and annotate `err` with the outcome.
+    ///
+    /// Each directory is removed relative to the pinned fd of its parent, from the innermost to
+    /// the outermost. Directories no longer empty are skipped.
+    fn rollback(&self, walk: &ScopedWalk, created: &[usize], err: Error) -> Error {
+        if !self.rollback || created.is_empty() {
+            return err;
+        }
+
+        let mut removed = 0;
+        let mut kept = 0;
+        for &depth in created.iter().rev() {
+            let name = &walk.names()[depth - 1];
+            let removed_one = walk
+                .fd_at(depth - 1)
+                .and_then(|parent| sys::unlinkat(&parent, name, libc::AT_REMOVEDIR));
+            match removed_one {
+                Ok(()) => removed += 1,
+                Err(e)
+                    if matches!(e.raw_os_error(), Some(libc::ENOTEMPTY) | Some(libc::EEXIST)) =>
+                {
+                    kept += 1
+                }
+                Err(e) => {
+                    let path = self
+                        .root
+                        .join(walk.names()[..depth].iter().collect::<PathBuf>());
+                    let note = format!("rollback failed at {}: {}", path.display(), e);
+                    return Annotated::wrap(err, note);
+                }
+            }
+        }
+        let note = match kept {
+            0 => format!("rolled back {} created directories", removed),
+            _ => format!(
+                "rolled back {} created directories, kept {} no longer empty",
+                removed, kept
+            ),
+        };
+        Annotated::wrap(err, note)
+    }
+
+    /// Attach the position where the creation is blocked to `err`, as
+    /// [CreateBlocked], the `blocker` is looked up under the directory `dir`.
+    fn blocked(
+        &self,
+        dir: &OwnedFd,
+        existing: PathBuf,
+        blocker: OsString,
+        not_attempted: Vec<OsString>,
+        err: Error,
+    ) -> Error {
+        let dir = PathBuf::from(format!("/proc/self/fd/{}", dir.as_raw_fd()));
+        let file_type = fs::symlink_metadata(dir.join(&blocker))
+            .ok()
+            .map(|m| m.file_type());
+        CreateBlocked::wrap(existing, blocker, file_type, not_attempted, err)
+    }
+
+    /// Attach the position to `err` where the deepest component pinned by `walk` is blocking.
+    fn blocked_at_last(
+        &self,
+        walk: &ScopedWalk,
+        not_attempted: Vec<OsString>,
+        err: Error,
+    ) -> Error {
+        let (blocker, parents) = match walk.names().split_last() {
+            Some((blocker, parents)) => (blocker.clone(), parents.iter().collect::<PathBuf>()),
+            None => return err,
+        };
+        match walk.parent_fd() {
+            Some(Ok(parent)) => self.blocked(&parent, parents, blocker, not_attempted, err),
+            _ => err,
+        }
+    }
+
+    /// Attach the position to `err` where the walk failed to open a component, if any.
+    fn walk_failed(&self, walk: &mut ScopedWalk, err: Error) -> Error {
+        let mut unwalked = walk.take_unwalked();
+        if unwalked.is_empty() {
+            return err;
+        }
+        // The deepest pinned component is not a directory, so nothing can be opened under it.
+        if err.kind() == ErrorKind::NotADirectory {
+            return self.blocked_at_last(walk, unwalked, err);
+        }
+        let blocker = unwalked.remove(0);
+        self.blocked(walk.fd(), walk.path(), blocker, unwalked, err)
+    }
+
+    /// Run the configured [SafeDirBuilder::after_create()] hook, if any, on the new directory
+    /// pinned by `fd`.
+    fn run_hook(&self, fd: &OwnedFd) -> Result<()> {
+        match &self.after_create {
+            Some(hook) => (hook.0)(&SafePathBuf::from_file(fd.try_clone()?.into())?),
+            None => Ok(()),
+        }
+    }
+
+    /// Apply the configured ACLs, if any, to the new directory pinned by `fd`, whose path is
+    /// got by `path` for reporting errors.
+    fn apply_acl<F: FnOnce() -> PathBuf>(&self, fd: &OwnedFd, path: F) -> Result<()> {
+        // fsetxattr() fails on O_PATH fds, so go through the magic link of the pinned fd.
+        let proc_path = PathBuf::from(format!("/proc/self/fd/{}", fd.as_raw_fd()));
+        for (name, entries) in [
+            (ACL_ACCESS_XATTR, &self.acl_access),
+            (ACL_DEFAULT_XATTR, &self.acl_default),
+        ] {
+            if entries.is_empty() {
+                continue;
+            }
+            match sys::setxattr(&proc_path, name, &acl::encode(entries)) {
+                Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                    return Err(SafePathError::AclUnsupported { path: path() }.into())
+                }
+                Err(e) => return Err(e),
+                Ok(()) => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the configured owner on the host, if any, mapping the owner in the container.
+    fn host_owner(&self) -> Result<Option<(u32, u32)>> {
+        let (uid, gid) = match self.container_owner {
+            Some(owner) => owner,
+            None => return Ok(self.owner),
+        };
+        let uid = IdMap::map(&self.uid_map, uid).ok_or(SafePathError::UnmappedId {
+            id: uid,
+            gid: false,
+        })?;
+        let gid = IdMap::map(&self.gid_map, gid)
+            .ok_or(SafePathError::UnmappedId { id: gid, gid: true })?;
+
+        Ok(Some((uid, gid)))
+    }
+
+    /// Verify the owner of the directory at `depth` in `walk` if configured, failing with
+    /// [SafePathError::OwnerMismatch] if it's not the configured owner.
+    fn check_owner(&self, walk: &ScopedWalk, depth: usize) -> Result<()> {
+        let expected = match self.host_owner()? {
+            Some(owner) if self.verify_owner => owner,
+            _ => return Ok(()),
+        };
+        let st = sys::fstat(&walk.fd_at(depth)?)?;
+        if (st.st_uid, st.st_gid) != expected {
+            return Err(SafePathError::OwnerMismatch {
+                path: self
+                    .root
+                    .join(walk.names()[..depth].iter().collect::<PathBuf>()),
+                expected,
+                actual: (st.st_uid, st.st_gid),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Apply the configured owner, if any, to the directory pinned by `fd`.
+    fn chown(&self, fd: &OwnedFd) -> Result<()> {
+        match self.host_owner()? {
+            Some((uid, gid)) => sys::fchown(fd, uid, gid),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Get the number of leading components `suffix` shares with `prefix`, if `walk` resolved
+/// `prefix` to the same names without expanding any symlink, so it can ascend to their common
+/// parent.
+fn shared_depth(walk: &ScopedWalk, prefix: &Path, suffix: &Path) -> Option<usize> {
+    let names = prefix
+        .components()
+        .map(|c| match c {
+            Component::Normal(name) => Some(name),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    if !walk
+        .names()
+        .iter()
+        .map(OsString::as_os_str)
+        .eq(names.iter().copied())
+    {
+        return None;
+    }
+    let shared = names
+        .iter()
+        .zip(suffix.components())
+        .take_while(|(name, c)| Component::Normal(name) == *c)
+        .count();
+    Some(shared)
+}
+
+/// Convert `time` to a `timespec` for `utimensat()`, `None` leaves the time unchanged.
+pub(crate) fn to_timespec(time: Option<SystemTime>) -> libc::timespec {
+    let (tv_sec, tv_nsec) = match time.map(|t| t.duration_since(UNIX_EPOCH)) {
+        None => (0, libc::UTIME_OMIT),
+        Some(Ok(d)) => (d.as_secs() as i64, d.subsec_nanos() as i64),
+        // Before the epoch, the nanoseconds are still counted forwards.
+        Some(Err(e)) => {
+            let d = e.duration();
+            match d.subsec_nanos() {
+                0 => (-(d.as_secs() as i64), 0),
+                n => (-(d.as_secs() as i64) - 1, 1_000_000_000 - n as i64),
+            }
+        }
+    };
+    libc::timespec {
+        tv_sec: tv_sec as libc::time_t,
+        tv_nsec: tv_nsec as _,
+    }
+}
+
+/// Safely join `unsafe_path` to `root`, creating any missing trailing directories, and return a
+/// pinned handle of the result.
+///
+/// The existing prefix of `unsafe_path` is resolved with the same rules as [crate::safe_join()],
+/// then each missing directory is created with `dir_mode` by `mkdirat()` relative to the pinned
+/// fd of its parent, so there's no window to redirect the creation between resolving and
+/// creating. Directories concurrently created by others are accepted. The `dir_mode` may contain
+/// the setuid, setgid and sticky bits, as for [SafeDirBuilder::default_mode()].
+///
+/// If the final component already exists but is not a directory, an error is returned unless
+/// `file_ok` is true, in which case a handle of the existing file is returned.
+///
+/// # Errors
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | `root` doesn't exist | `NotFound` |
+/// | `root` or a path component is not a directory | `NotADirectory` |
+/// | the final component is not a directory and `file_ok` is false | `NotADirectory` |
+/// | too many levels of symlinks | `FilesystemLoop` |
+/// | the path contains invalid component | `InvalidFilename` |
+pub fn safe_join_or_create<R: AsRef<Path>, U: AsRef<Path>>(
+    root: R,
+    unsafe_path: U,
+    dir_mode: u32,
+    file_ok: bool,
+) -> Result<SafePathBuf> {
+    let mut builder = SafeDirBuilder::new(root)?;
+    builder.recursive(true).mode(dir_mode);
+    builder
+        .do_create(unsafe_path.as_ref(), file_ok)
+        .map(|c| c.path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Racer;
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::os::unix::fs::{symlink, FileTypeExt, MetadataExt};
+    use std::sync::{Barrier, Mutex};
+    use std::thread;
+
+    #[test]
+    fn test_safe_dir_builder() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+
+        let err = SafeDirBuilder::new(rootfs_path.join("__does_not_exist__")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        fs::write(rootfs_path.join("txt"), "test").unwrap();
+        let err = SafeDirBuilder::new(rootfs_path.join("txt")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        let err = builder.create("/txt/a").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(matches!(
+            SafePathError::from_io_error(&err),
+            Some(SafePathError::OutsideRoot { .. })
+        ));
+
+        let path = builder.create(rootfs_path.join(".")).unwrap();
+        assert_eq!(path.target(), rootfs_path);
+        for path in ["a/b", "a/b/c"].iter() {
+            let err = builder.create(rootfs_path.join(path)).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::NotFound);
+            match SafePathError::from_io_error(&err) {
+                Some(SafePathError::ParentNotFound { path, relative }) => {
+                    assert_eq!(path, &rootfs_path.join("a"));
+                    assert_eq!(relative, Path::new("a"));
+                }
+                _ => panic!("unexpected error {}", err),
+            }
+        }
+        let err = builder.create(rootfs_path.join("txt")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(
+            err.to_string(),
+            format!("Path already exists: {}", rootfs_path.join("txt").display())
+        );
+        match SafePathError::from_io_error(&err) {
+            Some(SafePathError::AlreadyExists { path, relative }) => {
+                assert_eq!(path, &rootfs_path.join("txt"));
+                assert_eq!(relative, Path::new("txt"));
+            }
+            _ => panic!("unexpected error {}", err),
+        }
+
+        let path = builder.create(rootfs_path.join("a")).unwrap();
+        assert_eq!(path.target(), rootfs_path.join("a"));
+        assert!(rootfs_path.join("a").is_dir());
+
+        builder.recursive(true);
+        builder.mode(0o740);
+        let path = builder.create(rootfs_path.join("a/b/c/d")).unwrap();
+        assert_eq!(path.target(), rootfs_path.join("a/b/c/d"));
+        assert!(rootfs_path.join("a/b/c/d").is_dir());
+        assert_eq!(
+            rootfs_path.join("a").metadata().unwrap().mode() & 0o777,
+            DIRECTORY_MODE_DEFAULT,
+        );
+        assert_eq!(
+            rootfs_path.join("a/b").metadata().unwrap().mode() & 0o777,
+            0o740
+        );
+        assert_eq!(
+            rootfs_path.join("a/b/c").metadata().unwrap().mode() & 0o777,
+            0o740
+        );
+        assert_eq!(
+            rootfs_path.join("a/b/c/d").metadata().unwrap().mode() & 0o777,
+            0o740
+        );
+
+        // Don't depend on the mode configured above.
+        builder.reset().recursive(true);
+        assert_eq!(builder.current_mode(), DIRECTORY_MODE_DEFAULT);
+        let err = builder.create(rootfs_path.join("txt/e/f")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Blocked at /txt (a regular file) with e/f not attempted: \
+                 component 'txt' at {} is not a directory",
+                rootfs_path.join("txt").display()
+            )
+        );
+        assert!(matches!(
+            SafePathError::from_io_error(&err),
+            Some(SafePathError::NotADirectory { .. })
+        ));
+    }
+
+    #[test]
+    fn test_safe_dir_builder_blocked() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir_all(rootfs_path.join("a/b")).unwrap();
+        fs::write(rootfs_path.join("a/b/txt"), "").unwrap();
+        symlink("/a/b", rootfs_path.join("s")).unwrap();
+        let names = |names: &[&str]| names.iter().map(OsString::from).collect::<Vec<_>>();
+
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        builder.recursive(true);
+        for path in ["a/b/txt/h/i", "s/txt/h/i"].iter() {
+            let err = builder.create(path).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::NotADirectory);
+            assert!(matches!(
+                SafePathError::from_io_error(&err),
+                Some(SafePathError::NotADirectory { .. })
+            ));
+            let blocked = CreateBlocked::from_io_error(&err).unwrap();
+            assert_eq!(blocked.existing, Path::new("a/b"));
+            assert_eq!(blocked.blocker, "txt");
+            assert!(blocked.file_type.unwrap().is_file());
+            assert_eq!(blocked.not_attempted, names(&["h", "i"]));
+        }
+        let err = builder.create_file("a/b/txt/f").unwrap_err();
+        assert_eq!(CreateBlocked::from_io_error(&err).unwrap().blocker, "txt");
+
+        // sysfs refuses new directories even for root.
+        if Path::new("/sys/kernel").is_dir() {
+            let builder = SafeDirBuilder::new("/sys").unwrap();
+            let err = builder.clone().recursive(true).create("x/y").unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+            assert!(SafePathError::from_io_error(&err).is_none());
+            let blocked = CreateBlocked::from_io_error(&err).unwrap();
+            assert_eq!(blocked.existing, Path::new(""));
+            assert_eq!(blocked.blocker, "x");
+            assert!(blocked.file_type.is_none());
+            assert_eq!(blocked.not_attempted, names(&["y"]));
+            assert!(err
+                .to_string()
+                .starts_with("Blocked at /x (nonexistent) with y not"));
+        }
+    }
+
+    #[test]
+    fn test_safe_dir_builder_reusable() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        let mode = |path: &str| rootfs_path.join(path).metadata().unwrap().mode() & 0o7777;
+
+        let mut builder = SafeDirBuilder::with_defaults(rootfs_path).unwrap();
+        assert_eq!(builder.root(), rootfs_path.canonicalize().unwrap());
+        assert!(!builder.is_recursive());
+        assert_eq!(builder.current_mode(), DIRECTORY_MODE_DEFAULT);
+        builder.recursive(true).mode(0o750);
+        assert!(builder.is_recursive());
+        assert_eq!(builder.current_mode(), 0o750);
+        builder.create("a/b").unwrap();
+
+        // Toggle recursion off again.
+        builder.recursive(false);
+        assert!(!builder.is_recursive());
+        let err = builder.create("c/d").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let err = builder.create("a/b").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+
+        // A clone forks the settings.
+        let mut forked = builder.clone();
+        forked.recursive(true).mode(0o700);
+        forked.create("c/d").unwrap();
+        assert_eq!(mode("c/d"), 0o700);
+        builder.create("e").unwrap();
+        assert_eq!(mode("e"), 0o750);
+        assert!(!builder.is_recursive());
+
+        builder.reset();
+        assert!(!builder.is_recursive());
+        assert_eq!(builder.current_mode(), DIRECTORY_MODE_DEFAULT);
+
+        // Clones of a pinned builder share the pinned root.
+        let root = SafePathBuf::from_path(rootfs_path.join("a")).unwrap();
+        let mut builder = SafeDirBuilder::from_safe_path(root).unwrap();
+        builder.recursive(true);
+        let forked = builder.clone();
+        builder.reset();
+        assert_eq!(
+            forked.create("/x/y").unwrap().target(),
+            rootfs_path.join("a/x/y")
+        );
+        assert_eq!(
+            builder.create("/z").unwrap().target(),
+            rootfs_path.join("a/z")
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_safe_dir_builder_serde() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path().canonicalize().unwrap();
+
+        // The current ids, so the owner can be set without privileges.
+        // Safe because geteuid() and getegid() always succeed.
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let mut builder = SafeDirBuilder::new(&rootfs_path).unwrap();
+        builder
+            .recursive(true)
+            .mode(0o750)
+            .owner(uid, gid)
+            .after_create(|_| Ok(()));
+        let value = serde_json::to_value(&builder).unwrap();
+        assert_eq!(value["root"], rootfs_path.to_str().unwrap());
+        assert_eq!(value["mode"], 0o750);
+        assert_eq!(value["recursive"], true);
+        assert_eq!(value["owner"], serde_json::json!([uid, gid]));
+        assert!(value.get("root_fd").is_none());
+        assert!(value.get("after_create").is_none());
+
+        // A template stamps out builders with the same settings.
+        let json = serde_json::to_string(&builder).unwrap();
+        assert_eq!(serde_json::to_string(&builder.clone()).unwrap(), json);
+        let stamped: SafeDirBuilder = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&stamped).unwrap(), json);
+        stamped.create("a/b").unwrap();
+        let meta = rootfs_path.join("a/b").metadata().unwrap();
+        assert_eq!(meta.mode() & 0o7777, 0o750);
+        assert_eq!((meta.uid(), meta.gid()), (uid, gid));
+
+        let mut value = serde_json::to_value(&builder).unwrap();
+        value["root"] = rootfs_path.join("missing").to_str().unwrap().into();
+        assert!(serde_json::from_value::<SafeDirBuilder>(value).is_err());
+        builder.reset();
+        assert_eq!(
+            serde_json::to_value(&builder).unwrap(),
+            serde_json::to_value(SafeDirBuilder::with_defaults(&rootfs_path).unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_safe_dir_builder_exists_ok() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir(rootfs_path.join("dir")).unwrap();
+        fs::write(rootfs_path.join("txt"), "").unwrap();
+        symlink("dir", rootfs_path.join("link")).unwrap();
+        symlink("new", rootfs_path.join("dangling")).unwrap();
+
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        builder.exists_ok(true);
+        let created = builder.create_reporting("a").unwrap();
+        assert!(created.created);
+        let created = builder.create_reporting("dir").unwrap();
+        assert!(!created.created);
+        assert_eq!(created.path.target(), rootfs_path.join("dir"));
+        assert!(created.created_components.is_empty());
+
+        for recursive in [false, true].iter() {
+            builder.recursive(*recursive);
+            assert_eq!(
+                builder.create("dir").unwrap().target(),
+                rootfs_path.join("dir")
+            );
+            let err = builder.create("txt").unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::NotADirectory);
+            for name in ["link", "dangling"].iter() {
+                let err = builder.create(name).unwrap_err();
+                assert!(matches!(
+                    SafePathError::from_io_error(&err),
+                    Some(SafePathError::SymlinkEncountered { .. })
+                ));
+            }
+            assert!(!rootfs_path.join("new").exists());
+        }
+
+        // Symlinks before the final component are still followed.
+        assert_eq!(
+            builder.create("link/b").unwrap().target(),
+            rootfs_path.join("dir/b")
+        );
+    }
+
+    #[test]
+    fn test_safe_dir_builder_times() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir(rootfs_path.join("x")).unwrap();
+        let mtime = UNIX_EPOCH + std::time::Duration::new(1_000_000_000, 500);
+        let atime = UNIX_EPOCH + std::time::Duration::from_secs(1_500_000_000);
+        let times = |path: &str| {
+            let meta = rootfs_path.join(path).metadata().unwrap();
+            (meta.accessed().unwrap(), meta.modified().unwrap())
+        };
+
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        builder.recursive(true).times(Some(atime), Some(mtime));
+        builder.create("a/b/c").unwrap();
+        for path in ["a", "a/b", "a/b/c"].iter() {
+            assert_eq!(times(path), (atime, mtime));
+        }
+
+        // Only new directories are touched, even by a leaf created under them.
+        builder.times(None, Some(mtime));
+        builder.create_file("x/y/f").unwrap();
+        assert_eq!(times("x/y").1, mtime);
+        assert_ne!(times("x/y").0, atime);
+        assert_ne!(times("x").1, mtime);
+
+        // Times before the epoch.
+        let old = UNIX_EPOCH - std::time::Duration::new(10, 250);
+        builder.times(Some(old), Some(old));
+        builder.create("z").unwrap();
+        assert_eq!(times("z"), (old, old));
+    }
+
+    #[test]
+    fn test_safe_dir_builder_acl() {
+        use crate::{AclEntry, AclTag};
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::PermissionsExt;
+
+        let getxattr = |path: &Path, name: &str| {
+            let path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+            let name = std::ffi::CString::new(name).unwrap();
+            let mut buf = vec![0u8; 256];
+            let len = unsafe {
+                libc::getxattr(
+                    path.as_ptr(),
+                    name.as_ptr(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                )
+            };
+            buf.truncate(len.max(0) as usize);
+            buf
+        };
+        let access = [
+            AclEntry::new(AclTag::UserObj, 0o7),
+            AclEntry::new(AclTag::GroupObj, 0o5),
+            AclEntry::new(AclTag::Group(4242), 0o7),
+            AclEntry::new(AclTag::Mask, 0o5),
+            AclEntry::new(AclTag::Other, 0),
+        ];
+        let default = [
+            AclEntry::new(AclTag::UserObj, 0o7),
+            AclEntry::new(AclTag::GroupObj, 0o7),
+            AclEntry::new(AclTag::Other, 0o5),
+        ];
+
+        // The disk-backed tmpdir, and tmpfs if available.
+        let mut dirs = vec![tempfile::tempdir().expect("failed to create tmpdir")];
+        if Path::new("/dev/shm").is_dir() {
+            dirs.extend(tempfile::tempdir_in("/dev/shm").ok());
+        }
+        for rootfs_dir in dirs.iter() {
+            let rootfs_path = rootfs_dir.path();
+            fs::create_dir(rootfs_path.join("x")).unwrap();
+
+            let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+            builder.recursive(true).mode(0o700).acl(&access, &default);
+            match builder.create("x/a/b") {
+                Ok(_) => {}
+                Err(e) => {
+                    assert_eq!(e.kind(), ErrorKind::Unsupported);
+                    assert!(matches!(
+                        SafePathError::from_io_error(&e),
+                        Some(SafePathError::AclUnsupported { .. })
+                    ));
+                    continue;
+                }
+            }
+            for path in ["x/a", "x/a/b"].iter() {
+                let path = rootfs_path.join(path);
+                assert_eq!(
+                    getxattr(&path, ACL_ACCESS_XATTR),
+                    acl::encode(&access),
+                    "{}",
+                    path.display()
+                );
+                assert_eq!(getxattr(&path, ACL_DEFAULT_XATTR), acl::encode(&default));
+                // The group class bits follow the mask.
+                let mode = path.metadata().unwrap().permissions().mode();
+                assert_eq!(mode & 0o777, 0o750);
+            }
+            // Existing directories are never touched.
+            assert!(getxattr(&rootfs_path.join("x"), ACL_ACCESS_XATTR).is_empty());
+            // New files inherit the default ACL.
+            fs::create_dir(rootfs_path.join("x/a/b/c")).unwrap();
+            assert_eq!(
+                getxattr(&rootfs_path.join("x/a/b/c"), ACL_DEFAULT_XATTR),
+                acl::encode(&default)
+            );
+
+            // An incomplete ACL is rejected by the kernel.
+            builder.acl(&access[..3], &[]);
+            let err = builder.create("y").unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn test_safe_dir_builder_id_mappings() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        let maps = [
+            IdMap {
+                container_id: 0,
+                host_id: 100000,
+                size: 1000,
+            },
+            IdMap {
+                container_id: 1000,
+                host_id: 1000,
+                size: 1,
+            },
+            IdMap {
+                container_id: 2000,
+                host_id: u32::MAX - 1,
+                size: 10,
+            },
+        ];
+        assert_eq!(IdMap::map(&maps, 0), Some(100000));
+        assert_eq!(IdMap::map(&maps, 999), Some(100999));
+        assert_eq!(IdMap::map(&maps, 1000), Some(1000));
+        assert_eq!(IdMap::map(&maps, 1001), None);
+        assert_eq!(IdMap::map(&maps, 2000), Some(u32::MAX - 1));
+        assert_eq!(IdMap::map(&maps, 2001), None);
+        assert_eq!(IdMap::map(&[], 0), None);
+
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        builder
+            .recursive(true)
+            .id_mappings(&maps, &maps[..1])
+            .owner_in_container(0, 1000);
+        let err = builder.create("a/b").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(matches!(
+            SafePathError::from_io_error(&err),
+            Some(SafePathError::UnmappedId {
+                id: 1000,
+                gid: true
+            })
+        ));
+        assert!(!rootfs_path.join("a").exists());
+        assert_eq!(
+            builder.check("a").unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+
+        // Changing to other ids needs CAP_CHOWN.
+        if unsafe { libc::geteuid() } == 0 {
+            builder.owner_in_container(0, 999);
+            builder.create("a/b").unwrap();
+            for path in ["a", "a/b"].iter() {
+                let meta = rootfs_path.join(path).metadata().unwrap();
+                assert_eq!((meta.uid(), meta.gid()), (100000, 100999));
+            }
+        }
+    }
+
+    #[test]
+    fn test_safe_dir_builder_no_follow() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir_all(rootfs_path.join("elsewhere/b")).unwrap();
+        symlink("/elsewhere", rootfs_path.join("a")).unwrap();
+
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        builder.recursive(true);
+        let path = builder.create("a/b/c").unwrap();
+        assert_eq!(path.target(), rootfs_path.join("elsewhere/b/c"));
+
+        builder.no_follow(true);
+        let err = builder.create("a/b/d").unwrap_err();
+        assert_eq!(err.kind(), Error::from_raw_os_error(libc::ELOOP).kind());
+        assert!(matches!(
+            SafePathError::from_io_error(&err),
+            Some(SafePathError::SymlinkEncountered { path }) if path == &rootfs_path.join("a")
+        ));
+        assert!(!rootfs_path.join("elsewhere/b/d").exists());
+        let err = builder.create_file("a/b/f").unwrap_err();
+        assert!(matches!(
+            SafePathError::from_io_error(&err),
+            Some(SafePathError::SymlinkEncountered { .. })
+        ));
+        // The final component is checked too.
+        let err = builder.create("a").unwrap_err();
+        assert!(matches!(
+            SafePathError::from_io_error(&err),
+            Some(SafePathError::SymlinkEncountered { .. })
+        ));
+
+        let path = builder.create("elsewhere/b/d").unwrap();
+        assert_eq!(path.target(), rootfs_path.join("elsewhere/b/d"));
+    }
+
+    #[test]
+    fn test_safe_dir_builder_after_create() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir(rootfs_path.join("a")).unwrap();
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let recorded = calls.clone();
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        builder
+            .recursive(true)
+            .mode(0o750)
+            .after_create(move |dir| {
+                assert_eq!(dir.stat()?.mode() & 0o7777, 0o750);
+                recorded.lock().unwrap().push(dir.target().to_path_buf());
+                Ok(())
+            });
+        builder.create("a/b/c/d").unwrap();
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                rootfs_path.join("a/b"),
+                rootfs_path.join("a/b/c"),
+                rootfs_path.join("a/b/c/d"),
+            ]
+        );
+
+        // Fail on the second directory.
+        let count = Arc::new(Mutex::new(0));
+        builder.rollback_on_failure(true).after_create(move |_| {
+            let mut count = count.lock().unwrap();
+            *count += 1;
+            if *count == 2 {
+                return Err(Error::new(ErrorKind::PermissionDenied, "label"));
+            }
+            Ok(())
+        });
+        let err = builder.create("x/y/z").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert!(err
+            .to_string()
+            .contains("rolled back 2 created directories"));
+        assert!(!rootfs_path.join("x").exists());
+    }
+
+    #[test]
+    fn test_safe_dir_builder_limits() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir_all(rootfs_path.join("a/b")).unwrap();
+        let limit_exceeded = |err: &Error, limit, requested| {
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+            matches!(
+                SafePathError::from_io_error(err),
+                Some(SafePathError::LimitExceeded { limit: l, requested: r })
+                    if *l == limit && *r == requested
+            )
+        };
+
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        builder.recursive(true).max_depth(4).max_new_dirs(2);
+        builder.create("a/b/c/d").unwrap();
+        let err = builder.create("a/b/c/d/e").unwrap_err();
+        assert!(limit_exceeded(&err, 4, 5));
+        assert!(!rootfs_path.join("a/b/c/d/e").exists());
+
+        let err = builder.create("x/y/z").unwrap_err();
+        assert!(limit_exceeded(&err, 2, 3));
+        assert!(!rootfs_path.join("x").exists());
+        builder.create("x/y").unwrap();
+
+        // The parents of a file are limited too.
+        let err = builder.create_file("p/q/r/f").unwrap_err();
+        assert!(limit_exceeded(&err, 2, 3));
+        builder.create_file("p/q/f").unwrap();
+
+        // Generous but finite defaults.
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        builder.recursive(true);
+        // `repeat_n()` is not available on older toolchains.
+        #[allow(clippy::manual_repeat_n)]
+        let deep: PathBuf = std::iter::repeat("d").take(129).collect();
+        let err = builder.create(&deep).unwrap_err();
+        assert!(limit_exceeded(&err, 128, 129));
+        assert!(!rootfs_path.join("d").exists());
+    }
+
+    #[test]
+    fn test_safe_dir_builder_dry_run() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir(rootfs_path.join("a")).unwrap();
+        fs::write(rootfs_path.join("f"), "f").unwrap();
+        symlink("/a", rootfs_path.join("s")).unwrap();
+
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        builder.recursive(true);
+        sys::take_syscalls();
+        let plan = builder.dry_run("s/b/c").unwrap();
+        assert_eq!(
+            plan,
+            vec![rootfs_path.join("a/b"), rootfs_path.join("a/b/c")]
+        );
+        let syscalls = sys::take_syscalls();
+        assert!(!syscalls.contains(&"mkdirat"));
+        assert!(!rootfs_path.join("a/b").exists());
+        assert!(builder.dry_run("a").unwrap().is_empty());
+        assert_eq!(
+            builder
+                .create_reporting("s/b/c")
+                .unwrap()
+                .created_components,
+            plan
+        );
+
+        let err = builder.dry_run("/outside").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let err = builder.dry_run("f/b").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+        let err = builder.dry_run("f").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+
+        builder.recursive(false);
+        let err = builder.dry_run("x/y").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let err = builder.dry_run("a").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn test_safe_dir_builder_check() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir_all(rootfs_path.join("a/b")).unwrap();
+        fs::write(rootfs_path.join("f"), "f").unwrap();
+        symlink("/a", rootfs_path.join("s")).unwrap();
+        let mode = |path: &Path| path.metadata().unwrap().mode() & 0o7777;
+
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        builder.recursive(true).parents_mode(0o755).mode(0o700);
+        for path in ["s/b/c/d", "x/y", "a/b", "../a/z", "."].iter() {
+            let plan = builder.check(path).unwrap();
+            let created = builder.create_reporting(path).unwrap();
+            assert_eq!(plan.to_create, created.created_components, "{}", path);
+            match plan.to_create.split_last() {
+                Some((leaf, parents)) => {
+                    assert_eq!(created.path.target(), leaf);
+                    assert_eq!(plan.to_create[0].parent().unwrap(), plan.existing);
+                    assert_eq!(mode(leaf), plan.mode);
+                    for parent in parents {
+                        assert_eq!(mode(parent), plan.parents_mode);
+                    }
+                }
+                None => assert_eq!(created.path.target(), plan.existing),
+            }
+            assert_eq!(plan.owner, None);
+        }
+
+        // The same errors as create().
+        for path in ["/outside", "f", "f/b", "a/b/c/d/e/f/g/h/i"].iter() {
+            builder.max_depth(6);
+            let check = builder.check(path).unwrap_err();
+            let create = builder.create(path).unwrap_err();
+            assert_eq!(check.kind(), create.kind(), "{}", path);
+            assert_eq!(check.to_string(), create.to_string(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_safe_dir_builder_create_all() {
+        let paths = ["a/b", "a/b/c", "a/d", "txt/x", "a/b/c/../e", "a/b"];
+        let results: Vec<_> = [true, false]
+            .iter()
+            .map(|recursive| {
+                let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+                let rootfs_path = rootfs_dir.path();
+                fs::write(rootfs_path.join("txt"), "test").unwrap();
+                fs::create_dir(rootfs_path.join("a")).unwrap();
+                let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+                if *recursive {
+                    builder.recursive(true);
+                }
+
+                sys::take_syscalls();
+                let batch = builder.create_all(paths.iter());
+                let batch_syscalls = sys::take_syscalls().len();
+                fs::remove_dir_all(rootfs_path.join("a")).unwrap();
+                fs::create_dir(rootfs_path.join("a")).unwrap();
+                sys::take_syscalls();
+                let single: Vec<_> = paths.iter().map(|p| builder.create(p)).collect();
+                assert!(batch_syscalls < sys::take_syscalls().len());
+
+                // The same results as individual create() calls.
+                assert_eq!(batch.len(), single.len());
+                for (b, s) in batch.iter().zip(single.iter()) {
+                    match (b, s) {
+                        (Ok(b), Ok(s)) => assert_eq!(b.target(), s.target()),
+                        (Err(b), Err(s)) => assert_eq!(b.kind(), s.kind()),
+                        _ => panic!("{:?} != {:?}", b, s),
+                    }
+                }
+                batch
+                    .into_iter()
+                    .map(|r| r.map(|p| p.relative_to_root(rootfs_path).unwrap()))
+                    .map(|r| r.map_err(|e| e.kind()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        let ok = |p: &str| Ok(PathBuf::from(p));
+        assert_eq!(
+            results[0],
+            vec![
+                ok("a/b"),
+                ok("a/b/c"),
+                ok("a/d"),
+                Err(ErrorKind::NotADirectory),
+                ok("a/b/e"),
+                ok("a/b"),
+            ]
+        );
+        assert_eq!(
+            results[1],
+            vec![
+                ok("a/b"),
+                ok("a/b/c"),
+                ok("a/d"),
+                Err(ErrorKind::NotADirectory),
+                ok("a/b/e"),
+                Err(ErrorKind::AlreadyExists),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_safe_dir_builder_relative_path() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        symlink("/a/b", rootfs_path.join("s")).unwrap();
+
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        let err = builder.create("a/b").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        builder.recursive(true);
+        let path = builder.create("a/b/c").unwrap();
+        assert_eq!(path.target(), rootfs_path.join("a/b/c"));
+        let path = builder.create("../../a/../d").unwrap();
+        assert_eq!(path.target(), rootfs_path.join("d"));
+        let path = builder.create("s/../e").unwrap();
+        assert_eq!(path.target(), rootfs_path.join("a/e"));
+        let path = builder.create_file("s/f").unwrap();
+        assert_eq!(path.target(), rootfs_path.join("a/b/f"));
+
+        // Nothing is created relative to the current working directory.
+        let cwd = std::env::current_dir().unwrap();
+        let name = format!("safe-path-{}", std::process::id());
+        let path = builder.create(format!("{}/x", name)).unwrap();
+        assert_eq!(path.target(), rootfs_path.join(&name).join("x"));
+        assert!(!cwd.join(&name).exists());
+
+        // The absolute form still works.
+        let path = builder.create(rootfs_path.join("g")).unwrap();
+        assert_eq!(path.target(), rootfs_path.join("g"));
+        let err = builder.create("/g").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_safe_dir_builder_slash_root() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+
+        let mut builder = SafeDirBuilder::new("/").unwrap();
+        for path in ["/", "//", "/..", "/./"].iter() {
+            let path = builder.create(path).unwrap();
+            assert_eq!(path.target(), Path::new("/"));
+        }
+        let path = builder.create(rootfs_path.join("a")).unwrap();
+        assert_eq!(path.target(), rootfs_path.join("a"));
+        let err = builder.create(rootfs_path.join("a")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+
+        builder.recursive(true);
+        let path = builder
+            .create(Path::new("/..").join(rootfs_path).join("b/../c//d"))
+            .unwrap();
+        assert_eq!(path.target(), rootfs_path.join("c/d"));
+        let path = builder.create_file(rootfs_path.join("e/f")).unwrap();
+        assert_eq!(path.target(), rootfs_path.join("e/f"));
+        let err = builder.create_file("/").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidFilename);
+    }
+
+    #[test]
+    fn test_safe_dir_builder_create_file() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::write(rootfs_path.join("txt"), "test").unwrap();
+        symlink("/etc/passwd", rootfs_path.join("abs")).unwrap();
+        symlink("new", rootfs_path.join("rel")).unwrap();
+
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        builder.file_mode(0o640);
+        let path = builder.create_file(rootfs_path.join("f")).unwrap();
+        assert_eq!(path.target(), rootfs_path.join("f"));
+        let meta = rootfs_path.join("f").symlink_metadata().unwrap();
+        assert!(meta.is_file());
+        assert_eq!(meta.mode() & 0o777, 0o640);
+
+        // Pre-existing file.
+        let err = builder.create_file(rootfs_path.join("txt")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+
+        // Final component pre-placed as a symlink is never followed.
+        for name in ["abs", "rel"].iter() {
+            let err = builder.create_file(rootfs_path.join(name)).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        }
+        assert!(!rootfs_path.join("new").exists());
+
+        // Parent creation follows the recursive setting.
+        let err = builder.create_file(rootfs_path.join("a/b/f")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let err = builder.create_file(rootfs_path.join("txt/f")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+        builder.recursive(true).mode(0o750);
+        let path = builder.create_file(rootfs_path.join("a/b/f")).unwrap();
+        assert_eq!(path.target(), rootfs_path.join("a/b/f"));
+        assert_eq!(
+            rootfs_path.join("a/b").metadata().unwrap().mode() & 0o777,
+            0o750
+        );
+
+        builder.exists_ok(true);
+        let path = builder.create_file(rootfs_path.join("txt")).unwrap();
+        assert_eq!(path.target(), rootfs_path.join("txt"));
+        assert_eq!(fs::read_to_string(rootfs_path.join("txt")).unwrap(), "test");
+        let err = builder.create_file(rootfs_path.join("rel")).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ELOOP));
+        let err = builder.create_file(rootfs_path.join("a")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::IsADirectory);
+        let err = builder.create_file("/etc/f").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_safe_dir_builder_create_node() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        symlink("new", rootfs_path.join("rel")).unwrap();
+
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        builder.recursive(true).umask(0o002);
+        let path = builder
+            .create_fifo(rootfs_path.join("a/fifo"), 0o666)
+            .unwrap();
+        assert_eq!(path.target(), rootfs_path.join("a/fifo"));
+        let meta = rootfs_path.join("a/fifo").symlink_metadata().unwrap();
+        assert!(meta.file_type().is_fifo());
+        assert_eq!(meta.mode() & 0o777, 0o664);
+
+        // Final component pre-placed as a symlink is never followed.
+        let err = builder
+            .create_fifo(rootfs_path.join("rel"), 0o600)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert!(!rootfs_path.join("new").exists());
+
+        // Device creation depends on CAP_MKNOD, /dev/null is 1:3.
+        let dev = libc::makedev(1, 3);
+        match builder.create_device(rootfs_path.join("null"), 0o600, dev, DeviceKind::Char) {
+            Ok(path) => {
+                let meta = path.target().symlink_metadata().unwrap();
+                assert!(meta.file_type().is_char_device());
+                assert_eq!(meta.rdev(), dev);
+            }
+            Err(e) => {
+                assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+                assert!(!rootfs_path.join("null").exists());
+            }
+        }
+    }
+
+    #[test]
+    fn test_safe_dir_builder_create_symlink() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::write(rootfs_path.join("txt"), "test").unwrap();
+
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        let err = builder
+            .create_symlink(rootfs_path.join("etc/mtab"), Path::new("/proc/mounts"))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        // Parents are created with the configured mode, the dangling target is kept verbatim.
+        builder.recursive(true).mode(0o750);
+        builder
+            .create_symlink(rootfs_path.join("etc/mtab"), Path::new("/proc/mounts"))
+            .unwrap();
+        assert_eq!(
+            fs::read_link(rootfs_path.join("etc/mtab")).unwrap(),
+            Path::new("/proc/mounts")
+        );
+        assert_eq!(
+            rootfs_path.join("etc").metadata().unwrap().mode() & 0o777,
+            0o750
+        );
+        builder
+            .create_symlink(rootfs_path.join("etc/rel"), Path::new("../txt"))
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(rootfs_path.join("etc/rel")).unwrap(),
+            "test"
+        );
+
+        // Pre-existing entries at the link path, including symlinks, are never replaced.
+        for name in ["txt", "etc", "etc/mtab"].iter() {
+            let err = builder
+                .create_symlink(rootfs_path.join(name), Path::new("x"))
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+            match SafePathError::from_io_error(&err) {
+                Some(SafePathError::AlreadyExists { relative, .. }) => {
+                    assert_eq!(relative, Path::new(name))
+                }
+                _ => panic!("unexpected error: {:?}", err),
+            }
+        }
+        let err = builder
+            .create_symlink("/etc/link", Path::new("x"))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_safe_dir_builder_create_hardlink() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::write(rootfs_path.join("blob"), "test").unwrap();
+        fs::write(rootfs_path.join("txt"), "").unwrap();
+        let blob = SafePathBuf::new(rootfs_path, "blob").unwrap();
+
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        builder.recursive(true);
+        let path = builder
+            .create_hardlink(rootfs_path.join("usr/bin/tool"), &blob)
+            .unwrap();
+        assert_eq!(path.target(), rootfs_path.join("usr/bin/tool"));
+        assert_eq!(path.stat().unwrap().ino(), blob.stat().unwrap().ino());
+        assert_eq!(blob.stat().unwrap().nlink(), 2);
+
+        let err = builder
+            .create_hardlink(rootfs_path.join("txt"), &blob)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        match SafePathError::from_io_error(&err) {
+            Some(SafePathError::AlreadyExists { path, relative }) => {
+                assert_eq!(path, &rootfs_path.join("txt"));
+                assert_eq!(relative, Path::new("txt"));
+            }
+            _ => panic!("unexpected error: {:?}", err),
+        }
+
+        // procfs is always another filesystem.
+        let version = SafePathBuf::new("/proc", "version").unwrap();
+        let err = builder
+            .create_hardlink(rootfs_path.join("version"), &version)
+            .unwrap_err();
+        assert_eq!(err.kind(), Error::from_raw_os_error(libc::EXDEV).kind());
+        assert!(matches!(
+            SafePathError::from_io_error(&err),
+            Some(SafePathError::CrossDevice { .. })
+        ));
+        assert!(!rootfs_path.join("version").exists());
+    }
+
+    #[test]
+    fn test_safe_dir_builder_remove() {
+        let host_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        fs::write(host_dir.path().join("keep"), "keep").unwrap();
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir_all(rootfs_path.join("empty")).unwrap();
+        fs::create_dir_all(rootfs_path.join("tree/a/b")).unwrap();
+        fs::write(rootfs_path.join("tree/a/b/f"), "f").unwrap();
+        symlink(host_dir.path(), rootfs_path.join("tree/a/host")).unwrap();
+        symlink(host_dir.path(), rootfs_path.join("host")).unwrap();
+
+        let builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        builder.remove_dir("empty").unwrap();
+        assert!(!rootfs_path.join("empty").exists());
+        let err = builder.remove_dir("tree").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::DirectoryNotEmpty);
+        let err = builder.remove_dir("host").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+        let err = builder.remove_dir_all("missing").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        // Symlinks are unlinked, never descended into.
+        builder.remove_dir_all(rootfs_path.join("tree")).unwrap();
+        assert!(!rootfs_path.join("tree").exists());
+        builder.remove_dir_all("host").unwrap();
+        assert!(fs::symlink_metadata(rootfs_path.join("host")).is_err());
+        assert!(host_dir.path().join("keep").exists());
+
+        for path in [".", "..", "../.."].iter() {
+            let err = builder.remove_dir_all(path).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+            assert!(matches!(
+                SafePathError::from_io_error(&err),
+                Some(SafePathError::RootRemoval { .. })
+            ));
+        }
+        let err = builder.remove_dir(rootfs_path).unwrap_err();
+        assert!(matches!(
+            SafePathError::from_io_error(&err),
+            Some(SafePathError::RootRemoval { .. })
+        ));
+        assert!(rootfs_path.exists());
+    }
+
+    #[test]
+    fn test_safe_dir_builder_max_open_fds() {
+        // The fd limit is per process, so lower it in a child process running only this test.
+        const CHILD_ENV: &str = "SAFE_PATH_TEST_MAX_OPEN_FDS_CHILD";
+        if std::env::var_os(CHILD_ENV).is_none() {
+            let status = std::process::Command::new(std::env::current_exe().unwrap())
+                .args([
+                    "--exact",
+                    "safe_dir_builder::tests::test_safe_dir_builder_max_open_fds",
+                    "--test-threads=1",
+                ])
+                .env(CHILD_ENV, "1")
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return;
+        }
+
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        assert_eq!(
+            unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) },
+            0
+        );
+        limit.rlim_cur = 64;
+        assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) }, 0);
+
+        let deep = PathBuf::from(vec!["d"; 200].join("/"));
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        builder
+            .recursive(true)
+            .max_depth(1000)
+            .max_new_dirs(1000)
+            .max_open_fds(1000);
+        let err = builder.create(&deep).unwrap_err();
+        match SafePathError::from_io_error(&err) {
+            Some(SafePathError::TooManyOpenFiles { depth, error }) => {
+                assert!(*depth > 1 && *depth < 200);
+                assert_eq!(error.raw_os_error(), Some(libc::EMFILE));
+            }
+            _ => panic!("unexpected error {:?}", err),
+        }
+
+        builder.max_open_fds(16);
+        builder.create(&deep).unwrap();
+        assert!(rootfs_path.join(&deep).is_dir());
+        // Ascending to the outermost directories reopens them.
+        let up = vec![".."; 150].join("/");
+        builder.create(deep.join(up).join("x")).unwrap();
+        let shallow = vec!["d"; 50].join("/");
+        assert!(rootfs_path.join(&shallow).join("x").is_dir());
+
+        // A batch whose walks together would need more fds than the limit.
+        let batch: Vec<_> = (0..40).map(|i| format!("batch/{}/x/y/z", i)).collect();
+        for result in builder.create_all(&batch) {
+            result.unwrap();
+        }
+        assert!(rootfs_path.join("batch/39/x/y/z").is_dir());
+
+        // The outermost created directories are reopened to roll back.
+        let count = AtomicUsize::new(0);
+        builder.rollback_on_failure(true).after_create(move |_| {
+            match count.fetch_add(1, Ordering::SeqCst) {
+                150 => Err(Error::from_raw_os_error(libc::EIO)),
+                _ => Ok(()),
+            }
+        });
+        builder.create(Path::new("r").join(&deep)).unwrap_err();
+        assert!(!rootfs_path.join("r").exists());
+
+        builder.remove_dir_all("d").unwrap();
+        assert!(!rootfs_path.join("d").exists());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_safe_dir_builder_create_async() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path().to_path_buf();
+        fs::write(rootfs_path.join("txt"), "test").unwrap();
+        symlink("/a", rootfs_path.join("s")).unwrap();
+        let mut builder = SafeDirBuilder::new(&rootfs_path).unwrap();
+
+        let err = builder.create_async("a/b").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let err = builder.create_async("txt/b").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+
+        // The settings are taken when called, the builder may be reconfigured meanwhile.
+        builder.recursive(true).mode(0o750);
+        let future = builder.create_async("s/b/c");
+        builder.recursive(false);
+        let result = tokio::spawn(future).await.unwrap().unwrap();
+        assert_eq!(result.path.target(), rootfs_path.join("a/b/c"));
+        assert_eq!(
+            result.created_components,
+            vec![
+                rootfs_path.join("a"),
+                rootfs_path.join("a/b"),
+                rootfs_path.join("a/b/c")
+            ]
+        );
+        assert_eq!(
+            rootfs_path.join("a/b").metadata().unwrap().mode() & 0o777,
+            0o750
+        );
+
+        // Concurrent creators sharing the builder, each directory is reported exactly once.
+        builder.recursive(true);
+        let results = spawn_all(
+            (0..8)
+                .map(|i| builder.create_async(format!("x/{}/y", i % 2)))
+                .collect(),
+        )
+        .await;
+        let mut created: Vec<_> = results
+            .into_iter()
+            .flat_map(|r| r.unwrap().created_components)
+            .collect();
+        created.sort();
+        assert_eq!(
+            created,
+            vec![
+                rootfs_path.join("x"),
+                rootfs_path.join("x/0"),
+                rootfs_path.join("x/0/y"),
+                rootfs_path.join("x/1"),
+                rootfs_path.join("x/1/y")
+            ]
+        );
+    }
+
+    #[cfg(feature = "tokio")]
+    async fn spawn_all<F>(futures: Vec<F>) -> Vec<F::Output>
+    where
+        F: std::future::Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let handles: Vec<_> = futures.into_iter().map(tokio::spawn).collect();
+        let mut results = Vec::new();
+        for handle in handles {
+            results.push(handle.await.unwrap());
+        }
+        results
+    }
+
+    #[test]
+    fn test_safe_dir_builder_create_reporting() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path().to_path_buf();
+        let mut builder = SafeDirBuilder::new(&rootfs_path).unwrap();
+        builder.recursive(true);
+
+        // All new.
+        let result = builder.create_reporting(rootfs_path.join("a/b")).unwrap();
+        assert_eq!(result.path.target(), rootfs_path.join("a/b"));
+        assert!(result.created);
+        assert_eq!(
+            result.created_components,
+            vec![rootfs_path.join("a"), rootfs_path.join("a/b")]
+        );
+
+        // Partially existing.
+        let result = builder
+            .create_reporting(rootfs_path.join("a/b/c/d"))
+            .unwrap();
+        assert!(result.created);
+        assert_eq!(
+            result.created_components,
+            vec![rootfs_path.join("a/b/c"), rootfs_path.join("a/b/c/d")]
+        );
+
+        // Fully existing.
+        let result = builder.create_reporting(rootfs_path.join("a/b")).unwrap();
+        assert_eq!(result.path.target(), rootfs_path.join("a/b"));
+        assert!(!result.created);
+        assert!(result.created_components.is_empty());
+
+        // Concurrent creators, each directory is reported by exactly one of them.
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let rootfs_path = rootfs_path.clone();
+                thread::spawn(move || {
+                    let mut builder = SafeDirBuilder::new(&rootfs_path).unwrap();
+                    builder.recursive(true);
+                    let mut created = Vec::new();
+                    for i in 0..16 {
+                        let path = rootfs_path.join(format!("x/{}/y/{}", i % 2, i % 4));
+                        let result = builder.create_reporting(&path).unwrap();
+                        assert_eq!(result.path.target(), path);
+                        assert_eq!(
+                            result.created,
+                            result.created_components.last() == Some(&path)
+                        );
+                        created.extend(result.created_components);
+                    }
+                    created
+                })
+            })
+            .collect();
+        let mut created: Vec<PathBuf> = threads
+            .into_iter()
+            .flat_map(|t| t.join().unwrap())
+            .collect();
+        created.sort();
+        let mut expected = vec![rootfs_path.join("x")];
+        for i in 0..2 {
+            expected.push(rootfs_path.join(format!("x/{}", i)));
+            expected.push(rootfs_path.join(format!("x/{}/y", i)));
+            for j in [i, i + 2].iter() {
+                expected.push(rootfs_path.join(format!("x/{}/y/{}", i, j)));
+            }
+        }
+        expected.sort();
+        assert_eq!(created, expected);
+    }
+
+    #[test]
+    fn test_safe_dir_builder_create_checked() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path().to_path_buf();
+        fs::write(rootfs_path.join("f"), "f").unwrap();
+        let mut builder = SafeDirBuilder::new(&rootfs_path).unwrap();
+        builder.recursive(true);
+
+        let (path, created) = builder.create_checked("a/b").unwrap();
+        assert_eq!(path.target(), rootfs_path.join("a/b"));
+        assert!(created);
+        let (path, created) = builder.create_checked("a/b").unwrap();
+        assert_eq!(path.target(), rootfs_path.join("a/b"));
+        assert!(!created);
+        // Only the leaf counts.
+        let (_, created) = builder.create_checked("a/b/c").unwrap();
+        assert!(created);
+        let (_, created) = builder.create_checked("a").unwrap();
+        assert!(!created);
+
+        let err = builder.create_checked("f").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+        builder.recursive(false);
+        let err = builder.create_checked("a").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        builder.exists_ok(true);
+        let (_, created) = builder.create_checked("a").unwrap();
+        assert!(!created);
+    }
+
+    #[test]
+    fn test_safe_dir_builder_owner() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir(rootfs_path.join("a")).unwrap();
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        builder.recursive(true);
+
+        // Safe because geteuid() always succeeds.
+        if unsafe { libc::geteuid() } != 0 {
+            builder.owner(0, 0);
+            let err = builder.create(rootfs_path.join("a/b")).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+            return;
+        }
+
+        let owner = |path: &str| {
+            let meta = rootfs_path.join(path).metadata().unwrap();
+            (meta.uid(), meta.gid())
+        };
+        let existing = owner("a");
+        builder.owner(1234, 5678);
+        builder.create(rootfs_path.join("a/b/c")).unwrap();
+        assert_eq!(owner("a"), existing);
+        assert_eq!(owner("a/b"), (1234, 5678));
+        assert_eq!(owner("a/b/c"), (1234, 5678));
+
+        builder.owner(4321, 8765);
+        builder.create(rootfs_path.join("a/b")).unwrap();
+        assert_eq!(owner("a/b"), (1234, 5678));
+        builder.chown_existing(true);
+        builder.create(rootfs_path.join("a/b")).unwrap();
+        assert_eq!(owner("a"), existing);
+        assert_eq!(owner("a/b"), (4321, 8765));
+        assert_eq!(owner("a/b/c"), (1234, 5678));
+    }
+
+    #[test]
+    fn test_safe_dir_builder_verify_owner() {
+        // Safe because geteuid() always succeeds.
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir(rootfs_path.join("a")).unwrap();
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        builder
+            .recursive(true)
+            .owner(1234, 5678)
+            .verify_owner(true)
+            .rollback_on_failure(true);
+
+        builder.create("a/b/c").unwrap();
+        let meta = rootfs_path.join("a/b/c").metadata().unwrap();
+        assert_eq!((meta.uid(), meta.gid()), (1234, 5678));
+
+        // Simulate a racing chown of a created directory.
+        builder.after_create(|dir| {
+            if dir.target().ends_with("e") {
+                dir.chown(Some(1), None)?;
+            }
+            Ok(())
+        });
+        let err = builder.create("a/d/e/f").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Other);
+        match SafePathError::from_io_error(&err) {
+            Some(SafePathError::OwnerMismatch {
+                path,
+                expected,
+                actual,
+            }) => {
+                assert_eq!(path, &rootfs_path.join("a/d/e"));
+                assert_eq!(*expected, (1234, 5678));
+                assert_eq!(*actual, (1, 5678));
+            }
+            _ => panic!("unexpected error {}", err),
+        }
+        assert!(!rootfs_path.join("a/d").exists());
+
+        // Not verified unless enabled, or without an owner.
+        builder.verify_owner(false);
+        builder.create("a/d/e/f").unwrap();
+        builder.reset().recursive(true).verify_owner(true);
+        builder.create("a/g").unwrap();
+
+        // An existing directory is verified when chowned.
+        builder.owner(4321, 8765).chown_existing(true);
+        builder.create("a/b").unwrap();
+        let meta = rootfs_path.join("a/b").metadata().unwrap();
+        assert_eq!((meta.uid(), meta.gid()), (4321, 8765));
+    }
+
+    #[test]
+    fn test_safe_dir_builder_sync() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir(rootfs_path.join("a")).unwrap();
+        let fsyncs = || {
+            sys::take_syscalls()
+                .into_iter()
+                .filter(|s| *s == "fsync")
+                .count()
+        };
+
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        builder.recursive(true);
+        sys::take_syscalls();
+        let path = builder.create(rootfs_path.join("a/b")).unwrap();
+        assert_eq!(path.target(), rootfs_path.join("a/b"));
+        assert_eq!(fsyncs(), 0);
+
+        builder.sync(true);
+        // The new "c" and "d", and the parent "b".
+        let path = builder.create(rootfs_path.join("a/b/c/d")).unwrap();
+        assert_eq!(path.target(), rootfs_path.join("a/b/c/d"));
+        assert_eq!(fsyncs(), 3);
+        // Nothing is created, so nothing to sync.
+        builder.create(rootfs_path.join("a/b/c/d")).unwrap();
+        assert_eq!(fsyncs(), 0);
+        // The new file and its parent.
+        let path = builder.create_file(rootfs_path.join("a/f")).unwrap();
+        assert_eq!(path.target(), rootfs_path.join("a/f"));
+        assert_eq!(fsyncs(), 2);
+    }
+
+    #[test]
+    fn test_safe_dir_builder_rollback() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir(rootfs_path.join("a")).unwrap();
+        // Fails with ENAMETOOLONG after creating the parents.
+        let long = "x".repeat(256);
+
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        builder.recursive(true);
+        let err = builder.create(format!("a/b/c/{}", long)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidFilename);
+        assert!(rootfs_path.join("a/b/c").is_dir());
+        fs::remove_dir_all(rootfs_path.join("a/b")).unwrap();
+
+        builder.rollback_on_failure(true);
+        let err = builder.create(format!("a/b/c/{}", long)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidFilename);
+        assert!(err
+            .to_string()
+            .contains("rolled back 2 created directories"));
+        assert!(rootfs_path.join("a").is_dir());
+        assert!(!rootfs_path.join("a/b").exists());
+
+        let err = builder.create_file(format!("a/b/c/{}", long)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidFilename);
+        assert!(rootfs_path.join("a").is_dir());
+        assert!(!rootfs_path.join("a/b").exists());
+
+        // Nothing created, nothing to roll back.
+        let err = builder.create(format!("a/{}", long)).unwrap_err();
+        assert!(!err.to_string().contains("rolled back"));
+
+        // Directories populated by others in the meantime are kept.
+        builder.create("a/b").unwrap();
+        fs::write(rootfs_path.join("a/b/f"), b"").unwrap();
+        let walk = {
+            let mut walk = ScopedWalk::new(rootfs_path).unwrap();
+            walk.walk(Path::new("a/b"), true, false).unwrap();
+            walk
+        };
+        let err = builder.rollback(&walk, &[2], Error::other("injected"));
+        assert_eq!(err.kind(), ErrorKind::Other);
+        assert!(err.to_string().contains("kept 1 no longer empty"));
+        assert!(rootfs_path.join("a/b/f").exists());
+    }
+
+    #[test]
+    fn test_safe_dir_builder_from_safe_path() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir(rootfs_path.join("root")).unwrap();
+        fs::write(rootfs_path.join("txt"), "test").unwrap();
+
+        let err = SafeDirBuilder::from_safe_path(SafePathBuf::new(rootfs_path, "txt").unwrap())
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+
+        let root = SafePathBuf::new(rootfs_path, "root").unwrap();
+        let mut pinned = SafeDirBuilder::from_safe_path(root).unwrap();
+        pinned.recursive(true);
+        let mut unpinned = SafeDirBuilder::new(rootfs_path.join("root")).unwrap();
+        unpinned.recursive(true);
+
+        // Rename the root between creating the builders and creating directories.
+        fs::rename(rootfs_path.join("root"), rootfs_path.join("moved")).unwrap();
+        let err = unpinned.create(rootfs_path.join("root/a/b")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        let path = pinned.create("a/b").unwrap();
+        assert_eq!(path.target(), rootfs_path.join("moved/a/b"));
+        let path = pinned.create("/a/../../c").unwrap();
+        assert_eq!(path.target(), rootfs_path.join("moved/c"));
+        let path = pinned.create_file("a/f").unwrap();
+        assert_eq!(path.target(), rootfs_path.join("moved/a/f"));
+        assert!(!rootfs_path.join("root").exists());
+    }
+
+    #[test]
+    fn test_safe_dir_builder_create_staged() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        builder.recursive(true).mode(0o750);
+
+        let staged = builder.create_staged(rootfs_path.join("a/b")).unwrap();
+        let staging = staged.path().target().to_path_buf();
+        assert_eq!(staging.parent(), Some(rootfs_path.join("a").as_path()));
+        assert!(staging
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with(".staging."));
+        fs::write(staged.path().join("x"), "x").unwrap();
+        assert!(!rootfs_path.join("a/b").exists());
+
+        let path = staged.publish().unwrap();
+        assert_eq!(path.target(), rootfs_path.join("a/b"));
+        assert_eq!(fs::read_to_string(rootfs_path.join("a/b/x")).unwrap(), "x");
+        assert_eq!(
+            rootfs_path.join("a/b").metadata().unwrap().mode() & 0o777,
+            0o750
+        );
+        assert!(!staging.exists());
+
+        // Replace an empty directory, but not a populated one.
+        let staged = builder.create_staged(rootfs_path.join("a/b")).unwrap();
+        let err = staged.publish().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::DirectoryNotEmpty);
+        fs::create_dir(rootfs_path.join("c")).unwrap();
+        let staged = builder.create_staged(rootfs_path.join("c")).unwrap();
+        fs::write(staged.path().join("y"), "y").unwrap();
+        staged.publish().unwrap();
+        assert!(rootfs_path.join("c/y").exists());
+
+        // A symlink at the final path is not followed.
+        symlink("/a", rootfs_path.join("s")).unwrap();
+        let staged = builder.create_staged(rootfs_path.join("s")).unwrap();
+        let err = staged.publish().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+        assert!(fs::symlink_metadata(rootfs_path.join("s"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+    }
+
+    #[test]
+    fn test_safe_dir_builder_create_temp() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        builder.recursive(true).mode(0o750);
+
+        let temp1 = builder.create_temp("state/tmp", "op-").unwrap();
+        let temp2 = builder.create_temp("state/tmp", "op-").unwrap();
+        let path1 = temp1.path().target().to_path_buf();
+        let path2 = temp2.path().target().to_path_buf();
+        assert_ne!(path1, path2);
+        assert_eq!(path1.parent().unwrap(), rootfs_path.join("state/tmp"));
+        assert!(path1
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("op-"));
+        assert_eq!(path1.metadata().unwrap().mode() & 0o7777, 0o750);
+
+        // Dropping removes the whole tree.
+        fs::create_dir(path1.join("d")).unwrap();
+        fs::write(path1.join("d/f"), "f").unwrap();
+        symlink("/", path1.join("s")).unwrap();
+        drop(temp1);
+        assert!(!path1.exists());
+        assert!(path2.exists());
+
+        let kept = temp2.keep();
+        assert_eq!(kept.target(), path2);
+        assert!(path2.is_dir());
+
+        // A directory replacing the moved temporary directory is left alone.
+        let temp3 = builder.create_temp("state/tmp", "op-").unwrap();
+        let path3 = temp3.path().target().to_path_buf();
+        fs::rename(&path3, rootfs_path.join("state/moved")).unwrap();
+        fs::create_dir(&path3).unwrap();
+        fs::write(path3.join("f"), "f").unwrap();
+        drop(temp3);
+        assert!(path3.join("f").exists());
+
+        for prefix in ["a/b", "a\0b"].iter() {
+            let err = builder.create_temp("state", prefix).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidFilename);
+        }
+        builder.recursive(false);
+        let err = builder.create_temp("missing", "op-").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_safe_dir_builder_special_bits() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        let mode = |path: &str| rootfs_path.join(path).metadata().unwrap().mode() & 0o7777;
+
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        builder.recursive(true).mode(0o2770);
+        builder.create(rootfs_path.join("shared/a")).unwrap();
+        assert_eq!(mode("shared"), 0o2770);
+        assert_eq!(mode("shared/a"), 0o2770);
+        assert_ne!(mode("shared") & libc::S_ISGID, 0);
+
+        builder.mode(0o1777);
+        builder.create(rootfs_path.join("tmp")).unwrap();
+        assert_eq!(mode("tmp"), 0o1777);
+        let staged = builder.create_staged(rootfs_path.join("tmp2")).unwrap();
+        staged.publish().unwrap();
+        assert_eq!(mode("tmp2"), 0o1777);
+
+        // Existing directories are left alone.
+        builder.mode(0o2700);
+        builder.create(rootfs_path.join("tmp")).unwrap();
+        assert_eq!(mode("tmp"), 0o1777);
+
+        // Bits beyond the special bits are still dropped.
+        builder.mode(0o17750);
+        builder.create(rootfs_path.join("b")).unwrap();
+        assert_eq!(mode("b"), 0o7750);
+    }
+
+    #[test]
+    fn test_safe_dir_builder_umask() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        let mode = |path: &str| rootfs_path.join(path).metadata().unwrap().mode() & 0o7777;
+
+        // Exact modes regardless of the process umask, for both directories and files.
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        builder.recursive(true).mode(0o777).file_mode(0o666);
+        builder.create_file("a/f").unwrap();
+        assert_eq!(mode("a"), 0o777);
+        assert_eq!(mode("a/f"), 0o666);
+
+        builder.umask(0o027).parents_mode(0o775);
+        builder.create("b/c").unwrap();
+        builder.create_file("b/f").unwrap();
+        assert_eq!(mode("b"), 0o750);
+        assert_eq!(mode("b/c"), 0o750);
+        assert_eq!(mode("b/f"), 0o640);
+        let plan = builder.check("d/e").unwrap();
+        assert_eq!((plan.mode, plan.parents_mode), (0o750, 0o750));
+
+        // An existing file is left alone.
+        builder.exists_ok(true).umask(0);
+        builder.create_file("b/f").unwrap();
+        assert_eq!(mode("b/f"), 0o640);
+    }
+
+    #[test]
+    fn test_safe_dir_builder_parents_mode() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        let mode = |path: &str| rootfs_path.join(path).metadata().unwrap().mode() & 0o7777;
+
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        builder.recursive(true).parents_mode(0o755).mode(0o700);
+        builder.create("a/b/c").unwrap();
+        assert_eq!(mode("a"), 0o755);
+        assert_eq!(mode("a/b"), 0o755);
+        assert_eq!(mode("a/b/c"), 0o700);
+
+        // The parents of a file are all intermediate ones.
+        builder.create_file("d/e/f").unwrap();
+        assert_eq!(mode("d"), 0o755);
+        assert_eq!(mode("d/e"), 0o755);
+
+        // Unset means the same mode for all.
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        builder.recursive(true).mode(0o750);
+        builder.create("g/h").unwrap();
+        assert_eq!(mode("g"), 0o750);
+        assert_eq!(mode("g/h"), 0o750);
+    }
+
+    #[test]
+    fn test_safe_dir_builder_syscalls() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        let mut builder = SafeDirBuilder::new(rootfs_path).unwrap();
+        builder.recursive(true);
+
+        let count = |syscalls: &[&str], name: &str| syscalls.iter().filter(|s| **s == name).count();
+
+        // Each missing directory is created by one mkdirat(), pinned by one openat() relative to
+        // its parent without resolving from the root again, and chmod()ed by the pinned fd.
+        sys::take_syscalls();
+        builder.create(rootfs_path.join("a/b/c/d/e/f/g/h")).unwrap();
+        let syscalls = sys::take_syscalls();
+        assert_eq!(count(&syscalls, "mkdirat"), 8);
+        assert_eq!(count(&syscalls, "openat"), 1 + 8);
+        assert_eq!(count(&syscalls, "chmod"), 8);
+        assert_eq!(syscalls.len(), 1 + 3 * 8);
+
+        // Each existing directory is pinned by one openat(O_DIRECTORY), without checking it by
+        // fstat().
+        builder
+            .create(rootfs_path.join("a/b/c/d/e/f/g/h/i"))
+            .unwrap();
+        let syscalls = sys::take_syscalls();
+        assert_eq!(count(&syscalls, "mkdirat"), 1);
+        assert_eq!(count(&syscalls, "openat"), 8 + 1 + 1);
+        assert_eq!(count(&syscalls, "fstat"), 0);
+        assert_eq!(count(&syscalls, "readlinkat"), 0);
+    }
+
+    #[test]
+    fn test_safe_join_or_create() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+
+        // Fully missing tail.
+        let path = safe_join_or_create(rootfs_path, "a/b/c", 0o750, false).unwrap();
+        assert_eq!(path.target(), rootfs_path.join("a/b/c"));
+        assert_eq!(
+            rootfs_path.join("a/b").metadata().unwrap().mode() & 0o777,
+            0o750
+        );
+
+        // Partially missing tail, the existing prefix is resolved through symlinks.
+        symlink("/a/b", rootfs_path.join("s")).unwrap();
+        let path = safe_join_or_create(rootfs_path, "s/../../s/d/e", 0o700, false).unwrap();
+        assert_eq!(path.target(), rootfs_path.join("a/b/d/e"));
+        let path = safe_join_or_create(rootfs_path, "../../a/b/d", 0o700, false).unwrap();
+        assert_eq!(path.target(), rootfs_path.join("a/b/d"));
+
+        // Symlink targets longer than the initial readlink buffer are read in full.
+        let long = format!("/a/{}b", "./".repeat(150));
+        symlink(&long, rootfs_path.join("long")).unwrap();
+        let path = safe_join_or_create(rootfs_path, "long/g", 0o700, false).unwrap();
+        assert_eq!(path.target(), rootfs_path.join("a/b/g"));
+
+        // Sticky bit for a "/tmp" like directory.
+        let path = safe_join_or_create(rootfs_path, "../tmp", 0o1777, false).unwrap();
+        assert_eq!(path.target(), rootfs_path.join("tmp"));
+        let mode = rootfs_path.join("tmp").metadata().unwrap().mode();
+        assert_eq!(mode & 0o7777, 0o1777);
+        assert_ne!(mode & libc::S_ISVTX, 0);
+
+        // Tail blocked by a file.
+        fs::write(rootfs_path.join("a/txt"), "test").unwrap();
+        let err = safe_join_or_create(rootfs_path, "a/txt/f", 0o700, false).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+        let err = safe_join_or_create(rootfs_path, "a/txt", 0o700, false).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+        let path = safe_join_or_create(rootfs_path, "a/txt", 0o700, true).unwrap();
+        assert_eq!(path.target(), rootfs_path.join("a/txt"));
+        assert!(!path.is_dir());
+    }
+
+    #[test]
+    fn test_safe_dir_builder_exists_ok_race() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path().to_path_buf();
+
+        let mut builder = SafeDirBuilder::new(&rootfs_path).unwrap();
+        builder.create("a").unwrap();
+        let err = builder.create("a").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        builder.exists_ok(true);
+        assert_eq!(builder.create("a").unwrap().target(), rootfs_path.join("a"));
+        fs::write(rootfs_path.join("f"), "f").unwrap();
+        let err = builder.create("f").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+
+        // Two threads creating the same deep chain, recursively or level by level.
+        let builder = Arc::new(builder);
+        let barrier = Arc::new(Barrier::new(2));
+        let threads: Vec<_> = (0..2)
+            .map(|n| {
+                let rootfs_path = rootfs_path.clone();
+                let builder = builder.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    let mut recursive = (*builder).clone();
+                    recursive.recursive(true);
+                    for i in 0..64 {
+                        let chain = format!("c/{}/d/e/f/g", i);
+                        barrier.wait();
+                        if n == 0 {
+                            recursive.create(&chain).unwrap();
+                        } else {
+                            let mut path = PathBuf::new();
+                            for comp in Path::new(&chain).iter() {
+                                path.push(comp);
+                                builder.create(&path).unwrap();
+                            }
+                        }
+                        assert!(rootfs_path.join(&chain).is_dir());
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        // The final component is swapped between a directory and a file concurrently, the
+        // returned handle is always a directory.
+        fs::create_dir(rootfs_path.join("t")).unwrap();
+        fs::write(rootfs_path.join("other"), "f").unwrap();
+        let swapper = {
+            use std::os::unix::ffi::OsStringExt;
+
+            let t = std::ffi::CString::new(rootfs_path.join("t").into_os_string().into_vec());
+            let other =
+                std::ffi::CString::new(rootfs_path.join("other").into_os_string().into_vec());
+            let (t, other) = (t.unwrap(), other.unwrap());
+            Racer::spawn(move || {
+                let ret = unsafe {
+                    libc::renameat2(
+                        libc::AT_FDCWD,
+                        t.as_ptr(),
+                        libc::AT_FDCWD,
+                        other.as_ptr(),
+                        libc::RENAME_EXCHANGE,
+                    )
+                };
+                assert_eq!(ret, 0);
+            })
+        };
+        let mut dirs = 0;
+        for _ in 0..2000 {
+            match builder.create("t") {
+                Ok(path) => {
+                    assert!(path.metadata().unwrap().is_dir());
+                    dirs += 1;
+                }
+                Err(e) => assert_eq!(e.kind(), ErrorKind::NotADirectory),
+            }
+        }
+        drop(swapper);
+        assert!(dirs > 0);
+    }
+
+    #[test]
+    fn test_safe_join_or_create_race() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path().to_path_buf();
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let rootfs_path = rootfs_path.clone();
+                thread::spawn(move || {
+                    for i in 0..32 {
+                        let path = format!("x/{}/y/{}/z", i % 4, i);
+                        let result =
+                            safe_join_or_create(&rootfs_path, &path, 0o700, false).unwrap();
+                        assert_eq!(result.target(), rootfs_path.join(&path));
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
     }
 }
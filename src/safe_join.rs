@@ -3,73 +3,404 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, OpenOptions};
 use std::io::{Error, ErrorKind, Result};
-use std::path::{Component, Path, PathBuf};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{BorrowedFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::resolver::{self, Backend};
+use crate::walk::{push_components, ScopedWalk, PARENT_DIR};
+use crate::{sys, OpenFlags, SafePathBuf, SafePathError};
 
 // Follow the same configuration as
 // [secure_join](https://github.com/cyphar/filepath-securejoin/blob/master/join.go#L51)
-const MAX_SYMLINK_DEPTH: u32 = 255;
+pub(crate) const MAX_SYMLINK_DEPTH: u32 = 255;
+// The default maximum number of components to process in a resolution, twice the number of
+// components of the longest path accepted by the kernel.
+const MAX_COMPONENTS_DEFAULT: usize = 4096;
+
+/// Options to control how [scoped_resolve_with()] resolves a path.
+#[derive(Clone, Debug, Default)]
+pub struct ResolveOptions {
+    forbid_fs_types: Vec<i64>,
+    backslash_separator: bool,
+    stop_at_non_directory: bool,
+    max_components: Option<usize>,
+    forbidden_prefixes: Vec<OsString>,
+}
+
+impl ResolveOptions {
+    /// Create a new set of options with the same behavior as [scoped_resolve()].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forbid resolving into any of the given filesystem types.
+    ///
+    /// Each existing path component is pinned and checked by `fstatfs(2)`, and the resolution fails with
+    /// [SafePathError::ForbiddenFilesystem] if its `f_type` matches any of `fs_types`, such as
+    /// `libc::PROC_SUPER_MAGIC` or `libc::SYSFS_MAGIC`. This prevents crossing into pseudo
+    /// filesystems mounted inside the root, which expose host-ish surfaces. The root itself is not
+    /// checked.
+    pub fn forbid_fs_types(&mut self, fs_types: &[i64]) -> &mut Self {
+        self.forbid_fs_types = fs_types.to_vec();
+        self
+    }
+
+    /// Treat backslashes in the input path as separators in addition to slashes, for paths
+    /// written on Windows, such as those in some OCI specs. Disabled by default.
+    ///
+    /// # Security
+    /// A backslash is a valid filename character on Linux, so enabling this changes which file a
+    /// path names: `a\b` names the file `a\b` in strict mode, but `b` under the directory `a`
+    /// when enabled, and `..\x` becomes `../x`. The result is still scoped under the root, but
+    /// a caller validating the path as a single component may be surprised. Files whose names
+    /// contain backslashes can't be reached when enabled. Only the input path is split, the
+    /// targets of symlinks are always strict.
+    pub fn treat_backslash_as_separator(&mut self, enabled: bool) -> &mut Self {
+        self.backslash_separator = enabled;
+        self
+    }
+
+    /// Stop at the first existing component which is not a directory but is followed by another
+    /// component, including "..", and fail with [SafePathError::NotADirectory] carrying its path.
+    /// Disabled by default.
+    ///
+    /// It mirrors the `ENOTDIR` of the kernel: by default, `a/b/c` resolves to `a/b/c` even if
+    /// `a/b` is a regular file, because `c` is assumed not to exist yet. The final component may
+    /// still be of any type.
+    pub fn stop_at_non_directory(&mut self, enabled: bool) -> &mut Self {
+        self.stop_at_non_directory = enabled;
+        self
+    }
+
+    /// Sets the maximum number of components to process in a resolution, replacing the default
+    /// of 4096.
+    ///
+    /// The count includes the components introduced by expanding symlinks and "..", not only
+    /// those of the input path, so a chain of symlinks each expanding to many components can't
+    /// make the resolution arbitrarily slow. The resolution fails with
+    /// [SafePathError::TooManyComponents] once the limit is exceeded.
+    pub fn max_components(&mut self, n: usize) -> &mut Self {
+        self.max_components = Some(n);
+        self
+    }
+
+    /// Forbid any component whose name starts with one of `prefixes`, such as `.` for hidden
+    /// files or `__` for reserved names.
+    ///
+    /// Each component is compared byte by byte when it's processed, including those introduced
+    /// by expanding symlinks, and the resolution fails with [SafePathError::ForbiddenComponent]
+    /// at the first match. `.` and `..` are not names, so they're never forbidden, but an empty
+    /// prefix forbids every other component.
+    pub fn forbidden_prefixes(&mut self, prefixes: &[&OsStr]) -> &mut Self {
+        self.forbidden_prefixes = prefixes.iter().map(|p| p.to_os_string()).collect();
+        self
+    }
+
+    fn component_limit(&self) -> usize {
+        self.max_components.unwrap_or(MAX_COMPONENTS_DEFAULT)
+    }
+
+    /// Convert the input path according to the options.
+    fn input_path(&self, path: &Path) -> PathBuf {
+        if !self.backslash_separator {
+            return path.to_path_buf();
+        }
+        let bytes = path
+            .as_os_str()
+            .as_bytes()
+            .iter()
+            .map(|&b| if b == b'\\' { b'/' } else { b })
+            .collect::<Vec<u8>>();
+        PathBuf::from(OsString::from_vec(bytes))
+    }
+
+    /// Check the existing component at `path` before going on with the next component.
+    fn check_parent(&self, path: &Path) -> Result<()> {
+        if !self.stop_at_non_directory {
+            return Ok(());
+        }
+        match path.symlink_metadata() {
+            Ok(meta) if !meta.is_dir() => Err(SafePathError::NotADirectory {
+                path: path.to_path_buf(),
+            }
+            .into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the name of the normal component `name` before resolving it.
+    fn check_name(&self, name: &OsStr) -> Result<()> {
+        let forbidden = self
+            .forbidden_prefixes
+            .iter()
+            .any(|p| name.as_bytes().starts_with(p.as_bytes()));
+        if forbidden {
+            return Err(SafePathError::ForbiddenComponent {
+                name: name.to_os_string(),
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Check the existing component at `path`, which is pinned by `fd`.
+    fn check_component(&self, fd: &OwnedFd, path: &Path) -> Result<()> {
+        let st = sys::fstatfs(fd)?;
+        #[allow(clippy::unnecessary_cast)]
+        let fs_type = st.f_type as i64;
+        if self.forbid_fs_types.contains(&fs_type) {
+            return Err(SafePathError::ForbiddenFilesystem {
+                path: path.to_path_buf(),
+                fs_type,
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Pinned fds of the existing components resolved so far, so they can be checked by fd instead
+/// of resolving their paths again.
+struct PinnedComponents {
+    // The root (index 0) and each existing component below it.
+    fds: Vec<OwnedFd>,
+    // Number of trailing components which don't exist, so there's nothing to pin.
+    missing: usize,
+}
+
+impl PinnedComponents {
+    fn new(root: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC)
+            .open(root)?;
+
+        Ok(PinnedComponents {
+            fds: vec![file.into()],
+            missing: 0,
+        })
+    }
+
+    /// Pin the normal component `name` below the deepest component, and get its fd if it exists.
+    fn push(&mut self, name: &OsStr) -> Result<Option<&OwnedFd>> {
+        if self.missing == 0 {
+            // Safe to unwrap() because the root fd is never popped.
+            let parent = self.fds.last().unwrap();
+            match sys::openat(parent, name, libc::O_PATH | libc::O_NOFOLLOW, 0) {
+                Ok(fd) => {
+                    self.fds.push(fd);
+                    return Ok(self.fds.last());
+                }
+                // The component doesn't exist yet or can't be looked up, nothing to pin.
+                Err(e)
+                    if e.kind() == ErrorKind::NotFound || e.kind() == ErrorKind::NotADirectory => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.missing += 1;
+        Ok(None)
+    }
+
+    /// Go to the parent of the deepest component, but never beyond the root.
+    fn pop(&mut self) {
+        if self.missing > 0 {
+            self.missing -= 1;
+        } else if self.fds.len() > 1 {
+            self.fds.pop();
+        }
+    }
+
+    /// Go back to the root.
+    fn reset(&mut self) {
+        self.fds.truncate(1);
+        self.missing = 0;
+    }
+}
+
+/// The path resolved so far relative to the root, which borrows a prefix of the input as long as
+/// it's the same as the input, so an input which is already resolved is never copied.
+enum Subpath<'a> {
+    /// The first bytes of the input, up to the given length.
+    Prefix(&'a [u8], usize),
+    Owned(PathBuf),
+}
+
+impl<'a> Subpath<'a> {
+    fn as_path(&self) -> &Path {
+        match self {
+            Subpath::Prefix(input, len) => Path::new(OsStr::from_bytes(&input[..*len])),
+            Subpath::Owned(path) => path,
+        }
+    }
+
+    /// Get the owned path, copying the borrowed prefix if needed.
+    fn to_mut(&mut self) -> &mut PathBuf {
+        if let Subpath::Prefix(..) = self {
+            *self = Subpath::Owned(self.as_path().to_path_buf());
+        }
+        match self {
+            Subpath::Owned(path) => path,
+            Subpath::Prefix(..) => unreachable!(),
+        }
+    }
+
+    fn push(&mut self, comp: &OsStr) {
+        if let Subpath::Prefix(input, len) = self {
+            // The next component of the input starts after the separator, if any.
+            let start = if *len == 0 { 0 } else { *len + 1 };
+            let end = start + comp.len();
+            if (*len == 0 || input.get(*len) == Some(&b'/'))
+                && input.get(start..end) == Some(comp.as_bytes())
+                && (end == input.len() || input[end] == b'/')
+            {
+                *len = end;
+                return;
+            }
+        }
+        self.to_mut().push(comp);
+    }
+
+    /// Get whether the whole input has been borrowed as is.
+    fn is_input(&self) -> bool {
+        matches!(self, Subpath::Prefix(input, len) if *len == input.len())
+    }
+
+    fn into_path_buf(self) -> PathBuf {
+        match self {
+            Subpath::Prefix(..) => self.as_path().to_path_buf(),
+            Subpath::Owned(path) => path,
+        }
+    }
+}
 
 fn do_scoped_resolve<R: AsRef<Path>, U: AsRef<Path>>(
     root: R,
     unsafe_path: U,
+    options: &ResolveOptions,
+) -> Result<(PathBuf, PathBuf)> {
+    let unsafe_path = unsafe_path.as_ref();
+    let mut queue = VecDeque::new();
+    push_components(&mut queue, &options.input_path(unsafe_path), unsafe_path)?;
+
+    resolve_components(root, queue, || unsafe_path.to_path_buf(), options)
+}
+
+/// Resolve the components in `queue` under `root`, `input` builds the original input to report
+/// in traces and errors.
+fn resolve_components<R: AsRef<Path>, F: Fn() -> PathBuf>(
+    root: R,
+    queue: VecDeque<OsString>,
+    input: F,
+    options: &ResolveOptions,
 ) -> Result<(PathBuf, PathBuf)> {
+    let (root, subpath) =
+        resolve_subpath(root, queue, input, Subpath::Owned(PathBuf::new()), options)?;
+    Ok((root, subpath.into_path_buf()))
+}
+
+/// Resolve the components in `queue` under `root` like [resolve_components()], starting from
+/// `subpath`.
+fn resolve_subpath<'a, R: AsRef<Path>, F: Fn() -> PathBuf>(
+    root: R,
+    mut queue: VecDeque<OsString>,
+    input: F,
+    mut subpath: Subpath<'a>,
+    options: &ResolveOptions,
+) -> Result<(PathBuf, Subpath<'a>)> {
     let root = root.as_ref().canonicalize()?;
     if !root.is_absolute() {
-        return Err(Error::new(
-            ErrorKind::Other,
-            format!("Invalid root path: {}", root.display()),
-        ));
+        return Err(SafePathError::InvalidRoot { root }.into());
     }
 
+    trace!(
+        "scoped_resolve: resolve {} under root {}",
+        input().display(),
+        root.display()
+    );
+
+    let mut pinned = if options.forbid_fs_types.is_empty() {
+        None
+    } else {
+        Some(PinnedComponents::new(&root)?)
+    };
     let mut nlinks = 0u32;
-    let mut curr_path = unsafe_path.as_ref().to_path_buf();
-    'restart: loop {
-        let mut subpath = PathBuf::new();
-        let mut iter = curr_path.components();
-
-        'next_comp: while let Some(comp) = iter.next() {
-            match comp {
-                Component::Prefix(_) => {
-                    return Err(Error::new(
-                        ErrorKind::Other,
-                        format!("Invalid path prefix in: {}", unsafe_path.as_ref().display()),
-                    ));
-                }
-                Component::RootDir | Component::CurDir => {
-                    continue 'next_comp;
-                }
-                Component::ParentDir => {
-                    subpath.pop();
-                }
-                Component::Normal(n) => {
-                    subpath.push(n);
-                    let path = root.join(&subpath);
-                    if let Ok(v) = path.read_link() {
-                        nlinks += 1;
-                        if nlinks > MAX_SYMLINK_DEPTH {
-                            return Err(Error::new(
-                                ErrorKind::Other,
-                                format!(
-                                    "Too many levels of symlinks: {}",
-                                    unsafe_path.as_ref().display()
-                                ),
-                            ));
-                        }
-                        curr_path = if v.is_absolute() {
-                            v.join(iter.as_path())
-                        } else {
-                            subpath.pop();
-                            subpath.join(v).join(iter.as_path())
-                        };
-                        continue 'restart;
-                    }
-                }
+    let limit = options.component_limit();
+    let mut count = 0;
+    while let Some(comp) = queue.pop_front() {
+        count += 1;
+        if count > limit {
+            return Err(SafePathError::TooManyComponents {
+                path: input(),
+                limit,
             }
+            .into());
+        }
+        trace!(
+            "scoped_resolve: component {:?} under {}",
+            comp,
+            subpath.as_path().display()
+        );
+        if !subpath.as_path().as_os_str().is_empty() {
+            options.check_parent(&root.join(subpath.as_path()))?;
         }
+        if comp == PARENT_DIR {
+            subpath.to_mut().pop();
+            if let Some(pinned) = pinned.as_mut() {
+                pinned.pop();
+            }
+            continue;
+        }
+        options.check_name(&comp)?;
 
-        return Ok((root, subpath));
+        subpath.push(&comp);
+        let path = root.join(subpath.as_path());
+        if let Ok(v) = path.read_link() {
+            nlinks += 1;
+            if nlinks > MAX_SYMLINK_DEPTH {
+                return Err(SafePathError::TooManySymlinks { path: input() }.into());
+            }
+            trace!(
+                "scoped_resolve: expand symlink {} -> {}",
+                subpath.as_path().display(),
+                v.display()
+            );
+            if v.is_absolute() {
+                subpath.to_mut().clear();
+                if let Some(pinned) = pinned.as_mut() {
+                    pinned.reset();
+                }
+            } else {
+                subpath.to_mut().pop();
+            }
+            let mut expanded = VecDeque::new();
+            push_components(&mut expanded, &v, &input())?;
+            expanded.append(&mut queue);
+            queue = expanded;
+            continue;
+        }
+        if let Some(pinned) = pinned.as_mut() {
+            if let Some(fd) = pinned.push(&comp)? {
+                options.check_component(fd, &path)?;
+            }
+        }
     }
+
+    trace!(
+        "scoped_resolve: {} resolved to {}",
+        input().display(),
+        subpath.as_path().display()
+    );
+    Ok((root, subpath))
 }
 
 /// Resolve `unsafe_path` to a relative path, rooted at and constrained by `root`.
@@ -91,8 +422,205 @@ fn do_scoped_resolve<R: AsRef<Path>, U: AsRef<Path>>(
 /// returned PathBuf are not modified (in other words are not replaced with symlinks on the
 /// filesystem) after this function has returned. You may use [crate::SafePathBuf] to protect from
 /// such TOCTOU attacks.
+///
+/// # Errors
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | `root` doesn't exist | `NotFound` |
+/// | too many levels of symlinks | `FilesystemLoop` |
+/// | `unsafe_path` contains a prefix component | `InvalidFilename` |
+///
+/// The `io::Error` carries a [crate::SafePathError] for failures detected by the crate itself.
 pub fn scoped_resolve<R: AsRef<Path>, U: AsRef<Path>>(root: R, unsafe_path: U) -> Result<PathBuf> {
-    do_scoped_resolve(root, unsafe_path).map(|(_root, path)| path)
+    instrument!(
+        "scoped_resolve",
+        root.as_ref(),
+        unsafe_path.as_ref(),
+        "follow",
+        do_scoped_resolve(&root, &unsafe_path, &ResolveOptions::default())
+            .map(|(_root, path)| path)
+    )
+}
+
+/// Resolve `unsafe_path` like [scoped_resolve()], and return the result as an `Arc<Path>`, which
+/// is cheap to clone and share across threads.
+///
+/// # Errors
+/// The same as [scoped_resolve()].
+pub fn scoped_resolve_shared<R: AsRef<Path>, U: AsRef<Path>>(
+    root: R,
+    unsafe_path: U,
+) -> Result<Arc<Path>> {
+    scoped_resolve(root, unsafe_path).map(Arc::from)
+}
+
+/// Resolve `unsafe_path` like [scoped_resolve()], borrowing `unsafe_path` as the result if it's
+/// already the resolved path.
+///
+/// It's the common case of a relative path with only normal components and no symlinks on the
+/// way, such as names generated by the caller. All the checks of [scoped_resolve()] still run,
+/// but the resolved path is only built once it differs from `unsafe_path`, which saves an
+/// allocation per path during bulk resolution.
+///
+/// # Errors
+/// The same as [scoped_resolve()].
+pub fn scoped_resolve_cow<'a, R, U>(root: R, unsafe_path: &'a U) -> Result<Cow<'a, Path>>
+where
+    R: AsRef<Path>,
+    U: AsRef<Path> + ?Sized,
+{
+    let unsafe_path = unsafe_path.as_ref();
+    instrument!(
+        "scoped_resolve_cow",
+        root.as_ref(),
+        unsafe_path,
+        "follow",
+        {
+            let mut queue = VecDeque::new();
+            push_components(&mut queue, unsafe_path, unsafe_path)?;
+            // The bytes are compared instead of the components, so "a//b" or "a/./b" is still owned.
+            let subpath = Subpath::Prefix(unsafe_path.as_os_str().as_bytes(), 0);
+            let input = || unsafe_path.to_path_buf();
+            let (_root, subpath) =
+                resolve_subpath(&root, queue, input, subpath, &ResolveOptions::default())?;
+            if subpath.is_input() {
+                Ok(Cow::Borrowed(unsafe_path))
+            } else {
+                Ok(Cow::Owned(subpath.into_path_buf()))
+            }
+        }
+    )
+}
+
+/// Resolve the path made of `components` like [scoped_resolve()], without building the path
+/// first.
+///
+/// Each item is a single path component: "." and empty components are ignored, ".." goes to the
+/// parent directory but constrained by `root`, and any other component is resolved with the
+/// same rules as [scoped_resolve()], including symlinks.
+///
+/// # Errors
+/// The same as [scoped_resolve()], plus:
+///
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | a component contains "/" or NUL | `InvalidFilename` |
+pub fn scoped_resolve_iter<R, I>(root: R, components: I) -> Result<PathBuf>
+where
+    R: AsRef<Path>,
+    I: IntoIterator<Item = OsString>,
+{
+    let mut queue = VecDeque::new();
+    // The input is kept as a single path for traces and errors, the components are moved into
+    // the queue.
+    let mut input = PathBuf::new();
+    for comp in components {
+        if comp.as_bytes().iter().any(|&b| b == b'/' || b == 0) {
+            return Err(SafePathError::InvalidComponent { path: comp.into() }.into());
+        }
+        if !comp.is_empty() && comp != "." {
+            input.push(&comp);
+            queue.push_back(comp);
+        }
+    }
+
+    resolve_components(root, queue, || input.clone(), &ResolveOptions::default())
+        .map(|(_root, path)| path)
+}
+
+/// Resolve `unsafe_path` scoped under `root`, and return a pinned [SafePathBuf] of each
+/// component of the resolved path, from the outermost to the target.
+///
+/// The path is resolved like [safe_open_handle()] by the userspace walk, each component opened
+/// with `O_PATH` relative to the pinned fd of its parent, and the handles are the fds pinned by
+/// the walk itself. So the chain is a consistent snapshot of the directories the kernel would
+/// traverse after expanding symlinks, for inspecting the type and ownership of each step, even
+/// if the path is changed afterwards. Symlinks expanded on the way and components removed by
+/// ".." are not part of the chain, and `root` itself is not included, so an empty chain is
+/// returned if `unsafe_path` resolves to `root`.
+///
+/// # Errors
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | `root` or a component doesn't exist | `NotFound` |
+/// | `root` or a path component is not a directory | `NotADirectory` |
+/// | too many levels of symlinks | `FilesystemLoop` |
+/// | `unsafe_path` contains invalid component | `InvalidFilename` |
+pub fn scoped_resolve_chain<R: AsRef<Path>, U: AsRef<Path>>(
+    root: R,
+    unsafe_path: U,
+) -> Result<Vec<SafePathBuf>> {
+    let mut walk = ScopedWalk::new(root)?;
+    walk.walk(unsafe_path.as_ref(), true, false)?;
+
+    (1..=walk.names().len())
+        .map(|depth| {
+            // The fds of outer components may be reopened by the walk, the handle owns a copy.
+            let fd = walk.fd_at(depth)?.try_clone()?;
+            SafePathBuf::from_file(fd.into())
+        })
+        .collect()
+}
+
+/// Resolve `unsafe_path` to a relative path, rooted at and constrained by the directory
+/// `root_rel`, which is itself resolved scoped under the directory `base_fd`.
+///
+/// It supports nested confinement, such as a volume of a tenant inside an already pinned base
+/// directory, without touching any absolute host path. `root_rel` is first resolved beneath
+/// `base_fd` with `base_fd` treated as the root, then `unsafe_path` is resolved beneath the
+/// pinned `root_rel` with `root_rel` treated as the root, so neither of them can escape its
+/// scope, whatever the symlinks or ".." components. Both walks open each component with
+/// `O_PATH | O_NOFOLLOW` relative to the pinned fd of its parent like [safe_open_handle()].
+///
+/// Like [scoped_resolve()], trailing components of `unsafe_path` needn't exist, and the
+/// returned path is relative to `root_rel`.
+///
+/// # Errors
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | `root_rel` or an intermediate component doesn't exist | `NotFound` |
+/// | `base_fd`, `root_rel` or a path component is not a directory | `NotADirectory` |
+/// | too many levels of symlinks | `FilesystemLoop` |
+/// | `root_rel` or `unsafe_path` contains invalid component | `InvalidFilename` |
+pub fn scoped_resolve_rel<R: AsRef<Path>, U: AsRef<Path>>(
+    base_fd: BorrowedFd<'_>,
+    root_rel: R,
+    unsafe_path: U,
+) -> Result<PathBuf> {
+    let root_rel = root_rel.as_ref();
+    let mut base = ScopedWalk::from_fd(PathBuf::new(), base_fd.try_clone_to_owned()?);
+    base.walk(root_rel, true, false)?;
+    if !base.is_dir() && !sys::is_dir(&sys::fstat(base.fd())?) {
+        return Err(SafePathError::NotADirectory { path: base.path() }.into());
+    }
+
+    let mut walk = ScopedWalk::from_fd(base.path(), base.fd().try_clone()?);
+    walk.walk(unsafe_path.as_ref(), true, true)?;
+    let mut path = walk.path();
+    path.extend(walk.take_missing());
+    Ok(path)
+}
+
+/// Resolve `unsafe_path` to a relative path, rooted at and constrained by `root`, with the
+/// behavior controlled by `options`.
+///
+/// It's the same as [scoped_resolve()] with the additional checks configured by [ResolveOptions].
+///
+/// # Errors
+/// The same as [scoped_resolve()], plus:
+///
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | a component is on a forbidden filesystem type | `PermissionDenied` |
+/// | a component starts with one of `forbidden_prefixes` | `PermissionDenied` |
+/// | a non-final component is not a directory with `stop_at_non_directory` | `NotADirectory` |
+/// | more components than `max_components` are processed | `InvalidInput` |
+pub fn scoped_resolve_with<R: AsRef<Path>, U: AsRef<Path>>(
+    root: R,
+    unsafe_path: U,
+    options: &ResolveOptions,
+) -> Result<PathBuf> {
+    do_scoped_resolve(root, unsafe_path, options).map(|(_root, path)| path)
 }
 
 /// Safely join `unsafe_path` to `root`, and ensure `unsafe_path` is scoped under `root`.
@@ -117,21 +645,238 @@ pub fn scoped_resolve<R: AsRef<Path>, U: AsRef<Path>>(root: R, unsafe_path: U) -
 ///   components (they will all get expanded).
 /// - When expanding symlinks, all symlink path components must be resolved relative to the provided
 ///   `root`. In particular, this can be considered a userspace implementation of how chroot(2)
-///    operates on file paths.
+///   operates on file paths.
 /// - Non-existent path components are unaffected.
 ///
+/// The `root` is canonicalized first, so the result is the canonical absolute host path: a
+/// symlinked `root` or one containing ".." yields the same form, and the ".." and symlinks in the
+/// existing components are all collapsed. Callers don't need to canonicalize it again, which
+/// would follow symlinks outside of `root`.
+///
 /// Note that the guarantees provided by this function only apply if the path components in the
 /// returned string are not modified (in other words are not replaced with symlinks on the
 /// filesystem) after this function has returned. You may use [crate::SafePathBuf] to protect from
 /// such TOCTOU attacks.
+///
+/// # Errors
+/// The same as [scoped_resolve()].
 pub fn safe_join<R: AsRef<Path>, U: AsRef<Path>>(root: R, unsafe_path: U) -> Result<PathBuf> {
-    do_scoped_resolve(root, unsafe_path).map(|(root, path)| root.join(path))
+    instrument!(
+        "safe_join",
+        root.as_ref(),
+        unsafe_path.as_ref(),
+        "follow",
+        do_scoped_resolve(&root, &unsafe_path, &ResolveOptions::default())
+            .map(|(root, path)| root.join(path))
+    )
+}
+
+/// Open `unsafe_path` scoped under `root` by the userspace walk, as for [open_handle()].
+fn walk_handle(root: &Path, unsafe_path: &Path, follow: bool, directory: bool) -> Result<OwnedFd> {
+    let mut walk = ScopedWalk::new(root)?;
+    walk.set_directories(directory);
+    walk.walk(unsafe_path, follow, false)?;
+    if directory && !walk.is_dir() {
+        return Err(SafePathError::NotADirectory {
+            path: walk.root().join(walk.path()),
+        }
+        .into());
+    }
+    Ok(walk.into_fd())
+}
+
+/// Safely open `unsafe_path` scoped under `root`, and return an `O_PATH` file descriptor of the
+/// target.
+///
+/// Unlike [safe_join()], the resolution never goes through an intermediate path string: starting
+/// from the `root` directory, each component is opened by `openat()` relative to the file
+/// descriptor of its parent with `O_PATH | O_NOFOLLOW`, and symlinks are expanded with `root`
+/// treated as the root of the filesystem. So the returned file descriptor always refers to an
+/// inode scoped under `root`, even if the path components are concurrently replaced with
+/// symlinks, instead of detecting such a race after the fact.
+///
+/// The resolution is done by `openat2(2)` with `RESOLVE_IN_ROOT` if the kernel supports it, see
+/// [crate::resolver_info()] for the active backend.
+///
+/// # Errors
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | `root` or the target doesn't exist | `NotFound` |
+/// | `root` or a path component is not a directory | `NotADirectory` |
+/// | too many levels of symlinks | `FilesystemLoop` |
+/// | `unsafe_path` contains invalid component | `InvalidFilename` |
+pub fn safe_open_handle<R: AsRef<Path>, U: AsRef<Path>>(
+    root: R,
+    unsafe_path: U,
+) -> Result<OwnedFd> {
+    open_handle(root.as_ref(), unsafe_path.as_ref(), true, false)
+}
+
+/// Safely open `unsafe_path` scoped under `root`, and return the pinned [SafePathBuf] of the
+/// target.
+///
+/// This is the one-step version of [safe_join()] followed by [SafePathBuf::from_path()]. The
+/// two-step leaves a window between resolving the path string and opening it, in which a
+/// component may be replaced by a symlink, so `from_path()` has to detect the race after the
+/// fact with [SafePathError::TargetChanged]. Here the target is resolved like
+/// [safe_open_handle()], each component opened with `O_PATH` relative to the pinned fd of its
+/// parent, so the returned handle is pinned as part of the resolution and there is no window
+/// at all. [SafePathBuf::target()] reports the scoped path the handle refers to.
+///
+/// # Errors
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | `root` or the target doesn't exist | `NotFound` |
+/// | `root` or a path component is not a directory | `NotADirectory` |
+/// | too many levels of symlinks | `FilesystemLoop` |
+/// | `unsafe_path` contains invalid component | `InvalidFilename` |
+pub fn safe_open<R: AsRef<Path>, U: AsRef<Path>>(root: R, unsafe_path: U) -> Result<SafePathBuf> {
+    instrument!(
+        "safe_open",
+        root.as_ref(),
+        unsafe_path.as_ref(),
+        "follow",
+        {
+            let fd = open_handle(root.as_ref(), unsafe_path.as_ref(), true, false)?;
+            SafePathBuf::from_file(fd.into())
+        }
+    )
+}
+
+/// Safely open `unsafe_path` scoped under `root` with `flags`, and return the opened file ready
+/// for IO.
+///
+/// This is the one-shot version of [safe_open()] on `root` followed by
+/// [SafePathBuf::open_beneath()], so the whole path is resolved relative to pinned fds and never
+/// re-resolved from a path string. A symlink at the final component is followed scoped under
+/// `root` unless [OpenFlags::NOFOLLOW] is given, and a missing final component is created by
+/// `openat(O_NOFOLLOW)` relative to the pinned fd of its parent with [OpenFlags::CREATE], even if
+/// it's reached through a dangling symlink. [OpenFlags::CREATE] with [OpenFlags::EXCLUSIVE]
+/// never opens an existing file or follows a final symlink, like `create_new()` of
+/// [std::fs::OpenOptions].
+///
+/// # Errors
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | `root` or the target doesn't exist, or its parent doesn't exist with [OpenFlags::CREATE] | `NotFound` |
+/// | `root` or a path component is not a directory | `NotADirectory` |
+/// | the target exists with [OpenFlags::CREATE] and [OpenFlags::EXCLUSIVE] | `AlreadyExists` |
+/// | the target is a directory, opened for writing or appending | `IsADirectory` |
+/// | the final component is a symlink with [OpenFlags::NOFOLLOW] | `FilesystemLoop` |
+/// | too many levels of symlinks | `FilesystemLoop` |
+/// | `flags` don't make sense, such as [OpenFlags::TRUNCATE] without write access | `InvalidInput` |
+/// | `unsafe_path` contains invalid component | `InvalidFilename` |
+pub fn safe_open_file<R: AsRef<Path>, U: AsRef<Path>>(
+    root: R,
+    unsafe_path: U,
+    flags: OpenFlags,
+) -> Result<File> {
+    flags.validate()?;
+    // Like `O_EXCL`, an existing symlink at the final component fails even if it's dangling.
+    let flags = if flags.contains(OpenFlags::EXCLUSIVE) {
+        flags | OpenFlags::NOFOLLOW
+    } else {
+        flags
+    };
+    let root =
+        SafePathBuf::from_file(open_handle(root.as_ref(), Path::new(""), true, true)?.into())?;
+    let file = root.open_beneath(unsafe_path, flags)?;
+    // Unlike writing, appending to a directory opened read-only is not refused by the kernel.
+    if flags.contains(OpenFlags::APPEND) && file.metadata()?.is_dir() {
+        return Err(Error::from_raw_os_error(libc::EISDIR));
+    }
+    Ok(file)
+}
+
+/// Resolve the existing prefix of `unsafe_path` scoped under `root`, and return the pinned
+/// deepest existing directory and the trailing components which don't exist yet.
+///
+/// This is the building block of "create if missing" flows, such as [crate::SafeDirBuilder]:
+/// the existing prefix is resolved with the same scoping and symlink rules as
+/// [safe_open_handle()], each component pinned relative to its parent, so the missing
+/// components may be created relative to the returned directory without a race window. The
+/// missing components are normal names in order, with ".." after a missing component already
+/// collapsed. If the whole path exists, the missing components are empty.
+///
+/// # Errors
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | `root` doesn't exist | `NotFound` |
+/// | `root` or an existing path component is not a directory | `NotADirectory` |
+/// | too many levels of symlinks | `FilesystemLoop` |
+/// | the path contains invalid component | `InvalidFilename` |
+pub fn resolve_existing_prefix<R: AsRef<Path>, U: AsRef<Path>>(
+    root: R,
+    unsafe_path: U,
+) -> Result<(SafePathBuf, Vec<OsString>)> {
+    let mut walk = ScopedWalk::new(root)?;
+    walk.walk(unsafe_path.as_ref(), true, true)?;
+    let missing = walk.take_missing();
+    let dir = SafePathBuf::from_file(walk.into_fd().into())?;
+    if !missing.is_empty() && !dir.is_dir() {
+        return Err(SafePathError::NotADirectory {
+            path: dir.target().to_path_buf(),
+        }
+        .into());
+    }
+
+    Ok((dir, missing))
+}
+
+/// Open `unsafe_path` scoped under `root` by the active backend. If `follow` is false, a symlink
+/// at the final component is opened itself instead of being expanded. If `directory` is true,
+/// the target is opened with `O_DIRECTORY`.
+pub(crate) fn open_handle(
+    root: &Path,
+    unsafe_path: &Path,
+    follow: bool,
+    directory: bool,
+) -> Result<OwnedFd> {
+    let mut flags = if follow { 0 } else { libc::O_NOFOLLOW };
+    if directory {
+        flags |= libc::O_DIRECTORY;
+    }
+    match resolver::backend() {
+        Backend::Openat2 => {
+            let root_fd = crate::open_by_path(root.canonicalize()?)?;
+            match resolver::openat2_in_root(&root_fd, unsafe_path, flags) {
+                // The kernel keeps asking to retry because of concurrent renames or mounts, the
+                // userspace walk doesn't care about them.
+                Err(e) if e.raw_os_error() == Some(libc::EAGAIN) => {
+                    walk_handle(root, unsafe_path, follow, directory)
+                }
+                result => result,
+            }
+        }
+        Backend::OpenatWalk => walk_handle(root, unsafe_path, follow, directory),
+        Backend::ProcReadlink => {
+            let path = match (follow, unsafe_path.parent(), unsafe_path.file_name()) {
+                (false, Some(parent), Some(name)) => safe_join(root, parent)?.join(name),
+                _ => safe_join(root, unsafe_path)?,
+            };
+            let file = OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_PATH | libc::O_CLOEXEC | flags)
+                .open(&path)?;
+            let safe_path = SafePathBuf::from_file(file)?;
+            if safe_path.target() != path {
+                return Err(SafePathError::TargetChanged {
+                    expected: path,
+                    actual: safe_path.target().to_path_buf(),
+                }
+                .into());
+            }
+            Ok(safe_path.into_file().into())
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::os::unix::fs;
+    use crate::test_util::{for_each_backend, Maze, HOST_FILE};
+    use std::os::unix::fs::{self, MetadataExt};
+    use std::os::unix::io::{AsFd, AsRawFd};
     use tempfile::tempdir;
 
     #[derive(Debug)]
@@ -145,7 +890,7 @@ mod tests {
     fn exec_tests(tests: &[TestData]) {
         for (i, t) in tests.iter().enumerate() {
             // Create a string containing details of the test
-            let msg = format!("test[{}]: {:?}", i, t);
+            let msg = format!("test[{}] {}: {:?}", i, t.name, t);
             let result = scoped_resolve(t.rootfs, t.unsafe_path).unwrap();
             let msg = format!("{}, result: {:?}", msg, result);
 
@@ -297,6 +1042,626 @@ mod tests {
         // Detect symlink loop.
         fs::symlink("/endpoint_b", rootfs_path.join("endpoint_a")).unwrap();
         fs::symlink("/endpoint_a", rootfs_path.join("endpoint_b")).unwrap();
-        safe_join(rootfs_path, "endpoint_a").unwrap_err();
+        let err = safe_join(rootfs_path, "endpoint_a").unwrap_err();
+        assert_eq!(
+            err.kind(),
+            std::io::Error::from_raw_os_error(libc::ELOOP).kind()
+        );
+        assert!(matches!(
+            SafePathError::from_io_error(&err),
+            Some(SafePathError::TooManySymlinks { .. })
+        ));
+    }
+
+    #[test]
+    fn test_scoped_resolve_forbid_fs_types() {
+        let rootfs_dir = tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        std::fs::create_dir(rootfs_path.join("a")).unwrap();
+        #[allow(clippy::unnecessary_cast)]
+        let fs_type = sys::fstatfs(&std::fs::File::open(rootfs_path).unwrap())
+            .unwrap()
+            .f_type as i64;
+
+        let mut options = ResolveOptions::new();
+        options.forbid_fs_types(&[fs_type]);
+        let err = scoped_resolve_with(rootfs_path, "a/b", &options).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert!(matches!(
+            SafePathError::from_io_error(&err),
+            Some(SafePathError::ForbiddenFilesystem { .. })
+        ));
+        assert_eq!(
+            scoped_resolve_with(rootfs_path, "b/c", &options).unwrap(),
+            Path::new("b/c")
+        );
+
+        #[allow(clippy::unnecessary_cast)]
+        let proc_type = libc::PROC_SUPER_MAGIC as i64;
+        options.forbid_fs_types(&[proc_type]);
+        assert_eq!(
+            scoped_resolve_with(rootfs_path, "a/b", &options).unwrap(),
+            Path::new("a/b")
+        );
+        if Path::new("/proc/self").exists() {
+            scoped_resolve_with("/", "proc/self", &options).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn test_scoped_resolve_backslash() {
+        let rootfs_dir = tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        std::fs::create_dir(rootfs_path.join("a")).unwrap();
+
+        // Strict by default, a backslash is a literal byte.
+        let options = ResolveOptions::new();
+        assert_eq!(
+            scoped_resolve_with(rootfs_path, "a\\b", &options).unwrap(),
+            PathBuf::from("a\\b")
+        );
+
+        let mut options = ResolveOptions::new();
+        options.treat_backslash_as_separator(true);
+        assert_eq!(
+            scoped_resolve_with(rootfs_path, "a\\b", &options).unwrap(),
+            PathBuf::from("a/b")
+        );
+        assert_eq!(
+            scoped_resolve_with(rootfs_path, "\\..\\..\\a\\b/c", &options).unwrap(),
+            PathBuf::from("a/b/c")
+        );
+    }
+
+    #[test]
+    fn test_scoped_resolve_max_components() {
+        let rootfs_dir = tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        std::fs::create_dir(rootfs_path.join("a")).unwrap();
+        // Each level expands to twice the components of the level below.
+        fs::symlink("a/../a/..", rootfs_path.join("l0")).unwrap();
+        for i in 1..3 {
+            let target = format!("l{}/l{}", i - 1, i - 1);
+            fs::symlink(target, rootfs_path.join(format!("l{}", i))).unwrap();
+        }
+
+        let mut options = ResolveOptions::new();
+        options.max_components(12);
+        let path = scoped_resolve_with(rootfs_path, "a/./b/../c", &options).unwrap();
+        assert_eq!(path, Path::new("a/c"));
+        let err =
+            scoped_resolve_with(rootfs_path, "a/b/c/d/e/f/g/h/i/j/k/l/m", &options).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(matches!(
+            SafePathError::from_io_error(&err),
+            Some(SafePathError::TooManyComponents { limit: 12, .. })
+        ));
+
+        // Components introduced by symlinks count too.
+        let path = scoped_resolve_with(rootfs_path, "l1", &options).unwrap();
+        assert_eq!(path, Path::new(""));
+        scoped_resolve_with(rootfs_path, "l2", &options).unwrap_err();
+        // Fewer symlinks than the limit of expansions, but too many components by default.
+        fs::symlink(vec!["a/.."; 20].join("/"), rootfs_path.join("m0")).unwrap();
+        for i in 1..8 {
+            let target = format!("m{}/m{}", i - 1, i - 1);
+            fs::symlink(target, rootfs_path.join(format!("m{}", i))).unwrap();
+        }
+        scoped_resolve(rootfs_path, "m6").unwrap();
+        let err = scoped_resolve(rootfs_path, "m7").unwrap_err();
+        assert!(matches!(
+            SafePathError::from_io_error(&err),
+            Some(SafePathError::TooManyComponents { limit: 4096, .. })
+        ));
+    }
+
+    #[test]
+    fn test_scoped_resolve_forbidden_prefixes() {
+        let rootfs_dir = tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        std::fs::create_dir_all(rootfs_path.join("a/.git")).unwrap();
+        fs::symlink("a/.git", rootfs_path.join("s")).unwrap();
+        fs::symlink("__reserved", rootfs_path.join("r")).unwrap();
+
+        let mut options = ResolveOptions::new();
+        options.forbidden_prefixes(&[OsStr::new("."), OsStr::new("__")]);
+        assert_eq!(
+            scoped_resolve_with(rootfs_path, "./a/../a/b.txt", &options).unwrap(),
+            PathBuf::from("a/b.txt")
+        );
+        for (path, name) in [
+            ("a/.git/config", ".git"),
+            ("a/.hidden", ".hidden"),
+            ("__init__", "__init__"),
+            // Components introduced by symlinks are checked too.
+            ("s", ".git"),
+            ("r/x", "__reserved"),
+        ]
+        .iter()
+        {
+            let err = scoped_resolve_with(rootfs_path, path, &options).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::PermissionDenied, "{}", path);
+            match SafePathError::from_io_error(&err) {
+                Some(SafePathError::ForbiddenComponent { name: n }) => assert_eq!(n, name),
+                _ => panic!("unexpected error {}", err),
+            }
+        }
+        // Not forbidden by default.
+        assert_eq!(
+            scoped_resolve(rootfs_path, "s").unwrap(),
+            PathBuf::from("a/.git")
+        );
+    }
+
+    #[test]
+    fn test_scoped_resolve_stop_at_non_directory() {
+        let rootfs_dir = tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        std::fs::create_dir(rootfs_path.join("a")).unwrap();
+        std::fs::write(rootfs_path.join("a/b"), "").unwrap();
+        fs::symlink("/a/b", rootfs_path.join("s")).unwrap();
+
+        // Lexical by default.
+        let options = ResolveOptions::new();
+        assert_eq!(
+            scoped_resolve_with(rootfs_path, "a/b/c", &options).unwrap(),
+            PathBuf::from("a/b/c")
+        );
+
+        let mut options = ResolveOptions::new();
+        options.stop_at_non_directory(true);
+        for path in ["a/b/c", "s/c", "a/b/../b"].iter() {
+            let err = scoped_resolve_with(rootfs_path, path, &options).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::NotADirectory);
+            match SafePathError::from_io_error(&err) {
+                Some(SafePathError::NotADirectory { path }) => {
+                    assert_eq!(path, &rootfs_path.canonicalize().unwrap().join("a/b"))
+                }
+                _ => panic!("unexpected error {}", err),
+            }
+        }
+        for path in ["a/b", "s", "a/x/y"].iter() {
+            scoped_resolve_with(rootfs_path, path, &options).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_scoped_resolve_slash_root() {
+        let rootfs_dir = tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+
+        for path in ["", "/", "//", "..", "../../", "/../.", "a/../.."].iter() {
+            assert_eq!(scoped_resolve("/", path).unwrap(), Path::new(""));
+            assert_eq!(safe_join("/", path).unwrap(), Path::new("/"));
+        }
+        let unsafe_path = Path::new("../..").join(rootfs_path).join("..//./a");
+        let expected = rootfs_path.parent().unwrap().join("a");
+        assert_eq!(
+            scoped_resolve("/", &unsafe_path).unwrap(),
+            expected.strip_prefix("/").unwrap()
+        );
+        assert_eq!(safe_join("/", &unsafe_path).unwrap(), expected);
+        assert_eq!(safe_join("//", &unsafe_path).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_scoped_resolve_alternating_chain() {
+        let tmp_dir = tempdir().expect("failed to create tmpdir");
+        let tmp_path = tmp_dir.path();
+        let rootfs_path = tmp_path.join("root");
+        std::fs::create_dir_all(rootfs_path.join("outside")).unwrap();
+        std::fs::create_dir(tmp_path.join("outside")).unwrap();
+        std::fs::write(rootfs_path.join("outside/secret"), "inside").unwrap();
+        std::fs::write(tmp_path.join("outside/secret"), "escaped").unwrap();
+
+        // Each hop alternates between an absolute target and a relative target with "..", which
+        // would land on the host "outside" directory if any hop isn't clamped to the root.
+        fs::symlink("/hop", rootfs_path.join("a")).unwrap();
+        fs::symlink("../../outside/../outside/secret", rootfs_path.join("hop")).unwrap();
+        fs::symlink("/outside/../../hop3", rootfs_path.join("b")).unwrap();
+        fs::symlink("../../../outside/c", rootfs_path.join("hop3")).unwrap();
+        fs::symlink("../../../../secret", rootfs_path.join("outside/c")).unwrap();
+        fs::symlink("../../../a", rootfs_path.join("outside/d")).unwrap();
+        fs::symlink(tmp_path.join("outside"), rootfs_path.join("hop4")).unwrap();
+
+        let host_path = tmp_path.join("outside/secret");
+        let tests = [
+            ("a", Path::new("outside/secret")),
+            ("b", Path::new("secret")),
+            ("outside/d", Path::new("outside/secret")),
+            ("hop4/secret", host_path.strip_prefix("/").unwrap()),
+        ];
+        for (path, result) in tests.iter() {
+            assert_eq!(&scoped_resolve(&rootfs_path, path).unwrap(), result);
+            assert_eq!(
+                safe_join(&rootfs_path, path).unwrap(),
+                rootfs_path.join(result)
+            );
+        }
+
+        for_each_backend(|backend| {
+            for path in ["a", "outside/d"].iter() {
+                let path = SafePathBuf::new(&rootfs_path, path).unwrap();
+                assert_eq!(path.target(), rootfs_path.join("outside/secret"));
+                assert_eq!(std::fs::read_to_string(&path).unwrap(), "inside");
+            }
+            for path in ["b", "hop4/secret"].iter() {
+                let err = SafePathBuf::new(&rootfs_path, path).unwrap_err();
+                assert_eq!(err.kind(), ErrorKind::NotFound, "backend {:?}", backend);
+            }
+        });
+    }
+
+    #[test]
+    fn test_scoped_resolve_shared() {
+        let rootfs_dir = tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::symlink("/a/b", rootfs_path.join("s")).unwrap();
+
+        let path = scoped_resolve_shared(rootfs_path, "s/c").unwrap();
+        let shared = path.clone();
+        let handle = std::thread::spawn(move || shared.to_path_buf());
+        assert_eq!(handle.join().unwrap(), PathBuf::from("a/b/c"));
+        assert_eq!(&*path, Path::new("a/b/c"));
+    }
+
+    #[test]
+    fn test_scoped_resolve_chain() {
+        let rootfs_dir = tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        let root = rootfs_path.canonicalize().unwrap();
+        std::fs::create_dir_all(rootfs_path.join("b/c")).unwrap();
+        std::fs::create_dir(rootfs_path.join("a")).unwrap();
+        std::fs::write(rootfs_path.join("b/c/f"), "f").unwrap();
+        fs::symlink("/b/c", rootfs_path.join("a/s")).unwrap();
+        fs::symlink("../../..", rootfs_path.join("b/up")).unwrap();
+
+        let chain = scoped_resolve_chain(rootfs_path, "a/s/f").unwrap();
+        let targets: Vec<_> = chain.iter().map(|p| p.target().to_path_buf()).collect();
+        assert_eq!(
+            targets,
+            vec![root.join("b"), root.join("b/c"), root.join("b/c/f")]
+        );
+        assert!(chain[1].is_dir());
+        assert!(!chain[2].is_dir());
+
+        // Each handle keeps pinning its inode after the path is changed.
+        let ino = std::fs::metadata(rootfs_path.join("b/c")).unwrap().ino();
+        std::fs::rename(rootfs_path.join("b"), rootfs_path.join("moved")).unwrap();
+        assert_eq!(chain[1].stat().unwrap().ino(), ino);
+        std::fs::rename(rootfs_path.join("moved"), rootfs_path.join("b")).unwrap();
+
+        let chain = scoped_resolve_chain(rootfs_path, "b/up/b").unwrap();
+        assert_eq!(chain.len(), 1);
+        assert_eq!(chain[0].target(), root.join("b"));
+        assert!(scoped_resolve_chain(rootfs_path, "").unwrap().is_empty());
+        // Deeper than the fds kept open by the walk.
+        let deep = vec!["d"; 100].join("/");
+        std::fs::create_dir_all(rootfs_path.join(&deep)).unwrap();
+        let chain = scoped_resolve_chain(rootfs_path, &deep).unwrap();
+        assert_eq!(chain.len(), 100);
+        assert_eq!(chain[99].target(), root.join(&deep));
+
+        let err = scoped_resolve_chain(rootfs_path, "a/s/x").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_scoped_resolve_rel() {
+        let maze = Maze::new();
+        maze.file("tenants/t1/vol/data/f", "f")
+            .file("tenants/t1/f", "f")
+            .symlink("tenants/link", "/tenants/t1")
+            .symlink("tenants/t1/vol/s", "/data")
+            .symlink("tenants/t1/vol/up", "../../../..")
+            .symlink("tenants/t1/vol/out", "../f");
+        let base = std::fs::File::open(maze.root()).unwrap();
+        let base_fd = base.as_fd();
+
+        for root_rel in [
+            "tenants/t1/vol",
+            "tenants/link/vol",
+            "../tenants/link/./vol",
+        ]
+        .iter()
+        {
+            for (unsafe_path, expected) in [
+                ("s/f", "data/f"),
+                ("up/data", "data"),
+                ("/up/../s/new/x", "data/new/x"),
+                ("out", "f"),
+                ("", ""),
+            ]
+            .iter()
+            {
+                let path = scoped_resolve_rel(base_fd, root_rel, unsafe_path).unwrap();
+                assert_eq!(path, Path::new(expected), "{} {}", root_rel, unsafe_path);
+            }
+        }
+
+        // Neither the sub-root nor the path escapes its scope.
+        for escape in maze.escapes("tenants/t1/vol/e").iter() {
+            let unsafe_path = escape
+                .strip_prefix("tenants/t1/vol")
+                .unwrap()
+                .join(HOST_FILE);
+            let path = scoped_resolve_rel(base_fd, "tenants/t1/vol", &unsafe_path).unwrap();
+            let target = maze.root().join("tenants/t1/vol").join(&path);
+            assert!(!target.exists(), "{}", path.display());
+            let err = scoped_resolve_rel(base_fd, escape, "").unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::NotFound);
+        }
+        let err = scoped_resolve_rel(base_fd, "tenants/t1/f", "x").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+        let err = scoped_resolve_rel(base_fd, "tenants/t2", "x").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        maze.assert_host_intact();
+    }
+
+    #[test]
+    fn test_safe_open_file() {
+        use std::io::{Read, Write};
+
+        let maze = Maze::new();
+        maze.file("a/f", "data")
+            .symlink("a/link", "/a/f")
+            .symlink("a/dangling", "../new")
+            .symlink("a/host", maze.host().join(HOST_FILE))
+            .symlink("link_dir", "/a");
+        let rootfs_path = maze.root();
+        let read = |path: &str| std::fs::read_to_string(rootfs_path.join(path)).unwrap();
+
+        let mut content = String::new();
+        let mut file = safe_open_file(rootfs_path, "../link_dir/link", OpenFlags::READ).unwrap();
+        file.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "data");
+        assert!(file.write_all(b"x").is_err());
+
+        // create_new semantics.
+        let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::EXCLUSIVE;
+        let mut file = safe_open_file(rootfs_path, "link_dir/g", flags).unwrap();
+        file.write_all(b"g").unwrap();
+        assert_eq!(read("a/g"), "g");
+        for path in ["a/g", "a/link", "a/dangling"].iter() {
+            let err = safe_open_file(rootfs_path, path, flags).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::AlreadyExists, "{}", path);
+        }
+        // A dangling symlink is created scoped under the root.
+        let flags = OpenFlags::WRITE | OpenFlags::CREATE;
+        let mut file = safe_open_file(rootfs_path, "a/dangling", flags).unwrap();
+        file.write_all(b"new").unwrap();
+        assert_eq!(read("new"), "new");
+
+        let flags = OpenFlags::WRITE | OpenFlags::TRUNCATE;
+        let mut file = safe_open_file(rootfs_path, "a/link", flags).unwrap();
+        file.write_all(b"d").unwrap();
+        assert_eq!(read("a/f"), "d");
+        let flags = OpenFlags::WRITE | OpenFlags::APPEND;
+        let mut file = safe_open_file(rootfs_path, "a/f", flags).unwrap();
+        file.write_all(b"x").unwrap();
+        assert_eq!(read("a/f"), "dx");
+
+        // The final symlink is refused with NOFOLLOW, and scoped under the root otherwise.
+        let flags = OpenFlags::READ | OpenFlags::NOFOLLOW;
+        let err = safe_open_file(rootfs_path, "a/link", flags).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ELOOP));
+        let err = safe_open_file(rootfs_path, "a/host", OpenFlags::READ).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+        maze.assert_escapes_rejected(|escape| {
+            safe_open_file(rootfs_path, escape.join(HOST_FILE), flags)
+        });
+
+        for flags in [
+            OpenFlags::READ | OpenFlags::CREATE,
+            OpenFlags::READ | OpenFlags::TRUNCATE,
+            OpenFlags::WRITE | OpenFlags::TRUNCATE | OpenFlags::APPEND,
+            OpenFlags::WRITE | OpenFlags::EXCLUSIVE,
+        ]
+        .iter()
+        {
+            let err = safe_open_file(rootfs_path, "a/f", *flags).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{:?}", flags);
+        }
+        for flags in [OpenFlags::WRITE, OpenFlags::READ | OpenFlags::APPEND].iter() {
+            let err = safe_open_file(rootfs_path, "link_dir", *flags).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::IsADirectory, "{:?}", flags);
+        }
+        assert_eq!(read("a/f"), "dx");
+    }
+
+    #[test]
+    fn test_scoped_resolve_cow() {
+        let rootfs_dir = tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        std::fs::create_dir_all(rootfs_path.join("a/b")).unwrap();
+        fs::symlink("/a/b", rootfs_path.join("s")).unwrap();
+
+        for path in ["a/b/c", "a", "x/y", ""].iter() {
+            match scoped_resolve_cow(rootfs_path, path).unwrap() {
+                Cow::Borrowed(p) => assert_eq!(p, Path::new(path)),
+                Cow::Owned(p) => panic!("unexpected owned {}", p.display()),
+            }
+        }
+        for (path, expected) in [
+            ("s/c", "a/b/c"),
+            ("/a/b", "a/b"),
+            ("a/./b", "a/b"),
+            ("a//b", "a/b"),
+            ("a/b/", "a/b"),
+            ("../a", "a"),
+            ("a/b/../b", "a/b"),
+            ("./a", "a"),
+        ]
+        .iter()
+        {
+            match scoped_resolve_cow(rootfs_path, path).unwrap() {
+                Cow::Owned(p) => assert_eq!(p, Path::new(expected), "{}", path),
+                Cow::Borrowed(p) => panic!("unexpected borrowed {}", p.display()),
+            }
+        }
+
+        let err = scoped_resolve_cow("/__does_not_exist__", "a").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_scoped_resolve_iter() {
+        let rootfs_dir = tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        std::fs::create_dir_all(rootfs_path.join("a/b")).unwrap();
+        fs::symlink("../../a", rootfs_path.join("a/s")).unwrap();
+
+        let comps = |names: &[&str]| names.iter().map(OsString::from).collect::<Vec<_>>();
+        let path = scoped_resolve_iter(rootfs_path, comps(&["..", "a", "s", "", ".", "b", "c"]));
+        assert_eq!(path.unwrap(), PathBuf::from("a/b/c"));
+        assert_eq!(
+            scoped_resolve_iter(rootfs_path, comps(&["a", "s", "b"])).unwrap(),
+            scoped_resolve(rootfs_path, "a/s/b").unwrap()
+        );
+        assert_eq!(
+            scoped_resolve_iter(rootfs_path, Vec::new()).unwrap(),
+            PathBuf::new()
+        );
+
+        for name in ["a/b", "/", "a\0b"].iter() {
+            let err = scoped_resolve_iter(rootfs_path, comps(&["a", name])).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidFilename);
+        }
+    }
+
+    #[test]
+    fn test_resolve_existing_prefix() {
+        let rootfs_dir = tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        std::fs::create_dir_all(rootfs_path.join("a/b")).unwrap();
+        fs::symlink("/a", rootfs_path.join("s")).unwrap();
+
+        let (dir, missing) = resolve_existing_prefix(rootfs_path, "s/b/c/d").unwrap();
+        assert_eq!(dir.target(), rootfs_path.join("a/b"));
+        assert_eq!(missing, vec![OsString::from("c"), OsString::from("d")]);
+
+        let (dir, missing) = resolve_existing_prefix(rootfs_path, "../a/x/../y").unwrap();
+        assert_eq!(dir.target(), rootfs_path.join("a"));
+        assert_eq!(missing, vec![OsString::from("y")]);
+
+        let (dir, missing) = resolve_existing_prefix(rootfs_path, "/s/b").unwrap();
+        assert_eq!(dir.target(), rootfs_path.join("a/b"));
+        assert!(missing.is_empty());
+
+        std::fs::write(rootfs_path.join("f"), "f").unwrap();
+        let err = resolve_existing_prefix(rootfs_path, "f/x").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+    }
+
+    #[test]
+    fn test_safe_join_symlinked_root() {
+        let rootfs_dir = tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        std::fs::create_dir_all(rootfs_path.join("root/a")).unwrap();
+        fs::symlink(rootfs_path.join("root"), rootfs_path.join("link")).unwrap();
+        fs::symlink("/a", rootfs_path.join("root/s")).unwrap();
+
+        let expected = rootfs_path.join("root/a/b");
+        for root in [rootfs_path.join("link"), rootfs_path.join("root/../link/.")].iter() {
+            let path = safe_join(root, "../s/../a/./b").unwrap();
+            assert_eq!(path, expected);
+            let path = safe_join(root, "s/b").unwrap();
+            assert_eq!(path, expected);
+        }
+        let path = safe_join(rootfs_path.join("link"), "..").unwrap();
+        assert_eq!(path, rootfs_path.join("root"));
+    }
+
+    #[test]
+    fn test_safe_open() {
+        let rootfs_dir = tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        std::fs::create_dir(rootfs_path.join("a")).unwrap();
+        std::fs::write(rootfs_path.join("a/b"), "b").unwrap();
+        fs::symlink("/a", rootfs_path.join("abs")).unwrap();
+        fs::symlink("../../../a/b", rootfs_path.join("a/rel")).unwrap();
+
+        let expected = rootfs_path.canonicalize().unwrap().join("a/b");
+        for path in ["a/b", "../abs/b", "a/rel", "/abs/../abs/rel"].iter() {
+            let safe_path = safe_open(rootfs_path, path).unwrap();
+            assert_eq!(safe_path.target(), expected);
+            assert_eq!(std::fs::read_to_string(&*safe_path).unwrap(), "b");
+        }
+        let err = safe_open(rootfs_path, "abs/c").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let err = safe_open(rootfs_path, "a/b/c").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+    }
+
+    #[test]
+    fn test_safe_open_handle_backends() {
+        let rootfs_dir = tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        std::fs::create_dir(rootfs_path.join("a")).unwrap();
+        std::fs::write(rootfs_path.join("a/b"), "b").unwrap();
+        fs::symlink("/a", rootfs_path.join("abs")).unwrap();
+        fs::symlink("../../../a/b", rootfs_path.join("a/rel")).unwrap();
+
+        for_each_backend(|backend| {
+            for (path, result) in [
+                ("", ""),
+                ("../a/b", "a/b"),
+                ("abs/b", "a/b"),
+                ("a/rel", "a/b"),
+                ("/abs/../abs/rel", "a/b"),
+            ] {
+                let fd = safe_open_handle(rootfs_path, path).unwrap();
+                let target = std::fs::read_link(format!("/proc/self/fd/{}", fd.as_raw_fd()));
+                assert_eq!(
+                    target.unwrap(),
+                    rootfs_path.canonicalize().unwrap().join(result),
+                    "backend {:?}, path {}",
+                    backend,
+                    path
+                );
+            }
+            safe_open_handle(rootfs_path, "a/c").unwrap_err();
+        });
+
+        // openat2() keeps asking to retry, so the walk takes over.
+        if crate::resolver_info().openat2_available {
+            let _guard = resolver::override_backend(Backend::Openat2);
+            for _ in 0..=sys::MAX_OPENAT2_RETRIES {
+                sys::inject_error("openat2", libc::EAGAIN);
+            }
+            sys::take_syscalls();
+            let fd = safe_open_handle(rootfs_path, "abs/b").unwrap();
+            let target = std::fs::read_link(format!("/proc/self/fd/{}", fd.as_raw_fd()));
+            assert_eq!(
+                target.unwrap(),
+                rootfs_path.canonicalize().unwrap().join("a/b")
+            );
+            assert!(sys::take_syscalls().contains(&"openat"));
+        }
+    }
+
+    #[test]
+    fn test_maze_escapes_scoped() {
+        let maze = Maze::new();
+        let escapes = maze.escapes("a/b");
+        let root = maze.root().canonicalize().unwrap();
+
+        for escape in escapes.iter() {
+            let path = escape.join(HOST_FILE);
+            let resolved = safe_join(&root, &path).unwrap();
+            assert!(resolved.starts_with(&root), "{}", resolved.display());
+            assert!(!resolved.exists(), "{}", resolved.display());
+            let err = SafePathBuf::new(&root, &path).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::NotFound, "{}", path.display());
+        }
+
+        for_each_backend(|backend| {
+            for escape in escapes.iter() {
+                let err = safe_open_handle(&root, escape.join(HOST_FILE)).unwrap_err();
+                assert_eq!(err.kind(), ErrorKind::NotFound, "{:?}", backend);
+            }
+        });
+        maze.assert_host_intact();
     }
 }
@@ -0,0 +1,309 @@
+// Copyright (c) 2022 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Result;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use crate::safe_join::open_handle;
+use crate::safe_rename::final_name;
+use crate::walk::{ScopedWalk, PARENT_DIR};
+use crate::{sys, SafePathError};
+
+/// Safely create a hardlink at `link` to `existing`, both scoped under `root`.
+///
+/// `existing` is resolved and pinned like [crate::safe_open_handle()], except that a symlink at
+/// the final component is linked itself like `link(2)` does, and the parent directory of `link`,
+/// which must exist, is resolved with the same rules. The hardlink is then created by `linkat()`
+/// from the pinned fd of `existing` into the pinned fd of the parent, so it always refers to the
+/// resolved inode.
+///
+/// It's the one-shot version of [crate::SafeDirBuilder::create_hardlink()].
+///
+/// # Errors
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | `root`, `existing` or the parent directory of `link` doesn't exist | `NotFound` |
+/// | `root` or a path component is not a directory | `NotADirectory` |
+/// | `link` already exists, including a symlink | `AlreadyExists`, with [SafePathError::AlreadyExists] |
+/// | `existing` is on another filesystem | `CrossesDevices`, with [SafePathError::CrossDevice] |
+/// | `existing` is a directory | `PermissionDenied` |
+/// | too many levels of symlinks | `FilesystemLoop` |
+/// | the final component of `link` is missing, `.` or `..` | `InvalidFilename` |
+/// | the path contains invalid component | `InvalidFilename` |
+pub fn safe_hardlink<R: AsRef<Path>, E: AsRef<Path>, L: AsRef<Path>>(
+    root: R,
+    existing: E,
+    link: L,
+) -> Result<()> {
+    let root = root.as_ref();
+    let source = open_handle(root, existing.as_ref(), false, false)?;
+    let (walk, name) = walk_parent(root, link.as_ref())?;
+
+    walk.link_at(&source, name, || {
+        let proc_path = format!("/proc/self/fd/{}", source.as_raw_fd());
+        fs::read_link(proc_path).unwrap_or_else(|_| existing.as_ref().into())
+    })
+}
+
+/// Safely create a symlink at `link` scoped under `root`, pointing to `target`.
+///
+/// The parent directory of `link`, which must exist, is resolved with the same rules as
+/// [crate::safe_open_handle()], then the symlink is created by `symlinkat()` relative to the
+/// pinned fd of the parent. The `target` is stored verbatim, it may be absolute or relative and
+/// needn't exist. Note that an absolute `target` is interpreted against the root of the
+/// filesystem by anyone following the symlink outside of this crate.
+///
+/// It's the one-shot version of [crate::SafeDirBuilder::create_symlink()].
+///
+/// # Errors
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | `root` or the parent directory of `link` doesn't exist | `NotFound` |
+/// | `root` or a path component is not a directory | `NotADirectory` |
+/// | `link` already exists, including a symlink | `AlreadyExists`, with [SafePathError::AlreadyExists] |
+/// | too many levels of symlinks | `FilesystemLoop` |
+/// | the final component of `link` is missing, `.` or `..` | `InvalidFilename` |
+/// | the path contains invalid component | `InvalidFilename` |
+pub fn safe_symlink<R: AsRef<Path>, T: AsRef<Path>, L: AsRef<Path>>(
+    root: R,
+    target: T,
+    link: L,
+) -> Result<()> {
+    let (walk, name) = walk_parent(root.as_ref(), link.as_ref())?;
+    sys::symlinkat(target.as_ref(), walk.fd(), name).map_err(|e| walk.exists_error(e, name))
+}
+
+/// Safely read the target of the symlink `unsafe_path` scoped under `root`, without following
+/// it.
+///
+/// The path is resolved with the same rules as [crate::safe_open_handle()], except that the
+/// final component is pinned itself instead of being followed, and it must be a symlink. The
+/// target is then read by `readlinkat()` from the pinned fd of the symlink, so it's the target of
+/// the checked symlink even if the path is changed underneath. The target is returned verbatim,
+/// of any length, and is not resolved, see [safe_read_link_resolved()] for that.
+///
+/// # Errors
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | `root` or the target doesn't exist | `NotFound` |
+/// | `root` or a path component is not a directory | `NotADirectory` |
+/// | the final component is not a symlink | `InvalidInput`, with [SafePathError::NotASymlink] |
+/// | too many levels of symlinks | `FilesystemLoop` |
+/// | the path contains invalid component | `InvalidFilename` |
+pub fn safe_read_link<R: AsRef<Path>, U: AsRef<Path>>(root: R, unsafe_path: U) -> Result<PathBuf> {
+    let walk = walk_symlink(root.as_ref(), unsafe_path.as_ref())?;
+    sys::readlinkat(walk.fd(), OsStr::new(""))
+}
+
+/// Safely read the target of the symlink `unsafe_path` scoped under `root` like
+/// [safe_read_link()], and return the path where the target lands under `root`.
+///
+/// The target is resolved relative to the pinned parent directory of the symlink, with `root`
+/// treated as the root of the filesystem like [crate::safe_join()], so an absolute target is
+/// relative to `root` and ".." never goes beyond `root`. Unlike [crate::safe_join()], the target
+/// or its trailing components needn't exist, which keep their names in the returned path.
+///
+/// # Errors
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | `root` or the symlink doesn't exist | `NotFound` |
+/// | `root` or a path component, including of the target, is not a directory | `NotADirectory` |
+/// | the final component is not a symlink | `InvalidInput`, with [SafePathError::NotASymlink] |
+/// | too many levels of symlinks | `FilesystemLoop` |
+/// | the path contains invalid component | `InvalidFilename` |
+pub fn safe_read_link_resolved<R: AsRef<Path>, U: AsRef<Path>>(
+    root: R,
+    unsafe_path: U,
+) -> Result<PathBuf> {
+    let mut walk = walk_symlink(root.as_ref(), unsafe_path.as_ref())?;
+    let target = sys::readlinkat(walk.fd(), OsStr::new(""))?;
+    if target.is_absolute() {
+        walk = ScopedWalk::from_fd(walk.root().to_path_buf(), walk.root_fd().try_clone()?);
+    } else {
+        // Step back to the parent of the symlink, to resolve the target as the kernel would.
+        walk.walk(Path::new(PARENT_DIR), false, false)?;
+    }
+    walk.walk(&target, true, true)?;
+    let mut path = walk.root().join(walk.path());
+    path.extend(walk.take_missing());
+    Ok(path)
+}
+
+/// Walk to the symlink `unsafe_path` under `root`, pinning the symlink itself.
+fn walk_symlink(root: &Path, unsafe_path: &Path) -> Result<ScopedWalk> {
+    let mut walk = ScopedWalk::new(root)?;
+    walk.walk(unsafe_path, false, false)?;
+    if walk.is_root() || !sys::is_symlink(&sys::fstat(walk.fd())?) {
+        return Err(SafePathError::NotASymlink {
+            path: walk.root().join(walk.path()),
+        }
+        .into());
+    }
+    Ok(walk)
+}
+
+/// Walk to the parent directory of `link` under `root`, and return the walk with the final
+/// component.
+pub(crate) fn walk_parent<'a>(root: &Path, link: &'a Path) -> Result<(ScopedWalk, &'a OsStr)> {
+    let name = final_name(link)?;
+    let mut walk = ScopedWalk::new(root)?;
+    walk.walk(link.parent().unwrap(), true, false)?;
+    Ok((walk, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{Maze, HOST_FILE};
+    use std::io::ErrorKind;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn test_safe_hardlink() {
+        let maze = Maze::new();
+        maze.file("a/f", "f")
+            .dir("b")
+            .symlink("a/s", "f")
+            .symlink("link_dir", "/b");
+        let rootfs_path = maze.root();
+
+        safe_hardlink(rootfs_path, "a/f", "../link_dir/f").unwrap();
+        let ino = fs::metadata(rootfs_path.join("a/f")).unwrap().ino();
+        assert_eq!(fs::metadata(rootfs_path.join("b/f")).unwrap().ino(), ino);
+        assert_eq!(fs::metadata(rootfs_path.join("a/f")).unwrap().nlink(), 2);
+        // A symlink is linked itself.
+        safe_hardlink(rootfs_path, "a/s", "b/s").unwrap();
+        assert_eq!(
+            fs::read_link(rootfs_path.join("b/s")).unwrap(),
+            Path::new("f")
+        );
+
+        for link in ["b/f", "b/s", "link_dir"].iter() {
+            let err = safe_hardlink(rootfs_path, "a/f", link).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::AlreadyExists, "{}", link);
+            assert!(matches!(
+                SafePathError::from_io_error(&err),
+                Some(SafePathError::AlreadyExists { .. })
+            ));
+        }
+        let err = safe_hardlink(rootfs_path, "a", "b/dir").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        let err = safe_hardlink(rootfs_path, "a/missing", "b/x").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        // Both paths stay under the root.
+        maze.assert_escapes_rejected(|escape| {
+            let err = safe_hardlink(rootfs_path, escape.join(HOST_FILE), "b/x").unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::NotFound);
+            safe_hardlink(rootfs_path, "a/f", escape.join("x"))
+        });
+    }
+
+    #[test]
+    fn test_safe_symlink() {
+        let maze = Maze::new();
+        maze.file("a/f", "f").symlink("link_dir", "/a");
+        let rootfs_path = maze.root();
+
+        // The target is stored verbatim.
+        safe_symlink(rootfs_path, "../../x", "../link_dir/s").unwrap();
+        assert_eq!(
+            fs::read_link(rootfs_path.join("a/s")).unwrap(),
+            Path::new("../../x")
+        );
+        safe_symlink(rootfs_path, maze.host().join(HOST_FILE), "a/host").unwrap();
+        assert_eq!(
+            fs::read_link(rootfs_path.join("a/host")).unwrap(),
+            maze.host().join(HOST_FILE)
+        );
+
+        for link in ["a/f", "a/s", "link_dir"].iter() {
+            let err = safe_symlink(rootfs_path, "t", link).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::AlreadyExists, "{}", link);
+            match SafePathError::from_io_error(&err) {
+                Some(SafePathError::AlreadyExists { path, relative }) => {
+                    assert!(path.starts_with(rootfs_path.canonicalize().unwrap()));
+                    assert_eq!(relative, Path::new(link));
+                }
+                _ => panic!("unexpected error {}", err),
+            }
+        }
+        let err = safe_symlink(rootfs_path, "t", "b/s").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let err = safe_symlink(rootfs_path, "t", "a/..").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidFilename);
+
+        maze.assert_escapes_rejected(|escape| safe_symlink(rootfs_path, "t", escape.join("x")));
+    }
+
+    #[test]
+    fn test_safe_read_link() {
+        let maze = Maze::new();
+        let long = "d/".repeat(1500) + "f";
+        maze.file("a/f", "f")
+            .symlink("a/rel", "../a/./f")
+            .symlink("a/abs", "/a/f")
+            .symlink("a/long", &long)
+            .symlink("link_dir", "/a");
+        let rootfs_path = maze.root();
+
+        // Intermediate symlinks are followed, the final one is read verbatim.
+        let target = safe_read_link(rootfs_path, "link_dir/rel").unwrap();
+        assert_eq!(target, Path::new("../a/./f"));
+        let target = safe_read_link(rootfs_path, "../link_dir/abs").unwrap();
+        assert_eq!(target, Path::new("/a/f"));
+        let target = safe_read_link(rootfs_path, "a/long").unwrap();
+        assert_eq!(target, Path::new(&long));
+        let target = safe_read_link(rootfs_path, "link_dir").unwrap();
+        assert_eq!(target, Path::new("/a"));
+
+        for path in ["a/f", "a", ""].iter() {
+            let err = safe_read_link(rootfs_path, path).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{}", path);
+            assert!(matches!(
+                SafePathError::from_io_error(&err),
+                Some(SafePathError::NotASymlink { .. })
+            ));
+        }
+        let err = safe_read_link(rootfs_path, "a/missing").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_safe_read_link_resolved() {
+        let maze = Maze::new();
+        maze.file("a/f", "f")
+            .symlink("a/rel", "../a/./f")
+            .symlink("a/abs", "/a/f")
+            .symlink("a/dangling", "../b/c")
+            .symlink("a/up", "../../../../a")
+            .symlink("link_dir", "/a")
+            .symlink("host", maze.host().join(HOST_FILE));
+        let rootfs_path = maze.root();
+        let root = rootfs_path.canonicalize().unwrap();
+
+        for path in ["a/rel", "link_dir/abs", "../a/abs"].iter() {
+            let resolved = safe_read_link_resolved(rootfs_path, path).unwrap();
+            assert_eq!(resolved, root.join("a/f"), "{}", path);
+        }
+        let resolved = safe_read_link_resolved(rootfs_path, "link_dir/dangling").unwrap();
+        assert_eq!(resolved, root.join("b/c"));
+        let resolved = safe_read_link_resolved(rootfs_path, "a/up").unwrap();
+        assert_eq!(resolved, root.join("a"));
+        let resolved = safe_read_link_resolved(rootfs_path, "host").unwrap();
+        assert_eq!(
+            resolved,
+            root.join(maze.host().strip_prefix("/").unwrap())
+                .join(HOST_FILE)
+        );
+
+        let err = safe_read_link_resolved(rootfs_path, "a/f").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        maze.assert_host_intact();
+    }
+}
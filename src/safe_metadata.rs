@@ -0,0 +1,140 @@
+// Copyright (c) 2022 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::fs::{File, Metadata};
+use std::io::Result;
+use std::path::Path;
+
+use crate::safe_join::open_handle;
+
+/// Safely query the metadata of `unsafe_path` scoped under `root`, following a symlink at the
+/// final component like [std::fs::metadata()].
+///
+/// The path is resolved with the same rules as [crate::safe_open_handle()], then the metadata is
+/// queried by `statx()` or `fstat()` on the pinned fd of the target itself. So it's the metadata
+/// of the resolved inode, which can't be redirected to another file by changing the path after
+/// the resolution, replacing a racy [crate::safe_join()] followed by [std::fs::metadata()].
+/// Escape attempts are scoped under `root` instead of failing, so they usually end up with
+/// `NotFound`.
+///
+/// # Errors
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | `root` or the target doesn't exist, including a dangling symlink | `NotFound` |
+/// | `root` or a path component is not a directory | `NotADirectory` |
+/// | too many levels of symlinks | `FilesystemLoop` |
+/// | the path contains invalid component | `InvalidFilename` |
+pub fn safe_metadata<R: AsRef<Path>, U: AsRef<Path>>(root: R, unsafe_path: U) -> Result<Metadata> {
+    let fd = open_handle(root.as_ref(), unsafe_path.as_ref(), true, false)?;
+    File::from(fd).metadata()
+}
+
+/// Safely query the metadata of `unsafe_path` scoped under `root`, without following a symlink
+/// at the final component like [std::fs::symlink_metadata()].
+///
+/// The same as [safe_metadata()], except that a symlink at the final component is pinned by
+/// `O_PATH | O_NOFOLLOW` and its own metadata is returned.
+///
+/// # Errors
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | `root` or the target doesn't exist | `NotFound` |
+/// | `root` or a path component is not a directory | `NotADirectory` |
+/// | too many levels of symlinks | `FilesystemLoop` |
+/// | the path contains invalid component | `InvalidFilename` |
+pub fn safe_symlink_metadata<R: AsRef<Path>, U: AsRef<Path>>(
+    root: R,
+    unsafe_path: U,
+) -> Result<Metadata> {
+    let fd = open_handle(root.as_ref(), unsafe_path.as_ref(), false, false)?;
+    File::from(fd).metadata()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{Maze, Racer, HOST_FILE};
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn test_safe_metadata() {
+        let maze = Maze::new();
+        maze.file("a/f", "data")
+            .symlink("a/link", "f")
+            .symlink("a/dangling", "missing")
+            .symlink("link_dir", "/a");
+        let rootfs_path = maze.root();
+
+        let meta = safe_metadata(rootfs_path, "link_dir/../a/link").unwrap();
+        assert!(meta.is_file());
+        assert_eq!(meta.len(), 4);
+        assert_eq!(
+            meta.ino(),
+            fs::metadata(rootfs_path.join("a/f")).unwrap().ino()
+        );
+        let meta = safe_symlink_metadata(rootfs_path, "link_dir/f").unwrap();
+        assert!(meta.is_file());
+
+        let meta = safe_metadata(rootfs_path, "link_dir").unwrap();
+        assert!(meta.is_dir());
+        let meta = safe_symlink_metadata(rootfs_path, "link_dir").unwrap();
+        assert!(meta.file_type().is_symlink());
+        let meta = safe_metadata(rootfs_path, "").unwrap();
+        assert_eq!(meta.ino(), fs::metadata(rootfs_path).unwrap().ino());
+
+        let meta = safe_symlink_metadata(rootfs_path, "link_dir/dangling").unwrap();
+        assert!(meta.file_type().is_symlink());
+        let err = safe_metadata(rootfs_path, "link_dir/dangling").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let err = safe_symlink_metadata(rootfs_path, "a/missing").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let err = safe_metadata(rootfs_path, "a/f/x").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+
+        maze.assert_escapes_rejected(|escape| safe_metadata(rootfs_path, escape.join(HOST_FILE)));
+    }
+
+    #[test]
+    fn test_safe_metadata_race() {
+        let maze = Maze::new();
+        maze.file(&format!("real/{}", HOST_FILE), "root")
+            .symlink("d", "real");
+        let rootfs_path = maze.root().to_path_buf();
+        let ino = fs::metadata(rootfs_path.join("real").join(HOST_FILE))
+            .unwrap()
+            .ino();
+        let host_ino = fs::metadata(maze.host().join(HOST_FILE)).unwrap().ino();
+
+        let racer = {
+            let root = rootfs_path.clone();
+            let host = maze.host().to_path_buf();
+            Racer::spawn(move || {
+                Maze::swap_symlink(&root, "d", &host);
+                Maze::swap_symlink(&root, "d", "real");
+            })
+        };
+
+        // The target is either the file in the root or doesn't exist, never the host file.
+        let path = Path::new("d").join(HOST_FILE);
+        for _ in 0..1000 {
+            for result in [
+                safe_metadata(&rootfs_path, &path),
+                safe_symlink_metadata(&rootfs_path, &path),
+            ] {
+                match result {
+                    Ok(meta) => {
+                        assert_eq!(meta.ino(), ino);
+                        assert_ne!(meta.ino(), host_ino);
+                    }
+                    Err(e) => assert_eq!(e.kind(), ErrorKind::NotFound),
+                }
+            }
+        }
+        drop(racer);
+        maze.assert_host_intact();
+    }
+}
@@ -0,0 +1,98 @@
+// Copyright (c) 2022 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::io::Result;
+use std::path::Path;
+
+use crate::walk::ScopedWalk;
+use crate::{sys, SafePathBuf, SafePathError};
+
+/// Safely create a filesystem node, such as a device node or a fifo, at `unsafe_path` scoped
+/// under `root`, and return a pinned handle of the new node.
+///
+/// The parent directory of `unsafe_path` is resolved with the same rules as
+/// [crate::safe_open_handle()], so it never escapes `root`, then the node is created by
+/// `mknodat(2)` relative to the pinned fd of the parent. The final component is never followed,
+/// so an existing symlink at the final component fails the creation instead of redirecting it.
+///
+/// The `mode` specifies both the file type, such as `libc::S_IFCHR`, and the permissions of the
+/// node, and `dev` is the device number for character and block devices, as for `mknod(2)`.
+///
+/// # Errors
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | `root` or the parent directory doesn't exist | `NotFound` |
+/// | `root` or a path component is not a directory | `NotADirectory` |
+/// | the final component already exists, including a symlink | `AlreadyExists` |
+/// | the final component is missing, `.` or `..` | `InvalidFilename` |
+/// | too many levels of symlinks | `FilesystemLoop` |
+/// | the path contains invalid component | `InvalidFilename` |
+///
+/// Errors from `mknodat(2)` are returned as is, for example `PermissionDenied` if the caller is
+/// not privileged to create device nodes.
+pub fn safe_mknod<R: AsRef<Path>, U: AsRef<Path>>(
+    root: R,
+    unsafe_path: U,
+    mode: u32,
+    dev: u64,
+) -> Result<SafePathBuf> {
+    let root = root.as_ref();
+    let unsafe_path = unsafe_path.as_ref();
+    instrument!(
+        "safe_mknod",
+        root,
+        unsafe_path,
+        format_args!("mode={:#o} dev={:#x}", mode, dev),
+        {
+            let name = match (unsafe_path.parent(), unsafe_path.file_name()) {
+                (Some(_), Some(name)) if !unsafe_path.ends_with("..") => name,
+                _ => return Err(SafePathError::invalid_name(unsafe_path).into()),
+            };
+            let mut walk = ScopedWalk::new(root)?;
+            walk.walk(unsafe_path.parent().unwrap(), true, false)?;
+
+            sys::mknodat(walk.fd(), name, mode, dev)?;
+            let fd = sys::openat(walk.fd(), name, libc::O_PATH | libc::O_NOFOLLOW, 0)?;
+            SafePathBuf::from_file(fd.into())
+        }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::os::unix::fs::{symlink, FileTypeExt, MetadataExt};
+
+    #[test]
+    fn test_safe_mknod() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir(rootfs_path.join("dev")).unwrap();
+        symlink("/dev", rootfs_path.join("d")).unwrap();
+        symlink("/etc/passwd", rootfs_path.join("dev/link")).unwrap();
+
+        let path = safe_mknod(rootfs_path, "../d/fifo", libc::S_IFIFO | 0o640, 0).unwrap();
+        assert_eq!(path.target(), rootfs_path.join("dev/fifo"));
+        let meta = fs::symlink_metadata(rootfs_path.join("dev/fifo")).unwrap();
+        assert!(meta.file_type().is_fifo());
+        assert_eq!(meta.mode() & 0o777 & !0o640, 0);
+
+        let err = safe_mknod(rootfs_path, "d/link", libc::S_IFIFO | 0o600, 0).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert!(fs::symlink_metadata(rootfs_path.join("dev/link"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+
+        let err = safe_mknod(rootfs_path, "d/missing/fifo", libc::S_IFIFO, 0).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        for path in ["/", "d/..", ""].iter() {
+            let err = safe_mknod(rootfs_path, path, libc::S_IFIFO, 0).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidFilename);
+        }
+    }
+}
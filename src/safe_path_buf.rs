@@ -3,13 +3,99 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use std::fs::{self, File};
-use std::io::{Error, ErrorKind, Result};
-use std::ops::Deref;
-use std::os::unix::io::AsRawFd;
-use std::path::{Path, PathBuf};
+use std::convert::TryFrom;
+use std::ffi::{OsStr, OsString};
+use std::fs::OpenOptions;
+use std::fs::{self, File, Metadata};
+use std::io::{Error, ErrorKind, Read, Result};
+use std::ops::{BitOr, Deref};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
 
-use crate::{open_by_path, safe_join};
+use crate::safe_join::open_handle;
+use crate::statx::{Statx, StatxMask};
+use crate::walk::ScopedWalk;
+use crate::{open_by_path, sys, SafePathError};
+
+/// An `O_PATH | O_DIRECTORY` file descriptor of a directory, to be used as the `dirfd` argument
+/// of the `*at()` syscalls.
+#[derive(Debug)]
+pub struct DirHandle(OwnedFd);
+
+impl AsRawFd for DirHandle {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl AsFd for DirHandle {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
+
+impl From<DirHandle> for OwnedFd {
+    fn from(handle: DirHandle) -> Self {
+        handle.0
+    }
+}
+
+/// The flags to open a file by [SafePathBuf::open_beneath()], which may be combined by `|`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OpenFlags(libc::c_int);
+
+impl OpenFlags {
+    /// Open for reading only, as `O_RDONLY`.
+    pub const READ: OpenFlags = OpenFlags(libc::O_RDONLY);
+    /// Open for writing only, as `O_WRONLY`.
+    pub const WRITE: OpenFlags = OpenFlags(libc::O_WRONLY);
+    /// Open for reading and writing, as `O_RDWR`.
+    pub const READ_WRITE: OpenFlags = OpenFlags(libc::O_RDWR);
+    /// Append to the end of the file on each write, as `O_APPEND`.
+    pub const APPEND: OpenFlags = OpenFlags(libc::O_APPEND);
+    /// Create the file if it doesn't exist, as `O_CREAT`.
+    pub const CREATE: OpenFlags = OpenFlags(libc::O_CREAT);
+    /// Fail if the file exists, together with [OpenFlags::CREATE], as `O_EXCL`.
+    pub const EXCLUSIVE: OpenFlags = OpenFlags(libc::O_EXCL);
+    /// Truncate the file to length 0, as `O_TRUNC`.
+    pub const TRUNCATE: OpenFlags = OpenFlags(libc::O_TRUNC);
+    /// Fail if the target is not a directory, as `O_DIRECTORY`.
+    pub const DIRECTORY: OpenFlags = OpenFlags(libc::O_DIRECTORY);
+    /// Don't follow a symlink at the final component, as `O_NOFOLLOW`.
+    pub const NOFOLLOW: OpenFlags = OpenFlags(libc::O_NOFOLLOW);
+
+    pub(crate) fn contains(self, other: OpenFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Reject the combinations which don't make sense, like `std::fs::OpenOptions` does.
+    pub(crate) fn validate(self) -> Result<()> {
+        let writable = self.contains(OpenFlags::WRITE) || self.contains(OpenFlags::READ_WRITE);
+        let msg = if (self.contains(OpenFlags::CREATE) || self.contains(OpenFlags::TRUNCATE))
+            && !writable
+        {
+            "creating or truncating a file requires write access"
+        } else if self.contains(OpenFlags::TRUNCATE) && self.contains(OpenFlags::APPEND) {
+            "truncating a file conflicts with appending to it"
+        } else if self.contains(OpenFlags::EXCLUSIVE) && !self.contains(OpenFlags::CREATE) {
+            "exclusive open requires creating the file"
+        } else {
+            return Ok(());
+        };
+        Err(Error::new(ErrorKind::InvalidInput, msg))
+    }
+}
+
+impl BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, rhs: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | rhs.0)
+    }
+}
 
 /// Safe version of `PathBuf` to protect from TOCTOU style of attacks.
 ///
@@ -25,9 +111,18 @@ use crate::{open_by_path, safe_join};
 /// - Compare the symlink target with the safe path, it's safe if these two paths equal.
 /// - Use the symlink target as a safe PathBuf.
 /// - Close the `fd_num` when dropping the `SafePathBuf` object.
+///
+/// When created by [SafePathBuf::new()], the target is opened by [crate::safe_open_handle()]
+/// instead, which pins the target during resolution, so there's no race window to detect at all.
 #[derive(Debug)]
 pub struct SafePathBuf {
+    // Declared before `file` so it's dropped while the fd is still open.
+    drop_check: Option<DropCheck>,
     file: File,
+    // The reopened fd holding the advisory lock, if any.
+    lock: Mutex<Option<File>>,
+    // The metadata read by the stat accessors, taken on first use.
+    metadata: Mutex<Option<Metadata>>,
     path: PathBuf,
     target: PathBuf,
 }
@@ -35,47 +130,657 @@ pub struct SafePathBuf {
 impl SafePathBuf {
     /// Create a `SafePathBuf` from the `root` and an unsafe `path`.
     ///
-    /// The `path` must be a subdirectory of `root`, otherwise error will be returned.
+    /// The `path` is resolved scoped under `root` by [crate::safe_open_handle()], the resolved
+    /// target is pinned during resolution and [SafePathBuf::target()] reports where it is.
+    ///
+    /// # Errors
+    /// | Condition | ErrorKind |
+    /// |-----------|-----------|
+    /// | `root` or the target doesn't exist | `NotFound` |
+    /// | `root` or a path component is not a directory | `NotADirectory` |
+    /// | too many levels of symlinks | `FilesystemLoop` |
+    /// | `path` contains invalid component | `InvalidFilename` |
     pub fn new<R: AsRef<Path>, U: AsRef<Path>>(root: R, path: U) -> Result<Self> {
-        let safe_path = safe_join(root, path)?;
-        Self::from_path(safe_path)
+        instrument!(
+            "SafePathBuf::new",
+            root.as_ref(),
+            path.as_ref(),
+            "follow",
+            {
+                let fd = open_handle(root.as_ref(), path.as_ref(), true, false)?;
+                Self::from_file(fd.into())
+            }
+        )
+    }
+
+    /// Create a `SafePathBuf` from the `root` and an unsafe `path` like [SafePathBuf::new()],
+    /// but without following a symlink at the final component.
+    ///
+    /// The intermediate components are resolved scoped under `root`, then the final component is
+    /// opened with `O_NOFOLLOW`, so a final symlink is pinned itself instead of its target. This
+    /// is useful to validate a path before replacing it, as a symlink squatting at the final
+    /// component is detected instead of being followed.
+    ///
+    /// # Errors
+    /// The same as [SafePathBuf::new()].
+    pub fn new_nofollow<R: AsRef<Path>, U: AsRef<Path>>(root: R, path: U) -> Result<Self> {
+        instrument!(
+            "SafePathBuf::new_nofollow",
+            root.as_ref(),
+            path.as_ref(),
+            "nofollow",
+            {
+                let fd = open_handle(root.as_ref(), path.as_ref(), false, false)?;
+                Self::from_file(fd.into())
+            }
+        )
+    }
+
+    /// Create a `SafePathBuf` from the `root` and an unsafe `path` like [SafePathBuf::new()],
+    /// but reject a regular file with more than one hard link.
+    ///
+    /// A hardlinked file in an untrusted rootfs may alias an inode which is also reachable from
+    /// the host, such as a file linked into the rootfs before it's handed over, so writing to it
+    /// through the rootfs modifies the host file as well. The link count is checked by `fstat()`
+    /// on the pinned fd, see [SafePathBuf::hardlink_count()]. Other file types are accepted as
+    /// is, as directories legitimately have a link count above one, one for each subdirectory.
+    ///
+    /// # Errors
+    /// The same as [SafePathBuf::new()], plus:
+    ///
+    /// | Condition | ErrorKind |
+    /// |-----------|-----------|
+    /// | the target is a regular file with several links | `PermissionDenied`, with [SafePathError::HardlinkedFile] |
+    pub fn new_reject_hardlinks<R: AsRef<Path>, U: AsRef<Path>>(root: R, path: U) -> Result<Self> {
+        instrument!(
+            "SafePathBuf::new_reject_hardlinks",
+            root.as_ref(),
+            path.as_ref(),
+            "follow",
+            {
+                let fd = open_handle(root.as_ref(), path.as_ref(), true, false)?;
+                let safe_path = Self::from_file(fd.into())?;
+                let meta = safe_path.stat()?;
+                if meta.is_file() && meta.nlink() > 1 {
+                    return Err(SafePathError::HardlinkedFile {
+                        path: safe_path.target.clone(),
+                        nlink: meta.nlink(),
+                    }
+                    .into());
+                }
+                Ok(safe_path)
+            }
+        )
+    }
+
+    /// Create a `SafePathBuf` from the `root` and the `segments` joined in order, like
+    /// [SafePathBuf::new()] on the joined path.
+    ///
+    /// It's for paths built from known segments, such as `["var", "lib", name]`. Each segment is
+    /// checked on its own before joining, and a segment which is absolute or has a ".."
+    /// component is rejected, so an escape attempt is reported at the offending segment instead
+    /// of being silently scoped under `root`. Symlinks met during the resolution are still
+    /// expanded scoped under `root`.
+    ///
+    /// # Errors
+    /// The same as [SafePathBuf::new()], plus:
+    ///
+    /// | Condition | ErrorKind |
+    /// |-----------|-----------|
+    /// | a segment is empty, absolute, or has a ".." component | `InvalidFilename`, with [SafePathError::InvalidComponent] |
+    pub fn join_segments<R: AsRef<Path>>(root: R, segments: &[&OsStr]) -> Result<Self> {
+        let mut path = PathBuf::new();
+        for segment in segments {
+            let segment = Path::new(segment);
+            if segment.as_os_str().is_empty()
+                || segment.is_absolute()
+                || segment.components().any(|c| c == Component::ParentDir)
+            {
+                return Err(SafePathError::invalid_name(segment).into());
+            }
+            path.push(segment);
+        }
+        Self::new(root, path)
+    }
+
+    /// Create a `SafePathBuf` of a directory from the `root` and an unsafe `path` like
+    /// [SafePathBuf::new()].
+    ///
+    /// The target is opened with `O_DIRECTORY`, so it's guaranteed to be a directory by the
+    /// kernel at the time it's pinned, instead of checking the type of a pinned target
+    /// afterwards.
+    ///
+    /// # Errors
+    /// The same as [SafePathBuf::new()], plus `NotADirectory` if the target is not a directory.
+    pub fn new_dir<R: AsRef<Path>, U: AsRef<Path>>(root: R, path: U) -> Result<Self> {
+        instrument!(
+            "SafePathBuf::new_dir",
+            root.as_ref(),
+            path.as_ref(),
+            "directory",
+            {
+                let fd = open_handle(root.as_ref(), path.as_ref(), true, true)?;
+                Self::from_file(fd.into())
+            }
+        )
     }
 
     /// Create a `SafePathBuf` from an path.
     ///
     /// If the resolved value of `path` doesn't equal to `path`, an error will be returned.
+    ///
+    /// # Errors
+    /// | Condition | ErrorKind |
+    /// |-----------|-----------|
+    /// | `path` doesn't exist | `NotFound` |
+    /// | the target changes underneath | `Other`, with [SafePathError::TargetChanged] |
     pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let file = open_by_path(path.as_ref())?;
+        instrument!(
+            "SafePathBuf::from_path",
+            Path::new("/"),
+            path.as_ref(),
+            "verify",
+            {
+                let file = open_by_path(path.as_ref())?;
+                let safe_path = Self::from_file(file)?;
+
+                if safe_path.target() != path.as_ref() {
+                    Err(SafePathError::TargetChanged {
+                        expected: path.as_ref().to_path_buf(),
+                        actual: safe_path.target,
+                    }
+                    .into())
+                } else {
+                    Ok(safe_path)
+                }
+            }
+        )
+    }
+
+    /// Create a `SafePathBuf` from an opened file, which has already been pinned to the target.
+    pub(crate) fn from_file(file: File) -> Result<Self> {
         let proc_path = format!("/proc/self/fd/{}", file.as_raw_fd());
-        let link_path = fs::read_link(&proc_path)?;
-
-        if link_path.as_path() != path.as_ref() {
-            Err(Error::new(
-                ErrorKind::Other,
-                format!(
-                    "The target path changes from {} to {} underneath, possible under attacking!!!",
-                    path.as_ref().display(),
-                    link_path.display()
-                ),
-            ))
-        } else {
-            Ok(SafePathBuf {
-                file,
-                path: PathBuf::from(proc_path),
-                target: link_path,
-            })
+        let target = fs::read_link(&proc_path)?;
+
+        Ok(SafePathBuf {
+            drop_check: None,
+            file,
+            lock: Mutex::new(None),
+            metadata: Mutex::new(None),
+            path: PathBuf::from(proc_path),
+            target,
+        })
+    }
+
+    /// Enable checking whether the target changed during the lifetime of the handle when it's
+    /// dropped.
+    ///
+    /// This is a debugging aid for TOCTOU issues: on drop, `/proc/self/fd/{fd}` is read again,
+    /// and a warning is emitted through the `log` or `tracing` crate, if the corresponding
+    /// feature is enabled, when it no longer matches [SafePathBuf::target()], such as when the
+    /// target was moved or removed from under the live handle. The check is a no-op in release
+    /// builds, where `debug_assertions` are disabled.
+    pub fn with_drop_check(mut self) -> Self {
+        if cfg!(debug_assertions) {
+            self.drop_check = Some(DropCheck {
+                fd: self.file.as_raw_fd(),
+                target: self.target.clone(),
+            });
         }
+        self
+    }
+
+    /// Get the pinned `O_PATH` file.
+    pub(crate) fn as_file(&self) -> &File {
+        &self.file
+    }
+
+    /// Consume the `SafePathBuf` and get the pinned `O_PATH` file.
+    pub(crate) fn into_file(self) -> File {
+        self.file
     }
 
     /// Get the real target path.
+    ///
+    /// A `SafePathBuf` compares equal to a `Path` or `PathBuf` by this target, not by the fd path
+    /// it dereferences to.
     pub fn target(&self) -> &Path {
         &self.target
     }
 
+    /// Get the target path relative to `root`, such as the path seen inside a container whose
+    /// rootfs is `root`.
+    ///
+    /// The target is always canonical, so `root` is canonicalized if the target isn't under it
+    /// as is. `None` is returned if the target isn't under `root`, or if `root` can't be
+    /// canonicalized.
+    pub fn relative_to_root<R: AsRef<Path>>(&self, root: R) -> Option<PathBuf> {
+        let root = root.as_ref();
+        match self.target.strip_prefix(root) {
+            Ok(path) => Some(path.to_path_buf()),
+            Err(_) => {
+                let root = root.canonicalize().ok()?;
+                self.target.strip_prefix(root).ok().map(Path::to_path_buf)
+            }
+        }
+    }
+
     /// Check whether the target path is a directory.
     pub fn is_dir(&self) -> bool {
         self.target.is_dir()
     }
+
+    /// Get an iterator over the ancestors of the target, from its parent directory up to and
+    /// including `root`.
+    ///
+    /// Each ancestor is opened from the previous one by [SafePathBuf::open_parent_dir()], so a
+    /// directory by `openat(fd, "..")` on its pinned fd, and verified to still contain it, then
+    /// yielded as an independently pinned handle. So they are the real parents of the pinned
+    /// target, even if it has been moved. An ancestor which can't be opened or verified
+    /// yields an error and ends the iteration, as does reaching `/` if the target isn't under
+    /// `root`, with [SafePathError::OutsideRoot].
+    pub fn ancestors<R: AsRef<Path>>(
+        &self,
+        root: R,
+    ) -> impl Iterator<Item = Result<SafePathBuf>> + '_ {
+        let root = root.as_ref().to_path_buf();
+        let mut current: Option<SafePathBuf> = None;
+        let mut done = false;
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let next = (|| {
+                // Start from the current path of the target, wherever it has been moved to.
+                let child = match &current {
+                    Some(child) => child,
+                    None => current.insert(SafePathBuf::from_file(self.file.try_clone()?)?),
+                };
+                let root_st = fs::metadata(&root)?;
+                let st = sys::fstat(&child.file)?;
+                if (st.st_dev, st.st_ino) == (root_st.dev(), root_st.ino()) {
+                    return Ok(None);
+                }
+                let (dir, _) = child.open_parent_dir().map_err(|e| {
+                    match SafePathError::from_io_error(&e) {
+                        Some(SafePathError::InvalidComponent { .. }) => {
+                            SafePathError::OutsideRoot {
+                                path: self.target.clone(),
+                                root: root.clone(),
+                            }
+                            .into()
+                        }
+                        _ => e,
+                    }
+                })?;
+                let parent = SafePathBuf::from_file(File::from(dir.0.try_clone()?))?;
+                Ok(Some((parent, SafePathBuf::from_file(File::from(dir.0))?)))
+            })();
+            match next {
+                Ok(Some((parent, next))) => {
+                    current = Some(next);
+                    Some(Ok(parent))
+                }
+                Ok(None) => {
+                    done = true;
+                    None
+                }
+                Err(e) => {
+                    done = true;
+                    Some(Err(e))
+                }
+            }
+        })
+    }
+
+    /// Open the parent directory of the target, and get the file name of the target in it.
+    ///
+    /// If the target is a directory, its parent is opened by `openat(fd, "..")` on the pinned fd,
+    /// otherwise by the path of the parent. Either way, it's then verified by `fstatat()` that the
+    /// file name still refers to the pinned target in it, so `*at()` syscalls such as
+    /// `unlinkat()` or `renameat()` may act on the exact pinned parent.
+    ///
+    /// # Errors
+    /// | Condition | ErrorKind |
+    /// |-----------|-----------|
+    /// | the target is `/` | `InvalidFilename` |
+    /// | the target has been moved away or replaced | `Other`, with [SafePathError::IdentityMismatch] |
+    pub fn open_parent_dir(&self) -> Result<(DirHandle, OsString)> {
+        let (parent, name) = match (self.target.parent(), self.target.file_name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => return Err(SafePathError::invalid_name(&self.target).into()),
+        };
+        let expected = sys::fstat(&self.file)?;
+        let flags = libc::O_PATH | libc::O_DIRECTORY;
+        // A directory knows its real parent, wherever its path has been moved to.
+        let dir = if sys::is_dir(&expected) {
+            sys::openat(&self.file, OsStr::new(".."), flags, 0)?
+        } else {
+            sys::openat(&sys::CurrentDir, parent.as_os_str(), flags, 0)?
+        };
+
+        let actual = sys::fstatat_nofollow(&dir, name)?;
+        if (expected.st_dev, expected.st_ino) != (actual.st_dev, actual.st_ino) {
+            return Err(SafePathError::IdentityMismatch {
+                path: self.target.clone(),
+                expected: (expected.st_dev, expected.st_ino),
+                actual: (actual.st_dev, actual.st_ino),
+            }
+            .into());
+        }
+
+        Ok((DirHandle(dir), name.to_os_string()))
+    }
+
+    /// Get metadata of the pinned target by a single `fstat()` on the file descriptor.
+    ///
+    /// It also refreshes the snapshot read by the stat accessors, such as
+    /// [SafePathBuf::hardlink_count()].
+    pub fn stat(&self) -> Result<Metadata> {
+        let metadata = self.file.metadata()?;
+        *self.metadata.lock().unwrap() = Some(metadata.clone());
+        Ok(metadata)
+    }
+
+    /// Read a field of the metadata snapshot, taking it by `fstat()` if there's none yet.
+    fn snapshot<T, F: FnOnce(&Metadata) -> T>(&self, field: F) -> Result<T> {
+        let mut metadata = self.metadata.lock().unwrap();
+        if metadata.is_none() {
+            *metadata = Some(self.file.metadata()?);
+        }
+        Ok(field(metadata.as_ref().unwrap()))
+    }
+
+    /// Get extended metadata of the pinned target by `statx(fd, "", AT_EMPTY_PATH, mask)`,
+    /// such as the creation time and the mount id which `fstat()` lacks.
+    ///
+    /// Fields outside `mask` may still be provided if they're cheap for the filesystem, and
+    /// fields in `mask` may be missing if the filesystem or the kernel doesn't support them,
+    /// check [Statx::mask()] or the `Option` of each field. On kernels without `statx(2)`, only
+    /// the basic fields are provided by `fstat()`.
+    pub fn statx(&self, mask: StatxMask) -> Result<Statx> {
+        Statx::of(&self.file, mask)
+    }
+
+    /// Get metadata of the child `name` of the pinned target directory, without following a
+    /// symlink at `name`.
+    ///
+    /// It's a cheap probe for walk logic to decide whether to descend into a child, without
+    /// opening a new `SafePathBuf` for it. The child is opened by `openat()` with `O_PATH` and
+    /// `O_NOFOLLOW` relative to the pinned fd and then `fstat()`-ed, so it's always the child of
+    /// the pinned directory even if the target path has been changed.
+    ///
+    /// # Errors
+    /// | Condition | ErrorKind |
+    /// |-----------|-----------|
+    /// | `name` is not a single normal component | `InvalidFilename` |
+    /// | the pinned target is not a directory | `NotADirectory` |
+    /// | the child doesn't exist | `NotFound` |
+    pub fn stat_child<N: AsRef<OsStr>>(&self, name: N) -> Result<Metadata> {
+        let name = name.as_ref();
+        if name.is_empty() || name == "." || name == ".." || name.as_bytes().contains(&b'/') {
+            return Err(SafePathError::invalid_name(name).into());
+        }
+        let fd = sys::openat(&self.file, name, libc::O_PATH | libc::O_NOFOLLOW, 0)?;
+        File::from(fd).metadata()
+    }
+
+    /// Open `rel` beneath the pinned target directory with `flags`, and get the opened file.
+    ///
+    /// `rel` is resolved relative to the pinned fd as if the directory were the root, so `..`
+    /// and absolute symlinks can't escape it, even if the target path has been changed. An
+    /// existing file is reopened through the `/proc/self/fd/` magic link of the pinned fd of
+    /// it, and a missing final component is created by `openat()` with `O_NOFOLLOW` relative to
+    /// the pinned fd of its parent, if [OpenFlags::CREATE] is given. A new file is created with
+    /// mode `0o666` masked by the umask.
+    ///
+    /// # Errors
+    /// | Condition | ErrorKind |
+    /// |-----------|-----------|
+    /// | the pinned target is not a directory | `NotADirectory` |
+    /// | `rel` doesn't exist, or its parent doesn't exist with [OpenFlags::CREATE] | `NotFound` |
+    /// | `rel` exists with [OpenFlags::CREATE] and [OpenFlags::EXCLUSIVE] | `AlreadyExists` |
+    /// | too many levels of symlinks | `FilesystemLoop` |
+    /// | `rel` contains invalid component | `InvalidFilename` |
+    pub fn open_beneath<P: AsRef<Path>>(&self, rel: P, flags: OpenFlags) -> Result<File> {
+        if !sys::is_dir(&sys::fstat(&self.file)?) {
+            return Err(SafePathError::NotADirectory {
+                path: self.target.clone(),
+            }
+            .into());
+        }
+
+        let create = flags.contains(OpenFlags::CREATE);
+        let mut walk = ScopedWalk::from_fd(self.target.clone(), self.file.try_clone()?.into());
+        walk.walk(rel.as_ref(), !flags.contains(OpenFlags::NOFOLLOW), create)?;
+
+        let fd = match walk.take_missing().as_slice() {
+            [] if create && flags.contains(OpenFlags::EXCLUSIVE) => {
+                return Err(std::io::Error::from_raw_os_error(libc::EEXIST))
+            }
+            [] => {
+                let proc_path = format!("/proc/self/fd/{}", walk.fd().as_raw_fd());
+                // The magic link must be followed, a pinned symlink still fails with ELOOP.
+                let flags = flags.0 & !(libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW);
+                sys::openat(&sys::CurrentDir, OsStr::new(&proc_path), flags, 0)?
+            }
+            [name] => sys::openat(walk.fd(), name, flags.0 | libc::O_NOFOLLOW, 0o666)?,
+            _ => return Err(std::io::Error::from_raw_os_error(libc::ENOENT)),
+        };
+
+        Ok(File::from(fd))
+    }
+
+    /// Read the whole content of the pinned target file into a `String`.
+    ///
+    /// The `O_PATH` fd can't be read, so the pinned target is reopened read-only through its
+    /// `/proc/self/fd/` magic link, which always refers to the exact pinned inode, and the
+    /// reopened fd is closed before returning.
+    ///
+    /// # Errors
+    /// | Condition | ErrorKind |
+    /// |-----------|-----------|
+    /// | the pinned target is a directory | `IsADirectory` |
+    /// | the pinned target is a symlink | `FilesystemLoop` |
+    /// | the content is not valid UTF-8 | `InvalidData`, with [SafePathError::InvalidUtf8] |
+    pub fn read_to_string(&self) -> Result<String> {
+        if sys::is_dir(&sys::fstat(&self.file)?) {
+            return Err(std::io::Error::from_raw_os_error(libc::EISDIR));
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOCTTY | libc::O_CLOEXEC)
+            .open(&self.path)?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+
+        String::from_utf8(buf).map_err(|e| {
+            SafePathError::InvalidUtf8 {
+                path: self.target.clone(),
+                valid_up_to: e.utf8_error().valid_up_to(),
+            }
+            .into()
+        })
+    }
+
+    /// Check whether the pinned target is the inode identified by `dev` and `ino`, such as the
+    /// values recorded from [SafePathBuf::stat()] when the path was validated earlier.
+    ///
+    /// It's a cheap re-check by `fstat()` on the pinned fd for long-lived handles, comparing the
+    /// inode identity instead of the path name.
+    ///
+    /// # Errors
+    /// | Condition | ErrorKind |
+    /// |-----------|-----------|
+    /// | the target is another inode | `Other`, with [SafePathError::IdentityMismatch] |
+    pub fn assert_identity(&self, dev: u64, ino: u64) -> Result<()> {
+        let meta = self.stat()?;
+        if (meta.dev(), meta.ino()) != (dev, ino) {
+            return Err(SafePathError::IdentityMismatch {
+                path: self.target.clone(),
+                expected: (dev, ino),
+                actual: (meta.dev(), meta.ino()),
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Change the owner and group of the pinned target, `None` leaves the id unchanged.
+    ///
+    /// It's done by `fchownat(fd, "", uid, gid, AT_EMPTY_PATH)` on the pinned fd, so the change
+    /// can't be redirected to another inode by swapping a path component with a symlink. A
+    /// pinned symlink is changed itself instead of its target.
+    pub fn chown(&self, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+        // -1 leaves the id unchanged.
+        sys::fchown(&self.file, uid.unwrap_or(u32::MAX), gid.unwrap_or(u32::MAX))?;
+        *self.metadata.lock().unwrap() = None;
+        Ok(())
+    }
+
+    /// Acquire an exclusive advisory lock on the pinned target by `flock(2)`, blocking until it's
+    /// available.
+    ///
+    /// `flock()` fails on `O_PATH` fds, so the pinned target is reopened through its
+    /// `/proc/self/fd/` magic link, which always refers to the exact pinned inode, and the lock
+    /// is held by the reopened fd. So the lock is tied to the validated inode even if its name
+    /// is changed later. Calling it again converts the lock held by this `SafePathBuf`, and the
+    /// lock is released by [SafePathBuf::unlock()] or when the `SafePathBuf` is dropped.
+    ///
+    /// # Errors
+    /// Errors from reopening the target are returned as is, for example `PermissionDenied` if
+    /// it's not readable, or `FilesystemLoop` if the pinned target is a symlink.
+    pub fn lock_exclusive(&self) -> Result<()> {
+        self.flock(libc::LOCK_EX)
+    }
+
+    /// Acquire a shared advisory lock on the pinned target by `flock(2)`, blocking until it's
+    /// available.
+    ///
+    /// It's the same as [SafePathBuf::lock_exclusive()] except for the type of the lock.
+    ///
+    /// # Errors
+    /// The same as [SafePathBuf::lock_exclusive()].
+    pub fn lock_shared(&self) -> Result<()> {
+        self.flock(libc::LOCK_SH)
+    }
+
+    /// Release the advisory lock held by this `SafePathBuf`, if any.
+    pub fn unlock(&self) -> Result<()> {
+        let mut lock = self.lock.lock().unwrap();
+        match lock.take() {
+            Some(file) => sys::flock(&file, libc::LOCK_UN),
+            None => Ok(()),
+        }
+    }
+
+    fn flock(&self, operation: libc::c_int) -> Result<()> {
+        let mut lock = self.lock.lock().unwrap();
+        if let Some(file) = lock.as_ref() {
+            return sys::flock(file, operation);
+        }
+        // O_NONBLOCK avoids blocking on opening a fifo, it doesn't affect flock().
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_NOCTTY | libc::O_CLOEXEC)
+            .open(&self.path)?;
+        sys::flock(&file, operation)?;
+        *lock = Some(file);
+
+        Ok(())
+    }
+
+    /// Convert the pinned target directory into a [cap_std::fs::Dir], to keep operating on it
+    /// with the capability-based API of `cap-std`.
+    ///
+    /// The `O_PATH` fd can't be used to list the directory, so the pinned directory is reopened
+    /// as "." relative to it, which always refers to the exact pinned inode, and the reopened fd
+    /// is transferred into the `Dir`.
+    ///
+    /// # Errors
+    /// | Condition | ErrorKind |
+    /// |-----------|-----------|
+    /// | the pinned target is not a directory | `NotADirectory` |
+    #[cfg(feature = "cap-std")]
+    pub fn into_cap_std_dir(self) -> Result<cap_std::fs::Dir> {
+        let fd = sys::openat(
+            &self.file,
+            OsStr::new("."),
+            libc::O_RDONLY | libc::O_DIRECTORY,
+            0,
+        )
+        .map_err(|e| match e.raw_os_error() {
+            Some(libc::ENOTDIR) => SafePathError::NotADirectory {
+                path: self.target.clone(),
+            }
+            .into(),
+            _ => e,
+        })?;
+
+        Ok(cap_std::fs::Dir::from_std_file(File::from(fd)))
+    }
+
+    /// Get the number of hard links to the pinned target.
+    ///
+    /// This and the following accessors read the same snapshot of the metadata, taken by
+    /// `fstat()` on the first use and refreshed by [SafePathBuf::stat()], so several fields read
+    /// together are consistent with each other. Changes made through other handles or paths are
+    /// only seen after a refresh.
+    ///
+    /// A regular file with more than one link may alias an inode reachable from elsewhere, see
+    /// [SafePathBuf::new_reject_hardlinks()]. Directories legitimately have more than one link,
+    /// one from their parent, one from their own "." and one from ".." of each subdirectory.
+    pub fn hardlink_count(&self) -> Result<u64> {
+        self.snapshot(|m| m.nlink())
+    }
+
+    /// Get the size in bytes of the pinned target.
+    pub fn size(&self) -> Result<u64> {
+        self.snapshot(|m| m.size())
+    }
+
+    /// Get the user ID of the owner of the pinned target.
+    pub fn uid(&self) -> Result<u32> {
+        self.snapshot(|m| m.uid())
+    }
+
+    /// Get the group ID of the owner of the pinned target.
+    pub fn gid(&self) -> Result<u32> {
+        self.snapshot(|m| m.gid())
+    }
+}
+
+/// Check on drop whether the fd still links to the originally pinned target.
+#[derive(Debug)]
+struct DropCheck {
+    fd: RawFd,
+    target: PathBuf,
+}
+
+impl DropCheck {
+    /// Get the current target if it's different from the pinned one.
+    fn changed(&self) -> Option<PathBuf> {
+        let current = fs::read_link(format!("/proc/self/fd/{}", self.fd)).ok()?;
+        if current != self.target {
+            Some(current)
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for DropCheck {
+    fn drop(&mut self) {
+        #[allow(unused_variables)]
+        if let Some(current) = self.changed() {
+            warning!(
+                "target of pinned path changed from {} to {} during its lifetime",
+                self.target.display(),
+                current.display()
+            );
+        }
+    }
 }
 
 impl Deref for SafePathBuf {
@@ -92,9 +797,52 @@ impl AsRef<Path> for SafePathBuf {
     }
 }
 
+/// Compare the resolved target with a path, that is [SafePathBuf::target()] instead of the
+/// `/proc/self/fd/N` path the `SafePathBuf` dereferences to.
+impl PartialEq<Path> for SafePathBuf {
+    fn eq(&self, other: &Path) -> bool {
+        self.target == other
+    }
+}
+
+/// Compare the resolved target with a path, the same as `PartialEq<Path>`.
+impl PartialEq<PathBuf> for SafePathBuf {
+    fn eq(&self, other: &PathBuf) -> bool {
+        self.target == *other
+    }
+}
+
+/// Compare the resolved target with a path, the same as `PartialEq<Path>`.
+impl PartialEq<&Path> for SafePathBuf {
+    fn eq(&self, other: &&Path) -> bool {
+        self.target == *other
+    }
+}
+
+/// Resolve `(root, unsafe_path)` by [SafePathBuf::new()].
+impl TryFrom<(&Path, &Path)> for SafePathBuf {
+    type Error = std::io::Error;
+
+    fn try_from((root, path): (&Path, &Path)) -> Result<Self> {
+        SafePathBuf::new(root, path)
+    }
+}
+
+/// Pin an absolute trusted path by [SafePathBuf::from_path()].
+impl TryFrom<&Path> for SafePathBuf {
+    type Error = std::io::Error;
+
+    fn try_from(path: &Path) -> Result<Self> {
+        SafePathBuf::from_path(path)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::safe_join;
+    use crate::test_util::{for_each_backend, Maze, Racer, HOST_FILE};
+    use std::io::ErrorKind;
     use std::os::unix::fs::symlink;
     use std::sync::{Arc, Barrier};
     use std::thread;
@@ -120,6 +868,484 @@ mod tests {
         assert_eq!(&content, "test");
     }
 
+    #[test]
+    fn test_safe_path_buf_try_from() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir(rootfs_path.join("a")).unwrap();
+        symlink("/a", rootfs_path.join("s")).unwrap();
+
+        let path = SafePathBuf::try_from((rootfs_path, Path::new("s"))).unwrap();
+        assert_eq!(path, rootfs_path.join("a").canonicalize().unwrap());
+        let path = SafePathBuf::try_from(rootfs_path.join("a").as_path()).unwrap();
+        assert_eq!(path, rootfs_path.join("a").canonicalize().unwrap());
+
+        let err = SafePathBuf::try_from((rootfs_path, Path::new("s/missing"))).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let err = SafePathBuf::try_from(rootfs_path.join("missing").as_path()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_safe_path_buf_new_dir() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir(rootfs_path.join("a")).unwrap();
+        fs::write(rootfs_path.join("a/f"), "f").unwrap();
+        symlink("/a", rootfs_path.join("s")).unwrap();
+        symlink("/a/f", rootfs_path.join("sf")).unwrap();
+
+        for_each_backend(|backend| {
+            let path = SafePathBuf::new_dir(rootfs_path, "s").unwrap();
+            assert_eq!(path, rootfs_path.join("a").canonicalize().unwrap());
+            for file in ["a/f", "sf"].iter() {
+                let err = SafePathBuf::new_dir(rootfs_path, file).unwrap_err();
+                assert_eq!(err.kind(), ErrorKind::NotADirectory, "{:?}", backend);
+            }
+        });
+    }
+
+    #[test]
+    fn test_safe_path_buf_open_beneath() {
+        use std::io::{Read, Write};
+
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir_all(rootfs_path.join("a/b")).unwrap();
+        fs::write(rootfs_path.join("f"), "outside").unwrap();
+        fs::write(rootfs_path.join("a/f"), "inside").unwrap();
+        symlink("/f", rootfs_path.join("a/abs")).unwrap();
+        symlink("../../f", rootfs_path.join("a/b/rel")).unwrap();
+        symlink("/new", rootfs_path.join("a/dangling")).unwrap();
+
+        let dir = SafePathBuf::new(rootfs_path, "a").unwrap();
+        for rel in ["f", "abs", "b/rel", "../f", "/b/../f"].iter() {
+            let mut content = String::new();
+            let mut file = dir.open_beneath(rel, OpenFlags::READ).unwrap();
+            file.read_to_string(&mut content).unwrap();
+            assert_eq!(content, "inside", "{}", rel);
+        }
+        let err = dir.open_beneath("abs", OpenFlags::READ | OpenFlags::NOFOLLOW);
+        assert_eq!(err.unwrap_err().raw_os_error(), Some(libc::ELOOP));
+        let err = dir.open_beneath("missing", OpenFlags::READ).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        // A dangling symlink is created beneath the directory.
+        let flags = OpenFlags::WRITE | OpenFlags::CREATE;
+        let mut file = dir.open_beneath("dangling", flags).unwrap();
+        file.write_all(b"new").unwrap();
+        assert_eq!(fs::read(rootfs_path.join("a/new")).unwrap(), b"new");
+        assert!(!rootfs_path.join("new").exists());
+        let mut file = dir
+            .open_beneath("new", flags | OpenFlags::TRUNCATE)
+            .unwrap();
+        file.write_all(b"n").unwrap();
+        assert_eq!(fs::read(rootfs_path.join("a/new")).unwrap(), b"n");
+        let err = dir
+            .open_beneath("new", flags | OpenFlags::EXCLUSIVE)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        let err = dir.open_beneath("missing/new", flags).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        let file = dir.open_beneath("", OpenFlags::READ | OpenFlags::DIRECTORY);
+        assert!(file.unwrap().metadata().unwrap().is_dir());
+        let err = dir.open_beneath("f", OpenFlags::READ | OpenFlags::DIRECTORY);
+        assert_eq!(err.unwrap_err().kind(), ErrorKind::NotADirectory);
+
+        let file = SafePathBuf::new(rootfs_path, "a/f").unwrap();
+        let err = file.open_beneath("", OpenFlags::READ).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+    }
+
+    #[test]
+    fn test_safe_path_buf_new_nofollow() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir(rootfs_path.join("a")).unwrap();
+        fs::write(rootfs_path.join("a/b"), "b").unwrap();
+        symlink("/a", rootfs_path.join("s")).unwrap();
+        symlink("../../a/b", rootfs_path.join("a/l")).unwrap();
+
+        for_each_backend(|backend| {
+            // Intermediate symlinks are still followed.
+            let path = SafePathBuf::new_nofollow(rootfs_path, "../s/l").unwrap();
+            assert_eq!(path.target(), rootfs_path.join("a/l"), "{:?}", backend);
+            assert!(path.stat().unwrap().file_type().is_symlink());
+            let path = SafePathBuf::new_nofollow(rootfs_path, "s").unwrap();
+            assert_eq!(path.target(), rootfs_path.join("s"));
+            let path = SafePathBuf::new_nofollow(rootfs_path, "s/..").unwrap();
+            assert_eq!(path.target(), rootfs_path);
+            let path = SafePathBuf::new_nofollow(rootfs_path, "s/b").unwrap();
+            assert_eq!(path.target(), rootfs_path.join("a/b"));
+            let path = SafePathBuf::new(rootfs_path, "s/l").unwrap();
+            assert_eq!(path.target(), rootfs_path.join("a/b"));
+        });
+    }
+
+    #[test]
+    fn test_safe_path_buf_assert_identity() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::write(rootfs_path.join("a"), "a").unwrap();
+
+        let path = SafePathBuf::new(rootfs_path, "a").unwrap();
+        let meta = fs::metadata(rootfs_path.join("a")).unwrap();
+        path.assert_identity(meta.dev(), meta.ino()).unwrap();
+
+        // Replace the file underneath, the handle is still pinned to the original inode.
+        fs::remove_file(rootfs_path.join("a")).unwrap();
+        fs::write(rootfs_path.join("a"), "b").unwrap();
+        let meta = fs::metadata(rootfs_path.join("a")).unwrap();
+        let err = path.assert_identity(meta.dev(), meta.ino()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Other);
+        assert!(matches!(
+            SafePathError::from_io_error(&err),
+            Some(SafePathError::IdentityMismatch { .. })
+        ));
+        let path = SafePathBuf::new(rootfs_path, "a").unwrap();
+        path.assert_identity(meta.dev(), meta.ino()).unwrap();
+    }
+
+    #[test]
+    fn test_safe_path_buf_eq_path() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir(rootfs_path.join("a")).unwrap();
+        symlink("/a", rootfs_path.join("s")).unwrap();
+
+        let path = SafePathBuf::new(rootfs_path, "s").unwrap();
+        assert_eq!(path, rootfs_path.join("a"));
+        assert_eq!(path, rootfs_path.join("a").as_path());
+        assert!(path == *rootfs_path.join("a").as_path());
+        assert_ne!(path, rootfs_path.join("s"));
+        // Never the fd path.
+        assert_ne!(path, PathBuf::from(path.as_os_str()));
+    }
+
+    #[test]
+    fn test_safe_path_buf_statx() {
+        use crate::StatxMask;
+        use std::time::SystemTime;
+
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::write(rootfs_path.join("a"), "test").unwrap();
+        symlink("a", rootfs_path.join("s")).unwrap();
+
+        let path = SafePathBuf::new(rootfs_path, "s").unwrap();
+        let meta = path.stat().unwrap();
+        let stx = path.statx(StatxMask::ALL).unwrap();
+        assert!(stx.mask().contains(StatxMask::BASIC_STATS));
+        assert_eq!(stx.dev(), meta.dev());
+        assert_eq!(stx.ino(), Some(meta.ino()));
+        assert_eq!(stx.mode(), Some(meta.mode()));
+        assert_eq!(stx.size(), Some(4));
+        assert_eq!(stx.nlink(), Some(1));
+        assert_eq!(stx.modified(), Some(meta.modified().unwrap()));
+        if let Some(btime) = stx.created() {
+            assert!(btime <= SystemTime::now());
+        }
+
+        // Only the file type.
+        let stx = path.statx(StatxMask::TYPE).unwrap();
+        if !stx.mask().contains(StatxMask::MODE) {
+            assert_eq!(stx.mode(), Some(libc::S_IFREG));
+        }
+
+        // The pinned symlink itself.
+        let link = SafePathBuf::new_nofollow(rootfs_path, "s").unwrap();
+        let stx = link.statx(StatxMask::TYPE | StatxMask::SIZE).unwrap();
+        assert_eq!(stx.mode().unwrap() & libc::S_IFMT, libc::S_IFLNK);
+        assert_eq!(stx.size(), Some(1));
+
+        // A file on another mount has another mount id.
+        if Path::new("/proc/self").exists() {
+            let proc = SafePathBuf::new("/proc", "version").unwrap();
+            let mnt_id = proc.statx(StatxMask::MNT_ID).unwrap().mount_id();
+            let dir = SafePathBuf::new(rootfs_path, "").unwrap();
+            let dir_mnt_id = dir.statx(StatxMask::MNT_ID).unwrap().mount_id();
+            if let (Some(a), Some(b)) = (mnt_id, dir_mnt_id) {
+                assert_ne!(a, b);
+                let file_mnt_id = path.statx(StatxMask::MNT_ID).unwrap().mount_id();
+                assert_eq!(file_mnt_id, Some(b));
+            }
+        }
+    }
+
+    #[test]
+    fn test_safe_path_buf_stat_child() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir_all(rootfs_path.join("a/b")).unwrap();
+        fs::write(rootfs_path.join("a/f"), "f").unwrap();
+        symlink("/a", rootfs_path.join("a/s")).unwrap();
+
+        let path = SafePathBuf::new(rootfs_path, "a").unwrap();
+        assert!(path.stat_child("b").unwrap().is_dir());
+        assert!(path.stat_child("f").unwrap().is_file());
+        assert!(path.stat_child("s").unwrap().file_type().is_symlink());
+        let err = path.stat_child("x").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        for name in ["", ".", "..", "b/", "b/c", "/b"].iter() {
+            let err = path.stat_child(name).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidFilename, "{}", name);
+        }
+
+        // Still the child of the pinned directory after being moved.
+        fs::rename(rootfs_path.join("a"), rootfs_path.join("c")).unwrap();
+        assert!(path.stat_child("b").unwrap().is_dir());
+
+        let path = SafePathBuf::new(rootfs_path, "c/f").unwrap();
+        let err = path.stat_child("b").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+    }
+
+    #[test]
+    fn test_safe_path_buf_open_parent_dir() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir(rootfs_path.join("a")).unwrap();
+        fs::write(rootfs_path.join("a/b"), "b").unwrap();
+
+        let path = SafePathBuf::new(rootfs_path, "a/b").unwrap();
+        let (dir, name) = path.open_parent_dir().unwrap();
+        assert_eq!(name, "b");
+        let parent = fs::read_link(format!("/proc/self/fd/{}", dir.as_raw_fd())).unwrap();
+        assert_eq!(parent, rootfs_path.join("a"));
+        let name = std::ffi::CString::new("b").unwrap();
+        // Safe because `name` is a valid C string.
+        let ret = unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), 0) };
+        assert_eq!(ret, 0);
+        assert!(!rootfs_path.join("a/b").exists());
+
+        // The target has been replaced.
+        fs::write(rootfs_path.join("a/b"), "c").unwrap();
+        let err = path.open_parent_dir().unwrap_err();
+        assert!(matches!(
+            SafePathError::from_io_error(&err),
+            Some(SafePathError::IdentityMismatch { .. })
+        ));
+
+        // The parent of a directory is found by "..", even after it has been moved.
+        fs::create_dir(rootfs_path.join("a/c")).unwrap();
+        let path = SafePathBuf::new(rootfs_path, "a/c").unwrap();
+        fs::rename(rootfs_path.join("a"), rootfs_path.join("x")).unwrap();
+        fs::create_dir_all(rootfs_path.join("a/c")).unwrap();
+        let (dir, name) = path.open_parent_dir().unwrap();
+        assert_eq!(name, "c");
+        let parent = fs::read_link(format!("/proc/self/fd/{}", dir.as_raw_fd())).unwrap();
+        assert_eq!(parent, rootfs_path.join("x"));
+
+        let path = SafePathBuf::new("/", "/").unwrap();
+        let err = path.open_parent_dir().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidFilename);
+    }
+
+    #[test]
+    fn test_safe_path_buf_relative_to_root() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir_all(rootfs_path.join("root/a/b")).unwrap();
+        symlink(rootfs_path.join("root"), rootfs_path.join("link")).unwrap();
+
+        let path = SafePathBuf::new(rootfs_path.join("link"), "a/b").unwrap();
+        assert_eq!(
+            path.relative_to_root(rootfs_path.join("root")),
+            Some(PathBuf::from("a/b"))
+        );
+        assert_eq!(
+            path.relative_to_root(rootfs_path.join("link")),
+            Some(PathBuf::from("a/b"))
+        );
+        assert_eq!(
+            path.relative_to_root(rootfs_path.join("root/a/b")),
+            Some(PathBuf::new())
+        );
+        assert_eq!(path.relative_to_root(rootfs_path.join("root/a/c")), None);
+        assert_eq!(path.relative_to_root("/__does_not_exist__"), None);
+    }
+
+    #[test]
+    fn test_safe_path_buf_read_to_string() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::write(rootfs_path.join("config"), "{\"a\": 1}").unwrap();
+        fs::write(rootfs_path.join("bin"), b"ok\xff").unwrap();
+        fs::create_dir(rootfs_path.join("d")).unwrap();
+        symlink("config", rootfs_path.join("s")).unwrap();
+
+        let path = SafePathBuf::new(rootfs_path, "s").unwrap();
+        assert_eq!(path.read_to_string().unwrap(), "{\"a\": 1}");
+        // The pinned inode is read even if the name is replaced.
+        fs::rename(rootfs_path.join("config"), rootfs_path.join("old")).unwrap();
+        fs::write(rootfs_path.join("config"), "new").unwrap();
+        assert_eq!(path.read_to_string().unwrap(), "{\"a\": 1}");
+
+        let path = SafePathBuf::new(rootfs_path, "bin").unwrap();
+        let err = path.read_to_string().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(matches!(
+            SafePathError::from_io_error(&err),
+            Some(SafePathError::InvalidUtf8 { valid_up_to: 2, .. })
+        ));
+        let path = SafePathBuf::new(rootfs_path, "d").unwrap();
+        let err = path.read_to_string().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::IsADirectory);
+        let path = SafePathBuf::new_nofollow(rootfs_path, "s").unwrap();
+        let err = path.read_to_string().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ELOOP));
+    }
+
+    #[test]
+    fn test_safe_path_buf_new_reject_hardlinks() {
+        let maze = Maze::new();
+        maze.file("a", "a").dir("d/e").symlink("s", "b");
+        let rootfs_path = maze.root();
+        fs::hard_link(maze.host().join(HOST_FILE), rootfs_path.join("b")).unwrap();
+
+        let path = SafePathBuf::new_reject_hardlinks(rootfs_path, "a").unwrap();
+        assert_eq!(path.hardlink_count().unwrap(), 1);
+        // Directories may have several links, depending on the filesystem.
+        let nlink = rootfs_path.join("d").metadata().unwrap().nlink();
+        let path = SafePathBuf::new_reject_hardlinks(rootfs_path, "d").unwrap();
+        assert_eq!(path.hardlink_count().unwrap(), nlink);
+
+        for name in ["b", "s"].iter() {
+            let err = SafePathBuf::new_reject_hardlinks(rootfs_path, name).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+            match SafePathError::from_io_error(&err) {
+                Some(SafePathError::HardlinkedFile { path, nlink: 2 }) => {
+                    assert_eq!(path, &rootfs_path.canonicalize().unwrap().join("b"))
+                }
+                _ => panic!("unexpected error {}", err),
+            }
+        }
+        assert!(SafePathBuf::new(rootfs_path, "b").is_ok());
+        maze.assert_host_intact();
+    }
+
+    #[test]
+    fn test_safe_path_buf_join_segments() {
+        let maze = Maze::new();
+        maze.file("var/lib/c1/f", "f")
+            .symlink("var/lib/s", "/var/lib/c1");
+        let rootfs_path = maze.root();
+        let root = rootfs_path.canonicalize().unwrap();
+        let segments = |s: &[&'static str]| s.iter().map(|s| OsStr::new(*s)).collect::<Vec<_>>();
+
+        for s in [
+            &["var", "lib", "c1", "f"][..],
+            &["var/lib", "c1/f"],
+            &["var", "lib/./s", "f"],
+        ]
+        .iter()
+        {
+            let path = SafePathBuf::join_segments(rootfs_path, &segments(s)).unwrap();
+            assert_eq!(path.target(), root.join("var/lib/c1/f"), "{:?}", s);
+        }
+        let path = SafePathBuf::join_segments(rootfs_path, &[]).unwrap();
+        assert_eq!(path.target(), root);
+
+        for s in [
+            &["var", "..", "var"][..],
+            &["var", "lib/../.."],
+            &["var", "/etc"],
+            &["var", ""],
+        ]
+        .iter()
+        {
+            let err = SafePathBuf::join_segments(rootfs_path, &segments(s)).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidFilename, "{:?}", s);
+            assert!(matches!(
+                SafePathError::from_io_error(&err),
+                Some(SafePathError::InvalidComponent { .. })
+            ));
+        }
+        // Symlinks are still scoped under the root.
+        maze.assert_escapes_rejected(|escape| {
+            SafePathBuf::join_segments(rootfs_path, &[escape.as_os_str(), OsStr::new(HOST_FILE)])
+        });
+    }
+
+    #[test]
+    fn test_safe_path_buf_stat() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+
+        fs::write(rootfs_path.join("a"), "test").unwrap();
+        fs::hard_link(rootfs_path.join("a"), rootfs_path.join("b")).unwrap();
+        symlink("b", rootfs_path.join("s")).unwrap();
+
+        let path = SafePathBuf::new(rootfs_path, "s").unwrap();
+        let metadata = rootfs_path.join("a").metadata().unwrap();
+        assert_eq!(path.hardlink_count().unwrap(), 2);
+        assert_eq!(path.size().unwrap(), 4);
+        assert_eq!(path.uid().unwrap(), metadata.uid());
+        assert_eq!(path.gid().unwrap(), metadata.gid());
+
+        // The accessors share a snapshot, refreshed by stat().
+        fs::write(rootfs_path.join("a"), "longer").unwrap();
+        fs::remove_file(rootfs_path.join("b")).unwrap();
+        assert_eq!(
+            (path.hardlink_count().unwrap(), path.size().unwrap()),
+            (2, 4)
+        );
+        assert_eq!(path.stat().unwrap().ino(), metadata.ino());
+        assert_eq!(
+            (path.hardlink_count().unwrap(), path.size().unwrap()),
+            (1, 6)
+        );
+    }
+
+    #[test]
+    fn test_safe_path_buf_ancestors() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        let targets = |path: &SafePathBuf, root: &Path| -> Result<Vec<PathBuf>> {
+            path.ancestors(root)
+                .map(|p| p.map(|p| p.target().to_path_buf()))
+                .collect()
+        };
+
+        fs::create_dir_all(rootfs_path.join("a/b")).unwrap();
+        fs::create_dir(rootfs_path.join("x")).unwrap();
+        fs::write(rootfs_path.join("a/b/c"), "test").unwrap();
+        let path = SafePathBuf::new(rootfs_path, "a/b/c").unwrap();
+        assert_eq!(
+            targets(&path, rootfs_path).unwrap(),
+            vec![
+                rootfs_path.join("a/b"),
+                rootfs_path.join("a"),
+                rootfs_path.to_path_buf()
+            ]
+        );
+        assert_eq!(
+            targets(&path, &rootfs_path.join("a")).unwrap(),
+            vec![rootfs_path.join("a/b"), rootfs_path.join("a")]
+        );
+        let root = SafePathBuf::new(rootfs_path, "").unwrap();
+        assert!(targets(&root, rootfs_path).unwrap().is_empty());
+        assert_eq!(
+            targets(&path, Path::new("/")).unwrap().last().unwrap(),
+            Path::new("/")
+        );
+        let err = targets(&path, &rootfs_path.join("x")).unwrap_err();
+        assert!(matches!(
+            SafePathError::from_io_error(&err),
+            Some(SafePathError::OutsideRoot { .. })
+        ));
+
+        // The real parents of a moved directory, not the ones of its old path.
+        let dir = SafePathBuf::new(rootfs_path, "a/b").unwrap();
+        fs::rename(rootfs_path.join("a/b"), rootfs_path.join("x/d")).unwrap();
+        symlink("../x/d", rootfs_path.join("a/b")).unwrap();
+        assert_eq!(
+            targets(&dir, rootfs_path).unwrap(),
+            vec![rootfs_path.join("x"), rootfs_path.to_path_buf()]
+        );
+    }
+
     #[test]
     fn test_safe_path_race() {
         let root_dir = tempfile::tempdir().expect("failed to create tmpdir");
@@ -147,7 +1373,7 @@ mod tests {
             barrier2.wait();
         });
 
-        let path = safe_join(&root_path, "s").unwrap();
+        let path = safe_join(root_path, "s").unwrap();
         let data = fs::read_to_string(&path).unwrap();
         assert_eq!(&data, "a");
         assert!(path.is_file());
@@ -156,9 +1382,14 @@ mod tests {
         // Verify the target has been silently redirected.
         let data = fs::read_to_string(&path).unwrap();
         assert_eq!(&data, "b");
-        SafePathBuf::from_path(&path).unwrap_err();
+        let err = SafePathBuf::from_path(&path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Other);
+        assert!(matches!(
+            SafePathError::from_io_error(&err),
+            Some(SafePathError::TargetChanged { .. })
+        ));
 
-        let path = safe_join(&root_path, "s").unwrap();
+        let path = safe_join(root_path, "s").unwrap();
         let safe_path = SafePathBuf::from_path(&path).unwrap();
         let data = fs::read_to_string(&safe_path).unwrap();
         assert_eq!(&data, "b");
@@ -172,4 +1403,139 @@ mod tests {
 
         thread.join().unwrap();
     }
+
+    #[test]
+    fn test_safe_path_new_race() {
+        let maze = Maze::new();
+        maze.file("inside/data", "inside").symlink("link", "inside");
+        let root_path = maze.root().to_path_buf();
+        fs::write(maze.host().join("data"), "host").unwrap();
+
+        let mut escapes = maze.escape_targets();
+        escapes.push(PathBuf::from("inside"));
+        let root_path2 = root_path.clone();
+        let mut i = 0;
+        let racer = Racer::spawn(move || {
+            Maze::swap_symlink(&root_path2, "link", &escapes[i % escapes.len()]);
+            i += 1;
+        });
+
+        for _ in 0..2000 {
+            if let Ok(path) = SafePathBuf::new(&root_path, "link/data") {
+                assert!(path.target().starts_with(&root_path));
+                assert_eq!(fs::read_to_string(&path).unwrap(), "inside");
+            }
+        }
+        drop(racer);
+        maze.assert_host_intact();
+    }
+
+    #[test]
+    fn test_safe_path_buf_drop_check() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path().canonicalize().unwrap();
+        fs::create_dir(rootfs_path.join("a")).unwrap();
+
+        let path = SafePathBuf::new(&rootfs_path, "a").unwrap();
+        assert!(path.drop_check.is_none());
+        let path = path.with_drop_check();
+        if !cfg!(debug_assertions) {
+            assert!(path.drop_check.is_none());
+            return;
+        }
+        let check = path.drop_check.as_ref().unwrap();
+        assert_eq!(check.changed(), None);
+
+        fs::rename(rootfs_path.join("a"), rootfs_path.join("b")).unwrap();
+        assert_eq!(check.changed(), Some(rootfs_path.join("b")));
+        assert_eq!(path.target(), rootfs_path.join("a"));
+        drop(path);
+    }
+
+    #[test]
+    fn test_safe_path_buf_lock() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::write(rootfs_path.join("state"), "").unwrap();
+        let try_lock = |name, operation| {
+            let file = File::open(rootfs_path.join(name)).unwrap();
+            sys::flock(&file, operation | libc::LOCK_NB).is_ok()
+        };
+
+        let path = SafePathBuf::new(rootfs_path, "state").unwrap();
+        let other = SafePathBuf::new(rootfs_path, "state").unwrap();
+        path.lock_shared().unwrap();
+        other.lock_shared().unwrap();
+        assert!(try_lock("state", libc::LOCK_SH));
+        assert!(!try_lock("state", libc::LOCK_EX));
+        other.unlock().unwrap();
+
+        // The lock follows the pinned inode after a rename.
+        path.lock_exclusive().unwrap();
+        fs::rename(rootfs_path.join("state"), rootfs_path.join("moved")).unwrap();
+        assert!(!try_lock("moved", libc::LOCK_SH));
+        path.unlock().unwrap();
+        assert!(try_lock("moved", libc::LOCK_EX));
+        path.unlock().unwrap();
+
+        let dir = SafePathBuf::new(rootfs_path, ".").unwrap();
+        dir.lock_exclusive().unwrap();
+        drop(dir);
+        let file = File::open(rootfs_path).unwrap();
+        sys::flock(&file, libc::LOCK_EX | libc::LOCK_NB).unwrap();
+    }
+
+    #[cfg(feature = "cap-std")]
+    #[test]
+    fn test_safe_path_buf_into_cap_std_dir() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::create_dir(rootfs_path.join("a")).unwrap();
+        fs::write(rootfs_path.join("a/f"), "test").unwrap();
+        symlink("/a", rootfs_path.join("s")).unwrap();
+
+        let dir = SafePathBuf::new(rootfs_path, "s")
+            .unwrap()
+            .into_cap_std_dir()
+            .unwrap();
+        assert_eq!(dir.read_to_string("f").unwrap(), "test");
+        assert_eq!(dir.entries().unwrap().count(), 1);
+        // cap-std keeps the scope of the directory on its own.
+        assert!(dir.open("../a/f").is_err());
+
+        let err = SafePathBuf::new(rootfs_path, "a/f")
+            .unwrap()
+            .into_cap_std_dir()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+    }
+
+    #[test]
+    fn test_safe_path_buf_chown() {
+        let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+        let rootfs_path = rootfs_dir.path();
+        fs::write(rootfs_path.join("f"), "").unwrap();
+        symlink("f", rootfs_path.join("s")).unwrap();
+
+        let path = SafePathBuf::new(rootfs_path, "f").unwrap();
+        let (uid, gid) = (path.uid().unwrap(), path.gid().unwrap());
+        path.chown(Some(uid), None).unwrap();
+        path.chown(None, None).unwrap();
+        assert_eq!((path.uid().unwrap(), path.gid().unwrap()), (uid, gid));
+
+        // Changing to other ids needs CAP_CHOWN.
+        if unsafe { libc::geteuid() } == 0 {
+            path.chown(Some(1000), None).unwrap();
+            assert_eq!((path.uid().unwrap(), path.gid().unwrap()), (1000, gid));
+            path.chown(None, Some(1001)).unwrap();
+            assert_eq!((path.uid().unwrap(), path.gid().unwrap()), (1000, 1001));
+
+            // A pinned symlink is changed itself.
+            let link = SafePathBuf::new_nofollow(rootfs_path, "s").unwrap();
+            link.chown(Some(1002), Some(1002)).unwrap();
+            assert_eq!((path.uid().unwrap(), path.gid().unwrap()), (1000, 1001));
+            let meta = fs::symlink_metadata(rootfs_path.join("s")).unwrap();
+            assert_eq!((meta.uid(), meta.gid()), (1002, 1002));
+        }
+    }
 }
@@ -0,0 +1,306 @@
+// Copyright (c) 2022 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::io::Result;
+use std::path::{Path, PathBuf};
+
+use crate::remove::{self, RemovedStats};
+use crate::walk::{ScopedWalk, MAX_OPEN_FDS_DEFAULT};
+use crate::{sys, SafePathError};
+
+/// Options to control how [safe_remove_dir_all_with()] removes a tree.
+#[derive(Clone, Debug, Default)]
+pub struct RemoveOptions {
+    allow_xdev: bool,
+}
+
+impl RemoveOptions {
+    /// Create a new set of options with the same behavior as [safe_remove_dir_all()].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow removing the contents of other filesystems mounted inside the tree. Disabled by
+    /// default.
+    ///
+    /// When disabled, an entry on another mount than the parent of the tree fails the removal
+    /// with [SafePathError::CrossesMount] before anything in it is removed. When enabled, the
+    /// mounted filesystems are emptied too, but the mount points themselves can't be removed
+    /// while mounted, so the removal still fails with `EBUSY` at the first of them.
+    pub fn allow_xdev(&mut self, enabled: bool) -> &mut Self {
+        self.allow_xdev = enabled;
+        self
+    }
+}
+
+/// Safely remove the file at `unsafe_path` scoped under `root`.
+///
+/// The parent directory of `unsafe_path` is resolved with the same rules as
+/// [crate::safe_open_handle()], so it never escapes `root`, then the final component is removed
+/// by `unlinkat(2)` relative to the pinned fd of the parent. `unlinkat(2)` never follows the final
+/// component, so if it's a symlink, the symlink itself is removed instead of its target, and
+/// swapping the final component with a symlink to a host file can't redirect the removal.
+///
+/// # Errors
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | `root`, the parent directory or the file doesn't exist | `NotFound` |
+/// | `root` or a path component is not a directory | `NotADirectory` |
+/// | the final component is a directory | `IsADirectory` |
+/// | the final component is missing, `.` or `..` | `InvalidFilename` |
+/// | too many levels of symlinks | `FilesystemLoop` |
+/// | the path contains invalid component | `InvalidFilename` |
+pub fn safe_remove_file<R: AsRef<Path>, U: AsRef<Path>>(root: R, unsafe_path: U) -> Result<()> {
+    let unsafe_path = unsafe_path.as_ref();
+    let name = match (unsafe_path.parent(), unsafe_path.file_name()) {
+        (Some(_), Some(name)) if !unsafe_path.ends_with("..") => name,
+        _ => return Err(SafePathError::invalid_name(unsafe_path).into()),
+    };
+    let mut walk = ScopedWalk::new(root)?;
+    walk.walk(unsafe_path.parent().unwrap(), true, false)?;
+
+    sys::unlinkat(walk.fd(), name, 0)
+}
+
+/// Safely remove `unsafe_path` scoped under `root`, recursively if it's a directory, without
+/// crossing into other filesystems.
+///
+/// Same as [safe_remove_dir_all_with()] with the default [RemoveOptions].
+pub fn safe_remove_dir_all<R: AsRef<Path>, U: AsRef<Path>>(
+    root: R,
+    unsafe_path: U,
+) -> Result<RemovedStats> {
+    safe_remove_dir_all_with(root, unsafe_path, &RemoveOptions::default())
+}
+
+/// Safely remove `unsafe_path` scoped under `root`, recursively if it's a directory, and return
+/// the number of entries removed.
+///
+/// `unsafe_path` is resolved like [crate::safe_open_handle()], except that a symlink at the final
+/// component is not followed. Each directory of the tree is then opened by
+/// `openat(O_NOFOLLOW | O_DIRECTORY)` relative to the pinned fd of its parent and its entries are
+/// removed by `unlinkat(2)` relative to it, so symlinks in the tree, including one at
+/// `unsafe_path`, are removed themselves instead of being followed, and the tree is never
+/// re-resolved from `root`.
+///
+/// Unless [RemoveOptions::allow_xdev()] is set, the removal stops at the first entry on another
+/// mount than the parent of `unsafe_path`, such as a mount point inside the tree, including a
+/// bind mount of the same filesystem if `statx(2)` reports mount ids. The tree is not scanned
+/// beforehand, so the entries visited before it are already removed by then.
+///
+/// # Errors
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | `root` or `unsafe_path` doesn't exist | `NotFound` |
+/// | `root` or a path component is not a directory | `NotADirectory` |
+/// | `unsafe_path` resolves to `root` | `PermissionDenied`, with [SafePathError::RootRemoval] |
+/// | a mount point is inside the tree | `CrossesDevices`, with [SafePathError::CrossesMount] |
+/// | out of fds | [SafePathError::TooManyOpenFiles] |
+/// | too many levels of symlinks | `FilesystemLoop` |
+/// | the path contains invalid component | `InvalidFilename` |
+pub fn safe_remove_dir_all_with<R: AsRef<Path>, U: AsRef<Path>>(
+    root: R,
+    unsafe_path: U,
+    options: &RemoveOptions,
+) -> Result<RemovedStats> {
+    let mut walk = ScopedWalk::new(root)?;
+    walk.walk(unsafe_path.as_ref(), false, false)?;
+    if walk.is_root() {
+        return Err(SafePathError::RootRemoval {
+            root: walk.root().to_path_buf(),
+        }
+        .into());
+    }
+    let names = walk.names();
+    let name = &names[names.len() - 1];
+    // Safe to unwrap() because the walk is never at the root.
+    let parent = walk.parent_fd().unwrap()?;
+
+    remove::remove_all_at(&parent, name, MAX_OPEN_FDS_DEFAULT, options.allow_xdev).map_err(|e| {
+        match SafePathError::from_io_error(&e) {
+            // The path is relative to the parent, make it absolute.
+            Some(SafePathError::CrossesMount {
+                path,
+                root_dev,
+                dev,
+            }) => SafePathError::CrossesMount {
+                path: walk
+                    .root()
+                    .join(names[..names.len() - 1].iter().collect::<PathBuf>())
+                    .join(path),
+                root_dev: *root_dev,
+                dev: *dev,
+            }
+            .into(),
+            _ => e,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::statx::{Statx, StatxMask};
+    use crate::test_util::{Maze, Racer, TestMount, HOST_FILE};
+    use std::fs;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn test_safe_remove_file() {
+        let maze = Maze::new();
+        maze.file("a/f", "f")
+            .symlink("s", "/a")
+            .symlink("a/link", maze.host().join(HOST_FILE));
+        let rootfs_path = maze.root();
+
+        safe_remove_file(rootfs_path, "../s/f").unwrap();
+        assert!(!rootfs_path.join("a/f").exists());
+        // The symlink itself is removed, not its target.
+        safe_remove_file(rootfs_path, "s/link").unwrap();
+        assert!(fs::symlink_metadata(rootfs_path.join("a/link")).is_err());
+        maze.assert_escapes_rejected(|escape| {
+            safe_remove_file(rootfs_path, escape.join(HOST_FILE))
+        });
+
+        let err = safe_remove_file(rootfs_path, "a/f").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let err = safe_remove_file(rootfs_path, "b/f").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let err = safe_remove_file(rootfs_path, "a").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EISDIR));
+        safe_remove_file(rootfs_path, "s").unwrap();
+        assert!(rootfs_path.join("a").is_dir());
+        for path in ["/", "a/..", "", "."].iter() {
+            let err = safe_remove_file(rootfs_path, path).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidFilename, "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_safe_remove_file_race() {
+        let maze = Maze::new();
+        let rootfs_path = maze.root().to_path_buf();
+        let host_path = maze.host().to_path_buf();
+
+        let root = rootfs_path.clone();
+        let racer = Racer::lockstep(move || {
+            // Swap the parent directory with a symlink to the host directory.
+            fs::rename(root.join("dir"), root.join("old")).unwrap();
+            Maze::swap_symlink(&root, "dir", &host_path);
+        });
+
+        for _ in 0..200 {
+            fs::create_dir(rootfs_path.join("dir")).unwrap();
+            fs::write(rootfs_path.join("dir").join(HOST_FILE), "victim").unwrap();
+            // Either the original file is removed, or the swapped parent is rejected.
+            let path = Path::new("dir").join(HOST_FILE);
+            if let Err(e) = racer.race(|| safe_remove_file(&rootfs_path, &path)) {
+                assert_eq!(e.kind(), ErrorKind::NotFound);
+            }
+            maze.assert_host_intact();
+            fs::remove_file(rootfs_path.join("dir")).unwrap();
+            let _ = fs::remove_file(rootfs_path.join("old").join(HOST_FILE));
+            fs::remove_dir(rootfs_path.join("old")).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_safe_remove_dir_all() {
+        let maze = Maze::new();
+        maze.file("a/b/c/f", "f")
+            .file("a/g", "g")
+            .dir("a/d/e")
+            .symlink("a/b/host", maze.host())
+            .symlink("a/b/host_file", maze.host().join(HOST_FILE))
+            .symlink("s", "/a");
+        maze.escapes("a/b/e");
+        let rootfs_path = maze.root();
+
+        // The escapes are 5 symlinks plus the 32 "up" links, none of them followed.
+        let stats = safe_remove_dir_all(rootfs_path, "../s/b").unwrap();
+        assert_eq!(stats, RemovedStats { files: 40, dirs: 3 });
+        assert!(!rootfs_path.join("a/b").exists());
+        maze.assert_host_intact();
+
+        // A symlink at the final component is removed itself.
+        let stats = safe_remove_dir_all(rootfs_path, "s").unwrap();
+        assert_eq!(stats, RemovedStats { files: 1, dirs: 0 });
+        assert!(rootfs_path.join("a/d/e").is_dir());
+
+        let stats = safe_remove_dir_all(rootfs_path, "a").unwrap();
+        assert_eq!(stats, RemovedStats { files: 1, dirs: 3 });
+        assert!(!rootfs_path.join("a").exists());
+
+        let err = safe_remove_dir_all(rootfs_path, "a").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        for path in ["", "/", "..", "./."].iter() {
+            let err = safe_remove_dir_all(rootfs_path, path).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::PermissionDenied, "{}", path);
+            assert!(matches!(
+                SafePathError::from_io_error(&err),
+                Some(SafePathError::RootRemoval { .. })
+            ));
+        }
+        assert!(rootfs_path.exists());
+    }
+
+    #[test]
+    fn test_safe_remove_dir_all_xdev() {
+        let maze = Maze::new();
+        maze.file("a/m/f", "f");
+        let rootfs_path = maze.root();
+        let _tmpfs = match TestMount::tmpfs(&rootfs_path.join("a/m")) {
+            Some(tmpfs) => tmpfs,
+            None => return,
+        };
+        fs::write(rootfs_path.join("a/m/g"), "g").unwrap();
+
+        let err = safe_remove_dir_all(rootfs_path, "a").unwrap_err();
+        assert_eq!(
+            err.kind(),
+            std::io::Error::from_raw_os_error(libc::EXDEV).kind()
+        );
+        match SafePathError::from_io_error(&err) {
+            Some(SafePathError::CrossesMount { path, .. }) => {
+                assert_eq!(path, &rootfs_path.canonicalize().unwrap().join("a/m"))
+            }
+            _ => panic!("unexpected error {:?}", err),
+        }
+        assert!(rootfs_path.join("a/m/g").exists());
+
+        // The mounted tmpfs is emptied, but its mount point can't be removed.
+        let err = safe_remove_dir_all_with(rootfs_path, "a", RemoveOptions::new().allow_xdev(true))
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBUSY));
+        assert!(!rootfs_path.join("a/m/g").exists());
+        drop(_tmpfs);
+        let stats = safe_remove_dir_all(rootfs_path, "a").unwrap();
+        assert_eq!(stats, RemovedStats { files: 1, dirs: 2 });
+
+        // A bind mount of the same filesystem is only told apart by its mount id.
+        maze.file("src/f", "f").dir("b/m");
+        let _bind = TestMount::bind(&rootfs_path.join("src"), &rootfs_path.join("b/m")).unwrap();
+        let err = safe_remove_dir_all(rootfs_path, "b");
+        let root = fs::File::open(rootfs_path).unwrap();
+        if Statx::of(&root, StatxMask::MNT_ID)
+            .unwrap()
+            .mount_id()
+            .is_some()
+        {
+            match SafePathError::from_io_error(&err.unwrap_err()) {
+                Some(SafePathError::CrossesMount {
+                    path,
+                    root_dev,
+                    dev,
+                }) => {
+                    assert_eq!(path, &rootfs_path.canonicalize().unwrap().join("b/m"));
+                    assert_eq!(root_dev, dev);
+                }
+                e => panic!("unexpected error {:?}", e),
+            }
+            assert!(rootfs_path.join("src/f").exists());
+        }
+    }
+}
@@ -0,0 +1,403 @@
+// Copyright (c) 2022 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::ffi::OsStr;
+use std::io::{Error, Result};
+use std::os::unix::io::OwnedFd;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::walk::ScopedWalk;
+use crate::{sys, SafePathError};
+
+/// Safely rename `from` to `to`, both scoped under `root`, replacing an existing `to`.
+///
+/// The parent directories of `from` and `to` are resolved with the same rules as
+/// [crate::safe_open_handle()], so they never escape `root`, then the final components are
+/// renamed by `renameat(2)` relative to the pinned fds of the parents. The final components are
+/// never followed, so if `from` is a symlink, or is swapped with one during the rename, the
+/// symlink itself is moved instead of its target, and nothing outside of `root` is ever moved.
+/// `from` and `to` may be under different directories.
+///
+/// # Errors
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | `root`, a parent directory or `from` doesn't exist | `NotFound` |
+/// | `root` or a path component is not a directory | `NotADirectory` |
+/// | `from` and `to` are on different filesystems | `CrossesDevices`, with [SafePathError::CrossDevice] |
+/// | too many levels of symlinks | `FilesystemLoop` |
+/// | a final component is missing, `.` or `..` | `InvalidFilename` |
+/// | the path contains invalid component | `InvalidFilename` |
+///
+/// Other errors of `renameat(2)` are returned as is, such as `IsADirectory` when renaming a
+/// non-directory over a directory.
+pub fn safe_rename<R: AsRef<Path>, F: AsRef<Path>, T: AsRef<Path>>(
+    root: R,
+    from: F,
+    to: T,
+) -> Result<()> {
+    rename(root.as_ref(), from.as_ref(), to.as_ref(), false)
+}
+
+/// Safely rename `from` to `to`, both scoped under `root`, failing if `to` exists.
+///
+/// The same as [safe_rename()], except that the rename is done by `renameat2(2)` with
+/// `RENAME_NOREPLACE`, so an existing `to` is atomically refused with `AlreadyExists` instead of
+/// being replaced, for example when moving a prepared directory from staging into place.
+///
+/// On kernels before Linux 3.15, or on filesystems without `RENAME_NOREPLACE`, a non-directory
+/// is moved by `linkat(2)` then `unlinkat(2)`, which also refuses an existing `to` atomically but
+/// briefly leaves both names visible. A directory is moved by `renameat(2)` after checking that
+/// `to` doesn't exist, which is weaker: a `to` created between the check and the rename may be
+/// replaced if it's an empty directory.
+///
+/// # Errors
+/// The same as [safe_rename()], in addition:
+///
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | `to` exists | `AlreadyExists` |
+pub fn safe_rename_noreplace<R: AsRef<Path>, F: AsRef<Path>, T: AsRef<Path>>(
+    root: R,
+    from: F,
+    to: T,
+) -> Result<()> {
+    rename(root.as_ref(), from.as_ref(), to.as_ref(), true)
+}
+
+/// Safely exchange `a` and `b`, both scoped under `root`, atomically.
+///
+/// The parent directories of `a` and `b` are resolved independently like [safe_rename()], both
+/// final entries are checked to exist, then they are exchanged by `renameat2(2)` with
+/// `RENAME_EXCHANGE` relative to the pinned fds of the parents, so each name refers to either the
+/// old or the new entry at any time, for example when swapping `config.d.new` with `config.d`.
+/// The entries may be of different types, such as a file and a directory, and symlinks are
+/// exchanged themselves instead of their targets.
+///
+/// # Errors
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | `root`, a parent directory, `a` or `b` doesn't exist | `NotFound` |
+/// | `root` or a path component is not a directory | `NotADirectory` |
+/// | `a` and `b` are on different filesystems | `CrossesDevices`, with [SafePathError::CrossDevice] |
+/// | the kernel lacks `RENAME_EXCHANGE` | `Unsupported`, with [SafePathError::ExchangeUnsupported] |
+/// | `a` is an ancestor of `b` or the reverse, or the filesystem lacks `RENAME_EXCHANGE` | `InvalidInput`, the same kind as `EINVAL` |
+/// | too many levels of symlinks | `FilesystemLoop` |
+/// | a final component is missing, `.` or `..` | `InvalidFilename` |
+/// | the path contains invalid component | `InvalidFilename` |
+pub fn safe_exchange<R: AsRef<Path>, A: AsRef<Path>, B: AsRef<Path>>(
+    root: R,
+    a: A,
+    b: B,
+) -> Result<()> {
+    let (a_walk, a_name, b_walk, b_name) = walk_parents(root.as_ref(), a.as_ref(), b.as_ref())?;
+    let (a_dir, b_dir) = (a_walk.fd(), b_walk.fd());
+    sys::fstatat_nofollow(a_dir, a_name)?;
+    sys::fstatat_nofollow(b_dir, b_name)?;
+
+    let a_path = || a_walk.root().join(a_walk.path()).join(a_name);
+    let b_path = || b_walk.root().join(b_walk.path()).join(b_name);
+    let unsupported = || {
+        SafePathError::ExchangeUnsupported {
+            a: a_path(),
+            b: b_path(),
+        }
+        .into()
+    };
+    if !exchange_supported() {
+        return Err(unsupported());
+    }
+    sys::renameat2(a_dir, a_name, b_dir, b_name, libc::RENAME_EXCHANGE).map_err(|e| {
+        match e.raw_os_error() {
+            Some(libc::ENOSYS) => unsupported(),
+            Some(libc::EXDEV) => SafePathError::CrossDevice {
+                source: a_path(),
+                path: b_path(),
+            }
+            .into(),
+            _ => e,
+        }
+    })
+}
+
+/// Check once whether the kernel supports `RENAME_EXCHANGE`, by exchanging two empty paths,
+/// which fails with `ENOENT` only after the flags are accepted.
+fn exchange_supported() -> bool {
+    static SUPPORTED: OnceLock<bool> = OnceLock::new();
+    *SUPPORTED.get_or_init(|| {
+        let empty = OsStr::new("");
+        let cwd = sys::CurrentDir;
+        match sys::renameat2(&cwd, empty, &cwd, empty, libc::RENAME_EXCHANGE) {
+            Err(e) => !matches!(e.raw_os_error(), Some(libc::ENOSYS) | Some(libc::EINVAL)),
+            Ok(()) => true,
+        }
+    })
+}
+
+fn rename(root: &Path, from: &Path, to: &Path, noreplace: bool) -> Result<()> {
+    let (from_walk, from_name, to_walk, to_name) = walk_parents(root, from, to)?;
+    let (from_dir, to_dir) = (from_walk.fd(), to_walk.fd());
+
+    let result = if noreplace {
+        match sys::renameat2(from_dir, from_name, to_dir, to_name, libc::RENAME_NOREPLACE) {
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ENOSYS) | Some(libc::EINVAL)) => {
+                rename_noreplace_fallback(from_dir, from_name, to_dir, to_name)
+            }
+            result => result,
+        }
+    } else {
+        sys::renameat(from_dir, from_name, to_dir, to_name)
+    };
+
+    result.map_err(|e| match e.raw_os_error() {
+        Some(libc::EXDEV) => SafePathError::CrossDevice {
+            source: from_walk.root().join(from_walk.path()).join(from_name),
+            path: to_walk.root().join(to_walk.path()).join(to_name),
+        }
+        .into(),
+        _ => e,
+    })
+}
+
+/// Walk to the parent directories of `a` and `b` under `root`, and return the walks with the
+/// final components.
+fn walk_parents<'a, 'b>(
+    root: &Path,
+    a: &'a Path,
+    b: &'b Path,
+) -> Result<(ScopedWalk, &'a OsStr, ScopedWalk, &'b OsStr)> {
+    let a_name = final_name(a)?;
+    let b_name = final_name(b)?;
+
+    let mut a_walk = ScopedWalk::new(root)?;
+    a_walk.walk(a.parent().unwrap(), true, false)?;
+    // Share the pinned root, so both parents are resolved under the same directory.
+    let root_fd = a_walk.root_fd().try_clone()?;
+    let mut b_walk = ScopedWalk::from_fd(a_walk.root().to_path_buf(), root_fd);
+    b_walk.walk(b.parent().unwrap(), true, false)?;
+
+    Ok((a_walk, a_name, b_walk, b_name))
+}
+
+/// Emulate `RENAME_NOREPLACE` where `renameat2(2)` doesn't support it.
+fn rename_noreplace_fallback(
+    from_dir: &OwnedFd,
+    from_name: &OsStr,
+    to_dir: &OwnedFd,
+    to_name: &OsStr,
+) -> Result<()> {
+    let st = sys::fstatat_nofollow(from_dir, from_name)?;
+    if sys::is_dir(&st) {
+        match sys::fstatat_nofollow(to_dir, to_name) {
+            Ok(_) => return Err(Error::from_raw_os_error(libc::EEXIST)),
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {}
+            Err(e) => return Err(e),
+        }
+        return sys::renameat(from_dir, from_name, to_dir, to_name);
+    }
+
+    // linkat() never replaces an existing name, and doesn't follow a symlink without
+    // AT_SYMLINK_FOLLOW.
+    sys::linkat(from_dir, from_name, to_dir, to_name, 0)?;
+    sys::unlinkat(from_dir, from_name, 0).inspect_err(|_| {
+        let _ = sys::unlinkat(to_dir, to_name, 0);
+    })
+}
+
+/// Get the final component of `path`, which must be a normal component.
+pub(crate) fn final_name(path: &Path) -> Result<&OsStr> {
+    match (path.parent(), path.file_name()) {
+        (Some(_), Some(name)) if !path.ends_with("..") => Ok(name),
+        _ => Err(SafePathError::invalid_name(path).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{Maze, Racer};
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_safe_rename() {
+        let maze = Maze::new();
+        maze.file("staging/a/f", "a")
+            .file("staging/b", "b")
+            .file("final/b", "old")
+            .symlink("s", "/final")
+            .symlink("host", maze.host());
+        let rootfs_path = maze.root();
+
+        // Across directories, through a symlink resolved under the root.
+        safe_rename(rootfs_path, "staging/a", "../s/a").unwrap();
+        assert_eq!(
+            fs::read_to_string(rootfs_path.join("final/a/f")).unwrap(),
+            "a"
+        );
+        safe_rename(rootfs_path, "staging/b", "s/b").unwrap();
+        assert_eq!(
+            fs::read_to_string(rootfs_path.join("final/b")).unwrap(),
+            "b"
+        );
+        assert!(!rootfs_path.join("staging/b").exists());
+
+        // The symlink is moved itself, not the host directory.
+        safe_rename(rootfs_path, "host", "final/host").unwrap();
+        assert!(fs::symlink_metadata(rootfs_path.join("final/host"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        let err = safe_rename(rootfs_path, "final/host/secret", "stolen").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        maze.assert_host_intact();
+
+        let err = safe_rename(rootfs_path, "staging/c", "final/c").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        for path in ["/", "a/..", "", "."].iter() {
+            let err = safe_rename(rootfs_path, path, "x").unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidFilename, "{}", path);
+            let err = safe_rename(rootfs_path, "final/b", path).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidFilename, "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_safe_rename_noreplace() {
+        let maze = Maze::new();
+        maze.file("staging/a/f", "a")
+            .file("staging/b", "b")
+            .file("final/b", "old")
+            .dir("final/a");
+        let rootfs_path = maze.root();
+
+        let err = safe_rename_noreplace(rootfs_path, "staging/b", "final/b").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(
+            fs::read_to_string(rootfs_path.join("final/b")).unwrap(),
+            "old"
+        );
+        // An empty directory is replaced by safe_rename(), but not here.
+        let err = safe_rename_noreplace(rootfs_path, "staging/a", "final/a").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert!(rootfs_path.join("staging/a/f").exists());
+
+        safe_rename(rootfs_path, "staging/b", "final/b").unwrap();
+        assert_eq!(
+            fs::read_to_string(rootfs_path.join("final/b")).unwrap(),
+            "b"
+        );
+        safe_rename(rootfs_path, "staging/a", "final/a").unwrap();
+        assert!(rootfs_path.join("final/a/f").exists());
+
+        safe_rename_noreplace(rootfs_path, "final/a", "final/c").unwrap();
+        assert!(rootfs_path.join("final/c/f").exists());
+        let err = safe_rename_noreplace(rootfs_path, "final/a", "final/d").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_safe_rename_race() {
+        let maze = Maze::new();
+        maze.dir("final");
+        let rootfs_path = maze.root().to_path_buf();
+        let host_path = maze.host().to_path_buf();
+        let target = host_path.clone();
+
+        let root = rootfs_path.clone();
+        let racer = Racer::lockstep(move || {
+            // Swap the source with a symlink to the host directory, unless it's moved.
+            if fs::rename(root.join("src"), root.join("src.real")).is_ok() {
+                Maze::swap_symlink(&root, "src", &target);
+            }
+        });
+
+        for i in 0..200 {
+            fs::create_dir(rootfs_path.join("src")).unwrap();
+            fs::write(rootfs_path.join("src/f"), "f").unwrap();
+            let to = PathBuf::from(format!("final/{}", i));
+            let result = racer.race(|| safe_rename_noreplace(&rootfs_path, "src", &to));
+
+            // Either the real directory or the symlink is moved, never the host directory.
+            if result.is_ok() {
+                let moved = rootfs_path.join(&to);
+                let meta = fs::symlink_metadata(&moved).unwrap();
+                if meta.file_type().is_symlink() {
+                    assert_eq!(fs::read_link(&moved).unwrap(), host_path);
+                } else {
+                    assert_eq!(fs::read_to_string(moved.join("f")).unwrap(), "f");
+                }
+            }
+            maze.assert_host_intact();
+            assert_eq!(fs::read_dir(&host_path).unwrap().count(), 1);
+            let _ = fs::remove_file(rootfs_path.join("src"));
+            let _ = fs::remove_dir_all(rootfs_path.join("src.real"));
+        }
+    }
+
+    #[test]
+    fn test_safe_exchange() {
+        let maze = Maze::new();
+        maze.file("config", "old")
+            .file("staging/config.new", "new")
+            .file("d/f", "f")
+            .symlink("s", "/staging")
+            .symlink("host", maze.host());
+        let rootfs_path = maze.root();
+
+        safe_exchange(rootfs_path, "config", "../s/config.new").unwrap();
+        assert_eq!(
+            fs::read_to_string(rootfs_path.join("config")).unwrap(),
+            "new"
+        );
+        assert_eq!(
+            fs::read_to_string(rootfs_path.join("staging/config.new")).unwrap(),
+            "old"
+        );
+
+        // A file with a directory.
+        safe_exchange(rootfs_path, "config", "d").unwrap();
+        assert_eq!(
+            fs::read_to_string(rootfs_path.join("config/f")).unwrap(),
+            "f"
+        );
+        assert_eq!(fs::read_to_string(rootfs_path.join("d")).unwrap(), "new");
+
+        // The symlink is exchanged itself, not the host directory.
+        safe_exchange(rootfs_path, "host", "d").unwrap();
+        assert!(fs::symlink_metadata(rootfs_path.join("d"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        maze.assert_host_intact();
+
+        let err = safe_exchange(rootfs_path, "config", "missing").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let err = safe_exchange(rootfs_path, "missing", "config").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let err = safe_exchange(rootfs_path, "config", "..").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidFilename);
+
+        // Not an unsupported exchange, but a directory with its own child.
+        let err = safe_exchange(rootfs_path, "config", "config/f").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        assert!(SafePathError::from_io_error(&err).is_none());
+
+        assert!(exchange_supported());
+        sys::inject_error("renameat2", libc::ENOSYS);
+        let err = safe_exchange(rootfs_path, "config", "host").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        match SafePathError::from_io_error(&err) {
+            Some(SafePathError::ExchangeUnsupported { a, b }) => {
+                let root = rootfs_path.canonicalize().unwrap();
+                assert_eq!(a, &root.join("config"));
+                assert_eq!(b, &root.join("host"));
+            }
+            _ => panic!("unexpected error {}", err),
+        }
+        assert!(rootfs_path.join("config/f").exists());
+    }
+}
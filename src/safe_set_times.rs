@@ -0,0 +1,100 @@
+// Copyright (c) 2022 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::ffi::OsStr;
+use std::io::Result;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::safe_dir_builder::to_timespec;
+use crate::sys;
+use crate::walk::ScopedWalk;
+
+/// Safely set the access and modification times of `unsafe_path` scoped under `root`, `None`
+/// leaves the time unchanged as `UTIME_OMIT`.
+///
+/// The path is resolved with the same rules as [crate::safe_open_handle()], except that a
+/// symlink at the final component is not followed, then the times are set by
+/// `utimensat(2)` with `AT_SYMLINK_NOFOLLOW` relative to the pinned fd of its parent. So the
+/// change can't be redirected to another file by changing the path, and a final symlink gets
+/// its own times set instead of its target, for example to build deterministic image layers.
+/// If `unsafe_path` resolves to `root`, the times of `root` are set.
+///
+/// # Errors
+/// | Condition | ErrorKind |
+/// |-----------|-----------|
+/// | `root` or the target doesn't exist | `NotFound` |
+/// | `root` or a path component is not a directory | `NotADirectory` |
+/// | too many levels of symlinks | `FilesystemLoop` |
+/// | the path contains invalid component | `InvalidFilename` |
+pub fn safe_set_times<R: AsRef<Path>, U: AsRef<Path>>(
+    root: R,
+    unsafe_path: U,
+    atime: Option<SystemTime>,
+    mtime: Option<SystemTime>,
+) -> Result<()> {
+    let mut walk = ScopedWalk::new(root)?;
+    walk.walk(unsafe_path.as_ref(), false, false)?;
+
+    let times = [to_timespec(atime), to_timespec(mtime)];
+    match walk.names().last() {
+        Some(name) => {
+            // Safe to unwrap() because the walk is not at the root.
+            let parent = walk.parent_fd().unwrap()?;
+            sys::utimensat(&parent, name, &times, libc::AT_SYMLINK_NOFOLLOW)
+        }
+        None => sys::utimensat(walk.fd(), OsStr::new("."), &times, 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{Maze, HOST_FILE};
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_safe_set_times() {
+        let maze = Maze::new();
+        maze.file("a/f", "f")
+            .symlink("s", "/a")
+            .symlink("a/link", "f")
+            .symlink("host", maze.host().join(HOST_FILE));
+        let rootfs_path = maze.root();
+        let times = |path: &str| {
+            let meta = fs::symlink_metadata(rootfs_path.join(path)).unwrap();
+            (meta.accessed().unwrap(), meta.modified().unwrap())
+        };
+        let atime = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let mtime = UNIX_EPOCH + Duration::new(1_000_000_000, 500);
+
+        safe_set_times(rootfs_path, "../s/f", Some(atime), Some(mtime)).unwrap();
+        assert_eq!(times("a/f"), (atime, mtime));
+        safe_set_times(rootfs_path, "a/f", None, Some(UNIX_EPOCH)).unwrap();
+        assert_eq!(times("a/f"), (atime, UNIX_EPOCH));
+        safe_set_times(rootfs_path, "", Some(atime), Some(mtime)).unwrap();
+        assert_eq!(times(""), (atime, mtime));
+
+        // A final symlink gets its own times, not its target's.
+        safe_set_times(rootfs_path, "s/link", Some(atime), Some(atime)).unwrap();
+        assert_eq!(times("a/link"), (atime, atime));
+        assert_eq!(times("a/f"), (atime, UNIX_EPOCH));
+        let host_mtime = fs::metadata(maze.host().join(HOST_FILE))
+            .unwrap()
+            .modified()
+            .unwrap();
+        safe_set_times(rootfs_path, "host", Some(atime), Some(atime)).unwrap();
+        let meta = fs::metadata(maze.host().join(HOST_FILE)).unwrap();
+        assert_eq!(meta.modified().unwrap(), host_mtime);
+        maze.assert_escapes_rejected(|escape| {
+            safe_set_times(rootfs_path, escape.join(HOST_FILE), None, Some(atime))
+        });
+
+        let err = safe_set_times(rootfs_path, "a/missing", None, None).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+}
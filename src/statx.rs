@@ -0,0 +1,250 @@
+// Copyright (c) 2022 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::io::Result;
+use std::ops::BitOr;
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::sys;
+
+/// The fields requested from [crate::SafePathBuf::statx()], which may be combined by `|`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StatxMask(u32);
+
+impl StatxMask {
+    /// The file type, as `STATX_TYPE`.
+    pub const TYPE: StatxMask = StatxMask(libc::STATX_TYPE);
+    /// The permission bits, as `STATX_MODE`.
+    pub const MODE: StatxMask = StatxMask(libc::STATX_MODE);
+    /// The number of hard links, as `STATX_NLINK`.
+    pub const NLINK: StatxMask = StatxMask(libc::STATX_NLINK);
+    /// The owner, as `STATX_UID`.
+    pub const UID: StatxMask = StatxMask(libc::STATX_UID);
+    /// The group, as `STATX_GID`.
+    pub const GID: StatxMask = StatxMask(libc::STATX_GID);
+    /// The last access time, as `STATX_ATIME`.
+    pub const ATIME: StatxMask = StatxMask(libc::STATX_ATIME);
+    /// The last modification time, as `STATX_MTIME`.
+    pub const MTIME: StatxMask = StatxMask(libc::STATX_MTIME);
+    /// The last status change time, as `STATX_CTIME`.
+    pub const CTIME: StatxMask = StatxMask(libc::STATX_CTIME);
+    /// The inode number, as `STATX_INO`.
+    pub const INO: StatxMask = StatxMask(libc::STATX_INO);
+    /// The size, as `STATX_SIZE`.
+    pub const SIZE: StatxMask = StatxMask(libc::STATX_SIZE);
+    /// The number of allocated blocks, as `STATX_BLOCKS`.
+    pub const BLOCKS: StatxMask = StatxMask(libc::STATX_BLOCKS);
+    /// All the fields of `stat(2)`, as `STATX_BASIC_STATS`.
+    pub const BASIC_STATS: StatxMask = StatxMask(libc::STATX_BASIC_STATS);
+    /// The creation time, as `STATX_BTIME`.
+    pub const BTIME: StatxMask = StatxMask(libc::STATX_BTIME);
+    /// The id of the mount containing the file, as `STATX_MNT_ID`.
+    pub const MNT_ID: StatxMask = StatxMask(libc::STATX_MNT_ID);
+    /// All the fields above.
+    pub const ALL: StatxMask =
+        StatxMask(libc::STATX_BASIC_STATS | libc::STATX_BTIME | libc::STATX_MNT_ID);
+
+    /// Check whether all the fields of `other` are in the mask.
+    pub fn contains(self, other: StatxMask) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for StatxMask {
+    type Output = StatxMask;
+
+    fn bitor(self, rhs: StatxMask) -> StatxMask {
+        StatxMask(self.0 | rhs.0)
+    }
+}
+
+/// Extended metadata of a file got by `statx(2)`, see [crate::SafePathBuf::statx()].
+///
+/// A field is `None` if it wasn't requested, or if the filesystem or the kernel doesn't provide
+/// it, such as the creation time on some filesystems, or the mount id before Linux 5.8.
+#[derive(Clone, Debug)]
+pub struct Statx {
+    mask: u32,
+    dev: u64,
+    mode: u16,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    ino: u64,
+    size: u64,
+    blocks: u64,
+    atime: SystemTime,
+    mtime: SystemTime,
+    ctime: SystemTime,
+    btime: SystemTime,
+    mnt_id: u64,
+}
+
+impl Statx {
+    /// Get extended metadata of the file referred by `fd`, with at least the fields of `mask`
+    /// if provided.
+    ///
+    /// On kernels without `statx(2)`, before Linux 4.11, or when it's blocked by a seccomp
+    /// filter, the basic fields are got by `fstat(2)` instead.
+    pub(crate) fn of<F: AsRawFd>(fd: &F, mask: StatxMask) -> Result<Self> {
+        match sys::statx(fd, mask.0) {
+            Ok(stx) => Ok(Statx {
+                mask: stx.stx_mask,
+                dev: libc::makedev(stx.stx_dev_major, stx.stx_dev_minor),
+                mode: stx.stx_mode,
+                nlink: stx.stx_nlink,
+                uid: stx.stx_uid,
+                gid: stx.stx_gid,
+                ino: stx.stx_ino,
+                size: stx.stx_size,
+                blocks: stx.stx_blocks,
+                atime: to_system_time(stx.stx_atime.tv_sec, stx.stx_atime.tv_nsec),
+                mtime: to_system_time(stx.stx_mtime.tv_sec, stx.stx_mtime.tv_nsec),
+                ctime: to_system_time(stx.stx_ctime.tv_sec, stx.stx_ctime.tv_nsec),
+                btime: to_system_time(stx.stx_btime.tv_sec, stx.stx_btime.tv_nsec),
+                mnt_id: stx.stx_mnt_id,
+            }),
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ENOSYS) | Some(libc::EPERM)) => {
+                let st = sys::fstat(fd)?;
+                #[allow(clippy::unnecessary_cast)]
+                Ok(Statx {
+                    mask: libc::STATX_BASIC_STATS,
+                    dev: st.st_dev as u64,
+                    mode: st.st_mode as u16,
+                    nlink: st.st_nlink as u32,
+                    uid: st.st_uid,
+                    gid: st.st_gid,
+                    ino: st.st_ino as u64,
+                    size: st.st_size as u64,
+                    blocks: st.st_blocks as u64,
+                    atime: to_system_time(st.st_atime as i64, st.st_atime_nsec as u32),
+                    mtime: to_system_time(st.st_mtime as i64, st.st_mtime_nsec as u32),
+                    ctime: to_system_time(st.st_ctime as i64, st.st_ctime_nsec as u32),
+                    btime: UNIX_EPOCH,
+                    mnt_id: 0,
+                })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Get the fields actually provided, which may be more or less than requested.
+    pub fn mask(&self) -> StatxMask {
+        StatxMask(self.mask)
+    }
+
+    /// Get the id of the device containing the file, which is always provided.
+    pub fn dev(&self) -> u64 {
+        self.dev
+    }
+
+    /// Get the file type and permission bits, as `st_mode` of `stat(2)`.
+    ///
+    /// The file type bits are only valid with [StatxMask::TYPE], and the permission bits with
+    /// [StatxMask::MODE].
+    pub fn mode(&self) -> Option<u32> {
+        let mode = u32::from(self.mode);
+        match (
+            self.get(libc::STATX_TYPE, ()),
+            self.get(libc::STATX_MODE, ()),
+        ) {
+            (Some(_), Some(_)) => Some(mode),
+            (Some(_), None) => Some(mode & libc::S_IFMT),
+            (None, Some(_)) => Some(mode & !libc::S_IFMT),
+            (None, None) => None,
+        }
+    }
+
+    /// Get the number of hard links.
+    pub fn nlink(&self) -> Option<u32> {
+        self.get(libc::STATX_NLINK, self.nlink)
+    }
+
+    /// Get the user ID of the owner.
+    pub fn uid(&self) -> Option<u32> {
+        self.get(libc::STATX_UID, self.uid)
+    }
+
+    /// Get the group ID of the owner.
+    pub fn gid(&self) -> Option<u32> {
+        self.get(libc::STATX_GID, self.gid)
+    }
+
+    /// Get the inode number.
+    pub fn ino(&self) -> Option<u64> {
+        self.get(libc::STATX_INO, self.ino)
+    }
+
+    /// Get the size in bytes.
+    pub fn size(&self) -> Option<u64> {
+        self.get(libc::STATX_SIZE, self.size)
+    }
+
+    /// Get the number of 512-byte blocks allocated.
+    pub fn blocks(&self) -> Option<u64> {
+        self.get(libc::STATX_BLOCKS, self.blocks)
+    }
+
+    /// Get the last access time.
+    pub fn accessed(&self) -> Option<SystemTime> {
+        self.get(libc::STATX_ATIME, self.atime)
+    }
+
+    /// Get the last modification time.
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.get(libc::STATX_MTIME, self.mtime)
+    }
+
+    /// Get the last status change time.
+    pub fn changed(&self) -> Option<SystemTime> {
+        self.get(libc::STATX_CTIME, self.ctime)
+    }
+
+    /// Get the creation time, also known as the birth time.
+    pub fn created(&self) -> Option<SystemTime> {
+        self.get(libc::STATX_BTIME, self.btime)
+    }
+
+    /// Get the id of the mount containing the file, as the first field of
+    /// `/proc/self/mountinfo`.
+    pub fn mount_id(&self) -> Option<u64> {
+        self.get(libc::STATX_MNT_ID, self.mnt_id)
+    }
+
+    fn get<T>(&self, mask: u32, value: T) -> Option<T> {
+        if self.mask & mask == mask {
+            Some(value)
+        } else {
+            None
+        }
+    }
+}
+
+/// Convert a timestamp in seconds and nanoseconds since the epoch to a `SystemTime`.
+fn to_system_time(sec: i64, nsec: u32) -> SystemTime {
+    let nsec = Duration::from_nanos(u64::from(nsec));
+    if sec >= 0 {
+        UNIX_EPOCH + Duration::from_secs(sec as u64) + nsec
+    } else {
+        // The nanoseconds are still counted forwards before the epoch.
+        UNIX_EPOCH - Duration::from_secs(sec.unsigned_abs()) + nsec
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_system_time() {
+        assert_eq!(to_system_time(0, 0), UNIX_EPOCH);
+        assert_eq!(to_system_time(1, 500), UNIX_EPOCH + Duration::new(1, 500));
+        assert_eq!(
+            to_system_time(-2, 250),
+            UNIX_EPOCH - Duration::new(1, 999_999_750)
+        );
+    }
+}
@@ -0,0 +1,746 @@
+// Copyright (c) 2022 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Thin wrappers around the `*at()` family of syscalls, used to walk the filesystem anchored at
+//! directory file descriptors instead of path strings.
+
+#[cfg(test)]
+use std::cell::RefCell;
+use std::ffi::{CString, OsStr, OsString};
+use std::io::{Error, Result};
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+
+use crate::SafePathError;
+
+#[cfg(test)]
+thread_local! {
+    static SYSCALLS: RefCell<Vec<&'static str>> = const { RefCell::new(Vec::new()) };
+    static INJECTED: RefCell<Vec<(&'static str, i32)>> = const { RefCell::new(Vec::new()) };
+}
+
+// Record a syscall issued by the current thread, so tests may assert on the issued syscalls.
+fn record(_name: &'static str) {
+    #[cfg(test)]
+    SYSCALLS.with(|s| s.borrow_mut().push(_name));
+}
+
+/// Take the names of the syscalls issued by the current thread since the last call.
+#[cfg(test)]
+pub(crate) fn take_syscalls() -> Vec<&'static str> {
+    SYSCALLS.with(|s| std::mem::take(&mut *s.borrow_mut()))
+}
+
+/// Make the next call of the syscall `name` by the current thread fail with `errno`, to test
+/// error paths which the kernel of the test host never takes.
+#[cfg(test)]
+pub(crate) fn inject_error(name: &'static str, errno: i32) {
+    INJECTED.with(|s| s.borrow_mut().push((name, errno)));
+}
+
+/// Make the next call of the syscall `name` by the current thread abort the process before
+/// issuing it, to simulate a crash at that point.
+#[cfg(test)]
+pub(crate) fn inject_crash(name: &'static str) {
+    inject_error(name, 0);
+}
+
+// Fail with the error injected for the syscall `name`, if any.
+fn injected(_name: &'static str) -> Result<()> {
+    #[cfg(test)]
+    INJECTED.with(|s| {
+        let mut injected = s.borrow_mut();
+        match injected.iter().position(|(n, _)| *n == _name) {
+            Some(i) => match injected.remove(i).1 {
+                0 => std::process::abort(),
+                errno => Err(Error::from_raw_os_error(errno)),
+            },
+            None => Ok(()),
+        }
+    })?;
+    Ok(())
+}
+
+fn to_cstring(name: &OsStr) -> Result<CString> {
+    CString::new(name.as_bytes()).map_err(|_| SafePathError::invalid_name(name).into())
+}
+
+fn cvt(ret: libc::c_int) -> Result<libc::c_int> {
+    if ret < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// The max number of retries of a syscall interrupted by a signal, so a storm of signals fails
+/// the syscall with `EINTR` instead of spinning forever.
+pub(crate) const MAX_EINTR_RETRIES: usize = 64;
+
+/// The max number of retries of `openat2(2)` asking to retry with `EAGAIN`, so a stream of
+/// concurrent renames or mounts fails the syscall instead of spinning forever.
+pub(crate) const MAX_OPENAT2_RETRIES: usize = 64;
+
+// Issue the syscall `name` by `f`, retrying up to `MAX_EINTR_RETRIES` times if it's interrupted
+// by a signal before doing anything. Container runtimes install handlers without `SA_RESTART`,
+// and slow filesystems such as FUSE or NFS let even `openat()` and `readlinkat()` be interrupted.
+fn retry<T, F: FnMut() -> Result<T>>(name: &'static str, mut f: F) -> Result<T> {
+    let mut retries = 0;
+    loop {
+        match injected(name).and_then(|_| f()) {
+            Err(e) if e.raw_os_error() == Some(libc::EINTR) && retries < MAX_EINTR_RETRIES => {
+                retries += 1
+            }
+            result => return result,
+        }
+    }
+}
+
+// The same as `cvt()`, retrying the syscall `name` if it's interrupted by a signal.
+fn cvt_r<F: FnMut() -> libc::c_int>(name: &'static str, mut f: F) -> Result<libc::c_int> {
+    retry(name, || cvt(f()))
+}
+
+/// The current working directory, to be passed as `dirfd` of the `*at()` syscalls.
+pub(crate) struct CurrentDir;
+
+impl AsRawFd for CurrentDir {
+    fn as_raw_fd(&self) -> RawFd {
+        libc::AT_FDCWD
+    }
+}
+
+/// Open `name` relative to the directory `dirfd`, the returned fd is always `O_CLOEXEC`.
+pub(crate) fn openat<F: AsRawFd>(
+    dirfd: &F,
+    name: &OsStr,
+    flags: libc::c_int,
+    mode: u32,
+) -> Result<OwnedFd> {
+    record("openat");
+    let name = to_cstring(name)?;
+    // Safe because `name` is a valid C string and the returned fd is owned by us.
+    let fd = cvt_r("openat", || unsafe {
+        libc::openat(
+            dirfd.as_raw_fd(),
+            name.as_ptr(),
+            flags | libc::O_CLOEXEC,
+            mode as libc::c_uint,
+        )
+    })?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Open `path` relative to the directory `dirfd` by `openat2(2)` with the `resolve` flags, the
+/// returned fd is always `O_CLOEXEC`.
+pub(crate) fn openat2<F: AsRawFd>(
+    dirfd: &F,
+    path: &Path,
+    flags: libc::c_int,
+    resolve: u64,
+) -> Result<OwnedFd> {
+    record("openat2");
+    let path = to_cstring(path.as_os_str())?;
+    // Safe because `open_how` is a plain C struct, all zero means no flags.
+    let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+    how.flags = (flags | libc::O_CLOEXEC) as u64;
+    how.resolve = resolve;
+    let mut retries = 0;
+    loop {
+        // Safe because `path` is a valid C string, `how` is valid for the given size, and the
+        // returned fd is owned by us.
+        let fd = cvt_r("openat2", || unsafe {
+            libc::syscall(
+                libc::SYS_openat2,
+                dirfd.as_raw_fd(),
+                path.as_ptr(),
+                &how as *const libc::open_how,
+                std::mem::size_of::<libc::open_how>(),
+            ) as libc::c_int
+        });
+        match fd {
+            Ok(fd) => return Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+            // The kernel asks to retry if there's a concurrent rename or mount with
+            // RESOLVE_IN_ROOT.
+            Err(e) if e.raw_os_error() == Some(libc::EAGAIN) && retries < MAX_OPENAT2_RETRIES => {
+                retries += 1
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Create directory `name` under the directory `dirfd`.
+pub(crate) fn mkdirat<F: AsRawFd>(dirfd: &F, name: &OsStr, mode: u32) -> Result<()> {
+    record("mkdirat");
+    let name = to_cstring(name)?;
+    // Safe because `name` is a valid C string.
+    cvt_r("mkdirat", || unsafe {
+        libc::mkdirat(dirfd.as_raw_fd(), name.as_ptr(), mode as libc::mode_t)
+    })?;
+    Ok(())
+}
+
+/// Create the filesystem node `name` under the directory `dirfd`.
+pub(crate) fn mknodat<F: AsRawFd>(dirfd: &F, name: &OsStr, mode: u32, dev: u64) -> Result<()> {
+    record("mknodat");
+    let name = to_cstring(name)?;
+    // Safe because `name` is a valid C string.
+    cvt_r("mknodat", || unsafe {
+        libc::mknodat(
+            dirfd.as_raw_fd(),
+            name.as_ptr(),
+            mode as libc::mode_t,
+            dev as libc::dev_t,
+        )
+    })?;
+    Ok(())
+}
+
+/// Create a symlink `name` under the directory `dirfd`, pointing to `target` verbatim.
+pub(crate) fn symlinkat<F: AsRawFd>(target: &Path, dirfd: &F, name: &OsStr) -> Result<()> {
+    record("symlinkat");
+    let target = to_cstring(target.as_os_str())?;
+    let name = to_cstring(name)?;
+    // Safe because `target` and `name` are valid C strings.
+    cvt_r("symlinkat", || unsafe {
+        libc::symlinkat(target.as_ptr(), dirfd.as_raw_fd(), name.as_ptr())
+    })?;
+    Ok(())
+}
+
+/// Create a hardlink `newname` under `newdirfd` to `oldname` under `olddirfd`.
+pub(crate) fn linkat<F: AsRawFd, G: AsRawFd>(
+    olddirfd: &F,
+    oldname: &OsStr,
+    newdirfd: &G,
+    newname: &OsStr,
+    flags: libc::c_int,
+) -> Result<()> {
+    record("linkat");
+    let oldname = to_cstring(oldname)?;
+    let newname = to_cstring(newname)?;
+    // Safe because `oldname` and `newname` are valid C strings.
+    cvt_r("linkat", || unsafe {
+        libc::linkat(
+            olddirfd.as_raw_fd(),
+            oldname.as_ptr(),
+            newdirfd.as_raw_fd(),
+            newname.as_ptr(),
+            flags,
+        )
+    })?;
+    Ok(())
+}
+
+/// Remove `name` under the directory `dirfd`, `flags` may be `AT_REMOVEDIR` to remove a directory.
+pub(crate) fn unlinkat<F: AsRawFd>(dirfd: &F, name: &OsStr, flags: libc::c_int) -> Result<()> {
+    record("unlinkat");
+    let name = to_cstring(name)?;
+    // Safe because `name` is a valid C string.
+    cvt_r("unlinkat", || unsafe {
+        libc::unlinkat(dirfd.as_raw_fd(), name.as_ptr(), flags)
+    })?;
+    Ok(())
+}
+
+/// Check the accessibility of `name` under the directory `dirfd` by `faccessat(2)`.
+pub(crate) fn faccessat<F: AsRawFd>(
+    dirfd: &F,
+    name: &OsStr,
+    mode: libc::c_int,
+    flags: libc::c_int,
+) -> Result<()> {
+    record("faccessat");
+    let name = to_cstring(name)?;
+    // Safe because `name` is a valid C string.
+    cvt_r("faccessat", || unsafe {
+        libc::faccessat(dirfd.as_raw_fd(), name.as_ptr(), mode, flags)
+    })?;
+    Ok(())
+}
+
+/// Read the names of the entries in the directory `dirfd`, excluding "." and "..".
+///
+/// The `dirfd` may be an `O_PATH` fd, the directory is reopened for reading relative to it.
+pub(crate) fn read_dir<F: AsRawFd>(dirfd: &F) -> Result<Vec<OsString>> {
+    let fd = openat(
+        dirfd,
+        OsStr::new("."),
+        libc::O_RDONLY | libc::O_DIRECTORY,
+        0,
+    )?;
+    record("readdir");
+    // Safe because `fd` is a valid fd, whose ownership is passed to the `DIR` stream on success.
+    let dir = unsafe { libc::fdopendir(fd.as_raw_fd()) };
+    if dir.is_null() {
+        return Err(Error::last_os_error());
+    }
+    std::mem::forget(fd);
+
+    let mut names = Vec::new();
+    let result = loop {
+        // Safe because `dir` is a valid `DIR` stream, reset errno to tell the end of stream from
+        // errors.
+        let entry = unsafe {
+            *libc::__errno_location() = 0;
+            libc::readdir64(dir)
+        };
+        if entry.is_null() {
+            match Error::last_os_error().raw_os_error() {
+                Some(0) => break Ok(names),
+                _ => break Err(Error::last_os_error()),
+            }
+        }
+        // Safe because `d_name` of a valid entry is a C string.
+        let name = unsafe { std::ffi::CStr::from_ptr((*entry).d_name.as_ptr()) };
+        let name = name.to_bytes();
+        if name != b"." && name != b".." {
+            names.push(OsString::from_vec(name.to_vec()));
+        }
+    };
+    // Safe because `dir` is a valid `DIR` stream, which also closes the fd.
+    unsafe { libc::closedir(dir) };
+    result
+}
+
+/// Fill `buf` with random bytes by `getrandom(2)`.
+pub(crate) fn getrandom(buf: &mut [u8]) -> Result<()> {
+    record("getrandom");
+    let mut filled = 0;
+    while filled < buf.len() {
+        // Safe because the kernel writes at most `buf.len() - filled` bytes into `buf`.
+        let ret = unsafe {
+            libc::getrandom(
+                buf[filled..].as_mut_ptr() as *mut libc::c_void,
+                buf.len() - filled,
+                0,
+            )
+        };
+        if ret < 0 {
+            let err = Error::last_os_error();
+            if err.kind() != std::io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        } else {
+            filled += ret as usize;
+        }
+    }
+    Ok(())
+}
+
+/// Rename `old` under the directory `olddirfd` to `new` under the directory `newdirfd`.
+pub(crate) fn renameat<F: AsRawFd, G: AsRawFd>(
+    olddirfd: &F,
+    old: &OsStr,
+    newdirfd: &G,
+    new: &OsStr,
+) -> Result<()> {
+    record("renameat");
+    let old = to_cstring(old)?;
+    let new = to_cstring(new)?;
+    // Safe because `old` and `new` are valid C strings.
+    cvt_r("renameat", || unsafe {
+        libc::renameat(
+            olddirfd.as_raw_fd(),
+            old.as_ptr(),
+            newdirfd.as_raw_fd(),
+            new.as_ptr(),
+        )
+    })?;
+    Ok(())
+}
+
+/// Rename `old` under the directory `olddirfd` to `new` under the directory `newdirfd` by
+/// `renameat2(2)`, `flags` may be `RENAME_NOREPLACE` or `RENAME_EXCHANGE`.
+pub(crate) fn renameat2<F: AsRawFd, G: AsRawFd>(
+    olddirfd: &F,
+    old: &OsStr,
+    newdirfd: &G,
+    new: &OsStr,
+    flags: libc::c_uint,
+) -> Result<()> {
+    record("renameat2");
+    let old = to_cstring(old)?;
+    let new = to_cstring(new)?;
+    // Safe because `old` and `new` are valid C strings.
+    cvt_r("renameat2", || unsafe {
+        libc::renameat2(
+            olddirfd.as_raw_fd(),
+            old.as_ptr(),
+            newdirfd.as_raw_fd(),
+            new.as_ptr(),
+            flags,
+        )
+    })?;
+    Ok(())
+}
+
+/// Copy up to `len` bytes from the current offset of `fd_in` to the current offset of `fd_out`
+/// in the kernel, and return the number of bytes copied, 0 at the end of `fd_in`.
+pub(crate) fn copy_file_range<F: AsRawFd, G: AsRawFd>(
+    fd_in: &F,
+    fd_out: &G,
+    len: usize,
+) -> Result<usize> {
+    record("copy_file_range");
+    retry("copy_file_range", || {
+        // Safe because the null offsets make the kernel use and update the file offsets.
+        let ret = unsafe {
+            libc::copy_file_range(
+                fd_in.as_raw_fd(),
+                std::ptr::null_mut(),
+                fd_out.as_raw_fd(),
+                std::ptr::null_mut(),
+                len,
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(ret as usize)
+    })
+}
+
+/// Change the mode of the file referred by `fd`, which may be an `O_PATH` fd.
+///
+/// `fchmod()` doesn't accept `O_PATH` fds, so the mode is changed through the `/proc/self/fd/`
+/// magic link, which always refers to the exact inode of `fd`.
+pub(crate) fn fchmod<F: AsRawFd>(fd: &F, mode: u32) -> Result<()> {
+    record("chmod");
+    let path = CString::new(format!("/proc/self/fd/{}", fd.as_raw_fd())).unwrap();
+    // Safe because `path` is a valid C string.
+    cvt_r("chmod", || unsafe {
+        libc::chmod(path.as_ptr(), mode as libc::mode_t)
+    })?;
+    Ok(())
+}
+
+/// Change the access and modification times of the file referred by `fd`, which may be an
+/// `O_PATH` fd, `UTIME_OMIT` leaves a time unchanged.
+///
+/// `futimens()` doesn't accept `O_PATH` fds, so the times are changed through the
+/// `/proc/self/fd/` magic link, which always refers to the exact inode of `fd`.
+pub(crate) fn futimens<F: AsRawFd>(fd: &F, times: &[libc::timespec; 2]) -> Result<()> {
+    record("utimensat");
+    let path = CString::new(format!("/proc/self/fd/{}", fd.as_raw_fd())).unwrap();
+    // Safe because `path` is a valid C string and `times` has two elements.
+    cvt_r("utimensat", || unsafe {
+        libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), 0)
+    })?;
+    Ok(())
+}
+
+/// Change the access and modification times of `name` under the directory `dirfd`, `flags` may be
+/// `AT_SYMLINK_NOFOLLOW` to change a symlink itself.
+pub(crate) fn utimensat<F: AsRawFd>(
+    dirfd: &F,
+    name: &OsStr,
+    times: &[libc::timespec; 2],
+    flags: libc::c_int,
+) -> Result<()> {
+    record("utimensat");
+    let name = to_cstring(name)?;
+    // Safe because `name` is a valid C string and `times` has two elements.
+    cvt_r("utimensat", || unsafe {
+        libc::utimensat(dirfd.as_raw_fd(), name.as_ptr(), times.as_ptr(), flags)
+    })?;
+    Ok(())
+}
+
+/// Change the owner of the file referred by `fd`, which may be an `O_PATH` fd.
+pub(crate) fn fchown<F: AsRawFd>(fd: &F, uid: u32, gid: u32) -> Result<()> {
+    record("fchownat");
+    // Safe because the path is a valid empty C string.
+    cvt_r("fchownat", || unsafe {
+        libc::fchownat(
+            fd.as_raw_fd(),
+            b"\0".as_ptr() as *const libc::c_char,
+            uid,
+            gid,
+            libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW,
+        )
+    })?;
+    Ok(())
+}
+
+/// Apply or remove an advisory lock on the file referred by `fd`, which must not be an `O_PATH`
+/// fd.
+pub(crate) fn flock<F: AsRawFd>(fd: &F, operation: libc::c_int) -> Result<()> {
+    record("flock");
+    // Safe because flock() doesn't touch any memory.
+    cvt_r("flock", || unsafe {
+        libc::flock(fd.as_raw_fd(), operation)
+    })?;
+    Ok(())
+}
+
+/// Get file status of the file referred by `fd`, which may be an `O_PATH` fd.
+pub(crate) fn fstat<F: AsRawFd>(fd: &F) -> Result<libc::stat> {
+    record("fstat");
+    let mut st = MaybeUninit::<libc::stat>::uninit();
+    // Safe because the kernel fully initializes `st` on success.
+    cvt_r("fstat", || unsafe {
+        libc::fstat(fd.as_raw_fd(), st.as_mut_ptr())
+    })?;
+    Ok(unsafe { st.assume_init() })
+}
+
+/// Get extended file status of the file referred by `fd`, which may be an `O_PATH` fd, by
+/// `statx(fd, "", AT_EMPTY_PATH, mask)`.
+///
+/// The raw syscall is used instead of the libc wrapper, which is missing from older C libraries.
+pub(crate) fn statx<F: AsRawFd>(fd: &F, mask: u32) -> Result<libc::statx> {
+    record("statx");
+    let mut stx = MaybeUninit::<libc::statx>::uninit();
+    // Safe because the empty path is a valid C string and the kernel fully initializes `stx` on
+    // success.
+    cvt_r("statx", || unsafe {
+        libc::syscall(
+            libc::SYS_statx,
+            fd.as_raw_fd(),
+            b"\0".as_ptr() as *const libc::c_char,
+            libc::AT_EMPTY_PATH,
+            mask,
+            stx.as_mut_ptr(),
+        ) as libc::c_int
+    })?;
+    Ok(unsafe { stx.assume_init() })
+}
+
+/// Get the mount id of `name` under the directory `dirfd` without following a symlink, or
+/// `None` if it's not reported by `statx(2)`, before Linux 5.8 or when it's blocked.
+pub(crate) fn mount_id_at<F: AsRawFd>(dirfd: &F, name: &OsStr) -> Result<Option<u64>> {
+    record("statx");
+    let name = to_cstring(name)?;
+    let mut stx = MaybeUninit::<libc::statx>::uninit();
+    // Safe because `name` is a valid C string and the kernel fully initializes `stx` on success.
+    let ret = cvt_r("statx", || unsafe {
+        libc::syscall(
+            libc::SYS_statx,
+            dirfd.as_raw_fd(),
+            name.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+            libc::STATX_MNT_ID,
+            stx.as_mut_ptr(),
+        ) as libc::c_int
+    });
+    match ret {
+        Ok(_) => {
+            let stx = unsafe { stx.assume_init() };
+            Ok(Some(stx.stx_mnt_id).filter(|_| stx.stx_mask & libc::STATX_MNT_ID != 0))
+        }
+        Err(e) if matches!(e.raw_os_error(), Some(libc::ENOSYS) | Some(libc::EPERM)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Get file status of `name` under the directory `dirfd`, without following a symlink.
+pub(crate) fn fstatat_nofollow<F: AsRawFd>(dirfd: &F, name: &OsStr) -> Result<libc::stat> {
+    record("fstatat");
+    let name = to_cstring(name)?;
+    let mut st = MaybeUninit::<libc::stat>::uninit();
+    // Safe because `name` is a valid C string and the kernel fully initializes `st` on success.
+    cvt_r("fstatat", || unsafe {
+        libc::fstatat(
+            dirfd.as_raw_fd(),
+            name.as_ptr(),
+            st.as_mut_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    })?;
+    Ok(unsafe { st.assume_init() })
+}
+
+/// Read the target of the symlink `name` under `dirfd`.
+///
+/// An empty `name` reads the symlink referred by `dirfd` itself, which must be opened with
+/// `O_PATH | O_NOFOLLOW`.
+pub(crate) fn readlinkat<F: AsRawFd>(dirfd: &F, name: &OsStr) -> Result<PathBuf> {
+    record("readlinkat");
+    let name = to_cstring(name)?;
+    let mut buf = Vec::with_capacity(256);
+    loop {
+        // Safe because the kernel writes at most `buf.capacity()` bytes into `buf`.
+        let len = cvt_r("readlinkat", || unsafe {
+            libc::readlinkat(
+                dirfd.as_raw_fd(),
+                name.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.capacity(),
+            ) as libc::c_int
+        })? as usize;
+        if len < buf.capacity() {
+            // Safe because the kernel has initialized the first `len` bytes.
+            unsafe { buf.set_len(len) };
+            return Ok(PathBuf::from(OsString::from_vec(buf)));
+        }
+        // The target may have been truncated, retry with a bigger buffer. `buf` is still empty,
+        // so reserving grows the capacity to exactly the requested amount.
+        buf.reserve(buf.capacity() * 2);
+    }
+}
+
+/// Flush the file referred by `fd` to the storage.
+///
+/// Filesystems which don't support syncing, reported by `EINVAL`, are tolerated.
+pub(crate) fn fsync<F: AsRawFd>(fd: &F) -> Result<()> {
+    record("fsync");
+    // Safe because fsync() doesn't touch any memory.
+    match cvt_r("fsync", || unsafe { libc::fsync(fd.as_raw_fd()) }) {
+        Err(e) if e.raw_os_error() != Some(libc::EINVAL) => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Flush the directory referred by `fd`, which may be an `O_PATH` fd, to the storage.
+pub(crate) fn fsync_dir<F: AsRawFd>(fd: &F) -> Result<()> {
+    let dir = openat(fd, OsStr::new("."), libc::O_RDONLY | libc::O_DIRECTORY, 0)?;
+    fsync(&dir)
+}
+
+/// Set the xattr `name` of the file at `path` to `value`, following symlinks, such as the
+/// `/proc/self/fd/` magic link of a pinned fd.
+pub(crate) fn setxattr(path: &Path, name: &str, value: &[u8]) -> Result<()> {
+    record("setxattr");
+    let path = to_cstring(path.as_os_str())?;
+    let name = to_cstring(OsStr::new(name))?;
+    // Safe because `path` and `name` are valid C strings and `value` is valid for its length.
+    cvt_r("setxattr", || unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    })
+    .map(|_| ())
+}
+
+/// Get filesystem statistics of the filesystem containing the file referred by `fd`, which may
+/// be an `O_PATH` fd.
+pub(crate) fn fstatfs<F: AsRawFd>(fd: &F) -> Result<libc::statfs> {
+    record("fstatfs");
+    let mut st = MaybeUninit::<libc::statfs>::uninit();
+    // Safe because the kernel fully initializes `st` on success.
+    cvt_r("fstatfs", || unsafe {
+        libc::fstatfs(fd.as_raw_fd(), st.as_mut_ptr())
+    })?;
+    Ok(unsafe { st.assume_init() })
+}
+
+/// Check whether the `st_mode` describes a directory.
+pub(crate) fn is_dir(st: &libc::stat) -> bool {
+    st.st_mode & libc::S_IFMT == libc::S_IFDIR
+}
+
+/// Check whether the `st_mode` describes a symlink.
+pub(crate) fn is_symlink(st: &libc::stat) -> bool {
+    st.st_mode & libc::S_IFMT == libc::S_IFLNK
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Maze;
+    use crate::walk::ScopedWalk;
+    use std::fs::OpenOptions;
+    use std::io::ErrorKind;
+    use std::os::unix::thread::JoinHandleExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    static SIGNALS: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn count_signal(_: libc::c_int) {
+        SIGNALS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_retry_eintr_signal() {
+        let maze = Maze::new();
+        let fifo = maze.root().join("fifo");
+        let path = to_cstring(fifo.as_os_str()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+
+        // Install the handler without SA_RESTART, so the blocked open() of the fifo fails with
+        // EINTR instead of being restarted by the kernel, and restore the old one at the end.
+        struct Restore(libc::sigaction);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                unsafe { libc::sigaction(libc::SIGUSR1, &self.0, std::ptr::null_mut()) };
+            }
+        }
+        let _restore = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = count_signal as extern "C" fn(libc::c_int) as usize;
+            libc::sigemptyset(&mut action.sa_mask);
+            let mut old: libc::sigaction = std::mem::zeroed();
+            assert_eq!(libc::sigaction(libc::SIGUSR1, &action, &mut old), 0);
+            Restore(old)
+        };
+
+        let (tid_tx, tid_rx) = std::sync::mpsc::channel();
+        let reader_path = fifo.clone();
+        let reader = thread::spawn(move || {
+            tid_tx.send(unsafe { libc::gettid() }).unwrap();
+            openat(&CurrentDir, reader_path.as_os_str(), libc::O_RDONLY, 0)
+        });
+        // The syscall the reader is blocked in, as reported by the kernel.
+        let syscall = format!("/proc/self/task/{}/syscall", tid_rx.recv().unwrap());
+        let in_openat = || {
+            let current = std::fs::read_to_string(&syscall).unwrap();
+            current.split(' ').next() == Some(libc::SYS_openat.to_string().as_str())
+        };
+        // Interrupt the reader only once it's blocked in open(), then wait for the handler.
+        for i in 1..=3 {
+            while !in_openat() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            unsafe { libc::pthread_kill(reader.as_pthread_t(), libc::SIGUSR1) };
+            while SIGNALS.load(Ordering::SeqCst) < i {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        while !in_openat() {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // The reader is still waiting for a writer rather than having failed.
+        let _writer = OpenOptions::new().write(true).open(&fifo).unwrap();
+        let fd = reader.join().unwrap().unwrap();
+        assert!(fstat(&fd).unwrap().st_mode & libc::S_IFMT == libc::S_IFIFO);
+    }
+
+    #[test]
+    fn test_retry_eintr_bounded() {
+        let maze = Maze::new();
+        maze.file("a/b/f", "f").symlink("a/s", "b");
+
+        // Interruptions of both openat() and readlinkat() are retried during a resolution.
+        for _ in 0..3 {
+            inject_error("openat", libc::EINTR);
+            inject_error("readlinkat", libc::EINTR);
+        }
+        let mut walk = ScopedWalk::new(maze.root()).unwrap();
+        walk.walk(Path::new("a/s/f"), true, false).unwrap();
+        assert_eq!(walk.path(), Path::new("a/b/f"));
+        INJECTED.with(|s| assert!(s.borrow().is_empty()));
+
+        // A storm of signals eventually surfaces.
+        for _ in 0..=MAX_EINTR_RETRIES {
+            inject_error("openat", libc::EINTR);
+        }
+        let mut walk = ScopedWalk::new(maze.root()).unwrap();
+        let err = walk.walk(Path::new("a/b/f"), true, false).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Interrupted);
+        INJECTED.with(|s| assert!(s.borrow().is_empty()));
+    }
+}
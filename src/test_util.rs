@@ -0,0 +1,345 @@
+// Copyright (c) 2022 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Adversarial directory layouts shared by the tests of several modules.
+
+use std::ffi::CString;
+use std::fs;
+use std::io::{ErrorKind, Result};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Barrier};
+use std::thread::{self, JoinHandle};
+
+use tempfile::TempDir;
+
+use crate::resolver::{self, Backend};
+
+/// The file in the host directory which must never be touched through the root.
+pub(crate) const HOST_FILE: &str = "secret";
+/// The content of [HOST_FILE].
+pub(crate) const HOST_DATA: &str = "host";
+
+/// Run `f` with each resolution backend supported by the running system, overridden for the
+/// current thread only.
+pub(crate) fn for_each_backend<F: FnMut(Backend)>(mut f: F) {
+    let mut backends = vec![Backend::OpenatWalk, Backend::ProcReadlink];
+    if resolver::resolver_info().openat2_available {
+        backends.push(Backend::Openat2);
+    }
+    for backend in backends {
+        let _guard = resolver::override_backend(backend);
+        f(backend);
+    }
+}
+
+/// A root directory to populate with symlink mazes, and a host directory outside of it which
+/// escape attempts point at.
+pub(crate) struct Maze {
+    root: TempDir,
+    host: TempDir,
+}
+
+impl Maze {
+    /// Create an empty root, and a host directory holding [HOST_FILE].
+    pub(crate) fn new() -> Self {
+        let root = tempfile::tempdir().expect("failed to create tmpdir");
+        let host = tempfile::tempdir().expect("failed to create tmpdir");
+        fs::write(host.path().join(HOST_FILE), HOST_DATA).unwrap();
+        Maze { root, host }
+    }
+
+    /// Get the path of the root directory.
+    pub(crate) fn root(&self) -> &Path {
+        self.root.path()
+    }
+
+    /// Get the path of the host directory.
+    pub(crate) fn host(&self) -> &Path {
+        self.host.path()
+    }
+
+    /// Create the directory `path` under the root, with all its parents.
+    pub(crate) fn dir(&self, path: &str) -> &Self {
+        fs::create_dir_all(self.root().join(path)).unwrap();
+        self
+    }
+
+    /// Create the file `path` under the root with `data`, creating its parents.
+    pub(crate) fn file(&self, path: &str, data: &str) -> &Self {
+        let path = self.root().join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+        self
+    }
+
+    /// Create the symlink `path` under the root pointing to `target`, creating its parents.
+    pub(crate) fn symlink<P: AsRef<Path>>(&self, path: &str, target: P) -> &Self {
+        let path = self.root().join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        symlink(target, path).unwrap();
+        self
+    }
+
+    /// Get symlink targets which point at the host directory if they are followed naively from
+    /// anywhere under the root, and stay under the root if they are scoped.
+    pub(crate) fn escape_targets(&self) -> Vec<PathBuf> {
+        let host = self.host().strip_prefix("/").unwrap();
+        vec![
+            // Absolute.
+            self.host().to_path_buf(),
+            // Climbing above the root, whatever the depth of the link is.
+            Path::new(&vec![".."; 64].join("/")).join(host),
+            // Through the root of the process.
+            Path::new("/proc/self/root").join(host),
+            // Through "." and empty components, longer than the first buffer to read a symlink.
+            Path::new("/./").join(vec![".."; 64].join("//")).join(host),
+        ]
+    }
+
+    /// Create a symlink under the directory `dir` for each of [Maze::escape_targets()], and a
+    /// chain of symlinks climbing one level each, and return the paths of the symlinks relative
+    /// to the root.
+    ///
+    /// Each escape resolves to the host directory if it's followed naively, so appending
+    /// [HOST_FILE] to any of them reaches the host file unless the resolution is scoped.
+    pub(crate) fn escapes(&self, dir: &str) -> Vec<PathBuf> {
+        self.dir(dir);
+        let dir = Path::new(dir);
+        let mut escapes = Vec::new();
+        for (i, target) in self.escape_targets().into_iter().enumerate() {
+            let link = dir.join(format!("escape{}", i));
+            self.symlink(link.to_str().unwrap(), target);
+            escapes.push(link);
+        }
+
+        // up0 -> "..", up1 -> "up0/..", ..., and the last one is joined with the host path.
+        let mut prev = PathBuf::from("..");
+        for i in 0..32 {
+            let link = dir.join(format!("up{}", i));
+            self.symlink(link.to_str().unwrap(), &prev);
+            prev = PathBuf::from(format!("up{}/..", i));
+        }
+        let link = dir.join("climb");
+        let host = self.host().strip_prefix("/").unwrap();
+        self.symlink(link.to_str().unwrap(), Path::new(&prev).join(host));
+        escapes.push(link);
+
+        escapes
+    }
+
+    /// Create a chain of `len` symlinks `path`, `path.1`, ..., each pointing to the next one,
+    /// and the last one pointing to `target`.
+    pub(crate) fn chain<P: AsRef<Path>>(&self, path: &str, len: usize, target: P) -> &Self {
+        for i in 0..len {
+            let link = match i {
+                0 => path.to_string(),
+                i => format!("{}.{}", path, i),
+            };
+            if i + 1 == len {
+                self.symlink(&link, target.as_ref());
+            } else {
+                let next = format!("{}.{}", path, i + 1);
+                self.symlink(&link, Path::new(&next).file_name().unwrap());
+            }
+        }
+        self
+    }
+
+    /// Atomically replace `path` under the root with a symlink to `target`, as an attacker
+    /// racing with a resolution does.
+    pub(crate) fn swap_symlink<P: AsRef<Path>>(root: &Path, path: &str, target: P) {
+        let path = root.join(path);
+        let tmp = path.with_extension("swap");
+        symlink(target, &tmp).unwrap();
+        fs::rename(&tmp, &path).unwrap();
+    }
+
+    /// Check that the host directory still holds [HOST_FILE] untouched.
+    pub(crate) fn assert_host_intact(&self) {
+        let data = fs::read_to_string(self.host().join(HOST_FILE)).unwrap();
+        assert_eq!(data, HOST_DATA);
+    }
+
+    /// Create [Maze::escapes()] under the directory "e", and check that `f` fails with
+    /// `NotFound` for the path of each of them relative to the root, without touching the host
+    /// directory.
+    pub(crate) fn assert_escapes_rejected<T, F: FnMut(&Path) -> Result<T>>(&self, mut f: F) {
+        let entries = fs::read_dir(self.host()).unwrap().count();
+        for escape in self.escapes("e").iter() {
+            match f(escape) {
+                Ok(_) => panic!("escape {} accepted", escape.display()),
+                Err(e) => assert_eq!(e.kind(), ErrorKind::NotFound, "{}", escape.display()),
+            }
+        }
+        self.assert_host_intact();
+        assert_eq!(fs::read_dir(self.host()).unwrap().count(), entries);
+    }
+}
+
+/// An attacker thread racing with a test, which is stopped and joined when dropped.
+pub(crate) struct Racer {
+    done: Arc<AtomicBool>,
+    // Only set for an attacker running in lockstep with `Racer::race()`.
+    barrier: Option<Arc<Barrier>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Racer {
+    /// Run `attack` repeatedly in another thread, as fast as it goes.
+    pub(crate) fn spawn<F: FnMut() + Send + 'static>(mut attack: F) -> Self {
+        let done = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let done = done.clone();
+            thread::spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    attack();
+                }
+            })
+        };
+        Racer {
+            done,
+            barrier: None,
+            thread: Some(thread),
+        }
+    }
+
+    /// Run `attack` in another thread once for each call of [Racer::race()], concurrently with
+    /// the victim of the call.
+    pub(crate) fn lockstep<F: FnMut() + Send + 'static>(mut attack: F) -> Self {
+        let done = Arc::new(AtomicBool::new(false));
+        let barrier = Arc::new(Barrier::new(2));
+        let thread = {
+            let (done, barrier) = (done.clone(), barrier.clone());
+            thread::spawn(move || loop {
+                barrier.wait();
+                if done.load(Ordering::SeqCst) {
+                    break;
+                }
+                attack();
+                barrier.wait();
+            })
+        };
+        Racer {
+            done,
+            barrier: Some(barrier),
+            thread: Some(thread),
+        }
+    }
+
+    /// Run `victim` concurrently with one run of the attack of a [Racer::lockstep()] racer, and
+    /// return its result once both are done.
+    pub(crate) fn race<T, F: FnOnce() -> T>(&self, victim: F) -> T {
+        let barrier = self.barrier.as_ref().expect("not a lockstep racer");
+        barrier.wait();
+        let result = victim();
+        barrier.wait();
+        result
+    }
+}
+
+impl Drop for Racer {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::SeqCst);
+        // A failed test may have left the attacker in the middle of a round.
+        if thread::panicking() {
+            return;
+        }
+        if let Some(barrier) = self.barrier.as_ref() {
+            barrier.wait();
+        }
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
+/// A filesystem mounted for a test, unmounted when dropped.
+pub(crate) struct TestMount {
+    path: CString,
+}
+
+impl TestMount {
+    /// Mount a tmpfs at the existing directory `path`, or return `None` if it's not permitted,
+    /// such as when the tests don't run as root.
+    pub(crate) fn tmpfs(path: &Path) -> Option<Self> {
+        Self::mount(Path::new("tmpfs"), path, "tmpfs", 0)
+    }
+
+    /// Bind mount the directory `source` at the existing directory `path`, or return `None` if
+    /// it's not permitted.
+    pub(crate) fn bind(source: &Path, path: &Path) -> Option<Self> {
+        Self::mount(source, path, "none", libc::MS_BIND)
+    }
+
+    fn mount(source: &Path, path: &Path, fstype: &str, flags: libc::c_ulong) -> Option<Self> {
+        let source = CString::new(source.as_os_str().as_bytes()).unwrap();
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let fstype = CString::new(fstype).unwrap();
+        // Safe because all the strings are nul-terminated and outlive the call.
+        let ret = unsafe {
+            libc::mount(
+                source.as_ptr(),
+                path.as_ptr(),
+                fstype.as_ptr(),
+                flags,
+                std::ptr::null(),
+            )
+        };
+        if ret != 0 {
+            return None;
+        }
+        Some(TestMount { path })
+    }
+}
+
+impl Drop for TestMount {
+    fn drop(&mut self) {
+        // Safe because the path is nul-terminated.
+        unsafe { libc::umount2(self.path.as_ptr(), libc::MNT_DETACH) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_maze_escapes() {
+        let maze = Maze::new();
+        maze.file("a/b/data", "data");
+        let escapes = maze.escapes("a/b");
+        assert_eq!(escapes.len(), maze.escape_targets().len() + 1);
+
+        // Followed naively, each escape reaches the host file, except through /proc if it's not
+        // mounted.
+        for escape in escapes.iter() {
+            let path = maze.root().join(escape).join(HOST_FILE);
+            if escape.ends_with("escape2") && !Path::new("/proc/self/root").exists() {
+                continue;
+            }
+            assert_eq!(
+                fs::read_to_string(&path).unwrap(),
+                HOST_DATA,
+                "{}",
+                path.display()
+            );
+        }
+
+        maze.chain("a/c", 3, "b");
+        assert_eq!(
+            fs::read_to_string(maze.root().join("a/c/data")).unwrap(),
+            "data"
+        );
+        Maze::swap_symlink(maze.root(), "a/c", "/");
+        assert_eq!(
+            fs::read_link(maze.root().join("a/c")).unwrap(),
+            Path::new("/")
+        );
+        maze.assert_host_intact();
+    }
+}
@@ -0,0 +1,523 @@
+// Copyright (c) 2022 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::collections::VecDeque;
+use std::ffi::{OsStr, OsString};
+use std::fs::OpenOptions;
+use std::io::{Error, Result};
+use std::ops::Deref;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+use std::path::{Component, Path, PathBuf};
+
+use crate::safe_join::MAX_SYMLINK_DEPTH;
+use crate::{sys, SafePathError};
+
+pub(crate) const PARENT_DIR: &str = "..";
+/// The default maximum number of fds held open by a walk or a recursive removal.
+pub(crate) const MAX_OPEN_FDS_DEFAULT: usize = 64;
+
+/// A directory pinned by a walk, whose fd may be closed to bound the number of open fds.
+#[derive(Debug)]
+struct Pinned {
+    fd: Option<OwnedFd>,
+    // Whether it's opened with `O_DIRECTORY`, so it's a directory as enforced by the kernel.
+    dir: bool,
+    // The `(dev, ino)` pair recorded when the fd is closed, to verify it when reopened.
+    id: (u64, u64),
+}
+
+impl Pinned {
+    fn new(fd: OwnedFd, dir: bool) -> Self {
+        Pinned {
+            fd: Some(fd),
+            dir,
+            id: (0, 0),
+        }
+    }
+
+    /// Close the fd, recording the identity of the directory to verify it when reopened.
+    fn close(&mut self) -> bool {
+        match self.fd.as_ref().map(sys::fstat) {
+            Some(Ok(st)) => {
+                self.id = file_id(&st);
+                self.fd = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Walk a path component by component, anchored at directory file descriptors and scoped
+/// under a root directory.
+///
+/// Every existing component is opened with `O_PATH | O_NOFOLLOW` relative to its parent's fd,
+/// so the walk never re-resolves a path string from the top. Symlinks are expanded with the root
+/// treated as the root of the filesystem, and ".." never goes beyond the root.
+#[derive(Debug)]
+pub(crate) struct ScopedWalk {
+    root: PathBuf,
+    // Pinned fds of the root (index 0) and each resolved component below it. The fds of the
+    // outermost components below the root are closed when more than `max_fds` are open.
+    fds: Vec<Pinned>,
+    // The number of fds open in `fds`.
+    open: usize,
+    // The index in `fds` of the outermost component which may have its fd open.
+    first_open: usize,
+    max_fds: usize,
+    // Names of the resolved components below the root, `names[i]` is pinned by `fds[i + 1]`.
+    names: Vec<OsString>,
+    // Trailing components which don't exist yet.
+    missing: Vec<OsString>,
+    // Whether to fail on any symlink instead of expanding it.
+    no_follow: bool,
+    // Whether to open the components with `O_DIRECTORY` first.
+    directories: bool,
+    // The component which failed to open in the last walk, followed by the ones after it.
+    unwalked: Vec<OsString>,
+}
+
+impl ScopedWalk {
+    /// Start a walk at the directory `root`.
+    pub(crate) fn new<P: AsRef<Path>>(root: P) -> Result<Self> {
+        let root = root.as_ref().canonicalize()?;
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC)
+            .open(&root)?;
+
+        Ok(Self::from_fd(root, file.into()))
+    }
+
+    /// Start a walk at the directory pinned by `fd`, whose path is `root`.
+    pub(crate) fn from_fd(root: PathBuf, fd: OwnedFd) -> Self {
+        ScopedWalk {
+            root,
+            fds: vec![Pinned::new(fd, true)],
+            open: 1,
+            first_open: 1,
+            max_fds: MAX_OPEN_FDS_DEFAULT,
+            names: Vec::new(),
+            missing: Vec::new(),
+            no_follow: false,
+            directories: false,
+            unwalked: Vec::new(),
+        }
+    }
+
+    /// Fail with [SafePathError::SymlinkEncountered] on any symlink met by later walks, instead
+    /// of expanding it or pinning it.
+    pub(crate) fn set_no_follow(&mut self, no_follow: bool) {
+        self.no_follow = no_follow;
+    }
+
+    /// Open each component with `O_DIRECTORY` first, so a directory is pinned as a directory
+    /// enforced by the kernel, see [ScopedWalk::is_dir()], without checking it by `fstat()`.
+    ///
+    /// Other file types are retried without `O_DIRECTORY`, and handled as usual.
+    pub(crate) fn set_directories(&mut self, directories: bool) {
+        self.directories = directories;
+    }
+
+    /// Keep at most `max_fds` fds open, at least the root and the deepest component.
+    ///
+    /// The fds of the outermost components below the root are closed first, and reopened on
+    /// demand relative to a pinned neighbour, verifying the identity of the directory.
+    pub(crate) fn set_max_fds(&mut self, max_fds: usize) {
+        self.max_fds = max_fds.max(2);
+        self.evict();
+    }
+
+    /// Resolve `unsafe_path` relative to the current position of the walk.
+    ///
+    /// If `follow` is false, a symlink at the final component is pinned itself instead of being
+    /// expanded. If `allow_missing` is true, non-existent trailing components are recorded in
+    /// `missing()` instead of failing the walk.
+    pub(crate) fn walk(
+        &mut self,
+        unsafe_path: &Path,
+        follow: bool,
+        allow_missing: bool,
+    ) -> Result<()> {
+        let mut nlinks = 0u32;
+        let mut queue = VecDeque::new();
+        push_components(&mut queue, unsafe_path, unsafe_path)?;
+        self.unwalked.clear();
+
+        while let Some(comp) = queue.pop_front() {
+            if comp == PARENT_DIR {
+                if self.missing.pop().is_none() && self.fds.len() > 1 {
+                    self.pop()?;
+                }
+                continue;
+            }
+            // Components below a missing directory can't exist either.
+            if !self.missing.is_empty() {
+                self.missing.push(comp);
+                continue;
+            }
+
+            let flags = libc::O_PATH | libc::O_NOFOLLOW;
+            if self.directories {
+                match sys::openat(self.fd(), &comp, flags | libc::O_DIRECTORY, 0) {
+                    Ok(fd) => {
+                        self.push_pinned(comp, fd, true);
+                        continue;
+                    }
+                    // Not a directory, or a symlink, so open it again to find out.
+                    Err(e) if e.raw_os_error() == Some(libc::ENOTDIR) => {}
+                    Err(e) if allow_missing && e.raw_os_error() == Some(libc::ENOENT) => {
+                        self.missing.push(comp);
+                        continue;
+                    }
+                    Err(e) => {
+                        self.unwalked = std::iter::once(comp).chain(queue).collect();
+                        return Err(too_many_open_files(e, self.names.len() + 1));
+                    }
+                }
+            }
+            let fd = match sys::openat(self.fd(), &comp, flags, 0) {
+                Ok(fd) => fd,
+                Err(e) if allow_missing && e.raw_os_error() == Some(libc::ENOENT) => {
+                    self.missing.push(comp);
+                    continue;
+                }
+                Err(e) => {
+                    let e = too_many_open_files(e, self.names.len() + 1);
+                    self.unwalked = std::iter::once(comp).chain(queue).collect();
+                    if e.raw_os_error() == Some(libc::ENOTDIR) {
+                        return Err(SafePathError::NotADirectory {
+                            path: self.root.join(self.path()),
+                        }
+                        .into());
+                    }
+                    return Err(e);
+                }
+            };
+            let st = sys::fstat(&fd)?;
+            if sys::is_symlink(&st) && self.no_follow {
+                return Err(SafePathError::SymlinkEncountered {
+                    path: self.root.join(self.path()).join(&comp),
+                }
+                .into());
+            }
+            if sys::is_symlink(&st) && (follow || !queue.is_empty()) {
+                nlinks += 1;
+                if nlinks > MAX_SYMLINK_DEPTH {
+                    return Err(SafePathError::TooManySymlinks {
+                        path: unsafe_path.to_path_buf(),
+                    }
+                    .into());
+                }
+                let target = sys::readlinkat(&fd, OsStr::new(""))?;
+                if target.is_absolute() {
+                    self.fds.truncate(1);
+                    self.names.clear();
+                    self.open = 1;
+                    self.first_open = 1;
+                }
+                let mut expanded = VecDeque::new();
+                push_components(&mut expanded, &target, unsafe_path)?;
+                expanded.append(&mut queue);
+                queue = expanded;
+                continue;
+            }
+
+            self.push_pinned(comp, fd, sys::is_dir(&st));
+        }
+
+        Ok(())
+    }
+
+    /// Get the path of the root directory.
+    pub(crate) fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Check whether the walk is still at the root directory.
+    pub(crate) fn is_root(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Get the resolved path of the deepest existing component, relative to the root.
+    pub(crate) fn path(&self) -> PathBuf {
+        self.names.iter().collect()
+    }
+
+    /// Create a hardlink `name` in the deepest existing component to the pinned `source`.
+    ///
+    /// The link is created by `linkat(AT_EMPTY_PATH)`, which needs `CAP_DAC_READ_SEARCH` on
+    /// older kernels, so the `/proc/self/fd/` magic link of `source` is followed instead if it's
+    /// refused. `source_path` names the source in a [SafePathError::CrossDevice] error.
+    pub(crate) fn link_at<F, S>(&self, source: &F, name: &OsStr, source_path: S) -> Result<()>
+    where
+        F: AsRawFd,
+        S: FnOnce() -> PathBuf,
+    {
+        let result = match sys::linkat(source, OsStr::new(""), self.fd(), name, libc::AT_EMPTY_PATH)
+        {
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {
+                let proc_path = format!("/proc/self/fd/{}", source.as_raw_fd());
+                let flags = libc::AT_SYMLINK_FOLLOW;
+                sys::linkat(
+                    &sys::CurrentDir,
+                    OsStr::new(&proc_path),
+                    self.fd(),
+                    name,
+                    flags,
+                )
+            }
+            result => result,
+        };
+        result.map_err(|e| match e.raw_os_error() {
+            Some(libc::EXDEV) => SafePathError::CrossDevice {
+                source: source_path(),
+                path: self.root.join(self.path()).join(name),
+            }
+            .into(),
+            _ => self.exists_error(e, name),
+        })
+    }
+
+    /// Report an existing `name` in the deepest existing component by
+    /// [SafePathError::AlreadyExists].
+    pub(crate) fn exists_error(&self, err: Error, name: &OsStr) -> Error {
+        match err.raw_os_error() {
+            Some(libc::EEXIST) => {
+                let relative = self.path().join(name);
+                SafePathError::AlreadyExists {
+                    path: self.root.join(&relative),
+                    relative,
+                }
+                .into()
+            }
+            _ => err,
+        }
+    }
+
+    /// Take the component which failed to open in the last walk, followed by the ones after it,
+    /// leaving none recorded.
+    pub(crate) fn take_unwalked(&mut self) -> Vec<OsString> {
+        std::mem::take(&mut self.unwalked)
+    }
+
+    /// Take the trailing components which don't exist, leaving none recorded.
+    pub(crate) fn take_missing(&mut self) -> Vec<OsString> {
+        std::mem::take(&mut self.missing)
+    }
+
+    /// Check whether the deepest existing component is pinned as a directory, which is opened
+    /// with `O_DIRECTORY` or checked by `fstat()`.
+    pub(crate) fn is_dir(&self) -> bool {
+        // Safe to unwrap() because the root fd is never popped.
+        self.fds.last().unwrap().dir
+    }
+
+    /// Get the pinned fd of the deepest existing component.
+    pub(crate) fn fd(&self) -> &OwnedFd {
+        // Safe to unwrap() because the root fd is never popped, and the fd of the deepest
+        // component is never closed.
+        self.fds.last().unwrap().fd.as_ref().unwrap()
+    }
+
+    /// Get the pinned fd of the root.
+    pub(crate) fn root_fd(&self) -> &OwnedFd {
+        // Safe to unwrap() because the root fd is never closed.
+        self.fds[0].fd.as_ref().unwrap()
+    }
+
+    /// Get the pinned fd of the root at `depth` 0, or of `names()[depth - 1]`, temporarily
+    /// reopening it if it has been closed to bound the number of open fds.
+    pub(crate) fn fd_at(&self, depth: usize) -> Result<DirFd<'_>> {
+        match &self.fds[depth].fd {
+            Some(fd) => Ok(DirFd::Pinned(fd)),
+            None => self.reopen(depth).map(DirFd::Reopened),
+        }
+    }
+
+    /// Get the pinned fd of the parent of the deepest existing component, or `None` at the root.
+    pub(crate) fn parent_fd(&self) -> Option<Result<DirFd<'_>>> {
+        match self.names.len() {
+            0 => None,
+            depth => Some(self.fd_at(depth - 1)),
+        }
+    }
+
+    /// Get the names of the resolved components below the root, `names()[i]` is pinned at
+    /// depth `i + 1`.
+    pub(crate) fn names(&self) -> &[OsString] {
+        &self.names
+    }
+
+    /// Descend into the child directory `name` which is pinned by `fd`, opened with
+    /// `O_DIRECTORY`.
+    pub(crate) fn push(&mut self, name: OsString, fd: OwnedFd) {
+        self.push_pinned(name, fd, true);
+    }
+
+    /// Descend into the child `name` which is pinned by `fd`.
+    fn push_pinned(&mut self, name: OsString, fd: OwnedFd, dir: bool) {
+        self.fds.push(Pinned::new(fd, dir));
+        self.names.push(name);
+        self.open += 1;
+        self.evict();
+    }
+
+    /// Ascend to the component at `depth`, 0 being the root, reopening the closed fds on the
+    /// way.
+    pub(crate) fn truncate(&mut self, depth: usize) -> Result<()> {
+        while self.names.len() > depth {
+            self.pop()?;
+        }
+        Ok(())
+    }
+
+    /// Consume the walk and get the pinned fd of the deepest existing component.
+    pub(crate) fn into_fd(mut self) -> OwnedFd {
+        // Safe to unwrap() because the root fd is never popped, and the fd of the deepest
+        // component is never closed.
+        self.fds.pop().unwrap().fd.unwrap()
+    }
+
+    /// Ascend to the parent of the deepest existing component, reopening it if needed.
+    fn pop(&mut self) -> Result<()> {
+        // Keep the fd of the child until its parent is reopened, which may be relative to it.
+        let depth = self.fds.len() - 2;
+        if self.fds[depth].fd.is_none() {
+            self.fds[depth].fd = Some(self.reopen(depth)?);
+            self.open += 1;
+        }
+        self.fds.pop();
+        self.names.pop();
+        self.open -= 1;
+        self.first_open = self.first_open.min(depth.max(1));
+        self.evict();
+        Ok(())
+    }
+
+    /// Close the fds of the outermost components below the root until at most `max_fds` fds
+    /// are open, keeping the fd of the deepest component.
+    fn evict(&mut self) {
+        let last = self.fds.len() - 1;
+        while self.open > self.max_fds && self.first_open < last {
+            if self.fds[self.first_open].close() {
+                self.open -= 1;
+            }
+            self.first_open += 1;
+        }
+    }
+
+    /// Reopen the closed fd at `depth`, by ".." relative to the child if it's open, otherwise
+    /// by the names from the nearest open ancestor, verifying the identity of each directory
+    /// reopened.
+    fn reopen(&self, depth: usize) -> Result<OwnedFd> {
+        let child = self.fds.get(depth + 1).and_then(|p| p.fd.as_ref());
+        let fd = match child {
+            Some(child) => self.reopen_one(child, OsStr::new(PARENT_DIR), depth)?,
+            None => {
+                // The root fd is never closed, so there's always an open ancestor.
+                let start = (0..depth)
+                    .rev()
+                    .find(|&i| self.fds[i].fd.is_some())
+                    .unwrap_or(0);
+                let mut fd = self.reopen_one(
+                    self.fds[start].fd.as_ref().unwrap(),
+                    &self.names[start],
+                    start + 1,
+                )?;
+                for i in start + 1..depth {
+                    fd = self.reopen_one(&fd, &self.names[i], i + 1)?;
+                }
+                fd
+            }
+        };
+        Ok(fd)
+    }
+
+    /// Open `name` under `dir` as the directory at `depth`, and verify its identity.
+    fn reopen_one(&self, dir: &OwnedFd, name: &OsStr, depth: usize) -> Result<OwnedFd> {
+        let flags = libc::O_PATH | libc::O_NOFOLLOW | libc::O_DIRECTORY;
+        let fd = sys::openat(dir, name, flags, 0).map_err(|e| too_many_open_files(e, depth))?;
+        let actual = file_id(&sys::fstat(&fd)?);
+        let expected = self.fds[depth].id;
+        if actual != expected {
+            return Err(SafePathError::IdentityMismatch {
+                path: self
+                    .root
+                    .join(self.names[..depth].iter().collect::<PathBuf>()),
+                expected,
+                actual,
+            }
+            .into());
+        }
+        Ok(fd)
+    }
+}
+
+/// The fd of a directory pinned by a walk, either kept open by the walk or temporarily reopened.
+pub(crate) enum DirFd<'a> {
+    Pinned(&'a OwnedFd),
+    Reopened(OwnedFd),
+}
+
+impl Deref for DirFd<'_> {
+    type Target = OwnedFd;
+
+    fn deref(&self) -> &OwnedFd {
+        match self {
+            DirFd::Pinned(fd) => fd,
+            DirFd::Reopened(fd) => fd,
+        }
+    }
+}
+
+impl AsRawFd for DirFd<'_> {
+    fn as_raw_fd(&self) -> RawFd {
+        (**self).as_raw_fd()
+    }
+}
+
+/// Get the `(dev, ino)` pair identifying a file.
+#[allow(clippy::unnecessary_cast)]
+pub(crate) fn file_id(st: &libc::stat) -> (u64, u64) {
+    (st.st_dev as u64, st.st_ino as u64)
+}
+
+/// Convert running out of fds when opening the component at `depth` into
+/// [SafePathError::TooManyOpenFiles].
+pub(crate) fn too_many_open_files(err: Error, depth: usize) -> Error {
+    match err.raw_os_error() {
+        Some(libc::EMFILE) | Some(libc::ENFILE) => {
+            SafePathError::TooManyOpenFiles { depth, error: err }.into()
+        }
+        _ => err,
+    }
+}
+
+/// Append the components of `path` to `queue`, dropping "/" and "." and keeping "..".
+///
+/// `unsafe_path` is the original input, reported if `path` contains a prefix component.
+pub(crate) fn push_components(
+    queue: &mut VecDeque<OsString>,
+    path: &Path,
+    unsafe_path: &Path,
+) -> Result<()> {
+    for comp in path.components() {
+        match comp {
+            Component::Prefix(_) => {
+                return Err(SafePathError::InvalidComponent {
+                    path: unsafe_path.to_path_buf(),
+                }
+                .into());
+            }
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir => queue.push_back(OsString::from(PARENT_DIR)),
+            Component::Normal(n) => queue.push_back(n.to_os_string()),
+        }
+    }
+
+    Ok(())
+}
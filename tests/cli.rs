@@ -0,0 +1,142 @@
+// Copyright (c) 2022 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{symlink, MetadataExt};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Output};
+
+fn safe_path(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_safe-path"))
+        .args(args)
+        .output()
+        .expect("failed to run safe-path")
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(output.status.success(), "{:?}", output);
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+#[test]
+fn test_cli() {
+    let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+    let rootfs_path = rootfs_dir.path();
+    let root = rootfs_path.to_str().unwrap();
+    fs::create_dir(rootfs_path.join("a")).unwrap();
+    fs::write(rootfs_path.join("txt"), "test").unwrap();
+    symlink("/a", rootfs_path.join("s")).unwrap();
+
+    let output = safe_path(&["join", root, "../s/b"]);
+    assert_eq!(stdout(&output), format!("{}/a/b\n", root));
+
+    let output = safe_path(&["resolve", root, "s/../../s/b"]);
+    assert_eq!(stdout(&output), "a/b\n");
+
+    let output = safe_path(&["mkdir", "--mode", "750", root, "s/c/d"]);
+    assert_eq!(stdout(&output), format!("{}/a/c/d\n", root));
+    let mode = rootfs_path.join("a/c/d").metadata().unwrap().mode();
+    assert_eq!(mode & 0o777, 0o750);
+
+    let output = safe_path(&["mkdir", root, "txt/e"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("NotADirectory: "), "{}", stderr);
+
+    let output = safe_path(&["join", root]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_cli_mkdir_umask() {
+    let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+    let rootfs_path = rootfs_dir.path();
+    let root = rootfs_path.to_str().unwrap();
+
+    let mut command = Command::new(env!("CARGO_BIN_EXE_safe-path"));
+    command.args(["mkdir", "--mode", "755", root, "a/b"]);
+    // Safe because umask() is async-signal-safe and always succeeds.
+    unsafe {
+        command.pre_exec(|| {
+            libc::umask(0o077);
+            Ok(())
+        });
+    }
+    let output = command.output().expect("failed to run safe-path");
+    assert_eq!(stdout(&output), format!("{}/a/b\n", root));
+    for path in ["a", "a/b"].iter() {
+        let mode = rootfs_path.join(path).metadata().unwrap().mode();
+        assert_eq!(mode & 0o7777, 0o755);
+    }
+}
+
+#[test]
+fn test_cli_options() {
+    let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+    let rootfs_path = rootfs_dir.path();
+    let root = rootfs_path.to_str().unwrap();
+    fs::write(rootfs_path.join("txt"), "test").unwrap();
+    symlink("txt", rootfs_path.join("l")).unwrap();
+    symlink("loop", rootfs_path.join("loop")).unwrap();
+
+    // A final symlink is pinned itself.
+    let output = safe_path(&["join", root, "l"]);
+    assert_eq!(stdout(&output), format!("{}/txt\n", root));
+    let output = safe_path(&["join", "--no-follow", root, "l"]);
+    assert_eq!(stdout(&output), format!("{}/l\n", root));
+    let output = safe_path(&["resolve", "--no-follow", root, "l"]);
+    assert_eq!(stdout(&output), "l\n");
+    let output = safe_path(&["resolve", "--no-follow", root, "missing"]);
+    assert_eq!(output.status.code(), Some(1));
+
+    // A non-directory followed by another component.
+    let output = safe_path(&["resolve", root, "txt/x"]);
+    assert_eq!(stdout(&output), "txt/x\n");
+    let output = safe_path(&["resolve", "--strict", root, "txt/x"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("NotADirectory: "), "{}", stderr);
+
+    // The typed error is named rather than its kind.
+    let output = safe_path(&["resolve", root, "loop"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("TooManySymlinks: "), "{}", stderr);
+
+    for args in [
+        &["mkdir", "--no-follow", root, "a"][..],
+        &["mkdir", "--strict", root, "a"],
+        &["mkdir", "--mode", "abc", root, "a"],
+        &["join", "--mode", "755", root, "a"],
+        &["join", "--unknown", root, "a"],
+    ]
+    .iter()
+    {
+        assert_eq!(safe_path(args).status.code(), Some(2));
+    }
+}
+
+#[test]
+fn test_cli_non_utf8() {
+    let rootfs_dir = tempfile::tempdir().expect("failed to create tmpdir");
+    let rootfs_path = rootfs_dir.path();
+    let name = OsStr::from_bytes(b"a\xff");
+    let mut expected = rootfs_path.join(name).into_os_string().into_vec();
+    expected.push(b'\n');
+
+    for cmd in ["mkdir", "join"].iter() {
+        let output = Command::new(env!("CARGO_BIN_EXE_safe-path"))
+            .arg(cmd)
+            .arg(rootfs_path)
+            .arg(name)
+            .output()
+            .expect("failed to run safe-path");
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(output.stdout, expected);
+    }
+    assert!(rootfs_path.join(name).is_dir());
+}
@@ -0,0 +1,21 @@
+// Copyright (c) 2022 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use safe_path::{force_backend, resolver_info, Backend};
+
+// Forcing the backend affects the whole process, so it's tested in its own test binary instead
+// of racing with the unit tests running in parallel.
+#[test]
+fn test_force_backend() {
+    let default = resolver_info().backend;
+    force_backend(Some(Backend::ProcReadlink));
+    assert_eq!(resolver_info().backend, Backend::ProcReadlink);
+    let backend = std::thread::spawn(|| resolver_info().backend)
+        .join()
+        .unwrap();
+    assert_eq!(backend, Backend::ProcReadlink);
+    force_backend(None);
+    assert_eq!(resolver_info().backend, default);
+}